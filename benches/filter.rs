@@ -0,0 +1,86 @@
+use std::hint::black_box;
+
+use criterion::{BenchmarkGroup, BenchmarkId, Criterion, criterion_group, criterion_main};
+use slq::client::{Departure, DepartureFilter, Line};
+use slq::types::TransportMode;
+use time::macros::datetime;
+
+const MODES: [TransportMode; 4] = [
+    TransportMode::Bus,
+    TransportMode::Metro,
+    TransportMode::Train,
+    TransportMode::Tram,
+];
+
+const DESTINATIONS: [&str; 5] = [
+    "Mörby centrum",
+    "Fruängen",
+    "Södertälje centrum",
+    "Österskär",
+    "Ropsten",
+];
+
+/// A departure board with `size` rows cycling through a handful of lines,
+/// modes and destinations.
+fn board(size: usize) -> Vec<Departure> {
+    (0..size)
+        .map(|i| Departure {
+            destination: DESTINATIONS[i % DESTINATIONS.len()].to_string(),
+            expected: datetime!(2025-11-02 11:14:02 +1),
+            line: Line {
+                designation: format!("{}{}", 10 + i % 40, if i % 7 == 0 { "s" } else { "" }),
+                transport_mode: MODES[i % MODES.len()],
+                id: None,
+                group_of_lines: None,
+                transport_authority_id: None,
+            },
+            stop_point: None,
+            journey: None,
+            scheduled: None,
+            state: None,
+            display: None,
+            direction: None,
+            direction_code: None,
+            stop_area: None,
+        })
+        .collect()
+}
+
+fn filter_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("filter_departures");
+    for size in [1_000, 5_000, 10_000] {
+        let departures = board(size);
+        // Filtering borrows the board, so no iteration pays for cloning it
+        let bench = |name: &str, filter: DepartureFilter, group: &mut BenchmarkGroup<_>| {
+            group.bench_with_input(BenchmarkId::new(name, size), &departures, |b, d| {
+                b.iter(|| filter.apply(black_box(d)).count())
+            });
+        };
+
+        bench(
+            "line",
+            DepartureFilter::default().line(Some("28".to_string())),
+            &mut group,
+        );
+
+        bench(
+            "destination",
+            DepartureFilter::default().destination(Some("sodertalje")),
+            &mut group,
+        );
+
+        bench(
+            "combined",
+            DepartureFilter::default()
+                .line(Some("1".to_string()))
+                .count(Some(20))
+                .mode(Some(TransportMode::Metro))
+                .destination(Some("fruangen")),
+            &mut group,
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, filter_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,130 @@
+//! Picking which of a few stops within walking distance to head for, to get
+//! somewhere else as early as possible.
+
+use serde::Serialize;
+use time::{Duration, OffsetDateTime};
+
+use crate::client::PlannedJourney;
+use crate::clock::Clock;
+use crate::dates::sl_datetime;
+use crate::types::SiteId;
+
+/// A stop that could be walked to
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CandidateStop {
+    pub site_id: SiteId,
+    pub name: String,
+    pub walk_minutes: i64,
+}
+
+/// The journey from a stop that arrives first, out of those that can still
+/// be caught after walking there
+#[derive(Serialize, Clone, Debug)]
+pub struct Choice {
+    #[serde(flatten)]
+    pub stop: CandidateStop,
+    #[serde(with = "sl_datetime")]
+    pub departs: OffsetDateTime,
+    #[serde(with = "sl_datetime")]
+    pub arrives: OffsetDateTime,
+    /// The line of the first ride, e.g. "43"
+    pub line: Option<String>,
+    pub journey: PlannedJourney,
+}
+
+/// The earliest arriving of `journeys` from `stop` that leaves no sooner
+/// than it takes to walk there. `None` when every one leaves too soon.
+pub fn earliest_arrival(
+    stop: &CandidateStop,
+    journeys: &[PlannedJourney],
+    clock: &dyn Clock,
+) -> Option<Choice> {
+    let there = clock.now() + Duration::minutes(stop.walk_minutes);
+    journeys
+        .iter()
+        .filter_map(|journey| Some((journey, journey.departure()?, journey.arrival()?)))
+        .filter(|(_, departs, _)| *departs >= there)
+        .min_by_key(|(_, departs, arrives)| (*arrives, *departs))
+        .map(|(journey, departs, arrives)| Choice {
+            stop: stop.clone(),
+            departs,
+            arrives,
+            line: journey.first_line().map(str::to_string),
+            journey: journey.clone(),
+        })
+}
+
+/// Choices in the order to recommend them: earliest arrival first, then the
+/// shorter walk, then the later departure, since waiting beats hurrying
+pub fn rank(choices: &mut [Choice]) {
+    choices.sort_by(|a, b| {
+        a.arrives
+            .cmp(&b.arrives)
+            .then(a.stop.walk_minutes.cmp(&b.stop.walk_minutes))
+            .then(b.departs.cmp(&a.departs))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use time::macros::datetime;
+
+    fn journey(departs: &str, arrives: &str, line: &str) -> PlannedJourney {
+        serde_json::from_value(serde_json::json!({
+            "legs": [{
+                "origin": { "departureTimePlanned": departs },
+                "destination": { "arrivalTimePlanned": arrives },
+                "transportation": { "disassembledName": line }
+            }]
+        }))
+        .unwrap()
+    }
+
+    fn stop(site_id: u32, walk_minutes: i64) -> CandidateStop {
+        CandidateStop {
+            site_id: SiteId::from(site_id),
+            name: format!("Stop {site_id}"),
+            walk_minutes,
+        }
+    }
+
+    #[test]
+    fn journeys_leaving_before_the_walk_is_done_are_missed() {
+        let clock = FixedClock(datetime!(2025-11-02 10:00 UTC));
+        let journeys = [
+            journey("2025-11-02T10:03:00Z", "2025-11-02T10:20:00Z", "43"),
+            journey("2025-11-02T10:08:00Z", "2025-11-02T10:25:00Z", "43"),
+            journey("2025-11-02T10:06:00Z", "2025-11-02T10:30:00Z", "55"),
+        ];
+        let choice = earliest_arrival(&stop(1, 5), &journeys, &clock).unwrap();
+        assert_eq!(datetime!(2025-11-02 10:25 UTC), choice.arrives);
+        assert_eq!(Some("43".to_string()), choice.line);
+        assert!(earliest_arrival(&stop(1, 10), &journeys, &clock).is_none());
+    }
+
+    #[test]
+    fn the_earliest_arrival_wins_and_then_the_shorter_walk() {
+        let clock = FixedClock(datetime!(2025-11-02 10:00 UTC));
+        let near = [journey("2025-11-02T10:05:00Z", "2025-11-02T10:30:00Z", "4")];
+        let far = [journey(
+            "2025-11-02T10:10:00Z",
+            "2025-11-02T10:22:00Z",
+            "17",
+        )];
+        let same = [journey(
+            "2025-11-02T10:12:00Z",
+            "2025-11-02T10:22:00Z",
+            "19",
+        )];
+        let mut choices: Vec<Choice> =
+            [(stop(1, 2), &near), (stop(2, 8), &far), (stop(3, 4), &same)]
+                .iter()
+                .filter_map(|(stop, journeys)| earliest_arrival(stop, *journeys, &clock))
+                .collect();
+        rank(&mut choices);
+        let order: Vec<u32> = choices.iter().map(|c| c.stop.site_id.get()).collect();
+        assert_eq!(vec![3, 2, 1], order);
+    }
+}
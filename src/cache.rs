@@ -0,0 +1,396 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Kinds of cached responses. Each lives in its own directory and is kept
+/// around for a different amount of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    /// Departure boards and deviations, which go out of date quickly
+    Responses,
+    /// The site directory, which changes a few times a year
+    Sites,
+}
+
+impl Namespace {
+    pub const ALL: [Namespace; 2] = [Namespace::Responses, Namespace::Sites];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Namespace::Responses => "responses",
+            Namespace::Sites => "sites",
+        }
+    }
+
+    /// Entries older than this are expired and removed by `slq cache gc`
+    pub fn max_age(self) -> Duration {
+        match self {
+            Namespace::Responses => Duration::from_secs(24 * 60 * 60),
+            Namespace::Sites => Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Lookups served from the cache and lookups that went to the network
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Counters {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// What `slq cache stats` reports for one namespace
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NamespaceStats {
+    pub entries: u64,
+    pub bytes: u64,
+    pub expired: u64,
+}
+
+/// On disk cache of API responses, stored under `$SLQ_CACHE_DIR` or
+/// `$XDG_CACHE_HOME/slq` (falling back to `~/.cache/slq`). Every response
+/// body is kept verbatim in a file named after a hash of its URL, and its
+/// age is the file's modification time.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("SLQ_CACHE_DIR") {
+            return Some(PathBuf::from(path));
+        }
+
+        let cache_home = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(cache_home.join("slq"))
+    }
+
+    /// The user's cache, or `None` when there is nowhere to put one.
+    pub fn open() -> Option<Cache> {
+        Cache::path().map(Cache::at)
+    }
+
+    pub fn at(dir: PathBuf) -> Cache {
+        Cache { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Make sure entries can be written, by creating and removing a file.
+    pub fn check_writable(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let probe = self.dir.join(format!(".probe.{}", std::process::id()));
+        fs::write(&probe, b"")
+            .with_context(|| format!("cannot write to {}", self.dir.display()))?;
+        fs::remove_file(&probe).with_context(|| format!("failed to remove {}", probe.display()))
+    }
+
+    fn entry_path(&self, namespace: Namespace, url: &str) -> PathBuf {
+        self.dir
+            .join(namespace.name())
+            .join(format!("{:016x}.json", fnv1a(url.as_bytes())))
+    }
+
+    /// The `ETag` the API sent with the cached body of `url`, if any
+    pub fn etag(&self, namespace: Namespace, url: &str) -> Option<String> {
+        let path = self.entry_path(namespace, url);
+        if !path.exists() {
+            return None;
+        }
+        fs::read_to_string(path.with_extension("etag")).ok()
+    }
+
+    /// Mark the cached body of `url` as fresh again after the API confirmed
+    /// it hasn't changed, and open it.
+    pub fn revalidate(&self, namespace: Namespace, url: &str) -> Option<File> {
+        let path = self.entry_path(namespace, url);
+        let now = SystemTime::now();
+        for path in [path.with_extension("etag"), path.clone()] {
+            File::options()
+                .append(true)
+                .open(path)
+                .ok()?
+                .set_modified(now)
+                .ok()?;
+        }
+        File::open(path).ok()
+    }
+
+    /// Open the cached body of `url` if it is younger than `ttl`. Every
+    /// lookup counts as a hit or a miss in the statistics.
+    pub fn lookup(&self, namespace: Namespace, url: &str, ttl: Duration) -> Option<File> {
+        let path = self.entry_path(namespace, url);
+        let fresh = age(&path).is_some_and(|age| age < ttl);
+        let file = if fresh { File::open(path).ok() } else { None };
+        self.record(file.is_some());
+        file
+    }
+
+    /// Open the cached body of `url` however old it is, as long as it hasn't
+    /// expired, together with its age. Used when the API can't be reached.
+    pub fn lookup_stale(&self, namespace: Namespace, url: &str) -> Option<(File, Duration)> {
+        let path = self.entry_path(namespace, url);
+        let age = age(&path).filter(|age| *age < namespace.max_age())?;
+        Some((File::open(path).ok()?, age))
+    }
+
+    /// Wrap a response body so that it is written to the cache as it is read,
+    /// along with its `ETag`. The entry only replaces the previous one once
+    /// the body has been read to the end.
+    pub fn store<R: Read>(
+        &self,
+        namespace: Namespace,
+        url: &str,
+        body: R,
+        etag: Option<String>,
+    ) -> CachingReader<R> {
+        let path = self.entry_path(namespace, url);
+        let partial = path.with_extension(format!("partial.{}", std::process::id()));
+        let file = path
+            .parent()
+            .and_then(|parent| fs::create_dir_all(parent).ok())
+            .and_then(|_| File::create(&partial).ok());
+        CachingReader {
+            inner: body,
+            file,
+            partial,
+            path,
+            etag,
+        }
+    }
+
+    fn stats_path(&self) -> PathBuf {
+        self.dir.join("stats.json")
+    }
+
+    pub fn counters(&self) -> Counters {
+        fs::read_to_string(self.stats_path())
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Count a hit or a miss. Statistics are best effort, a failure to
+    /// write them never fails the request.
+    fn record(&self, hit: bool) {
+        let mut counters = self.counters();
+        if hit {
+            counters.hits += 1;
+        } else {
+            counters.misses += 1;
+        }
+        if fs::create_dir_all(&self.dir).is_ok()
+            && let Ok(contents) = serde_json::to_string(&counters)
+        {
+            let _ = fs::write(self.stats_path(), contents);
+        }
+    }
+
+    fn entries(&self, namespace: Namespace) -> Vec<PathBuf> {
+        let Ok(dir) = fs::read_dir(self.dir.join(namespace.name())) else {
+            return Vec::new();
+        };
+        dir.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
+    pub fn stats(&self, namespace: Namespace) -> NamespaceStats {
+        let mut stats = NamespaceStats::default();
+        for path in self.entries(namespace) {
+            stats.bytes += fs::metadata(&path).map(|m| m.len()).unwrap_or_default();
+            // ETags and unfinished downloads only count towards the size
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            stats.entries += 1;
+            if age(&path).is_none_or(|age| age >= namespace.max_age()) {
+                stats.expired += 1;
+            }
+        }
+        stats
+    }
+
+    /// Remove every cached response and reset the statistics. Returns the
+    /// number of bytes freed.
+    pub fn clear(&self) -> Result<u64> {
+        let mut freed = 0;
+        for namespace in Namespace::ALL {
+            freed += self.stats(namespace).bytes;
+            let dir = self.dir.join(namespace.name());
+            if dir.exists() {
+                fs::remove_dir_all(&dir)
+                    .with_context(|| format!("failed to remove {}", dir.display()))?;
+            }
+        }
+        let stats = self.stats_path();
+        if stats.exists() {
+            fs::remove_file(&stats)
+                .with_context(|| format!("failed to remove {}", stats.display()))?;
+        }
+        Ok(freed)
+    }
+
+    /// Remove expired entries, including leftovers from interrupted downloads.
+    /// Returns the number of entries removed and the bytes freed.
+    pub fn gc(&self) -> Result<(u64, u64)> {
+        let (mut removed, mut freed) = (0, 0);
+        for namespace in Namespace::ALL {
+            for path in self.entries(namespace) {
+                if age(&path).is_some_and(|age| age < namespace.max_age()) {
+                    continue;
+                }
+                let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or_default();
+                fs::remove_file(&path)
+                    .with_context(|| format!("failed to remove {}", path.display()))?;
+                removed += 1;
+                freed += bytes;
+            }
+        }
+        Ok((removed, freed))
+    }
+}
+
+/// A reader that copies everything read through it into a cache entry
+pub struct CachingReader<R> {
+    inner: R,
+    file: Option<File>,
+    partial: PathBuf,
+    path: PathBuf,
+    etag: Option<String>,
+}
+
+impl<R: Read> Read for CachingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(file) = &mut self.file {
+            if read == 0 && !buf.is_empty() {
+                // End of the body, the entry is complete
+                if fs::rename(&self.partial, &self.path).is_ok() {
+                    self.file = None;
+                    let etag_path = self.path.with_extension("etag");
+                    let _ = match &self.etag {
+                        Some(etag) => fs::write(etag_path, etag),
+                        None => fs::remove_file(etag_path),
+                    };
+                }
+            } else if file.write_all(&buf[..read]).is_err() {
+                self.file = None;
+                let _ = fs::remove_file(&self.partial);
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl<R> Drop for CachingReader<R> {
+    fn drop(&mut self) {
+        // The body wasn't read to the end, don't keep half a response
+        if self.file.is_some() {
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+fn age(path: &Path) -> Option<Duration> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    SystemTime::now().duration_since(modified).ok()
+}
+
+/// 64 bit FNV-1a, stable across Rust releases unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Byte counts for people, e.g. "1.5 MiB"
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cache(name: &str) -> Cache {
+        let dir = env::temp_dir().join(format!("slq-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Cache::at(dir)
+    }
+
+    #[test]
+    fn bodies_are_cached_once_read_to_the_end() -> Result<()> {
+        let cache = temp_cache("store");
+        let url = "https://example.com/departures";
+        let ttl = Duration::from_secs(60);
+
+        let mut partial = cache.store(Namespace::Responses, url, &b"{\"departures\":[]}"[..], None);
+        partial.read_exact(&mut [0; 4])?;
+        drop(partial);
+        assert!(cache.lookup(Namespace::Responses, url, ttl).is_none());
+
+        let mut body = String::new();
+        cache
+            .store(
+                Namespace::Responses,
+                url,
+                &b"{\"departures\":[]}"[..],
+                Some("\"v1\"".to_string()),
+            )
+            .read_to_string(&mut body)?;
+        assert_eq!(
+            Some("\"v1\"".to_string()),
+            cache.etag(Namespace::Responses, url)
+        );
+        let mut cached = String::new();
+        cache
+            .lookup(Namespace::Responses, url, ttl)
+            .expect("cached body")
+            .read_to_string(&mut cached)?;
+        assert_eq!(body, cached);
+
+        let counters = cache.counters();
+        assert_eq!((1, 1), (counters.hits, counters.misses));
+        assert!(
+            cache
+                .lookup(Namespace::Responses, url, Duration::ZERO)
+                .is_none()
+        );
+        assert!(cache.lookup_stale(Namespace::Responses, url).is_some());
+        assert!(cache.revalidate(Namespace::Responses, url).is_some());
+        assert!(cache.lookup(Namespace::Responses, url, ttl).is_some());
+        assert_eq!(1, cache.stats(Namespace::Responses).entries);
+
+        cache.clear()?;
+        assert_eq!(0, cache.stats(Namespace::Responses).entries);
+        Ok(())
+    }
+
+    #[test]
+    fn bytes_are_shown_in_binary_units() {
+        assert_eq!("512 B", human_bytes(512));
+        assert_eq!("1.5 KiB", human_bytes(1536));
+        assert_eq!("2.0 MiB", human_bytes(2 * 1024 * 1024));
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt;
+
+use time::{Duration, OffsetDateTime, UtcOffset};
+
+use crate::cache::{Cache, Namespace, human_bytes};
+use crate::client::SlClient;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::dates::Timezone;
+use crate::query::MAX_WALK_MINUTES;
+
+/// How far the local clock may drift from SL's before wait times are
+/// noticeably wrong
+const MAX_CLOCK_SKEW: Duration = Duration::seconds(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        f.pad(label)
+    }
+}
+
+/// The outcome of checking a single config entry
+pub struct Finding {
+    pub severity: Severity,
+    pub subject: String,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, subject: String, message: impl Into<String>) -> Finding {
+        Finding {
+            severity,
+            subject,
+            message: message.into(),
+        }
+    }
+}
+
+/// Render an error together with everything that caused it
+fn describe(err: impl Into<anyhow::Error>) -> String {
+    format!("{:#}", err.into())
+}
+
+/// Verify aliases, saved queries and walking times against current SL data.
+pub fn check_config(client: &SlClient, config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for (alias, site_id) in &config.aliases {
+        let subject = format!("alias '{alias}'");
+        let finding = match client.get_site(*site_id) {
+            Ok(Some(site)) => Finding::new(
+                Severity::Ok,
+                subject,
+                format!("{} ({})", site.name, site.id),
+            ),
+            Ok(None) => Finding::new(
+                Severity::Error,
+                subject,
+                format!(
+                    "site {site_id} no longer exists, find its new id with `slq search <name>`"
+                ),
+            ),
+            Err(err) => Finding::new(Severity::Error, subject, describe(err)),
+        };
+        findings.push(finding);
+    }
+
+    for (name, saved) in &config.queries {
+        let subject = format!("query '{name}'");
+        if let Err(err) = saved.query().validate() {
+            findings.push(Finding::new(Severity::Error, subject, describe(err)));
+            continue;
+        }
+
+        let station = match config.resolve_station(client, &saved.station) {
+            Ok(Some(station)) => station,
+            Ok(None) => {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    subject,
+                    format!(
+                        "station '{}' not found, check the spelling or add an alias",
+                        saved.station
+                    ),
+                ));
+                continue;
+            }
+            Err(err) => {
+                findings.push(Finding::new(Severity::Error, subject, describe(err)));
+                continue;
+            }
+        };
+
+        let Some(line) = &saved.line else {
+            findings.push(Finding::new(Severity::Ok, subject, station.name));
+            continue;
+        };
+
+        let mut request = client.departures(station.id).line(line.as_str()).count(1);
+        if let Some(mode) = saved.transport_mode {
+            request = request.mode(mode);
+        }
+        let departures = request.fetch();
+        let finding = match departures {
+            Ok(departures) if departures.is_empty() => Finding::new(
+                Severity::Warning,
+                subject,
+                format!(
+                    "line {line} has no upcoming departures from {}, it may have been renumbered or rerouted (see `slq site {}`)",
+                    station.name, station.id
+                ),
+            ),
+            Ok(_) => Finding::new(
+                Severity::Ok,
+                subject,
+                format!("line {line} from {}", station.name),
+            ),
+            Err(err) => Finding::new(
+                Severity::Warning,
+                subject,
+                format!("could not verify line {line}: {}", describe(err)),
+            ),
+        };
+        findings.push(finding);
+    }
+
+    for (station, minutes) in &config.walk_minutes {
+        let subject = format!("walk_minutes '{station}'");
+        let finding = if *minutes < 0 {
+            Finding::new(Severity::Error, subject, "walking time cannot be negative")
+        } else if *minutes > MAX_WALK_MINUTES {
+            Finding::new(
+                Severity::Error,
+                subject,
+                format!(
+                    "{minutes} minutes would hide every departure, use at most {MAX_WALK_MINUTES}"
+                ),
+            )
+        } else if *minutes == 0 {
+            Finding::new(Severity::Warning, subject, "0 minutes has no effect")
+        } else {
+            match config.resolve_station(client, station) {
+                Ok(Some(resolved)) => Finding::new(
+                    Severity::Ok,
+                    subject,
+                    format!("{minutes} minutes to {}", resolved.name),
+                ),
+                Ok(None) => Finding::new(
+                    Severity::Error,
+                    subject,
+                    "not an alias, site id or known station name",
+                ),
+                Err(err) => Finding::new(Severity::Error, subject, describe(err)),
+            }
+        };
+        findings.push(finding);
+    }
+
+    findings
+}
+
+/// Check everything slq depends on: the SL APIs, the config file, the cache,
+/// and the clock and timezone that wait times are calculated with.
+pub fn doctor(client: &SlClient) -> Vec<Finding> {
+    let clock = SystemClock;
+    let mut findings = Vec::new();
+
+    let mut server_time = None;
+    for (name, url) in client.endpoints() {
+        let finding = match client.ping(&url) {
+            Ok(ping) => {
+                server_time = server_time.or(ping.server_time);
+                Finding::new(
+                    Severity::Ok,
+                    name.to_string(),
+                    format!("reachable, answered in {}ms", ping.elapsed.as_millis()),
+                )
+            }
+            Err(err) => Finding::new(Severity::Error, name.to_string(), describe(err)),
+        };
+        findings.push(finding);
+    }
+    findings.push(check_clock(clock.now(), server_time));
+    findings.push(check_timezone(
+        clock.now(),
+        UtcOffset::current_local_offset().ok(),
+    ));
+
+    let subject = "config".to_string();
+    match (Config::path(), Config::load()) {
+        (Some(path), Ok(config)) if path.exists() => {
+            findings.push(Finding::new(
+                Severity::Ok,
+                subject,
+                path.display().to_string(),
+            ));
+            findings.extend(check_config(client, &config));
+        }
+        (_, Ok(_)) => findings.push(Finding::new(
+            Severity::Ok,
+            subject,
+            "no config file, using defaults",
+        )),
+        (_, Err(err)) => findings.push(Finding::new(Severity::Error, subject, describe(err))),
+    }
+
+    let subject = "cache".to_string();
+    let finding = match Cache::open() {
+        None => Finding::new(
+            Severity::Warning,
+            subject,
+            "no cache directory, set SLQ_CACHE_DIR or HOME to avoid refetching everything",
+        ),
+        Some(cache) => match cache.check_writable() {
+            Ok(()) => {
+                let (entries, bytes) = Namespace::ALL
+                    .iter()
+                    .map(|namespace| cache.stats(*namespace))
+                    .fold((0, 0), |(entries, bytes), stats| {
+                        (entries + stats.entries, bytes + stats.bytes)
+                    });
+                Finding::new(
+                    Severity::Ok,
+                    subject,
+                    format!(
+                        "{}, {entries} response(s) using {}",
+                        cache.dir().display(),
+                        human_bytes(bytes)
+                    ),
+                )
+            }
+            Err(err) => Finding::new(Severity::Error, subject, describe(err)),
+        },
+    };
+    findings.push(finding);
+
+    findings
+}
+
+/// Compare the local clock with the time SL's servers reported.
+fn check_clock(now: OffsetDateTime, server_time: Option<OffsetDateTime>) -> Finding {
+    let subject = "clock".to_string();
+    let Some(server_time) = server_time else {
+        return Finding::new(
+            Severity::Warning,
+            subject,
+            "could not compare with SL's clock, no API answered",
+        );
+    };
+
+    let skew = now - server_time;
+    if skew.abs() > MAX_CLOCK_SKEW {
+        Finding::new(
+            Severity::Warning,
+            subject,
+            format!(
+                "{}s {} SL's clock, wait times will be off by as much",
+                skew.whole_seconds().abs(),
+                if skew.is_positive() {
+                    "ahead of"
+                } else {
+                    "behind"
+                }
+            ),
+        )
+    } else {
+        Finding::new(Severity::Ok, subject, "in sync with SL's clock")
+    }
+}
+
+/// SL's timestamps carry no timezone and are read as Stockholm time. Point
+/// out when the machine's timezone differs, since times are then shown in
+/// Stockholm time rather than the local one unless `--timezone` is given.
+fn check_timezone(now: OffsetDateTime, local: Option<UtcOffset>) -> Finding {
+    let subject = "timezone".to_string();
+    let stockholm = Timezone::sl().offset_at(now);
+    let message = match local {
+        None => format!(
+            "could not determine the local timezone, times are shown in Stockholm time ({})",
+            utc_offset(stockholm)
+        ),
+        Some(local) if local != stockholm => format!(
+            "local time is {} while Stockholm is on {}, times are shown in Stockholm time unless --timezone is given",
+            utc_offset(local),
+            utc_offset(stockholm)
+        ),
+        Some(local) => format!("{}, same as Stockholm", utc_offset(local)),
+    };
+    Finding::new(Severity::Ok, subject, message)
+}
+
+/// An offset the way people write it, e.g. "UTC+02:00"
+fn utc_offset(offset: UtcOffset) -> String {
+    format!(
+        "UTC{:+03}:{:02}",
+        offset.whole_hours(),
+        offset.minutes_past_hour().abs()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{datetime, offset};
+
+    #[test]
+    fn timezones_other_than_stockholm_are_pointed_out() {
+        let now = datetime!(2025-07-01 12:00 UTC);
+        let finding = check_timezone(now, Some(offset!(+2)));
+        assert_eq!(Severity::Ok, finding.severity);
+        assert_eq!("UTC+02:00, same as Stockholm", finding.message);
+
+        let finding = check_timezone(now, Some(offset!(UTC)));
+        assert_eq!(Severity::Ok, finding.severity);
+        assert!(finding.message.contains("UTC+00:00"));
+        assert!(finding.message.contains("UTC+02:00"));
+    }
+
+    #[test]
+    fn clock_skew_is_flagged() {
+        let now = datetime!(2025-07-01 12:00 UTC);
+        let server = now - Duration::seconds(10);
+        assert_eq!(Severity::Ok, check_clock(now, Some(server)).severity);
+
+        let finding = check_clock(now, Some(now + Duration::minutes(2)));
+        assert_eq!(Severity::Warning, finding.severity);
+        assert!(finding.message.starts_with("120s behind"));
+    }
+}
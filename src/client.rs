@@ -1,185 +1,2257 @@
-use anyhow::{Context, Result};
-use deunicode::deunicode;
-use reqwest::blocking::Client;
-use serde::Deserialize;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::types::TransportMode;
+use deunicode::{deunicode, deunicode_with_tofu_cow};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, Request};
+use reqwest::header::{DATE, ETAG, IF_NONE_MATCH};
+use reqwest::{Certificate, Proxy, Url};
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+use time::format_description::well_known::{Rfc2822, Rfc3339};
+use tracing::{debug, trace};
+
+use crate::cache::{Cache, Namespace};
+use crate::clock::Clock;
+use crate::dates::{parse_sl_datetime, sl_datetime};
+use crate::fixtures::{Fixtures, RecordingTransport, ReplayTransport};
+use crate::i18n::line_group_name;
+use crate::notice;
+use crate::types::{Language, SiteId, TransportMode};
 
 const BUNDLED_SITES_JSON: &str = include_str!("../data/sites.json");
+/// Where the SL Transport API lives unless `--api-base-url` says otherwise
+pub const DEFAULT_API_BASE: &str = "https://transport.integration.sl.se/v1";
+const DEVIATIONS_URL: &str = "https://deviations.integration.sl.se/v1/messages";
+const JOURNEY_PLANNER_URL: &str = "https://journeyplanner.integration.sl.se/v2/trips";
+const STOP_FINDER_URL: &str = "https://journeyplanner.integration.sl.se/v2/stop-finder";
 
-#[derive(Deserialize, Clone, Debug)]
+/// How long cached responses are used before asking the API again
+const DEPARTURES_TTL: Duration = Duration::from_secs(15);
+const DEVIATIONS_TTL: Duration = Duration::from_secs(5 * 60);
+const SITES_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const JOURNEYS_TTL: Duration = Duration::from_secs(60);
+
+/// Everything that can go wrong when talking to the SL APIs
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The request never got a response, e.g. no connectivity or a timeout
+    #[error("could not reach the SL API")]
+    Network(#[source] reqwest::Error),
+    /// The API answered with an unexpected HTTP status
+    #[error("the SL API responded with HTTP {status}: {message}")]
+    Status { status: u16, message: String },
+    /// The API is down, usually for maintenance
+    #[error("the SL API is temporarily unavailable (HTTP {status}), try again later")]
+    Unavailable { status: u16 },
+    /// The response or the bundled site snapshot couldn't be parsed
+    #[error("could not understand the SL API response")]
+    Decode(#[source] serde_json::Error),
+    /// The requested resource doesn't exist, e.g. an unknown site id
+    #[error("{0} was not found")]
+    NotFound(String),
+    /// Too many requests were made in a short time
+    #[error("the SL API is rate limiting requests, try again in a moment")]
+    RateLimited,
+    /// The proxy or CA certificate settings are unusable
+    #[error("could not set up the HTTP client")]
+    Setup(#[source] reqwest::Error),
+    /// `--dry-run` stopped a request from being sent
+    #[error("dry run, the request was not sent")]
+    DryRun,
+    /// A response couldn't be recorded to or replayed from a fixture file
+    #[error("could not use the fixture {}", path.display())]
+    Fixture {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl ClientError {
+    /// Failures that are likely to go away when the request is repeated
+    fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Network(_) | ClientError::Unavailable { .. } => true,
+            ClientError::Status { status, .. } => *status >= 500,
+            ClientError::Decode(_)
+            | ClientError::NotFound(_)
+            | ClientError::RateLimited
+            | ClientError::Setup(_)
+            | ClientError::DryRun
+            | ClientError::Fixture { .. } => false,
+        }
+    }
+}
+
+pub type Result<T, E = ClientError> = std::result::Result<T, E>;
+
+/// How often and how patiently transient failures are retried
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every retry after it
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 2,
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Longest pause between two attempts, however many retries came before
+    const MAX_DELAY: Duration = Duration::from_secs(30);
+
+    /// Pause before retry number `attempt` (starting at 0). `jitter` in
+    /// `0.0..1.0` spreads the pause over the upper half of the backoff window
+    /// so clients that failed together don't retry together.
+    fn backoff(&self, attempt: u32, jitter: f64) -> Duration {
+        let window = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(Self::MAX_DELAY);
+        window.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// How many requests per minute a client sends at most, with short
+/// bursts of up to `burst` requests allowed
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            per_minute: 300,
+            burst: 20,
+        }
+    }
+}
+
+/// Token bucket refilled at the rate limit. Tokens can be borrowed ahead,
+/// which makes later requests wait longer, so waiting callers are served in
+/// the order they asked.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: f64::from(limit.burst.max(1)),
+            per_second: f64::from(limit.per_minute.max(1)) / 60.0,
+            tokens: f64::from(limit.burst.max(1)),
+            updated: now,
+        }
+    }
+
+    /// Take a token and return how long to wait before using it.
+    fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_second)
+        }
+    }
+}
+
+/// How requests reach the network. `HTTP_PROXY`, `HTTPS_PROXY` and
+/// `NO_PROXY` are honored unless a proxy is given explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// Proxy URL used for all requests, e.g. `http://proxy.example.com:3128`
+    pub proxy: Option<String>,
+    /// PEM encoded certificates to trust in addition to the system ones
+    pub ca_certificates: Option<Vec<u8>>,
+}
+
+/// Everything an [`SlClient`] can be configured with
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    /// Base URL of the Transport API, e.g. a staging mirror or a mock
+    pub api_base: String,
+    pub retry: RetryPolicy,
+    pub rate_limit: RateLimit,
+    pub http: HttpOptions,
+    /// Print requests instead of sending them
+    pub dry_run: bool,
+    /// Record responses to, or replay them from, a directory
+    pub fixtures: Option<Fixtures>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        ClientSettings {
+            api_base: DEFAULT_API_BASE.to_string(),
+            retry: RetryPolicy::default(),
+            rate_limit: RateLimit::default(),
+            http: HttpOptions::default(),
+            dry_run: false,
+            fixtures: None,
+        }
+    }
+}
+
+/// A response to a [`TransportApi`] request. The body is left unread so it
+/// can be decoded as a stream.
+pub struct ApiResponse {
+    pub status: StatusCode,
+    /// The `ETag` header, for revalidating a cached copy later
+    pub etag: Option<String>,
+    /// The `Date` header, the API's own clock
+    pub date: Option<String>,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl ApiResponse {
+    fn text(self) -> String {
+        let mut text = String::new();
+        let mut body = self.body;
+        body.read_to_string(&mut text).ok();
+        text
+    }
+}
+
+/// How an [`SlClient`] GETs a URL. Retries, rate limiting and caching are
+/// layered on top, so an implementation only has to answer one request:
+/// [`HttpTransport`] sends it to SL, [`FixtureTransport`] answers it from
+/// canned responses.
+pub trait TransportApi: Send + Sync {
+    /// GET `url`, conditionally when an `etag` is given. HTTP error statuses
+    /// are responses like any other, only failing to get one is an error.
+    fn get(&self, url: &str, etag: Option<&str>) -> Result<ApiResponse>;
+}
+
+/// Requests sent over the network with reqwest
+pub struct HttpTransport {
+    http: Client,
+}
+
+impl HttpTransport {
+    pub fn new(http: Client) -> HttpTransport {
+        HttpTransport { http }
+    }
+}
+
+impl TransportApi for HttpTransport {
+    fn get(&self, url: &str, etag: Option<&str>) -> Result<ApiResponse> {
+        let mut request = self.http.get(url);
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let res = request.send().map_err(ClientError::Network)?;
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Ok(ApiResponse {
+            status: res.status(),
+            etag: header(ETAG),
+            date: header(DATE),
+            body: Box::new(res),
+        })
+    }
+}
+
+/// Canned responses keyed by URL, so the client can be tested without
+/// reaching SL. Unknown URLs get a `404 Not Found`.
+#[derive(Debug, Clone, Default)]
+pub struct FixtureTransport {
+    responses: HashMap<String, (StatusCode, String)>,
+}
+
+impl FixtureTransport {
+    pub fn new() -> FixtureTransport {
+        FixtureTransport::default()
+    }
+
+    /// Answer GET requests for `url` with `body` and a `200 OK`
+    pub fn with(self, url: &str, body: impl Into<String>) -> FixtureTransport {
+        self.with_status(url, StatusCode::OK, body)
+    }
+
+    /// Answer GET requests for `url` with `body` and `status`
+    pub fn with_status(
+        mut self,
+        url: &str,
+        status: StatusCode,
+        body: impl Into<String>,
+    ) -> FixtureTransport {
+        self.responses
+            .insert(url.to_string(), (status, body.into()));
+        self
+    }
+}
+
+impl TransportApi for FixtureTransport {
+    fn get(&self, url: &str, _etag: Option<&str>) -> Result<ApiResponse> {
+        let (status, body) = self.responses.get(url).cloned().unwrap_or_else(|| {
+            let message = format!(r#"{{"message": "no fixture for {url}"}}"#);
+            (StatusCode::NOT_FOUND, message)
+        });
+        Ok(ApiResponse {
+            status,
+            etag: None,
+            date: None,
+            body: Box::new(io::Cursor::new(body)),
+        })
+    }
+}
+
+/// Long lived handle on the SL APIs. It owns the HTTP client, so commands
+/// that poll, like `wait`, keep their connections alive between requests,
+/// along with the retry policy, rate limiter and response cache.
+pub struct SlClient {
+    http: Client,
+    transport: Box<dyn TransportApi>,
+    api_base: String,
+    retry: RetryPolicy,
+    limiter: Mutex<TokenBucket>,
+    cache: Option<Cache>,
+    dry_run: bool,
+}
+
+impl SlClient {
+    pub fn new(settings: ClientSettings) -> Result<SlClient> {
+        let http = http_client(&settings.http)?;
+        let transport: Box<dyn TransportApi> = match &settings.fixtures {
+            None => Box::new(HttpTransport::new(http.clone())),
+            Some(Fixtures::Record(dir)) => Box::new(RecordingTransport::new(
+                HttpTransport::new(http.clone()),
+                dir.clone(),
+            )),
+            Some(Fixtures::Replay(dir)) => Box::new(ReplayTransport::new(dir.clone())),
+        };
+        // A cached response would hide the request a dry run is meant to
+        // show, and keep responses from being recorded or replayed
+        let cache = Cache::open().filter(|_| !settings.dry_run && settings.fixtures.is_none());
+        Ok(SlClient {
+            cache,
+            ..SlClient::assemble(settings, http, transport)
+        })
+    }
+
+    /// A client whose requests are answered by `transport`, e.g. a
+    /// [`FixtureTransport`] in tests. Responses aren't cached, so every
+    /// request reaches the transport.
+    pub fn with_transport(
+        settings: ClientSettings,
+        transport: impl TransportApi + 'static,
+    ) -> Result<SlClient> {
+        let http = http_client(&settings.http)?;
+        Ok(SlClient::assemble(settings, http, Box::new(transport)))
+    }
+
+    fn assemble(
+        settings: ClientSettings,
+        http: Client,
+        transport: Box<dyn TransportApi>,
+    ) -> SlClient {
+        SlClient {
+            http,
+            transport,
+            api_base: settings.api_base.trim_end_matches('/').to_string(),
+            retry: settings.retry,
+            limiter: Mutex::new(TokenBucket::new(settings.rate_limit, Instant::now())),
+            cache: None,
+            dry_run: settings.dry_run,
+        }
+    }
+
+    /// The underlying HTTP client, for other requests that should go through
+    /// the same proxy and certificates, like notifications
+    pub fn http(&self) -> &Client {
+        &self.http
+    }
+
+    /// Whether requests are printed instead of sent
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// The APIs slq depends on, each with a cheap request to check it with
+    pub fn endpoints(&self) -> [(&'static str, String); 2] {
+        [
+            ("Transport API", self.api_url("/sites/9001/departures")),
+            ("Deviations API", format!("{DEVIATIONS_URL}?site=9001")),
+        ]
+    }
+
+    /// Send a single request to `url`, bypassing the cache and retries, and
+    /// report how long the API took to answer.
+    pub fn ping(&self, url: &str) -> Result<Ping> {
+        let started = Instant::now();
+        let res = self.send_once(url, None)?;
+        let server_time = res
+            .date
+            .and_then(|date| OffsetDateTime::parse(&date, &Rfc2822).ok());
+        Ok(Ping {
+            elapsed: started.elapsed(),
+            server_time,
+        })
+    }
+
+    /// URL of a Transport API endpoint, e.g. `api_url("/sites")`
+    fn api_url(&self, path: &str) -> String {
+        format!("{}{path}", self.api_base)
+    }
+
+    /// Block until the rate limit allows another request.
+    fn throttle(&self) {
+        let wait = self
+            .limiter
+            .lock()
+            .map(|mut bucket| bucket.take(Instant::now()))
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            debug!("rate limit reached, waiting {}ms", wait.as_millis());
+            thread::sleep(wait);
+        }
+    }
+
+    /// GET a URL, retrying transient failures with jittered exponential
+    /// backoff. The body of a successful response is left unread so it can
+    /// be decoded as a stream. With an `etag` the request is conditional,
+    /// and a `304 Not Modified` response counts as success.
+    fn send(&self, url: &str, etag: Option<&str>) -> Result<ApiResponse> {
+        let mut attempt = 0;
+        loop {
+            match self.send_once(url, etag) {
+                Err(err) if err.is_transient() && attempt < self.retry.retries => {
+                    let delay = self.retry.backoff(attempt, jitter());
+                    debug!(
+                        "retry {} of {} for {url} in {}ms: {err}",
+                        attempt + 1,
+                        self.retry.retries,
+                        delay.as_millis()
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// GET a URL once, turning HTTP failures into `ClientError`s.
+    fn send_once(&self, url: &str, etag: Option<&str>) -> Result<ApiResponse> {
+        if self.dry_run {
+            let mut request = self.http.get(url);
+            if let Some(etag) = etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            let request = request.build().map_err(ClientError::Network)?;
+            notice!("{}", describe_request(&request));
+            return Err(ClientError::DryRun);
+        }
+
+        self.throttle();
+        debug!("GET {url}");
+        let started = Instant::now();
+        let res = self.transport.get(url, etag)?;
+
+        let status = res.status;
+        debug!(
+            "{} from {url} after {}ms",
+            status,
+            started.elapsed().as_millis()
+        );
+        if status.is_success() || (etag.is_some() && status == StatusCode::NOT_MODIFIED) {
+            return Ok(res);
+        }
+
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Err(ClientError::RateLimited),
+            StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => Err(ClientError::Unavailable {
+                status: status.as_u16(),
+            }),
+            status => Err(ClientError::Status {
+                status: status.as_u16(),
+                message: api_error_message(&res.text()),
+            }),
+        }
+    }
+
+    /// GET a URL through the response cache. A fresh cached body is read
+    /// from disk, anything else is fetched and written to the cache as it is
+    /// read. Expired bodies with an `ETag` are revalidated with a conditional
+    /// request, so an unchanged response costs the API next to nothing. When
+    /// the API can't be reached, an older cached body is used instead.
+    fn open(
+        &self,
+        url: &str,
+        namespace: Namespace,
+        ttl: Duration,
+    ) -> Result<(Box<dyn Read>, Freshness)> {
+        let Some(cache) = &self.cache else {
+            return Ok((self.send(url, None)?.body, Freshness::Fresh));
+        };
+        if let Some(cached) = cache.lookup(namespace, url, ttl) {
+            debug!("cache hit for {url}");
+            return Ok((Box::new(cached), Freshness::Fresh));
+        }
+
+        let etag = cache.etag(namespace, url);
+        debug!(
+            "cache miss for {url}{}",
+            if etag.is_some() { ", revalidating" } else { "" }
+        );
+        match self.send(url, etag.as_deref()) {
+            Ok(res) if res.status == StatusCode::NOT_MODIFIED => {
+                debug!("cached response for {url} is still current");
+                match cache.revalidate(namespace, url) {
+                    Some(cached) => Ok((Box::new(cached), Freshness::Fresh)),
+                    // The entry vanished in the meantime, fetch it again
+                    None => {
+                        let res = self.send(url, None)?;
+                        Ok((
+                            Box::new(cache.store(namespace, url, res.body, None)),
+                            Freshness::Fresh,
+                        ))
+                    }
+                }
+            }
+            Ok(res) => Ok((
+                Box::new(cache.store(namespace, url, res.body, res.etag)),
+                Freshness::Fresh,
+            )),
+            Err(err) if err.is_transient() => match cache.lookup_stale(namespace, url) {
+                Some((cached, age)) => {
+                    debug!("using a {}s old cached response for {url}", age.as_secs());
+                    Ok((Box::new(cached), Freshness::Stale { age }))
+                }
+                None => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// GET a URL through the response cache and decode its JSON body.
+    fn fetch<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        namespace: Namespace,
+        ttl: Duration,
+    ) -> Result<T> {
+        let (body, _) = self.open(url, namespace, ttl)?;
+        serde_json::from_reader(BufReader::new(body)).map_err(ClientError::Decode)
+    }
+}
+
+/// An HTTP client going through the configured proxy and certificates
+fn http_client(options: &HttpOptions) -> Result<Client> {
+    // Nominatim turns away requests that don't say what they come from
+    let mut builder = Client::builder().user_agent(concat!("slq/", env!("CARGO_PKG_VERSION")));
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(Proxy::all(proxy).map_err(ClientError::Setup)?);
+    }
+    if let Some(pem) = &options.ca_certificates {
+        for certificate in Certificate::from_pem_bundle(pem).map_err(ClientError::Setup)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder.build().map_err(ClientError::Setup)
+}
+
+/// The method, URL, query parameters and headers of a request, one per
+/// line, as `--dry-run` prints them.
+pub fn describe_request(request: &Request) -> String {
+    let mut without_query = request.url().clone();
+    without_query.set_query(None);
+    let mut lines = vec![format!("{} {without_query}", request.method())];
+    lines.extend(
+        request
+            .url()
+            .query_pairs()
+            .map(|(name, value)| format!("  ?{name}={value}")),
+    );
+    lines.extend(
+        request
+            .headers()
+            .iter()
+            .map(|(name, value)| format!("  {name}: {}", value.to_str().unwrap_or("<binary>"))),
+    );
+    lines.join("\n")
+}
+
+/// A random number in `0.0..1.0`, good enough to spread out retries.
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// How an API answered a [`SlClient::ping`]
+pub struct Ping {
+    pub elapsed: Duration,
+    /// The API's clock, from the `Date` header
+    pub server_time: Option<OffsetDateTime>,
+}
+
+/// Whether a response is current, or an older copy from the cache used
+/// because the API couldn't be reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    Stale { age: Duration },
+}
+
+/// Pull a readable message out of an error response body, which is JSON
+/// with a `message` field when the API itself produced the error.
+fn api_error_message(body: &str) -> String {
+    const MAX_LEN: usize = 200;
+
+    #[derive(Deserialize)]
+    struct ApiError {
+        message: String,
+    }
+
+    if let Ok(error) = serde_json::from_str::<ApiError>(body) {
+        return error.message;
+    }
+
+    let body = body.trim();
+    if body.is_empty() {
+        "no details given".to_string()
+    } else if body.chars().count() > MAX_LEN {
+        format!("{}…", body.chars().take(MAX_LEN).collect::<String>())
+    } else {
+        body.to_string()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Site {
-    pub id: u32,
+    pub id: SiteId,
     pub name: String,
     pub lat: Option<f64>,
     pub lon: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abbreviation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_areas: Vec<StopArea>,
 }
 
-#[derive(Deserialize, Clone)]
+/// A group of stop points within a site, such as a metro station or a bus terminal
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StopArea {
+    pub id: u32,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub area_type: String,
+}
+
+impl Site {
+    /// Kinds of stop areas at this site, e.g. "metro" or "bus". Only available
+    /// when the site list was fetched with its stop areas expanded.
+    pub fn types(&self) -> Vec<String> {
+        let mut types: Vec<String> = Vec::new();
+        for stop_area in &self.stop_areas {
+            let label = match stop_area.area_type.as_str() {
+                "BUSTERM" => "bus".to_string(),
+                "METROSTN" => "metro".to_string(),
+                "TRAMSTN" => "tram".to_string(),
+                "RAILWSTN" => "train".to_string(),
+                "SHIPBER" => "ship".to_string(),
+                "FERRYBER" => "ferry".to_string(),
+                "AIRPORT" => "airport".to_string(),
+                "TAXITERM" => "taxi".to_string(),
+                other => other.to_lowercase(),
+            };
+            if !types.contains(&label) {
+                types.push(label);
+            }
+        }
+        types
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct Line {
     pub designation: String,
     pub transport_mode: TransportMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    /// E.g. `Tunnelbanans gröna linje` for the metro lines 17, 18 and 19, or
+    /// `Roslagsbanan`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_of_lines: Option<String>,
+    /// Who runs the line, see [`Line::operator`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport_authority_id: Option<u32>,
+}
+
+/// Transport authority of SL's own lines. Lines run for other regions,
+/// like Upptåget at shared stations, have other ids.
+pub const SL_TRANSPORT_AUTHORITY: u32 = 1;
+
+impl Line {
+    /// Who runs the line: `SL`, the id of another transport authority, or
+    /// nothing when the API didn't say
+    pub fn operator(&self) -> Option<String> {
+        self.transport_authority_id.map(|id| match id {
+            SL_TRANSPORT_AUTHORITY => "SL".to_string(),
+            id => id.to_string(),
+        })
+    }
+
+    /// The line as people call it, e.g. "Green line 17" or "Roslagsbanan 28".
+    /// Buses go by their number alone, their groups are just where they run.
+    pub fn name(&self, language: Language) -> String {
+        match &self.group_of_lines {
+            Some(group) if self.transport_mode != TransportMode::Bus => {
+                format!("{} {}", line_group_name(group, language), self.designation)
+            }
+            _ => self.designation.clone(),
+        }
+    }
 }
 
 /// Represents The response that comes back for the destination
 /// for a single trip
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Departure {
     pub destination: String,
-    pub expected: String,
+    /// Departure time in the timetable
+    #[serde(
+        default,
+        with = "sl_datetime::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub scheduled: Option<OffsetDateTime>,
+    #[serde(with = "sl_datetime")]
+    pub expected: OffsetDateTime,
+    /// E.g. `EXPECTED`, `ATSTOP`, `DEPARTED` or `CANCELLED`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    /// The time as SL's own signs show it, e.g. `Nu`, `3 min` or `08:12`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    /// Where the vehicle is heading, usually the same as `destination`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction: Option<String>,
+    /// `1` or `2`, telling the two directions of a line apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction_code: Option<u8>,
     pub line: Line,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_area: Option<StopArea>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_point: Option<StopPoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journey: Option<Journey>,
 }
 
-#[derive(Deserialize, Clone)]
-struct DestinationHttpResult {
-    /// List of destinations
-    departures: Vec<Departure>,
+/// The trip a departure belongs to. The id stays the same on every board
+/// the trip shows up on, so it can be followed along the way.
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Journey {
+    pub id: u64,
+    /// E.g. `EXPECTED`, `NORMALPROGRESS` or `CANCELLED`
+    pub state: Option<String>,
 }
 
-pub fn get_departures(
-    station_name_or_id: &str,
-    line: &Option<String>,
-    count: &Option<usize>,
-    transport_mode: &Option<TransportMode>,
-    destination: &Option<String>,
-) -> Result<Vec<Departure>> {
-    let url = format!(
-        "https://transport.integration.sl.se/v1/sites/{}/departures",
-        station_name_or_id
-    );
+impl Departure {
+    /// Time from now until the departure is expected, negative once it
+    /// has passed
+    pub fn time_until(&self, clock: &dyn Clock) -> time::Duration {
+        self.expected - clock.now()
+    }
+}
 
-    let client = Client::new();
-    let res = client.get(&url).send()?;
+impl Journey {
+    pub fn is_cancelled(&self) -> bool {
+        self.state.as_deref() == Some("CANCELLED")
+    }
+}
 
-    let api_response = res.json::<DestinationHttpResult>()?;
-    let departures = api_response.departures;
+/// A platform, bus stop or quay within a site
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct StopPoint {
+    pub id: u32,
+    pub name: String,
+    pub designation: Option<String>,
+}
 
-    let departures = match line {
-        Some(l) => departures
-            .iter()
-            .filter(|d| d.line.designation.starts_with(l))
-            .cloned()
-            .collect(),
-        None => departures,
-    };
-
-    let departures = match transport_mode {
-        Some(l) => departures
+/// What the upcoming departures of a site tell us about it
+pub struct SiteDetails {
+    pub stop_points: Vec<StopPoint>,
+    pub lines: Vec<Line>,
+}
+
+/// A disruption or planned work published in the deviations API
+#[derive(Deserialize, Clone, Debug)]
+pub struct Deviation {
+    /// Stays the same when SL updates the deviation
+    pub deviation_case_id: Option<u64>,
+    pub publish: Publish,
+    pub message_variants: Vec<MessageVariant>,
+    #[serde(default)]
+    pub scope: DeviationScope,
+    pub priority: Option<Priority>,
+}
+
+/// How much a deviation matters, as ranked by SL
+#[derive(Deserialize, Clone, Debug)]
+pub struct Priority {
+    /// From 1 (barely noticeable) to 9 (severe)
+    pub importance_level: u8,
+}
+
+/// When a deviation is in effect, as RFC 3339 timestamps
+#[derive(Deserialize, Clone, Debug)]
+pub struct Publish {
+    pub from: String,
+    pub upto: Option<String>,
+}
+
+/// The text of a deviation in one language
+#[derive(Deserialize, Clone, Debug)]
+pub struct MessageVariant {
+    pub header: String,
+    pub details: Option<String>,
+    pub language: String,
+}
+
+/// Lines and stop areas a deviation affects
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct DeviationScope {
+    #[serde(default)]
+    pub lines: Vec<DeviationLine>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct DeviationLine {
+    pub designation: String,
+    pub transport_mode: Option<TransportMode>,
+    /// E.g. "Tunnelbanans gröna linje" for the metro lines 17, 18 and 19
+    pub group_of_lines: Option<String>,
+}
+
+impl Deviation {
+    /// The message in `language`, falling back to the first one published.
+    pub fn message(&self, language: &str) -> Option<&MessageVariant> {
+        self.message_variants
             .iter()
-            .filter(|d| d.line.transport_mode == *l)
-            .cloned()
-            .collect(),
-        None => departures,
-    };
-
-    let departures = match destination {
-        Some(dest) => {
-            let query = deunicode(dest).to_lowercase();
-            departures
+            .find(|variant| variant.language == language)
+            .or_else(|| self.message_variants.first())
+    }
+
+    pub fn starts(&self) -> Option<OffsetDateTime> {
+        OffsetDateTime::parse(&self.publish.from, &Rfc3339).ok()
+    }
+
+    pub fn ends(&self) -> Option<OffsetDateTime> {
+        let upto = self.publish.upto.as_ref()?;
+        OffsetDateTime::parse(upto, &Rfc3339).ok()
+    }
+
+    /// Whether the deviation is in effect at some point between `start` and
+    /// `end`. Deviations with unparseable times are kept rather than
+    /// silently dropped.
+    pub fn overlaps(&self, start: OffsetDateTime, end: OffsetDateTime) -> bool {
+        let Some(from) = self.starts() else {
+            return true;
+        };
+        from <= end && self.ends().is_none_or(|upto| upto >= start)
+    }
+
+    /// SL's importance level, with deviations that have none counting as
+    /// the least important
+    pub fn importance(&self) -> u8 {
+        self.priority
+            .as_ref()
+            .map_or(1, |priority| priority.importance_level)
+    }
+
+    /// Whether the deviation affects one of `lines`, or any line when
+    /// `lines` is empty
+    pub fn affects_any(&self, lines: &[String]) -> bool {
+        lines.is_empty()
+            || self
+                .scope
+                .lines
                 .iter()
-                .filter(|d| {
-                    deunicode(d.destination.as_str())
-                        .to_lowercase()
-                        .contains(&query)
-                })
-                .cloned()
-                .collect()
+                .any(|line| lines.contains(&line.designation))
+    }
+
+    /// Designations of the affected lines, e.g. "41, 43"
+    pub fn line_designations(&self) -> String {
+        self.scope
+            .lines
+            .iter()
+            .map(|line| line.designation.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Trips between two stops suggested by SL's journey planner
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct JourneysResponse {
+    journeys: Vec<PlannedJourney>,
+}
+
+/// One way to get from one stop to another, as a series of legs
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedJourney {
+    /// Planned travel time in seconds
+    pub trip_duration: Option<u64>,
+    /// Expected travel time in seconds, taking delays into account
+    pub trip_rt_duration: Option<u64>,
+    pub interchanges: Option<u32>,
+    #[serde(default)]
+    pub legs: Vec<JourneyLeg>,
+}
+
+/// A ride on one vehicle, or a walk between two stops
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JourneyLeg {
+    /// In seconds
+    pub duration: Option<u64>,
+    pub origin: LegStop,
+    pub destination: LegStop,
+    /// Missing for walks
+    pub transportation: Option<Transportation>,
+}
+
+/// Where a leg starts or ends. Times are RFC 3339 in UTC.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LegStop {
+    pub name: Option<String>,
+    pub departure_time_planned: Option<String>,
+    pub departure_time_estimated: Option<String>,
+    pub arrival_time_planned: Option<String>,
+    pub arrival_time_estimated: Option<String>,
+}
+
+/// The line a leg rides on
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Transportation {
+    /// E.g. "Tunnelbana 14" or "Buss 43"
+    pub name: Option<String>,
+    /// The line designation, e.g. "43"
+    pub disassembled_name: Option<String>,
+    pub destination: Option<TransportationDestination>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TransportationDestination {
+    pub name: Option<String>,
+}
+
+impl PlannedJourney {
+    /// When the journey starts, as expected if SL knows of a delay. A walk
+    /// at the start has no times of its own, so it is counted back from the
+    /// leg after it.
+    pub fn departure(&self) -> Option<OffsetDateTime> {
+        let mut walked = 0;
+        for leg in &self.legs {
+            if let Some(time) = leg.origin.time(
+                &leg.origin.departure_time_estimated,
+                &leg.origin.departure_time_planned,
+            ) {
+                return Some(time - time::Duration::seconds(walked));
+            }
+            walked += leg.duration.unwrap_or(0) as i64;
+        }
+        None
+    }
+
+    /// When the journey ends, counting a walk at the end onto the leg
+    /// before it
+    pub fn arrival(&self) -> Option<OffsetDateTime> {
+        let mut walked = 0;
+        for leg in self.legs.iter().rev() {
+            if let Some(time) = leg.destination.time(
+                &leg.destination.arrival_time_estimated,
+                &leg.destination.arrival_time_planned,
+            ) {
+                return Some(time + time::Duration::seconds(walked));
+            }
+            walked += leg.duration.unwrap_or(0) as i64;
+        }
+        None
+    }
+
+    /// The line of the first ride, e.g. "43", `None` when it is all walking
+    pub fn first_line(&self) -> Option<&str> {
+        self.legs
+            .iter()
+            .find_map(|leg| leg.transportation.as_ref()?.disassembled_name.as_deref())
+    }
+}
+
+impl LegStop {
+    /// The estimated time if there is one, else the planned one
+    fn time(&self, estimated: &Option<String>, planned: &Option<String>) -> Option<OffsetDateTime> {
+        estimated
+            .as_deref()
+            .or(planned.as_deref())
+            .and_then(parse_sl_datetime)
+    }
+}
+
+/// The journey planner's id for a site, e.g. `9091001000009192` for 9192
+pub fn global_stop_id(site_id: SiteId) -> String {
+    format!("9091001{:09}", site_id.get())
+}
+
+/// The site a journey planner id belongs to, the reverse of [`global_stop_id`]
+fn site_id_from_global(id: &str) -> Option<SiteId> {
+    let site = id.strip_prefix("9091001").filter(|site| site.len() == 9)?;
+    site.parse::<u32>().ok().map(SiteId::from)
+}
+
+/// Places the journey planner's stop finder suggests for a name
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct StopFinderResponse {
+    locations: Vec<FoundLocation>,
+}
+
+#[derive(Deserialize)]
+struct FoundLocation {
+    id: String,
+    /// The locality the stop is in, which is its municipality
+    parent: Option<FoundLocality>,
+}
+
+#[derive(Deserialize)]
+struct FoundLocality {
+    name: Option<String>,
+}
+
+/// Where an address is, as a geocoder found it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    /// The geocoder's full name for the place, to check it found the right one
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// A result from a Nominatim search, which gives coordinates as strings
+#[derive(Deserialize)]
+struct NominatimPlace {
+    display_name: String,
+    lat: String,
+    lon: String,
+}
+
+impl NominatimPlace {
+    fn place(self) -> Result<Place> {
+        Ok(Place {
+            lat: serde_json::from_str(&self.lat).map_err(ClientError::Decode)?,
+            lon: serde_json::from_str(&self.lon).map_err(ClientError::Decode)?,
+            name: self.display_name,
+        })
+    }
+}
+
+impl SlClient {
+    /// Up to `count` ways to travel between two sites, leaving now.
+    pub fn plan_journey(
+        &self,
+        from: SiteId,
+        to: SiteId,
+        count: usize,
+    ) -> Result<Vec<PlannedJourney>> {
+        let url = format!(
+            "{JOURNEY_PLANNER_URL}?type_origin=any&name_origin={}&type_destination=any&name_destination={}&calc_number_of_trips={count}",
+            global_stop_id(from),
+            global_stop_id(to)
+        );
+        let response: JourneysResponse = self.fetch(&url, Namespace::Responses, JOURNEYS_TTL)?;
+        Ok(response.journeys)
+    }
+}
+
+impl SlClient {
+    /// Deviations in effect right now, across the whole network
+    pub fn get_current_deviations(&self) -> Result<Vec<Deviation>> {
+        self.fetch(DEVIATIONS_URL, Namespace::Responses, DEVIATIONS_TTL)
+    }
+
+    /// Current and future deviations affecting a site, including planned works
+    /// that haven't started yet.
+    pub fn get_planned_deviations(&self, site_id: SiteId) -> Result<Vec<Deviation>> {
+        let mut deviations: Vec<Deviation> = self.fetch(
+            &format!("{DEVIATIONS_URL}?future=true&site={site_id}"),
+            Namespace::Responses,
+            DEVIATIONS_TTL,
+        )?;
+        deviations.sort_by_key(|deviation| deviation.starts());
+        Ok(deviations)
+    }
+}
+
+/// Filters applied to departures while they are decoded
+#[derive(Clone, Default)]
+pub struct DepartureFilter {
+    line: Option<String>,
+    count: Option<usize>,
+    transport_mode: Option<TransportMode>,
+    /// Destination query with accents removed and lowercased
+    destination: Option<String>,
+    /// Name or transport authority id, see [`Line::operator`]
+    operator: Option<String>,
+}
+
+impl DepartureFilter {
+    /// Only lines whose designation starts with `line`, so `28` includes `28s`
+    pub fn line(mut self, line: Option<String>) -> DepartureFilter {
+        self.line = line;
+        self
+    }
+
+    /// At most `count` departures, see [`limit`](DepartureFilter::limit)
+    pub fn count(mut self, count: Option<usize>) -> DepartureFilter {
+        self.count = count;
+        self
+    }
+
+    pub fn mode(mut self, transport_mode: Option<TransportMode>) -> DepartureFilter {
+        self.transport_mode = transport_mode;
+        self
+    }
+
+    /// Only departures towards a destination containing `destination`,
+    /// ignoring case and accents
+    pub fn destination(mut self, destination: Option<&str>) -> DepartureFilter {
+        self.destination = destination.map(|d| deunicode(d).to_lowercase());
+        self
+    }
+
+    /// Only departures of lines run by `operator`, e.g. `SL` or `14`.
+    /// Departures that don't say who runs them never pass.
+    pub fn operator(mut self, operator: Option<String>) -> DepartureFilter {
+        self.operator = operator;
+        self
+    }
+
+    /// Whether a departure passes the line, transport mode and destination
+    /// filters. The count limit is up to the caller.
+    pub fn matches(&self, departure: &Departure) -> bool {
+        self.line
+            .as_ref()
+            .is_none_or(|l| departure.line.designation.starts_with(l.as_str()))
+            && self
+                .transport_mode
+                .is_none_or(|mode| departure.line.transport_mode == mode)
+            && self
+                .destination
+                .as_ref()
+                .is_none_or(|query| folded_contains(&departure.destination, query))
+            && self.operator.as_ref().is_none_or(|operator| {
+                departure
+                    .line
+                    .operator()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(operator))
+            })
+    }
+
+    /// The departures passing the filters, up to the count limit, without
+    /// collecting or cloning them. Works on owned departures as well as on
+    /// references to them.
+    pub fn apply<'a, I>(&'a self, departures: I) -> impl Iterator<Item = I::Item> + 'a
+    where
+        I: IntoIterator + 'a,
+        I::Item: Borrow<Departure>,
+    {
+        departures
+            .into_iter()
+            .filter(|departure| self.matches(departure.borrow()))
+            .take(self.limit())
+    }
+
+    pub fn limit(&self) -> usize {
+        self.count.unwrap_or(usize::MAX)
+    }
+}
+
+/// Whether `text` contains `query`, ignoring case and accents. `query` must
+/// already be folded. Destinations are mostly plain ASCII, so this usually
+/// gets by without allocating.
+fn folded_contains(text: &str, query: &str) -> bool {
+    let text = deunicode_with_tofu_cow(text, "[?]");
+    let query = query.as_bytes();
+    query.is_empty()
+        || text
+            .as_bytes()
+            .windows(query.len())
+            .any(|window| window.eq_ignore_ascii_case(query))
+}
+
+/// A site's departures response, ready to be decoded
+pub struct DepartureBoard {
+    body: Box<dyn Read>,
+    pub freshness: Freshness,
+}
+
+impl DepartureBoard {
+    /// Decode the departures straight from the response body, handing each
+    /// one that passes `filter` to `sink` as it arrives. Departures that are
+    /// filtered out are never kept around, so memory use stays flat no
+    /// matter how large the response is.
+    pub fn for_each(self, filter: &DepartureFilter, mut sink: impl FnMut(Departure)) -> Result<()> {
+        let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(self.body));
+        DeparturesResponse {
+            filter,
+            sink: &mut sink,
         }
-        None => departures,
-    };
+        .deserialize(&mut deserializer)
+        .and_then(|()| deserializer.end())
+        .map_err(ClientError::Decode)
+    }
+
+    /// Copy the response body to `out` exactly as the API sent it.
+    pub fn copy_raw(mut self, out: &mut impl Write) -> io::Result<u64> {
+        io::copy(&mut self.body, out)
+    }
+}
 
-    match count {
-        Some(limit) => {
-            let limited = departures.iter().cloned().take(*limit).collect();
-            Ok(limited)
+/// Departures of one site, narrowed down step by step and fetched with
+/// [`fetch`](DepartureRequest::fetch). Made by [`SlClient::departures`].
+#[must_use = "nothing is fetched until `fetch` or `for_each` is called"]
+pub struct DepartureRequest<'a> {
+    client: &'a SlClient,
+    site_id: SiteId,
+    filter: DepartureFilter,
+    forecast: Option<u32>,
+}
+
+impl DepartureRequest<'_> {
+    /// Only lines whose designation starts with `line`, so `28` includes `28s`
+    pub fn line(mut self, line: impl Into<String>) -> Self {
+        self.filter.line = Some(line.into());
+        self
+    }
+
+    pub fn mode(mut self, transport_mode: TransportMode) -> Self {
+        self.filter.transport_mode = Some(transport_mode);
+        self
+    }
+
+    /// Only departures towards a destination containing `destination`,
+    /// ignoring case and accents
+    pub fn destination(mut self, destination: &str) -> Self {
+        self.filter.destination = Some(deunicode(destination).to_lowercase());
+        self
+    }
+
+    /// Only departures of lines run by `operator`, see [`DepartureFilter::operator`]
+    pub fn operator(mut self, operator: impl Into<String>) -> Self {
+        self.filter.operator = Some(operator.into());
+        self
+    }
+
+    /// At most `count` departures
+    pub fn count(mut self, count: usize) -> Self {
+        self.filter.count = Some(count);
+        self
+    }
+
+    /// How many minutes ahead SL should look, 60 unless asked for
+    pub fn forecast(mut self, minutes: u32) -> Self {
+        self.forecast = Some(minutes);
+        self
+    }
+
+    pub fn fetch(self) -> Result<Vec<Departure>> {
+        let mut departures = Vec::new();
+        self.for_each(|departure| departures.push(departure))?;
+        Ok(departures)
+    }
+
+    /// Hand each matching departure to `sink` as it is decoded, see
+    /// [`DepartureBoard::for_each`]
+    pub fn for_each(self, sink: impl FnMut(Departure)) -> Result<()> {
+        self.client
+            .open_site_departures(self.site_id, self.forecast)?
+            .for_each(&self.filter, sink)
+    }
+}
+
+impl SlClient {
+    /// Start a request for the departures of `site_id`, e.g.
+    /// `client.departures(site).line("43").mode(TransportMode::Bus).count(5).fetch()`
+    pub fn departures(&self, site_id: SiteId) -> DepartureRequest<'_> {
+        DepartureRequest {
+            client: self,
+            site_id,
+            filter: DepartureFilter::default(),
+            forecast: None,
         }
-        None => Ok(departures),
     }
+
+    /// Fetch the departures of a site without decoding them yet, so callers can
+    /// tell whether the data is fresh before showing any of it.
+    pub fn open_departures(&self, site_id: SiteId) -> Result<DepartureBoard> {
+        self.open_site_departures(site_id, None)
+    }
+
+    fn open_site_departures(
+        &self,
+        site_id: SiteId,
+        forecast: Option<u32>,
+    ) -> Result<DepartureBoard> {
+        let url = match forecast {
+            Some(minutes) => {
+                self.api_url(&format!("/sites/{site_id}/departures?forecast={minutes}"))
+            }
+            None => self.api_url(&format!("/sites/{site_id}/departures")),
+        };
+
+        // The API answers unknown or malformed site ids with 400 or 404
+        let (body, freshness) = self
+            .open(&url, Namespace::Responses, DEPARTURES_TTL)
+            .map_err(|err| match err {
+                ClientError::Status {
+                    status: 400 | 404, ..
+                } => ClientError::NotFound(format!("site {site_id}")),
+                err => err,
+            })?;
+        Ok(DepartureBoard { body, freshness })
+    }
+
+    /// Fetch and decode the departures of a site, see [`DepartureBoard::for_each`].
+    pub fn for_each_departure(
+        &self,
+        site_id: SiteId,
+        filter: &DepartureFilter,
+        sink: impl FnMut(Departure),
+    ) -> Result<()> {
+        self.open_departures(site_id)?.for_each(filter, sink)
+    }
+}
+
+/// Streaming decoder for `{"departures": [...]}` that filters while it reads
+struct DeparturesResponse<'a> {
+    filter: &'a DepartureFilter,
+    sink: &'a mut dyn FnMut(Departure),
 }
 
-pub fn get_sites() -> Result<Vec<Site>> {
-    let snapshot_sites: Vec<Site> = serde_json::from_str(BUNDLED_SITES_JSON)
-        .context("failed to parse bundled station snapshot (data/sites.json)")?;
-    if !snapshot_sites.is_empty() {
-        return Ok(snapshot_sites);
+impl<'de> DeserializeSeed<'de> for DeparturesResponse<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
     }
+}
 
-    let url = "https://transport.integration.sl.se/v1/sites";
-    let client = Client::new();
+impl<'de> Visitor<'de> for DeparturesResponse<'_> {
+    type Value = ();
 
-    let res = client.get(url).send()?;
-    let api_response = res.json::<Vec<Site>>()?;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a departures response object")
+    }
 
-    Ok(api_response)
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let DeparturesResponse { filter, sink } = self;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "departures" {
+                map.next_value_seed(DepartureList {
+                    filter,
+                    sink: &mut *sink,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct DepartureList<'a> {
+    filter: &'a DepartureFilter,
+    sink: &'a mut dyn FnMut(Departure),
+}
+
+impl<'de> DeserializeSeed<'de> for DepartureList<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
 }
 
-pub fn site_has_transport_mode(site_id: u32, transport_mode: TransportMode) -> Result<bool> {
-    let site_id = site_id.to_string();
-    let count = Some(1usize);
-    let mode = Some(transport_mode);
-    let departures = get_departures(&site_id, &None, &count, &mode, &None)?;
-    Ok(!departures.is_empty())
+impl<'de> Visitor<'de> for DepartureList<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of departures")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let limit = self.filter.limit();
+        let mut seen = 0;
+        let mut taken = 0;
+        while taken < limit {
+            let Some(departure) = seq.next_element::<Departure>()? else {
+                debug!("{taken} of {seen} departures matched the filters");
+                return Ok(());
+            };
+            seen += 1;
+            if self.filter.matches(&departure) {
+                (self.sink)(departure);
+                taken += 1;
+            } else {
+                trace!(
+                    "filtered out line {} ({:?}) towards {}",
+                    departure.line.designation,
+                    departure.line.transport_mode,
+                    departure.destination
+                );
+            }
+        }
+        // Skip the rest without building departures we would throw away
+        while seq.next_element::<IgnoredAny>()?.is_some() {
+            seen += 1;
+        }
+        debug!("{taken} of {seen} departures matched the filters, stopped at the limit of {limit}");
+        Ok(())
+    }
 }
 
-pub fn get_site_transport_modes(site_id: u32, sample_size: usize) -> Result<Vec<TransportMode>> {
-    let site_id = site_id.to_string();
-    let count = Some(sample_size);
-    let departures = get_departures(&site_id, &None, &count, &None, &None)?;
+impl SlClient {
+    pub fn get_sites(&self) -> Result<Vec<Site>> {
+        let snapshot_sites: Vec<Site> =
+            serde_json::from_str(BUNDLED_SITES_JSON).map_err(ClientError::Decode)?;
+        if !snapshot_sites.is_empty() {
+            return Ok(snapshot_sites);
+        }
+
+        self.fetch(
+            &self.api_url("/sites?expand=true"),
+            Namespace::Sites,
+            SITES_TTL,
+        )
+    }
+
+    pub fn site_has_transport_mode(
+        &self,
+        site_id: SiteId,
+        transport_mode: TransportMode,
+    ) -> Result<bool> {
+        let departures = self
+            .departures(site_id)
+            .mode(transport_mode)
+            .count(1)
+            .fetch()?;
+        Ok(!departures.is_empty())
+    }
+
+    pub fn get_site_transport_modes(
+        &self,
+        site_id: SiteId,
+        sample_size: usize,
+    ) -> Result<Vec<TransportMode>> {
+        let departures = self.departures(site_id).count(sample_size).fetch()?;
 
-    let mut modes: Vec<TransportMode> = Vec::new();
-    for departure in departures {
-        let mode = departure.line.transport_mode;
-        if !modes.contains(&mode) {
-            modes.push(mode);
+        let mut modes: Vec<TransportMode> = Vec::new();
+        for departure in departures {
+            let mode = departure.line.transport_mode;
+            if !modes.contains(&mode) {
+                modes.push(mode);
+            }
         }
+
+        Ok(modes)
+    }
+
+    pub fn get_site(&self, site_id: SiteId) -> Result<Option<Site>> {
+        let sites = self.get_sites()?;
+        Ok(sites.into_iter().find(|s| s.id == site_id))
+    }
+
+    /// Collect the stop points and lines seen in a site's upcoming departures.
+    pub fn get_site_details(&self, site_id: SiteId) -> Result<SiteDetails> {
+        let departures = self.departures(site_id).fetch()?;
+
+        let mut stop_points: Vec<StopPoint> = Vec::new();
+        let mut lines: Vec<Line> = Vec::new();
+        for departure in departures {
+            if let Some(stop_point) = departure.stop_point
+                && !stop_points.contains(&stop_point)
+            {
+                stop_points.push(stop_point);
+            }
+            if !lines.contains(&departure.line) {
+                lines.push(departure.line);
+            }
+        }
+        stop_points.sort_by_key(|stop_point| stop_point.id);
+
+        Ok(SiteDetails { stop_points, lines })
     }
 
-    Ok(modes)
+    /// Distinct station names starting with `prefix`, ignoring case and accents.
+    pub fn station_names_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let prefix = deunicode(prefix).to_lowercase();
+        let mut names: Vec<String> = self
+            .get_sites()?
+            .into_iter()
+            .filter(|s| {
+                deunicode(s.name.as_str())
+                    .to_lowercase()
+                    .starts_with(&prefix)
+            })
+            .map(|s| s.name)
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    pub fn search_for_sites(&self, query: &str) -> Result<Vec<Site>> {
+        let sites = self.get_sites()?;
+        let query = deunicode(query).to_lowercase(); //Ignore accents on ö å ä
+        let mut sites: Vec<(Relevance, Site)> = sites
+            .iter()
+            .filter_map(|site| Some((relevance(&site.name, &query)?, site.clone())))
+            .collect();
+        // Shorter names are closer matches, e.g. `Centralen` before
+        // `Centralvägen` for "central"
+        sites.sort_by_cached_key(|(relevance, site)| {
+            (*relevance, site.name.chars().count(), site.name.clone())
+        });
+        Ok(sites.into_iter().map(|(_, site)| site).collect())
+    }
+
+    /// Look up an address, e.g. "Sveavägen 100", with a geocoder that
+    /// answers like Nominatim's `/search`. Only places in Sweden are
+    /// considered, and the best match is taken.
+    pub fn geocode(&self, geocoder: &Url, address: &str) -> Result<Place> {
+        let mut url = geocoder.clone();
+        url.query_pairs_mut()
+            .append_pair("q", address)
+            .append_pair("format", "jsonv2")
+            .append_pair("countrycodes", "se")
+            .append_pair("limit", "1");
+        let places: Vec<NominatimPlace> =
+            self.fetch(url.as_str(), Namespace::Responses, SITES_TTL)?;
+        places
+            .into_iter()
+            .next()
+            .ok_or_else(|| ClientError::NotFound(format!("the address '{address}'")))?
+            .place()
+    }
+
+    /// Sites matching a search, as [`SlClient::search_for_sites`] ranks
+    /// them, that are in the municipality `area`, e.g. "Solna". The site list
+    /// doesn't say where a site is, so the journey planner is asked.
+    pub fn search_for_sites_in(&self, query: &str, area: &str) -> Result<AreaSearch> {
+        let response: StopFinderResponse = self.fetch(
+            stop_finder_url(query).as_str(),
+            Namespace::Responses,
+            SITES_TTL,
+        )?;
+        let area = area_key(area);
+        let mut areas: Vec<String> = Vec::new();
+        let mut in_area: Vec<SiteId> = Vec::new();
+        let mut placed: Vec<SiteId> = Vec::new();
+        for location in &response.locations {
+            let (Some(site_id), Some(name)) = (
+                site_id_from_global(&location.id),
+                location
+                    .parent
+                    .as_ref()
+                    .and_then(|parent| parent.name.as_ref()),
+            ) else {
+                continue;
+            };
+            placed.push(site_id);
+            if area_key(name) == area {
+                in_area.push(site_id);
+            }
+            if !areas.contains(name) {
+                areas.push(name.clone());
+            }
+        }
+        areas.sort();
+
+        let mut sites = self.search_for_sites(query)?;
+        let unplaced = sites
+            .iter()
+            .filter(|site| !placed.contains(&site.id))
+            .count();
+        sites.retain(|site| in_area.contains(&site.id));
+        Ok(AreaSearch {
+            sites,
+            areas,
+            unplaced,
+        })
+    }
+}
+
+/// What [`SlClient::search_for_sites_in`] found
+#[derive(Debug)]
+pub struct AreaSearch {
+    /// The matching sites in the municipality
+    pub sites: Vec<Site>,
+    /// Every municipality the journey planner found stops named like the
+    /// search in, to tell a misspelled municipality from an empty one
+    pub areas: Vec<String>,
+    /// Matching sites the journey planner didn't put in any municipality,
+    /// which are left out
+    pub unplaced: usize,
+}
+
+/// The stop finder request for places named like `query`
+fn stop_finder_url(query: &str) -> Url {
+    Url::parse_with_params(
+        STOP_FINDER_URL,
+        [
+            ("name_sf", query),
+            ("any_obj_filter_sf", "2"),
+            ("type_sf", "any"),
+        ],
+    )
+    .expect("the stop finder URL is valid")
+}
+
+/// A municipality's name for comparing, so "Upplands Väsby" matches
+/// "Upplands-Väsby": lowercase, without accents, and with hyphens and runs of
+/// spaces made single spaces
+fn area_key(name: &str) -> String {
+    deunicode(name)
+        .to_lowercase()
+        .split(|c: char| c == '-' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// How well a site's name matches a search, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Relevance {
+    Exact,
+    Prefix,
+    WordPrefix,
+    Substring,
 }
 
-pub fn search_for_sites(query: &str) -> Result<Vec<Site>> {
-    let sites = get_sites()?;
-    let query = deunicode(query).to_lowercase(); //Ignore accents on ö å ä
-    let sites = sites
-        .iter()
-        .filter(|s| deunicode(s.name.as_str()).to_lowercase().contains(&query))
-        .cloned()
-        .collect();
-    Ok(sites)
+/// How `name` matches `query`, which is already lowercase without accents,
+/// or `None` when it doesn't contain it at all
+fn relevance(name: &str, query: &str) -> Option<Relevance> {
+    let name = deunicode(name).to_lowercase();
+    if name == query {
+        Some(Relevance::Exact)
+    } else if name.starts_with(query) {
+        Some(Relevance::Prefix)
+    } else if name
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| word.starts_with(query))
+    {
+        Some(Relevance::WordPrefix)
+    } else if name.contains(query) {
+        Some(Relevance::Substring)
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use time::macros::datetime;
+
+    fn client() -> SlClient {
+        SlClient::new(ClientSettings::default()).expect("default settings should be usable")
+    }
+
+    fn departure(designation: &str, mode: TransportMode, destination: &str) -> Departure {
+        Departure {
+            destination: destination.to_string(),
+            expected: datetime!(2025-11-02 11:14:02 +1),
+            line: Line {
+                designation: designation.to_string(),
+                transport_mode: mode,
+                id: None,
+                group_of_lines: None,
+                transport_authority_id: None,
+            },
+            stop_point: None,
+            journey: None,
+            scheduled: None,
+            state: None,
+            display: None,
+            direction: None,
+            direction_code: None,
+            stop_area: None,
+        }
+    }
+
+    fn board() -> Vec<Departure> {
+        vec![
+            departure("28", TransportMode::Train, "Österskär"),
+            departure("28s", TransportMode::Train, "Djursholms Ösby"),
+            departure("14", TransportMode::Metro, "Mörby centrum"),
+            departure("29", TransportMode::Train, "Näsbypark"),
+            departure("28", TransportMode::Train, "Kårsta"),
+        ]
+    }
+
+    #[test]
+    fn filter_matches_line_variants() {
+        let lines: Vec<String> = DepartureFilter::default()
+            .line(Some("28".to_string()))
+            .apply(board())
+            .map(|d| d.line.designation)
+            .collect();
+        assert_eq!(vec!["28", "28s", "28"], lines);
+    }
+
+    #[test]
+    fn filter_combines_filters_before_limiting() {
+        let departures: Vec<Departure> = DepartureFilter::default()
+            .count(Some(1))
+            .mode(Some(TransportMode::Train))
+            .destination(Some("karsta"))
+            .apply(board())
+            .collect();
+        assert_eq!(1, departures.len());
+        assert_eq!("Kårsta", departures[0].destination);
+    }
 
     #[test]
-    fn get_departures_should_obey_line_limit() {
-        let departures = get_departures("9600", &None, &Some(2), &None, &None);
-        let actual = departures.unwrap().len();
-        assert_eq!(2, actual);
+    fn filters_apply_to_borrowed_departures() {
+        let board = board();
+        let filter = DepartureFilter::default()
+            .count(Some(2))
+            .destination(Some("ÖSBY"));
+        let matched: Vec<&Departure> = filter.apply(&board).collect();
+        assert_eq!(1, matched.len());
+        assert!(std::ptr::eq(&board[1], matched[0]));
 
-        let departures = get_departures("9600", &None, &Some(1), &None, &None);
-        let actual = departures.unwrap().len();
-        assert_eq!(1, actual);
+        let filter = DepartureFilter::default()
+            .count(Some(2))
+            .mode(Some(TransportMode::Train));
+        assert_eq!(2, filter.apply(&board).count());
+    }
+
+    /// A client answering from `tests/fixtures` instead of the live API
+    fn fixture_client() -> SlClient {
+        let transport = FixtureTransport::new().with(
+            &format!("{DEFAULT_API_BASE}/sites/9600/departures"),
+            include_str!("../tests/fixtures/departures-9600.json"),
+        );
+        SlClient::with_transport(ClientSettings::default(), transport)
+            .expect("default settings should be usable")
     }
 
     #[test]
-    fn get_departures_should_filter_lines() -> Result<()> {
-        let departures = get_departures("9600", &Some("28".to_string()), &Some(1), &None, &None)?;
-        if !departures
-            .iter()
-            .all(|d| d.line.designation.starts_with("28"))
-        {
-            assert!(false, "Should only contain results for line 28");
-        }
+    fn departures_should_obey_count() {
+        let site = SiteId::from(9600);
+        let departures = fixture_client().departures(site).count(2).fetch();
+        assert_eq!(2, departures.unwrap().len());
+
+        let departures = fixture_client().departures(site).count(1).fetch();
+        assert_eq!(1, departures.unwrap().len());
+    }
+
+    #[test]
+    fn departures_should_filter_lines() -> anyhow::Result<()> {
+        let departures = fixture_client()
+            .departures(SiteId::from(9600))
+            .line("28")
+            .fetch()?;
+        let destinations: Vec<&str> = departures.iter().map(|d| d.destination.as_str()).collect();
+        assert_eq!(vec!["Österskär", "Vallentuna", "Österskär"], destinations);
+        assert!(
+            departures
+                .iter()
+                .all(|d| d.line.designation.starts_with("28"))
+        );
         Ok(())
     }
 
     #[test]
-    fn test_get_sites() -> Result<()> {
-        let sites = get_sites()?;
-        let count = sites.iter().count();
+    fn unknown_sites_are_not_found() {
+        assert!(matches!(
+            fixture_client().departures(SiteId::from(1)).fetch(),
+            Err(ClientError::NotFound(site)) if site == "site 1"
+        ));
+    }
+
+    #[test]
+    fn departures_can_look_further_ahead() -> anyhow::Result<()> {
+        let url = format!("{DEFAULT_API_BASE}/sites/9600/departures?forecast=180");
+        let transport = FixtureTransport::new()
+            .with(&url, include_str!("../tests/fixtures/departures-9600.json"));
+        let client = SlClient::with_transport(ClientSettings::default(), transport)?;
+        let departures = client
+            .departures(SiteId::from(9600))
+            .forecast(180)
+            .mode(TransportMode::Train)
+            .destination("osterskar")
+            .fetch()?;
+        assert_eq!(2, departures.len());
+        // Without the forecast it is a different request, which has no fixture
+        assert!(client.departures(SiteId::from(9600)).fetch().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_sites() -> anyhow::Result<()> {
+        let sites = client().get_sites()?;
+        let count = sites.len();
         assert_ne!(0, count);
         Ok(())
     }
+
+    #[test]
+    fn search_ranks_exact_and_prefix_matches_first() {
+        assert_eq!(Some(Relevance::Exact), relevance("Slussen", "slussen"));
+        assert_eq!(
+            Some(Relevance::Prefix),
+            relevance("Centralvägen", "central")
+        );
+        assert_eq!(
+            Some(Relevance::WordPrefix),
+            relevance("T-Centralen", "central")
+        );
+        assert_eq!(
+            Some(Relevance::Substring),
+            relevance("Stockholm City", "ity")
+        );
+        assert_eq!(None, relevance("Odenplan", "central"));
+        assert!(Relevance::Prefix < Relevance::Substring);
+
+        let names: Vec<String> = client()
+            .search_for_sites("central")
+            .unwrap()
+            .into_iter()
+            .map(|site| site.name)
+            .collect();
+        assert_eq!("Centralen", names[0]);
+        let position = |wanted: &str| names.iter().position(|name| name == wanted).unwrap();
+        assert!(position("T-Centralen") < position("Panncentralen"));
+    }
+
+    #[test]
+    fn stop_finder_locations_are_decoded() {
+        assert_eq!(
+            Some(SiteId::from(9192)),
+            site_id_from_global(&global_stop_id(SiteId::from(9192)))
+        );
+        assert_eq!(None, site_id_from_global("18001234"));
+        let response: StopFinderResponse = serde_json::from_str(
+            r#"{ "locations": [
+                { "id": "9091001000003156", "name": "Kyrkan, Upplands-Bro", "type": "stop",
+                  "parent": { "id": "9091ub", "name": "Upplands-Bro", "type": "locality" } },
+                { "id": "streetID:1500000143", "name": "Kyrkvägen", "type": "street" }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(2, response.locations.len());
+        let locality = response.locations[0].parent.as_ref().unwrap();
+        assert_eq!(Some("Upplands-Bro"), locality.name.as_deref());
+        assert!(response.locations[1].parent.is_none());
+    }
+
+    #[test]
+    fn searches_can_be_kept_to_a_municipality() -> anyhow::Result<()> {
+        let stop = |site: u32, area: &str| {
+            serde_json::json!({
+                "id": global_stop_id(SiteId::from(site)),
+                "type": "stop",
+                "parent": { "name": area, "type": "locality" }
+            })
+        };
+        let response = serde_json::json!({ "locations": [
+            stop(3156, "Upplands-Bro"),
+            stop(7958, "Stockholm"),
+            stop(2212, "Täby"),
+            { "id": "streetID:1500000143", "type": "street" }
+        ] });
+        let transport =
+            FixtureTransport::new().with(stop_finder_url("kyrkan").as_str(), response.to_string());
+        let client = SlClient::with_transport(ClientSettings::default(), transport)?;
+
+        let found = client.search_for_sites_in("kyrkan", "upplands  bro")?;
+        let ids: Vec<u32> = found.sites.iter().map(|site| site.id.get()).collect();
+        assert_eq!(vec![3156], ids);
+        assert_eq!(vec!["Stockholm", "Täby", "Upplands-Bro"], found.areas);
+        // Kyrkan in the bundled site list that the stop finder didn't return
+        let matching = client.search_for_sites("kyrkan")?.len();
+        assert_eq!(matching - 3, found.unplaced);
+
+        assert!(client.search_for_sites_in("kyrkan", "Taby")?.sites.len() == 1);
+        assert!(
+            client
+                .search_for_sites_in("kyrkan", "Solna")?
+                .sites
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn geocoded_places_are_decoded() {
+        let places: Vec<NominatimPlace> = serde_json::from_str(
+            r#"[{ "place_id": 1, "lat": "59.3454", "lon": "18.0546",
+                  "display_name": "100, Sveavägen, Vasastaden, Stockholm, Sverige",
+                  "category": "place", "type": "house" }]"#,
+        )
+        .unwrap();
+        let place = places.into_iter().next().unwrap().place().unwrap();
+        assert_eq!(59.3454, place.lat);
+        assert_eq!(18.0546, place.lon);
+        assert!(place.name.starts_with("100, Sveavägen"));
+    }
+
+    #[test]
+    fn planned_journeys_are_decoded() {
+        assert_eq!("9091001000009192", global_stop_id(SiteId::from(9192)));
+        let response: JourneysResponse = serde_json::from_str(
+            r#"{ "journeys": [{
+                "tripDuration": 960, "interchanges": 1,
+                "legs": [{
+                    "duration": 480,
+                    "origin": { "name": "Slussen", "departureTimePlanned": "2025-11-02T10:00:00Z" },
+                    "destination": { "name": "T-Centralen", "arrivalTimePlanned": "2025-11-02T10:08:00Z" },
+                    "transportation": { "name": "Tunnelbana 14", "disassembledName": "14",
+                                        "destination": { "name": "Mörby centrum" } }
+                }, {
+                    "origin": { "name": "T-Centralen" },
+                    "destination": { "name": "Centralstationen" }
+                }]
+            }] }"#,
+        )
+        .unwrap();
+        let journey = &response.journeys[0];
+        assert_eq!(Some(960), journey.trip_duration);
+        assert_eq!(2, journey.legs.len());
+        assert!(journey.legs[1].transportation.is_none());
+        assert_eq!(Some(datetime!(2025-11-02 10:00 UTC)), journey.departure());
+        // The walk at the end has no times of its own
+        assert_eq!(Some(datetime!(2025-11-02 10:08 UTC)), journey.arrival());
+        assert_eq!(Some("14"), journey.first_line());
+    }
+
+    #[test]
+    fn get_site_finds_site_by_id() -> anyhow::Result<()> {
+        let site = client()
+            .get_site(SiteId::from(1000))?
+            .expect("site 1000 should be in the snapshot");
+        assert_eq!("Sergels torg", site.name);
+        assert!(client().get_site(SiteId::from(0))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn departures_are_filtered_while_decoding() -> anyhow::Result<()> {
+        let body = r#"{
+            "departures": [
+                { "destination": "Mörby centrum", "expected": "2025-11-02T11:14:02",
+                  "line": { "designation": "14", "transport_mode": "METRO" },
+                  "journey": { "id": 2025110200142, "state": "NORMALPROGRESS" } },
+                { "destination": "Fruängen", "expected": "2025-11-02T11:16:02",
+                  "line": { "designation": "14", "transport_mode": "METRO" } },
+                { "destination": "Mörby centrum", "expected": "2025-11-02T11:20:02",
+                  "line": { "designation": "14", "transport_mode": "METRO" } },
+                { "not": "a departure" }
+            ],
+            "stop_deviations": []
+        }"#;
+
+        let filter = DepartureFilter::default()
+            .count(Some(1))
+            .destination(Some("morby"));
+        let mut departures = Vec::new();
+        DeparturesResponse {
+            filter: &filter,
+            sink: &mut |d| departures.push(d),
+        }
+        .deserialize(&mut serde_json::Deserializer::from_str(body))?;
+
+        assert_eq!(1, departures.len());
+        assert_eq!(datetime!(2025-11-02 11:14:02 +1), departures[0].expected);
+        let journey = departures[0].journey.as_ref().unwrap();
+        assert_eq!(2025110200142, journey.id);
+        assert!(!journey.is_cancelled());
+        Ok(())
+    }
+
+    #[test]
+    fn departures_with_bad_times_are_decode_errors() {
+        let departure = |expected: &str| {
+            serde_json::from_value::<Departure>(serde_json::json!({
+                "destination": "Fruängen",
+                "expected": expected,
+                "scheduled": "2025-11-02T11:14:00",
+                "line": { "designation": "14", "transport_mode": "METRO" },
+            }))
+        };
+        let parsed = departure("2025-11-02T11:14:02").unwrap();
+        assert_eq!(Some(datetime!(2025-11-02 11:14:00 +1)), parsed.scheduled);
+        let err = departure("in a bit").err().unwrap();
+        assert!(
+            err.to_string()
+                .contains("'in a bit' is not a date and time")
+        );
+    }
+
+    #[test]
+    fn departures_keep_what_sl_says_about_them() {
+        let departure: Departure = serde_json::from_value(serde_json::json!({
+            "destination": "Kårsta",
+            "direction_code": 1,
+            "direction": "Kårsta",
+            "state": "EXPECTED",
+            "display": "1 min",
+            "expected": "2025-05-16T08:03:12",
+            "stop_area": { "id": 6001, "name": "Stockholms östra", "type": "RAILWSTN" },
+            "line": { "designation": "27", "transport_mode": "TRAIN" },
+        }))
+        .unwrap();
+        assert_eq!(Some("1 min"), departure.display.as_deref());
+        assert_eq!(Some("Kårsta"), departure.direction.as_deref());
+        assert_eq!(Some(1), departure.direction_code);
+        assert_eq!(6001, departure.stop_area.as_ref().unwrap().id);
+
+        let json = serde_json::to_value(&departure).unwrap();
+        assert_eq!("1 min", json["display"]);
+        assert_eq!("RAILWSTN", json["stop_area"]["type"]);
+        assert!(json.get("stop_point").is_none());
+        assert!(json.get("journey").is_none());
+    }
+
+    #[test]
+    fn lines_are_named_after_their_group() {
+        let line = |designation: &str, mode, group: Option<&str>| Line {
+            designation: designation.to_string(),
+            transport_mode: mode,
+            id: None,
+            group_of_lines: group.map(str::to_string),
+            transport_authority_id: None,
+        };
+        let green = line("17", TransportMode::Metro, Some("Tunnelbanans gröna linje"));
+        assert_eq!("Green line 17", green.name(Language::English));
+        assert_eq!("Gröna linjen 17", green.name(Language::Swedish));
+        let roslagsbanan = line("28", TransportMode::Train, Some("Roslagsbanan"));
+        assert_eq!("Roslagsbanan 28", roslagsbanan.name(Language::Swedish));
+        let bus = line("43", TransportMode::Bus, Some("Innerstadsbussar"));
+        assert_eq!("43", bus.name(Language::English));
+        assert_eq!(
+            "7",
+            line("7", TransportMode::Tram, None).name(Language::English)
+        );
+    }
+
+    #[test]
+    fn departures_can_be_filtered_by_operator() {
+        let run_by = |authority: Option<u32>| {
+            let mut departure = departure("40", TransportMode::Train, "Uppsala C");
+            departure.line.transport_authority_id = authority;
+            departure
+        };
+        let board = [run_by(Some(1)), run_by(Some(14)), run_by(None)];
+        let count = |operator: &str| {
+            DepartureFilter::default()
+                .operator(Some(operator.to_string()))
+                .apply(&board)
+                .count()
+        };
+        assert_eq!(1, count("sl"));
+        assert_eq!(1, count("14"));
+        assert_eq!(0, count("7"));
+        assert_eq!(Some("SL".to_string()), board[0].line.operator());
+        assert_eq!(None, board[2].line.operator());
+    }
+
+    #[test]
+    fn unknown_transport_modes_are_other() {
+        let departure: Departure = serde_json::from_value(serde_json::json!({
+            "destination": "Djurgården",
+            "expected": "2025-05-16T08:00:00",
+            "line": { "designation": "80", "transport_mode": "HOVERCRAFT" },
+        }))
+        .unwrap();
+        assert_eq!(TransportMode::Other, departure.line.transport_mode);
+    }
+
+    #[test]
+    fn backoff_doubles_within_jitter_bounds() {
+        let policy = RetryPolicy {
+            retries: 5,
+            delay: Duration::from_millis(100),
+        };
+        assert_eq!(Duration::from_millis(50), policy.backoff(0, 0.0));
+        assert_eq!(Duration::from_millis(100), policy.backoff(0, 1.0));
+        assert_eq!(Duration::from_millis(400), policy.backoff(2, 1.0));
+        assert_eq!(RetryPolicy::MAX_DELAY, policy.backoff(40, 1.0));
+    }
+
+    #[test]
+    fn token_bucket_allows_bursts_then_paces_requests() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(
+            RateLimit {
+                per_minute: 60,
+                burst: 2,
+            },
+            start,
+        );
+        assert_eq!(Duration::ZERO, bucket.take(start));
+        assert_eq!(Duration::ZERO, bucket.take(start));
+        assert_eq!(Duration::from_secs(1), bucket.take(start));
+        assert_eq!(Duration::from_secs(2), bucket.take(start));
+
+        // Tokens come back at one per second, up to the burst size
+        let later = start + Duration::from_secs(60);
+        assert_eq!(Duration::ZERO, bucket.take(later));
+        assert_eq!(Duration::ZERO, bucket.take(later));
+        assert_eq!(Duration::from_secs(1), bucket.take(later));
+    }
+
+    #[test]
+    fn unusable_http_options_are_reported() {
+        let options = HttpOptions {
+            ca_certificates: Some(b"-----BEGIN CERTIFICATE-----\ngarbage".to_vec()),
+            ..Default::default()
+        };
+        let settings = ClientSettings {
+            http: options,
+            ..Default::default()
+        };
+        assert!(matches!(
+            SlClient::new(settings),
+            Err(ClientError::Setup(_))
+        ));
+    }
+
+    #[test]
+    fn dry_runs_describe_the_request() -> anyhow::Result<()> {
+        let request = Client::new()
+            .get("https://deviations.integration.sl.se/v1/messages?future=true&site=9192")
+            .header(IF_NONE_MATCH, "\"abc\"")
+            .build()?;
+        assert_eq!(
+            "GET https://deviations.integration.sl.se/v1/messages\n  ?future=true\n  ?site=9192\n  if-none-match: \"abc\"",
+            describe_request(&request)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        assert!(ClientError::Unavailable { status: 503 }.is_transient());
+        assert!(
+            ClientError::Status {
+                status: 500,
+                message: String::new()
+            }
+            .is_transient()
+        );
+        assert!(!ClientError::NotFound("site 1".to_string()).is_transient());
+    }
+
+    #[test]
+    fn deviations_are_matched_against_a_time_window() -> anyhow::Result<()> {
+        let deviation: Deviation = serde_json::from_str(
+            r#"{
+                "publish": {"from": "2025-05-16T22:00:00.000+02:00", "upto": "2025-05-19T04:00:00.000+02:00"},
+                "message_variants": [
+                    {"header": "Bussar ersätter", "language": "sv"},
+                    {"header": "Replacement buses", "language": "en"}
+                ],
+                "scope": {"lines": [{"designation": "41"}, {"designation": "43"}]}
+            }"#,
+        )?;
+
+        let friday = datetime!(2025-05-16 12:00:00 +2);
+        assert!(deviation.overlaps(friday, friday + time::Duration::days(1)));
+        assert!(!deviation.overlaps(friday - time::Duration::days(7), friday));
+        assert_eq!("Replacement buses", deviation.message("en").unwrap().header);
+        assert_eq!("Bussar ersätter", deviation.message("fi").unwrap().header);
+        assert_eq!("41, 43", deviation.line_designations());
+        Ok(())
+    }
+
+    #[test]
+    fn api_error_message_prefers_json_message() {
+        assert_eq!(
+            "Site id is not valid",
+            api_error_message(r#"{"message": "Site id is not valid", "status": 400}"#)
+        );
+        assert_eq!("Bad gateway", api_error_message("  Bad gateway\n"));
+        assert_eq!("no details given", api_error_message(""));
+        assert_eq!(201, api_error_message(&"x".repeat(500)).chars().count());
+    }
+
+    #[test]
+    fn site_types_come_from_stop_areas() -> anyhow::Result<()> {
+        let site: Site = serde_json::from_str(
+            r#"{
+                "id": 9001,
+                "name": "T-Centralen",
+                "lat": 59.331,
+                "lon": 18.059,
+                "stop_areas": [
+                    { "id": 1051, "name": "T-Centralen", "type": "METROSTN" },
+                    { "id": 1052, "name": "T-Centralen", "type": "METROSTN" },
+                    { "id": 1053, "name": "Centralen", "type": "BUSTERM" }
+                ]
+            }"#,
+        )?;
+        assert_eq!(vec!["metro", "bus"], site.types());
+        Ok(())
+    }
+
+    #[test]
+    fn station_names_with_prefix_ignores_case_and_accents() -> anyhow::Result<()> {
+        let names = client().station_names_with_prefix("slus")?;
+        assert!(names.contains(&"Slussen".to_string()));
+        assert!(
+            names
+                .iter()
+                .all(|name| name.to_lowercase().starts_with("slus"))
+        );
+
+        let names = client().station_names_with_prefix("alvsjo")?;
+        assert!(names.iter().any(|name| name.starts_with("Älvsjö")));
+        Ok(())
+    }
 }
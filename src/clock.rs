@@ -0,0 +1,39 @@
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::dates::Timezone;
+
+/// Source of the current time. Everything that compares API timestamps with
+/// "now" goes through a clock so that it can be tested deterministically.
+pub trait Clock {
+    fn now(&self) -> OffsetDateTime;
+
+    /// Stockholm's offset from UTC right now, for questions like which day
+    /// it is at SL
+    fn local_offset(&self) -> UtcOffset;
+}
+
+/// The machine's clock, in Stockholm time whatever the machine's timezone
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc().to_offset(self.local_offset())
+    }
+
+    fn local_offset(&self) -> UtcOffset {
+        Timezone::sl().offset_at(OffsetDateTime::now_utc())
+    }
+}
+
+/// A clock frozen at a single instant
+pub struct FixedClock(pub OffsetDateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> OffsetDateTime {
+        self.0
+    }
+
+    fn local_offset(&self) -> UtcOffset {
+        self.0.offset()
+    }
+}
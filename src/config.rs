@@ -0,0 +1,465 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use reqwest::Url;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::client::SlClient;
+use crate::query::Query;
+use crate::sandbox::ensure_trusted_config;
+use crate::theme::Theme;
+use crate::types::{SiteId, TransportMode};
+
+/// User configuration read from `$XDG_CONFIG_HOME/slq/config.toml`
+/// (falling back to `~/.config/slq/config.toml`). The `SLQ_CONFIG`
+/// environment variable points slq at a different file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub notify: NotifyConfig,
+    /// Short names for sites, e.g. `home = 9192`
+    pub aliases: BTreeMap<String, SiteId>,
+    /// Minutes it takes to walk to a station, keyed by alias, station name or site id.
+    /// Departures leaving sooner than that are hidden.
+    pub walk_minutes: BTreeMap<String, i64>,
+    /// Named departure queries that can be run with `slq run <name>`
+    pub queries: BTreeMap<String, SavedQuery>,
+    pub trafiklab: TrafiklabConfig,
+    pub mqtt: MqttConfig,
+    pub statusbar: StatusbarConfig,
+    /// Colors of departure boards with `--color`
+    pub theme: Theme,
+    pub deviations: DeviationsConfig,
+    pub daemon: DaemonConfig,
+    pub geocoder: GeocoderConfig,
+}
+
+/// What `slq daemon` runs. Every part is off unless configured.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Notify about new or escalated deviations on the lines under
+    /// `[deviations]`
+    pub deviations: bool,
+    /// Seconds between deviation checks
+    pub deviations_interval: u64,
+    /// Stations to publish to the broker under `[mqtt]`
+    pub mqtt_sites: Vec<String>,
+    /// Seconds between MQTT publishes
+    pub mqtt_interval: u64,
+    /// Address to serve the HTTP API on, e.g. `127.0.0.1:8080`
+    pub serve: Option<SocketAddr>,
+    /// Stations to record the departure history of, like `slq record`
+    pub record_sites: Vec<String>,
+    /// Seconds between samples of the recorded departure boards
+    pub record_interval: u64,
+    pub alarms: Vec<AlarmConfig>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            deviations: false,
+            deviations_interval: 300,
+            mqtt_sites: Vec::new(),
+            mqtt_interval: 60,
+            serve: None,
+            record_sites: Vec::new(),
+            record_interval: 60,
+            alarms: Vec::new(),
+        }
+    }
+}
+
+/// A departure alarm run by `slq daemon`, like `slq alarm --repeat`
+#[derive(Deserialize, Debug, Clone)]
+pub struct AlarmConfig {
+    /// Alias, station name or site id
+    pub station: String,
+    pub line: Option<String>,
+    pub destination: Option<String>,
+    pub transport_mode: Option<TransportMode>,
+    /// Minutes before the departure to notify
+    pub notify_before: u32,
+}
+
+impl AlarmConfig {
+    pub fn query(&self) -> Query {
+        Query {
+            line: self.line.clone(),
+            destination: self.destination.clone(),
+            transport_mode: self.transport_mode,
+            lead_time: Some(time::Duration::minutes(self.notify_before.into())),
+            poll_seconds: Some(ALARM_POLL_SECONDS),
+            ..Default::default()
+        }
+    }
+}
+
+/// Seconds between checks of the departure board for alarms in the config
+/// file, the same as `slq alarm` uses by default
+const ALARM_POLL_SECONDS: u64 = 30;
+
+/// Lines `slq deviations` reports on, unless `--line` is given
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct DeviationsConfig {
+    /// Line designations, e.g. `["43", "14"]`. Empty means every line
+    pub lines: Vec<String>,
+}
+
+/// Settings for `--format statusbar`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StatusbarConfig {
+    /// Longest line to print, unless `--max-width` says otherwise
+    pub max_width: Option<usize>,
+    /// Color the line when the next departure leaves within this many minutes
+    pub soon_minutes: i64,
+    /// Printed before and after the line when a departure is soon, e.g.
+    /// `%{F#e06c75}` and `%{F-}` for polybar
+    pub soon_start: String,
+    pub soon_end: String,
+}
+
+impl Default for StatusbarConfig {
+    fn default() -> Self {
+        StatusbarConfig {
+            max_width: None,
+            soon_minutes: 5,
+            soon_start: String::new(),
+            soon_end: String::new(),
+        }
+    }
+}
+
+/// Broker and topics for `slq mqtt`. Options given on the command line win.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// `host` or `host:port`
+    pub broker: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic templates, `{site}` is replaced by the site id
+    pub departures_topic: Option<String>,
+    pub deviations_topic: Option<String>,
+}
+
+/// Where `slq closest --address` looks up addresses
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GeocoderConfig {
+    /// A search endpoint that answers like Nominatim's, e.g. a self-hosted one
+    pub url: String,
+}
+
+impl Default for GeocoderConfig {
+    fn default() -> Self {
+        GeocoderConfig {
+            url: "https://nominatim.openstreetmap.org/search".to_string(),
+        }
+    }
+}
+
+impl GeocoderConfig {
+    pub fn url(&self) -> Result<Url> {
+        Url::parse(&self.url).with_context(|| format!("invalid geocoder url '{}'", self.url))
+    }
+}
+
+/// API keys for Trafiklab's open data, see <https://www.trafiklab.se>
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct TrafiklabConfig {
+    /// Key for "GTFS Regional Static data", used by `slq gtfs update`
+    pub static_key: Option<String>,
+    /// Key for "GTFS Regional Realtime", used by `slq vehicles`
+    pub realtime_key: Option<String>,
+}
+
+impl TrafiklabConfig {
+    /// The static data key, from `SLQ_TRAFIKLAB_STATIC_KEY` or the config file
+    pub fn static_key(&self) -> Option<String> {
+        env::var("SLQ_TRAFIKLAB_STATIC_KEY")
+            .ok()
+            .or_else(|| self.static_key.clone())
+    }
+
+    /// The realtime data key, from `SLQ_TRAFIKLAB_REALTIME_KEY` or the config file
+    pub fn realtime_key(&self) -> Option<String> {
+        env::var("SLQ_TRAFIKLAB_REALTIME_KEY")
+            .ok()
+            .or_else(|| self.realtime_key.clone())
+    }
+}
+
+/// A departures query stored in the config file
+#[derive(Deserialize, Debug, Clone)]
+pub struct SavedQuery {
+    /// Alias, station name or site id
+    pub station: String,
+    pub line: Option<String>,
+    #[serde(alias = "count")]
+    pub limit: Option<usize>,
+    pub destination: Option<String>,
+    pub transport_mode: Option<TransportMode>,
+}
+
+impl SavedQuery {
+    pub fn query(&self) -> Query {
+        Query {
+            limit: self.limit,
+            line: self.line.clone(),
+            destination: self.destination.clone(),
+            transport_mode: self.transport_mode,
+            ..Default::default()
+        }
+    }
+}
+
+/// A site a station argument resolved to
+pub struct ResolvedStation {
+    pub id: SiteId,
+    pub name: String,
+}
+
+/// Which notification backend to use and how to reach it
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum NotifyConfig {
+    /// Native desktop notification on the machine running slq
+    #[default]
+    Desktop,
+    /// Publish to an ntfy topic, e.g. `https://ntfy.sh/my-commute`
+    Ntfy { url: String, token: Option<String> },
+    /// Pushover application token and user key
+    Pushover { token: String, user: String },
+    /// Gotify server URL and application token
+    Gotify { url: String, token: String },
+    /// POST the title and message as JSON to any URL
+    Webhook { url: String, token: Option<String> },
+}
+
+impl NotifyConfig {
+    /// Name of the backend as written in the config file
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            NotifyConfig::Desktop => "desktop",
+            NotifyConfig::Ntfy { .. } => "ntfy",
+            NotifyConfig::Pushover { .. } => "pushover",
+            NotifyConfig::Gotify { .. } => "gotify",
+            NotifyConfig::Webhook { .. } => "webhook",
+        }
+    }
+
+    /// The backend for a URL given with `--notify-url`: ntfy for topics on
+    /// ntfy.sh, and a webhook for anything else. Self-hosted ntfy servers
+    /// are set up with the `ntfy` backend in the config file.
+    pub fn from_url(url: &str) -> NotifyConfig {
+        let host = url
+            .split_once("://")
+            .map_or(url, |(_, rest)| rest)
+            .split(['/', ':'])
+            .next()
+            .unwrap_or_default();
+        if host.eq_ignore_ascii_case("ntfy.sh") {
+            NotifyConfig::Ntfy {
+                url: url.to_string(),
+                token: None,
+            }
+        } else {
+            NotifyConfig::Webhook {
+                url: url.to_string(),
+                token: None,
+            }
+        }
+    }
+}
+
+/// Where slq keeps what it builds up over time, like the imported timetable:
+/// `$SLQ_DATA_DIR`, or `$XDG_DATA_HOME/slq` (falling back to
+/// `~/.local/share/slq`).
+pub fn data_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("SLQ_DATA_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share"))
+        })?;
+    Some(data_home.join("slq"))
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("SLQ_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_home.join("slq").join("config.toml"))
+    }
+
+    /// Load the configuration file, using defaults when it doesn't exist.
+    pub fn load() -> Result<Config> {
+        let Some(path) = Config::path() else {
+            return Ok(Config::default());
+        };
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        ensure_trusted_config(&path)?;
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    /// Resolve a station argument, trying aliases first, then numeric site ids
+    /// and finally the best match from a name search.
+    pub fn resolve_station(
+        &self,
+        client: &SlClient,
+        station: &str,
+    ) -> Result<Option<ResolvedStation>> {
+        if let Some(site_id) = self.aliases.get(station) {
+            let name = client
+                .get_site(*site_id)?
+                .map(|site| site.name)
+                .unwrap_or_else(|| station.to_string());
+            debug!("alias '{station}' is site {site_id}");
+            return Ok(Some(ResolvedStation { id: *site_id, name }));
+        }
+
+        if let Ok(site_id) = station.parse::<SiteId>() {
+            return Ok(Some(ResolvedStation {
+                id: site_id,
+                name: station.to_string(),
+            }));
+        }
+
+        let sites = client.search_for_sites(station)?;
+        debug!(
+            "'{station}' matches {} site(s), using {:?}",
+            sites.len(),
+            sites.first().map(|site| &site.name)
+        );
+        Ok(sites.first().map(|site| ResolvedStation {
+            id: site.id,
+            name: site.name.clone(),
+        }))
+    }
+
+    /// Walking time configured for a station, looked up by the argument the
+    /// user typed as well as the site it resolved to.
+    pub fn walk_minutes_for(&self, station: &str, resolved: &ResolvedStation) -> Option<i64> {
+        let id = resolved.id.to_string();
+        [station, id.as_str(), resolved.name.as_str()]
+            .iter()
+            .find_map(|key| self.walk_minutes.get(*key))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_desktop_notifications() {
+        let config: Config = toml::from_str("").unwrap();
+        assert!(matches!(config.notify, NotifyConfig::Desktop));
+    }
+
+    #[test]
+    fn notify_backend_is_selected_by_name() {
+        let config: Config = toml::from_str(
+            r#"
+            [notify]
+            backend = "ntfy"
+            url = "https://ntfy.sh/commute"
+            "#,
+        )
+        .unwrap();
+
+        match config.notify {
+            NotifyConfig::Ntfy { url, token } => {
+                assert_eq!("https://ntfy.sh/commute", url);
+                assert!(token.is_none());
+            }
+            other => panic!("expected ntfy backend, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn notify_urls_pick_ntfy_or_a_webhook() {
+        assert!(matches!(
+            NotifyConfig::from_url("https://ntfy.sh/commute"),
+            NotifyConfig::Ntfy { .. }
+        ));
+        assert!(matches!(
+            NotifyConfig::from_url("https://hooks.example.com/ntfy.sh"),
+            NotifyConfig::Webhook { .. }
+        ));
+    }
+
+    #[test]
+    fn aliases_walk_times_and_queries_are_parsed() {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            home = 9192
+
+            [walk_minutes]
+            home = 6
+
+            [queries.commute]
+            station = "home"
+            line = "43"
+            transport_mode = "bus"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(Some(&SiteId::from(9192)), config.aliases.get("home"));
+        assert_eq!(Some(&6), config.walk_minutes.get("home"));
+        let commute = &config.queries["commute"];
+        assert_eq!("home", commute.station);
+        assert_eq!(Some(TransportMode::Bus), commute.transport_mode);
+    }
+
+    #[test]
+    fn daemon_tasks_are_parsed() {
+        let config: Config = toml::from_str(
+            r#"
+            [daemon]
+            deviations = true
+            serve = "127.0.0.1:8080"
+
+            [[daemon.alarms]]
+            station = "home"
+            line = "43"
+            notify_before = 10
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.daemon.deviations);
+        assert_eq!(300, config.daemon.deviations_interval);
+        assert_eq!(Some(8080), config.daemon.serve.map(|addr| addr.port()));
+        let alarm = config.daemon.alarms[0].query();
+        assert_eq!(Some(time::Duration::minutes(10)), alarm.lead_time);
+        assert!(alarm.validate().is_ok());
+    }
+}
@@ -0,0 +1,104 @@
+//! Support for `slq daemon`: keeping its tasks running, and the systemd
+//! user unit that starts it at login and after reboots.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::outln;
+
+/// How long to wait before restarting a task that failed
+const RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// Run `task` until it finishes, restarting it whenever it fails so one
+/// problem, like an unreachable broker, doesn't stop the whole daemon.
+pub fn supervise(name: &str, task: impl Fn() -> Result<()>) {
+    loop {
+        match task() {
+            Ok(()) => return,
+            Err(err) => {
+                eprintln!(
+                    "Warning: {name} failed, restarting in {}s: {err:#}",
+                    RESTART_DELAY.as_secs()
+                );
+                thread::sleep(RESTART_DELAY);
+            }
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/systemd/user/slq.service`, falling back to
+/// `~/.config/systemd/user/slq.service`.
+pub fn unit_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("systemd").join("user").join("slq.service"))
+}
+
+/// A systemd user unit that runs `exe daemon`
+pub fn unit_file(exe: &Path) -> String {
+    format!(
+        "[Unit]
+Description=slq daemon: SL departure alarms, deviation alerts and exporters
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+ExecStart={} daemon
+Restart=on-failure
+RestartSec=30
+
+[Install]
+WantedBy=default.target
+",
+        escape_exec_path(exe)
+    )
+}
+
+/// Quote a path for `ExecStart=` when it has spaces
+fn escape_exec_path(path: &Path) -> String {
+    let path = path.display().to_string();
+    if path.contains(char::is_whitespace) {
+        format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        path
+    }
+}
+
+/// Write the unit for the running executable and explain how to enable it.
+/// systemctl isn't run for the user, so nothing starts without them saying so.
+pub fn install_service() -> Result<()> {
+    let path = unit_path().context("can't find the systemd user directory, is HOME set?")?;
+    let exe = env::current_exe().context("can't find the path of the slq executable")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    fs::write(&path, unit_file(&exe))
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    outln!("Wrote {}", path.display());
+    outln!("Start it now and at every login with:");
+    outln!("  systemctl --user daemon-reload");
+    outln!("  systemctl --user enable --now slq");
+    outln!("To keep it running while you're logged out, also run `loginctl enable-linger`");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_runs_the_daemon_from_the_executable() {
+        let unit = unit_file(Path::new("/home/me/.cargo/bin/slq"));
+        assert!(unit.contains("\nExecStart=/home/me/.cargo/bin/slq daemon\n"));
+        assert!(unit.contains("\nWantedBy=default.target\n"));
+
+        let unit = unit_file(Path::new("/opt/my tools/slq"));
+        assert!(unit.contains("\nExecStart=\"/opt/my tools/slq\" daemon\n"));
+    }
+}
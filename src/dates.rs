@@ -0,0 +1,522 @@
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, UtcOffset, Weekday};
+use time_tz::{Offset, OffsetDateTimeExt, PrimitiveDateTimeExt, TimeZone, Tz, timezones};
+
+use crate::clock::Clock;
+use crate::types::Language;
+
+/// Render a date relative to `today` the way a person would say it:
+/// "today", "tomorrow" or "Monday 12 May". The year is only added when it
+/// differs from the current one.
+pub fn natural_date(date: Date, today: Date, language: Language) -> String {
+    let relative = match (date - today).whole_days() {
+        -1 => Some(words(language).yesterday),
+        0 => Some(words(language).today),
+        1 => Some(words(language).tomorrow),
+        _ => None,
+    };
+    if let Some(relative) = relative {
+        return relative.to_string();
+    }
+
+    let mut text = format!(
+        "{} {} {}",
+        weekday_name(date.weekday(), language),
+        date.day(),
+        month_name(date.month(), language)
+    );
+    if date.year() != today.year() {
+        text.push_str(&format!(" {}", date.year()));
+    }
+    text
+}
+
+/// Render an instant as a natural date and a 24 hour time in `timezone`,
+/// e.g. "tomorrow 22:00".
+pub fn natural_datetime(
+    instant: OffsetDateTime,
+    clock: &dyn Clock,
+    timezone: Timezone,
+    language: Language,
+) -> String {
+    let instant = timezone.local(instant);
+    let today = timezone.local(clock.now()).date();
+    format!(
+        "{} {:02}:{:02}",
+        natural_date(instant.date(), today, language),
+        instant.hour(),
+        instant.minute()
+    )
+}
+
+/// A timezone from the tz database, e.g. `Europe/Stockholm`
+#[derive(Clone, Copy)]
+pub struct Timezone(&'static Tz);
+
+impl Timezone {
+    /// Europe/Stockholm, which the SL APIs give their times in, without an
+    /// offset
+    pub fn sl() -> Timezone {
+        Timezone(timezones::db::europe::STOCKHOLM)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    /// Offset from UTC at `instant`
+    pub fn offset_at(&self, instant: OffsetDateTime) -> UtcOffset {
+        self.0.get_offset_utc(&instant).to_utc()
+    }
+
+    /// What a wall clock in this timezone shows at `instant`
+    pub fn local(&self, instant: OffsetDateTime) -> PrimitiveDateTime {
+        let instant = instant.to_timezone(self.0);
+        PrimitiveDateTime::new(instant.date(), instant.time())
+    }
+
+    /// The instant a wall clock in this timezone shows `local`. In the hour
+    /// that repeats when summer time ends, the first one is picked. Times
+    /// skipped when it starts get the offset from after the change.
+    pub fn instant(&self, local: PrimitiveDateTime) -> OffsetDateTime {
+        local
+            .assume_timezone(self.0)
+            .take_first()
+            .unwrap_or_else(|| local.assume_timezone_utc(self.0))
+    }
+}
+
+impl Default for Timezone {
+    fn default() -> Timezone {
+        Timezone::sl()
+    }
+}
+
+impl PartialEq for Timezone {
+    fn eq(&self, other: &Timezone) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl fmt::Debug for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Timezone, String> {
+        timezones::get_by_name(name).map(Timezone).ok_or_else(|| {
+            format!("'{name}' is not in the tz database, try e.g. Europe/Stockholm or UTC")
+        })
+    }
+}
+
+/// A local time from the SL APIs as the instant it means
+pub fn sl_time(local: PrimitiveDateTime) -> OffsetDateTime {
+    Timezone::sl().instant(local)
+}
+
+/// An instant as an RFC 3339 timestamp, e.g. `2025-05-16T08:03:12+02:00`,
+/// so machine readable output says which day and timezone it means.
+pub fn timestamp(instant: OffsetDateTime) -> String {
+    instant
+        .format(&Rfc3339)
+        .expect("years in the SL APIs have four digits")
+}
+
+/// A time the way the SL APIs give it, Stockholm time without an offset
+/// like `2025-05-16T08:03:12`. RFC 3339 timestamps are read too, so
+/// departures slq wrote itself can be read back. `None` for anything else.
+pub fn parse_sl_datetime(text: &str) -> Option<OffsetDateTime> {
+    PrimitiveDateTime::parse(
+        text,
+        format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"),
+    )
+    .map(sl_time)
+    .or_else(|_| OffsetDateTime::parse(text, &Rfc3339))
+    .ok()
+}
+
+/// An instant written the way the SL APIs write times, in Stockholm time
+/// without an offset
+pub fn format_sl_datetime(instant: OffsetDateTime) -> String {
+    Timezone::sl()
+        .local(instant)
+        .format(format_description!(
+            "[year]-[month]-[day]T[hour]:[minute]:[second]"
+        ))
+        .expect("years in the SL APIs have four digits")
+}
+
+/// Serde for the times in SL's responses, see [`parse_sl_datetime`]. They
+/// are written as RFC 3339.
+pub mod sl_datetime {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use time::OffsetDateTime;
+
+    pub fn serialize<S: Serializer>(
+        instant: &OffsetDateTime,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::timestamp(*instant))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OffsetDateTime, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_sl_datetime(&text)
+            .ok_or_else(|| D::Error::custom(format!("'{text}' is not a date and time")))
+    }
+
+    /// [`sl_datetime`](super::sl_datetime) for times that may be missing
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            instant: &Option<OffsetDateTime>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match instant {
+                Some(instant) => serializer.serialize_some(&crate::dates::timestamp(*instant)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<OffsetDateTime>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|text| {
+                    crate::dates::parse_sl_datetime(&text)
+                        .ok_or_else(|| D::Error::custom(format!("'{text}' is not a date and time")))
+                })
+                .transpose()
+        }
+    }
+}
+
+/// How times of day are shown in text output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// `14:05`
+    #[default]
+    TwentyFourHour,
+    /// `2:05 PM`
+    TwelveHour,
+    /// A strftime pattern, e.g. `%H.%M`
+    Custom(String),
+}
+
+/// strftime conversions [`TimeFormat::Custom`] understands
+const STRFTIME_CONVERSIONS: &str = "HIklMSpPaAdebBmyY%";
+
+impl FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<TimeFormat, String> {
+        match value {
+            "24h" => return Ok(TimeFormat::TwentyFourHour),
+            "12h" => return Ok(TimeFormat::TwelveHour),
+            _ => {}
+        }
+        if !value.contains('%') {
+            return Err(format!(
+                "'{value}' is not 12h, 24h or a strftime pattern like %H:%M"
+            ));
+        }
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                continue;
+            }
+            let mut conversion = chars.next();
+            if conversion == Some('-') {
+                conversion = chars.next();
+            }
+            match conversion {
+                Some(conversion) if STRFTIME_CONVERSIONS.contains(conversion) => {}
+                Some(conversion) => {
+                    return Err(format!(
+                        "%{conversion} is not supported, use %H, %I, %k, %l, %M, %S, %p, %P, %a, \
+                         %A, %d, %e, %b, %B, %m, %y or %Y"
+                    ));
+                }
+                None => return Err(format!("'{value}' ends in the middle of a conversion")),
+            }
+        }
+        Ok(TimeFormat::Custom(value.to_string()))
+    }
+}
+
+impl TimeFormat {
+    pub fn format(&self, time: &PrimitiveDateTime) -> String {
+        match self {
+            TimeFormat::TwentyFourHour => strftime("%H:%M", time),
+            TimeFormat::TwelveHour => strftime("%-I:%M %p", time),
+            TimeFormat::Custom(pattern) => strftime(pattern, time),
+        }
+    }
+}
+
+/// Format `time` with a pattern already checked by [`TimeFormat::from_str`].
+/// Names are always English, like the C locale's. `%-` drops the padding
+/// from a number.
+fn strftime(pattern: &str, time: &PrimitiveDateTime) -> String {
+    let hour12 = match time.hour() % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut conversion = chars.next();
+        let pad = conversion != Some('-');
+        if !pad {
+            conversion = chars.next();
+        }
+        let number = |out: &mut String, value: u32, fill: char| {
+            let _ = match (pad, fill) {
+                (false, _) => write!(out, "{value}"),
+                (true, ' ') => write!(out, "{value:>2}"),
+                (true, _) => write!(out, "{value:02}"),
+            };
+        };
+        match conversion {
+            Some('H') => number(&mut out, time.hour().into(), '0'),
+            Some('k') => number(&mut out, time.hour().into(), ' '),
+            Some('I') => number(&mut out, hour12.into(), '0'),
+            Some('l') => number(&mut out, hour12.into(), ' '),
+            Some('M') => number(&mut out, time.minute().into(), '0'),
+            Some('S') => number(&mut out, time.second().into(), '0'),
+            Some('p') => out.push_str(if time.hour() < 12 { "AM" } else { "PM" }),
+            Some('P') => out.push_str(if time.hour() < 12 { "am" } else { "pm" }),
+            Some('a') => out.push_str(&weekday_name(time.weekday(), Language::English)[..3]),
+            Some('A') => out.push_str(weekday_name(time.weekday(), Language::English)),
+            Some('d') => number(&mut out, time.day().into(), '0'),
+            Some('e') => number(&mut out, time.day().into(), ' '),
+            Some('b') => out.push_str(&month_name(time.month(), Language::English)[..3]),
+            Some('B') => out.push_str(month_name(time.month(), Language::English)),
+            Some('m') => number(&mut out, u8::from(time.month()).into(), '0'),
+            Some('y') => number(&mut out, time.year().rem_euclid(100) as u32, '0'),
+            Some('Y') => {
+                let _ = write!(out, "{}", time.year());
+            }
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+struct Words {
+    yesterday: &'static str,
+    today: &'static str,
+    tomorrow: &'static str,
+}
+
+fn words(language: Language) -> Words {
+    match language {
+        Language::English => Words {
+            yesterday: "yesterday",
+            today: "today",
+            tomorrow: "tomorrow",
+        },
+        Language::Swedish => Words {
+            yesterday: "igår",
+            today: "idag",
+            tomorrow: "imorgon",
+        },
+    }
+}
+
+fn weekday_name(weekday: Weekday, language: Language) -> &'static str {
+    let index = weekday.number_days_from_monday() as usize;
+    match language {
+        Language::English => [
+            "Monday",
+            "Tuesday",
+            "Wednesday",
+            "Thursday",
+            "Friday",
+            "Saturday",
+            "Sunday",
+        ][index],
+        Language::Swedish => [
+            "måndag", "tisdag", "onsdag", "torsdag", "fredag", "lördag", "söndag",
+        ][index],
+    }
+}
+
+fn month_name(month: Month, language: Language) -> &'static str {
+    let index = u8::from(month) as usize - 1;
+    match language {
+        Language::English => [
+            "January",
+            "February",
+            "March",
+            "April",
+            "May",
+            "June",
+            "July",
+            "August",
+            "September",
+            "October",
+            "November",
+            "December",
+        ][index],
+        Language::Swedish => [
+            "januari",
+            "februari",
+            "mars",
+            "april",
+            "maj",
+            "juni",
+            "juli",
+            "augusti",
+            "september",
+            "oktober",
+            "november",
+            "december",
+        ][index],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use time::macros::{date, datetime, offset};
+
+    #[test]
+    fn times_follow_the_chosen_format() {
+        let afternoon = datetime!(2025-05-09 14:05:09);
+        let morning = datetime!(2025-05-09 00:30:00);
+        let parse = |value: &str| value.parse::<TimeFormat>().unwrap();
+
+        assert_eq!("14:05", TimeFormat::default().format(&afternoon));
+        assert_eq!("14:05", parse("24h").format(&afternoon));
+        assert_eq!("2:05 PM", parse("12h").format(&afternoon));
+        assert_eq!("12:30 AM", parse("12h").format(&morning));
+        assert_eq!("14.05.09", parse("%H.%M.%S").format(&afternoon));
+        assert_eq!(" 0:30", parse("%k:%M").format(&morning));
+        assert_eq!("02:05pm", parse("%I:%M%P").format(&afternoon));
+        assert_eq!(
+            "Fri 9 May 2025, 100%",
+            parse("%a %-d %b %Y, 100%%").format(&afternoon)
+        );
+
+        assert!("%H:%Q".parse::<TimeFormat>().is_err());
+        assert!("%H:%".parse::<TimeFormat>().is_err());
+        assert!("HH:MM".parse::<TimeFormat>().is_err());
+    }
+
+    #[test]
+    fn sl_times_are_read_as_stockholm_time() {
+        let summer = parse_sl_datetime("2025-05-16T23:58:00").unwrap();
+        assert_eq!(datetime!(2025-05-16 23:58:00 +2), summer);
+        assert_eq!("2025-05-16T23:58:00+02:00", timestamp(summer));
+        assert_eq!("2025-05-16T23:58:00", format_sl_datetime(summer));
+
+        let winter = parse_sl_datetime("2025-12-24T08:03:12").unwrap();
+        assert_eq!("2025-12-24T08:03:12+01:00", timestamp(winter));
+        assert_eq!(Some(winter), parse_sl_datetime("2025-12-24T07:03:12Z"));
+        assert_eq!(
+            "2025-12-24T08:03:12",
+            format_sl_datetime(datetime!(2025-12-24 07:03:12 UTC))
+        );
+        assert_eq!(None, parse_sl_datetime("08:03"));
+    }
+
+    #[test]
+    fn stockholm_follows_summer_time() {
+        let sl = Timezone::sl();
+        assert_eq!(offset!(+1), sl.offset_at(datetime!(2025-03-30 00:59 UTC)));
+        assert_eq!(offset!(+2), sl.offset_at(datetime!(2025-03-30 01:00 UTC)));
+        assert_eq!(offset!(+2), sl.offset_at(datetime!(2025-10-26 00:59 UTC)));
+        assert_eq!(offset!(+1), sl.offset_at(datetime!(2025-10-26 01:00 UTC)));
+
+        assert_eq!(
+            datetime!(2025-10-26 02:30 +2),
+            sl_time(datetime!(2025-10-26 02:30))
+        );
+        assert_eq!(
+            datetime!(2025-03-30 02:30 +2),
+            sl_time(datetime!(2025-03-30 02:30))
+        );
+    }
+
+    #[test]
+    fn times_can_be_shown_in_other_timezones() {
+        let new_york: Timezone = "America/New_York".parse().unwrap();
+        let departure = sl_time(datetime!(2025-05-16 08:03));
+        assert_eq!(datetime!(2025-05-16 02:03), new_york.local(departure));
+        assert_eq!(
+            datetime!(2025-05-16 08:03),
+            Timezone::default().local(departure)
+        );
+        assert!("Europe/Gothenburg".parse::<Timezone>().is_err());
+    }
+
+    #[test]
+    fn nearby_days_are_relative() {
+        let today = date!(2025 - 05 - 09);
+        assert_eq!("today", natural_date(today, today, Language::English));
+        assert_eq!(
+            "tomorrow",
+            natural_date(date!(2025 - 05 - 10), today, Language::English)
+        );
+        assert_eq!(
+            "igår",
+            natural_date(date!(2025 - 05 - 08), today, Language::Swedish)
+        );
+    }
+
+    #[test]
+    fn later_days_are_spelled_out() {
+        let today = date!(2025 - 05 - 09);
+        assert_eq!(
+            "Monday 12 May",
+            natural_date(date!(2025 - 05 - 12), today, Language::English)
+        );
+        assert_eq!(
+            "måndag 12 maj",
+            natural_date(date!(2025 - 05 - 12), today, Language::Swedish)
+        );
+        assert_eq!(
+            "Friday 2 January 2026",
+            natural_date(date!(2026 - 01 - 02), today, Language::English)
+        );
+    }
+
+    #[test]
+    fn datetimes_are_shown_in_the_timezone() {
+        let clock = FixedClock(datetime!(2025-05-09 12:00:00 +2));
+        let instant = datetime!(2025-05-10 20:00:00 UTC);
+        assert_eq!(
+            "tomorrow 22:00",
+            natural_datetime(instant, &clock, Timezone::sl(), Language::English)
+        );
+        assert_eq!(
+            "tomorrow 16:00",
+            natural_datetime(
+                instant,
+                &clock,
+                "America/New_York".parse().unwrap(),
+                Language::English
+            )
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! `--record-fixtures` and `--replay-fixtures`: saving the raw responses of
+//! the SL APIs to a directory, and answering requests from them later. Good
+//! for demos, working offline and producing deterministic test data.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use reqwest::StatusCode;
+
+use crate::client::{ApiResponse, ClientError, Result, TransportApi};
+
+/// Where an [`SlClient`](crate::client::SlClient) keeps its fixtures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fixtures {
+    /// Send requests as usual and save every successful response
+    Record(PathBuf),
+    /// Answer requests from saved responses, never touching the network
+    Replay(PathBuf),
+}
+
+/// The file a response from `url` is saved in: the URL without its scheme,
+/// with anything but letters, digits, dots and dashes replaced by `_`, e.g.
+/// `transport.integration.sl.se_v1_sites_9192_departures.json`.
+pub fn fixture_path(dir: &Path, url: &str) -> PathBuf {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let name: String = url
+        .trim_end_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(format!("{name}.json"))
+}
+
+/// Passes requests on to another transport, saving successful responses
+pub struct RecordingTransport<T> {
+    inner: T,
+    dir: PathBuf,
+}
+
+impl<T: TransportApi> RecordingTransport<T> {
+    pub fn new(inner: T, dir: PathBuf) -> RecordingTransport<T> {
+        RecordingTransport { inner, dir }
+    }
+}
+
+impl<T: TransportApi> TransportApi for RecordingTransport<T> {
+    fn get(&self, url: &str, etag: Option<&str>) -> Result<ApiResponse> {
+        let mut res = self.inner.get(url, etag)?;
+        if !res.status.is_success() {
+            return Ok(res);
+        }
+
+        let path = fixture_path(&self.dir, url);
+        let fixture_error = |source| ClientError::Fixture {
+            path: path.clone(),
+            source,
+        };
+        // The whole body is read so it can be saved before handing it on
+        let mut body = Vec::new();
+        res.body.read_to_end(&mut body).map_err(fixture_error)?;
+        fs::create_dir_all(&self.dir).map_err(fixture_error)?;
+        fs::write(&path, &body).map_err(fixture_error)?;
+        res.body = Box::new(io::Cursor::new(body));
+        Ok(res)
+    }
+}
+
+/// Answers requests from the responses saved by a [`RecordingTransport`]
+pub struct ReplayTransport {
+    dir: PathBuf,
+}
+
+impl ReplayTransport {
+    pub fn new(dir: PathBuf) -> ReplayTransport {
+        ReplayTransport { dir }
+    }
+}
+
+impl TransportApi for ReplayTransport {
+    fn get(&self, url: &str, _etag: Option<&str>) -> Result<ApiResponse> {
+        let path = fixture_path(&self.dir, url);
+        let body = match fs::read(&path) {
+            Ok(body) => body,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(ClientError::NotFound(format!(
+                    "a recorded response for {url} ({})",
+                    path.display()
+                )));
+            }
+            Err(source) => return Err(ClientError::Fixture { path, source }),
+        };
+        Ok(ApiResponse {
+            status: StatusCode::OK,
+            etag: None,
+            date: None,
+            body: Box::new(io::Cursor::new(body)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FixtureTransport;
+    use std::env;
+    use std::process;
+
+    fn read(res: ApiResponse) -> String {
+        let mut body = String::new();
+        let mut reader = res.body;
+        reader.read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn fixture_names_come_from_the_url() {
+        let dir = Path::new("fixtures");
+        assert_eq!(
+            dir.join("transport.integration.sl.se_v1_sites_9192_departures.json"),
+            fixture_path(
+                dir,
+                "https://transport.integration.sl.se/v1/sites/9192/departures"
+            )
+        );
+        assert_eq!(
+            dir.join("deviations.integration.sl.se_v1_messages_site_9001.json"),
+            fixture_path(
+                dir,
+                "https://deviations.integration.sl.se/v1/messages?site=9001"
+            )
+        );
+    }
+
+    #[test]
+    fn recorded_responses_are_replayed() {
+        let dir = env::temp_dir().join(format!("slq-fixtures-{}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let found = "http://sl.test/sites/9192/departures";
+        let missing = "http://sl.test/sites/1/departures";
+        let recorder = RecordingTransport::new(
+            FixtureTransport::new().with(found, r#"{"departures": []}"#),
+            dir.clone(),
+        );
+        assert_eq!(
+            r#"{"departures": []}"#,
+            read(recorder.get(found, None).unwrap())
+        );
+        // Errors are passed on without being recorded
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            recorder.get(missing, None).unwrap().status
+        );
+
+        let replay = ReplayTransport::new(dir.clone());
+        assert_eq!(
+            r#"{"departures": []}"#,
+            read(replay.get(found, None).unwrap())
+        );
+        assert!(matches!(
+            replay.get(missing, None),
+            Err(ClientError::NotFound(_))
+        ));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
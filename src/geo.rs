@@ -0,0 +1,106 @@
+//! Distances and directions on the map.
+
+use std::str::FromStr;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+/// A little slower than most people walk, since streets are rarely a
+/// straight line
+const WALKING_METERS_PER_MINUTE: f64 = 70.0;
+
+/// Great-circle distance between two points in decimal degrees.
+pub fn haversine_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+    EARTH_RADIUS_METERS * c
+}
+
+/// Minutes it takes to walk a straight line distance, rounded up
+pub fn walking_minutes(meters: f64) -> i64 {
+    (meters / WALKING_METERS_PER_MINUTE).ceil() as i64
+}
+
+/// Compass point for a bearing in degrees clockwise from north, e.g. `NE`
+pub fn compass_point(bearing: f64) -> &'static str {
+    const POINTS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+    let index = (bearing.rem_euclid(360.0) / 45.0).round() as usize % POINTS.len();
+    POINTS[index]
+}
+
+/// A rectangle on the map, given as `minlat,minlon,maxlat,maxlon`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl BoundingBox {
+    /// Whether a point is inside the box, edges included
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let corners = s
+            .split(',')
+            .map(|part| part.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid coordinate in '{s}': {e}"))?;
+        let [min_lat, min_lon, max_lat, max_lon] = corners[..] else {
+            return Err(format!(
+                "expected minlat,minlon,maxlat,maxlon but got {} numbers",
+                corners.len()
+            ));
+        };
+        if min_lat > max_lat || min_lon > max_lon {
+            return Err(format!(
+                "'{s}' is upside down, the south-west corner comes first"
+            ));
+        }
+        Ok(BoundingBox {
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn haversine_is_zero_for_identical_points() {
+        let distance = haversine_meters(59.331, 18.06, 59.331, 18.06);
+        assert!(distance < 0.001);
+    }
+
+    #[test]
+    fn bearings_round_to_the_nearest_compass_point() {
+        assert_eq!("N", compass_point(0.0));
+        assert_eq!("N", compass_point(350.0));
+        assert_eq!("NE", compass_point(40.0));
+        assert_eq!("S", compass_point(180.0));
+        assert_eq!("NW", compass_point(-45.0));
+    }
+
+    #[test]
+    fn bounding_boxes_are_parsed_south_west_corner_first() {
+        let area: BoundingBox = "59.32, 18.05,59.34,18.08".parse().unwrap();
+        assert!(area.contains(59.3313, 18.0604));
+        assert!(area.contains(59.32, 18.08));
+        assert!(!area.contains(59.35, 18.06));
+        assert!("59.32,18.05,59.34".parse::<BoundingBox>().is_err());
+        assert!("59.34,18.05,59.32,18.08".parse::<BoundingBox>().is_err());
+        assert!("north,18.05,59.34,18.08".parse::<BoundingBox>().is_err());
+    }
+}
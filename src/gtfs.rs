@@ -0,0 +1,1264 @@
+//! Offline timetables from Trafiklab's GTFS Regional static feed for SL.
+//!
+//! `slq gtfs update` downloads the feed and imports the parts slq needs into
+//! a SQLite database, which `slq timetable` then answers from without
+//! touching the network.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, OptionalExtension, params};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time};
+use tracing::debug;
+use zip::ZipArchive;
+
+use crate::client::{Departure, DepartureFilter, Line, SlClient};
+use crate::config::data_dir;
+use crate::dates::sl_time;
+use crate::geo::haversine_meters;
+use crate::types::TransportMode;
+
+/// Static GTFS feed for SL. Needs a key for "GTFS Regional Static data"
+/// from <https://www.trafiklab.se>.
+pub const FEED_URL: &str = "https://opendata.samtrafiken.se/gtfs/sl/sl.zip";
+
+/// Bumped whenever [`SCHEMA`] changes, so timetables imported by an older
+/// slq are downloaded again instead of failing on a missing column
+const SCHEMA_VERSION: i64 = 3;
+
+const SCHEMA: &str = "
+    CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE stops (
+        stop_id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        parent_station TEXT,
+        lat REAL,
+        lon REAL,
+        wheelchair_boarding INTEGER
+    );
+    CREATE TABLE routes (route_id TEXT PRIMARY KEY, short_name TEXT NOT NULL, route_type INTEGER NOT NULL);
+    CREATE TABLE trips (
+        trip_id TEXT PRIMARY KEY,
+        route_id TEXT NOT NULL,
+        service_id TEXT NOT NULL,
+        headsign TEXT,
+        direction_id INTEGER,
+        last_sequence INTEGER,
+        wheelchair_accessible INTEGER
+    );
+    CREATE TABLE stop_times (
+        trip_id TEXT NOT NULL,
+        stop_id TEXT NOT NULL,
+        sequence INTEGER NOT NULL,
+        departure INTEGER NOT NULL,
+        headsign TEXT
+    );
+    CREATE TABLE calendar (
+        service_id TEXT PRIMARY KEY,
+        days INTEGER NOT NULL,
+        start_date INTEGER NOT NULL,
+        end_date INTEGER NOT NULL
+    );
+    CREATE TABLE calendar_dates (service_id TEXT NOT NULL, date INTEGER NOT NULL, exception_type INTEGER NOT NULL);
+";
+
+/// Created after the import, which is a lot faster than keeping them up to
+/// date row by row
+const INDEXES: &str = "
+    CREATE INDEX stop_times_by_stop ON stop_times (stop_id, departure);
+    CREATE INDEX stop_times_by_trip ON stop_times (trip_id, sequence);
+    CREATE INDEX trips_by_route ON trips (route_id);
+    CREATE INDEX stops_by_parent ON stops (parent_station);
+    CREATE INDEX calendar_dates_by_date ON calendar_dates (date);
+";
+
+/// Services running on a date, `?1` as `YYYYMMDD` and `?2` the weekday bit
+/// as stored in `calendar.days`
+const ACTIVE_SERVICES: &str = "
+    SELECT service_id FROM calendar
+    WHERE start_date <= ?1 AND end_date >= ?1 AND days & ?2 != 0
+      AND service_id NOT IN (SELECT service_id FROM calendar_dates WHERE date = ?1 AND exception_type = 2)
+    UNION
+    SELECT service_id FROM calendar_dates WHERE date = ?1 AND exception_type = 1
+";
+
+/// How many rows were imported from each file
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    pub stops: u64,
+    pub routes: u64,
+    pub trips: u64,
+    pub stop_times: u64,
+}
+
+/// What the imported timetable covers
+pub struct FeedInfo {
+    pub imported_at: Option<OffsetDateTime>,
+    /// First and last day with any service
+    pub validity: Option<(Date, Date)>,
+    pub stops: u64,
+    pub trips: u64,
+}
+
+/// What the timetable knows about a trip
+#[derive(Clone)]
+pub struct Trip {
+    pub line: Line,
+    pub headsign: String,
+}
+
+/// A departure from the timetable, along with the trip it belongs to
+#[derive(Clone)]
+pub struct ScheduledDeparture {
+    pub trip_id: String,
+    pub departure: Departure,
+    /// Whether the vehicle takes wheelchairs, when the feed says
+    pub wheelchair_accessible: Option<bool>,
+    /// Whether the stop can be boarded from without steps, when the feed
+    /// says, for the stop itself or else for its station
+    pub wheelchair_boarding: Option<bool>,
+}
+
+impl ScheduledDeparture {
+    /// Known to take wheelchairs, from a stop not known to have steps
+    pub fn is_step_free(&self) -> bool {
+        self.wheelchair_accessible == Some(true) && self.wheelchair_boarding != Some(false)
+    }
+}
+
+/// One stop along a trip
+#[derive(Debug, Clone, PartialEq)]
+pub struct TripStop {
+    pub sequence: u32,
+    pub stop_id: String,
+    pub name: String,
+    pub scheduled: PrimitiveDateTime,
+    /// See [`ScheduledDeparture::wheelchair_boarding`]
+    pub wheelchair_boarding: Option<bool>,
+}
+
+/// The stops a line calls at in one direction
+pub struct Route {
+    /// GTFS direction, 0 or 1, when the feed has one
+    pub direction: Option<u8>,
+    pub line: Line,
+    pub headsign: String,
+    pub stops: Vec<RouteStop>,
+}
+
+pub struct RouteStop {
+    pub name: String,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+/// How far from a position [`Timetable::nearest_stop`] looks, in degrees.
+/// Roughly a kilometre in both directions at Stockholm's latitude.
+const NEARBY_LAT: f64 = 0.01;
+const NEARBY_LON: f64 = 0.02;
+
+/// The imported timetable
+pub struct Timetable {
+    conn: Connection,
+}
+
+impl Timetable {
+    /// `$SLQ_DATA_DIR/gtfs.sqlite`, or `$XDG_DATA_HOME/slq/gtfs.sqlite`
+    /// (falling back to `~/.local/share/slq/gtfs.sqlite`).
+    pub fn path() -> Option<PathBuf> {
+        Some(data_dir()?.join("gtfs.sqlite"))
+    }
+
+    /// Open the imported timetable, failing when there is none yet.
+    pub fn open() -> Result<Timetable> {
+        let Some(path) = Timetable::path() else {
+            bail!("no data directory for the timetable, set SLQ_DATA_DIR or HOME");
+        };
+        if !path.exists() {
+            bail!("no offline timetable yet, download one with `slq gtfs update`");
+        }
+        Timetable::open_at(&path)
+    }
+
+    pub fn open_at(path: &Path) -> Result<Timetable> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open the timetable at {}", path.display()))?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            bail!(
+                "the offline timetable was imported by another version of slq, run `slq gtfs update` again"
+            );
+        }
+        Ok(Timetable { conn })
+    }
+
+    /// Import a GTFS zip archive into a new database at `path`, replacing
+    /// any timetable already there once the import has finished.
+    pub fn import(path: &Path, feed: impl Read + Seek) -> Result<ImportStats> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let partial = path.with_extension(format!("partial.{}", std::process::id()));
+        let _ = fs::remove_file(&partial);
+
+        let stats = import_into(&partial, feed);
+        match stats {
+            Ok(stats) => {
+                fs::rename(&partial, path)
+                    .with_context(|| format!("failed to replace {}", path.display()))?;
+                Ok(stats)
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&partial);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn info(&self) -> Result<FeedInfo> {
+        let imported_at: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'imported_at'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let (first, last): (Option<i64>, Option<i64>) = self.conn.query_row(
+            "SELECT MIN(first), MAX(last) FROM (
+                SELECT MIN(start_date) AS first, MAX(end_date) AS last FROM calendar
+                UNION ALL
+                SELECT MIN(date), MAX(date) FROM calendar_dates WHERE exception_type = 1
+            )",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let count = |table: &str| -> Result<u64> {
+            Ok(self
+                .conn
+                .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                    row.get(0)
+                })?)
+        };
+
+        Ok(FeedInfo {
+            imported_at: imported_at.and_then(|at| OffsetDateTime::parse(&at, &Rfc3339).ok()),
+            validity: first
+                .zip(last)
+                .and_then(|(first, last)| Some((date_from_int(first)?, date_from_int(last)?))),
+            stops: count("stops")?,
+            trips: count("trips")?,
+        })
+    }
+
+    /// Ids of the stops called `name` and of the platforms within them
+    pub fn stops_named(&self, name: &str) -> Result<Vec<String>> {
+        let mut statement = self.conn.prepare(
+            "SELECT stop_id FROM stops WHERE name = ?1 COLLATE NOCASE
+             UNION
+             SELECT child.stop_id FROM stops child
+             JOIN stops parent ON child.parent_station = parent.stop_id
+             WHERE parent.name = ?1 COLLATE NOCASE",
+        )?;
+        let ids = statement
+            .query_map([name], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(ids)
+    }
+
+    /// Trips run by the line with this designation, keyed by trip id
+    pub fn trips_on_line(&self, line: &str) -> Result<HashMap<String, Trip>> {
+        let mut statement = self.conn.prepare(
+            "SELECT t.trip_id, r.short_name, r.route_type, COALESCE(t.headsign, '')
+             FROM trips t
+             JOIN routes r ON r.route_id = t.route_id
+             WHERE r.short_name = ?1 COLLATE NOCASE",
+        )?;
+        let trips = statement
+            .query_map([line.trim()], |row| {
+                Ok((
+                    row.get(0)?,
+                    Trip {
+                        line: Line {
+                            designation: row.get(1)?,
+                            transport_mode: transport_mode(row.get(2)?),
+                            id: None,
+                            group_of_lines: None,
+                            transport_authority_id: None,
+                        },
+                        headsign: row.get(3)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<HashMap<String, Trip>>>()?;
+        Ok(trips)
+    }
+
+    /// What the timetable knows about a single trip
+    pub fn trip(&self, trip_id: &str) -> Result<Option<Trip>> {
+        let trip = self
+            .conn
+            .query_row(
+                "SELECT r.short_name, r.route_type, COALESCE(t.headsign, '')
+                 FROM trips t
+                 JOIN routes r ON r.route_id = t.route_id
+                 WHERE t.trip_id = ?1",
+                [trip_id],
+                |row| {
+                    Ok(Trip {
+                        line: Line {
+                            designation: row.get(0)?,
+                            transport_mode: transport_mode(row.get(1)?),
+                            id: None,
+                            group_of_lines: None,
+                            transport_authority_id: None,
+                        },
+                        headsign: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(trip)
+    }
+
+    /// Every stop a trip calls at in order, with the times it is scheduled
+    /// to leave them when run on `service_day`
+    pub fn trip_stops(&self, trip_id: &str, service_day: Date) -> Result<Vec<TripStop>> {
+        let mut statement = self.conn.prepare(
+            "SELECT st.sequence, st.stop_id, s.name, st.departure,
+                    COALESCE(s.wheelchair_boarding, p.wheelchair_boarding)
+             FROM stop_times st
+             JOIN stops s ON s.stop_id = st.stop_id
+             LEFT JOIN stops p ON p.stop_id = s.parent_station
+             WHERE st.trip_id = ?1
+             ORDER BY st.sequence",
+        )?;
+        let midnight = service_day.midnight();
+        let stops = statement
+            .query_map([trip_id], |row| {
+                Ok(TripStop {
+                    sequence: row.get(0)?,
+                    stop_id: row.get(1)?,
+                    name: row.get(2)?,
+                    scheduled: midnight + Duration::seconds(row.get(3)?),
+                    wheelchair_boarding: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<TripStop>>>()?;
+        Ok(stops)
+    }
+
+    /// The stops served by a line, one route per direction. Each route
+    /// follows the trip with the most stops, so short turns are covered but
+    /// lines that branch only show their longest branch.
+    pub fn routes(&self, line: &str, direction: Option<u8>) -> Result<Vec<Route>> {
+        let mut statement = self.conn.prepare(
+            "SELECT t.trip_id, t.direction_id, COALESCE(t.headsign, ''), r.short_name, r.route_type
+             FROM trips t
+             JOIN routes r ON r.route_id = t.route_id
+             WHERE r.short_name = ?1 COLLATE NOCASE AND (?2 IS NULL OR t.direction_id = ?2)
+             ORDER BY t.direction_id, t.last_sequence DESC, t.trip_id",
+        )?;
+        let mut rows = statement.query(params![line.trim(), direction])?;
+
+        let mut longest: Vec<(String, Option<u8>, String, Line)> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let direction: Option<u8> = row.get(1)?;
+            if longest.iter().any(|(_, seen, _, _)| *seen == direction) {
+                continue;
+            }
+            let line = Line {
+                designation: row.get(3)?,
+                transport_mode: transport_mode(row.get(4)?),
+                id: None,
+                group_of_lines: None,
+                transport_authority_id: None,
+            };
+            longest.push((row.get(0)?, direction, row.get(2)?, line));
+        }
+
+        let mut stops = self.conn.prepare(
+            "SELECT s.name, s.lat, s.lon
+             FROM stop_times st
+             JOIN stops s ON s.stop_id = st.stop_id
+             WHERE st.trip_id = ?1
+             ORDER BY st.sequence",
+        )?;
+        longest
+            .into_iter()
+            .map(|(trip_id, direction, headsign, line)| {
+                let stops = stops
+                    .query_map([trip_id], |row| {
+                        Ok(RouteStop {
+                            name: row.get(0)?,
+                            lat: row.get(1)?,
+                            lon: row.get(2)?,
+                        })
+                    })?
+                    .collect::<rusqlite::Result<Vec<RouteStop>>>()?;
+                Ok(Route {
+                    direction,
+                    line,
+                    headsign,
+                    stops,
+                })
+            })
+            .collect()
+    }
+
+    /// Name of and distance in meters to the stop closest to a position,
+    /// when there is one within a kilometre or so
+    pub fn nearest_stop(&self, lat: f64, lon: f64) -> Result<Option<(String, f64)>> {
+        let mut statement = self.conn.prepare(
+            "SELECT name, lat, lon FROM stops
+             WHERE lat BETWEEN ?1 AND ?2 AND lon BETWEEN ?3 AND ?4",
+        )?;
+        let mut rows = statement.query(params![
+            lat - NEARBY_LAT,
+            lat + NEARBY_LAT,
+            lon - NEARBY_LON,
+            lon + NEARBY_LON
+        ])?;
+
+        let mut nearest: Option<(String, f64)> = None;
+        while let Some(row) = rows.next()? {
+            let distance = haversine_meters(lat, lon, row.get(1)?, row.get(2)?);
+            if nearest.as_ref().is_none_or(|(_, best)| distance < *best) {
+                nearest = Some((row.get(0)?, distance));
+            }
+        }
+        Ok(nearest)
+    }
+
+    /// Scheduled departures from `stops` at or after `at`, in order, that
+    /// pass `filter`, and are step free when `step_free_only` is set. Trips
+    /// running past midnight belong to the previous day's service, so those
+    /// are included as well.
+    pub fn departures(
+        &self,
+        stops: &[String],
+        at: PrimitiveDateTime,
+        filter: &DepartureFilter,
+        step_free_only: bool,
+    ) -> Result<Vec<ScheduledDeparture>> {
+        let seconds = i64::from(at.time().hour()) * 3600
+            + i64::from(at.time().minute()) * 60
+            + i64::from(at.time().second());
+        let limit = filter.limit();
+
+        let mut departures = Vec::new();
+        for (service_day, from) in [
+            (at.date().previous_day(), seconds + 86_400),
+            (Some(at.date()), seconds),
+        ] {
+            let Some(service_day) = service_day else {
+                continue;
+            };
+            departures.extend(self.departures_on(
+                stops,
+                service_day,
+                from,
+                filter,
+                step_free_only,
+                limit,
+            )?);
+        }
+        departures.sort_by_key(|d| d.departure.expected);
+        departures.truncate(limit);
+        Ok(departures)
+    }
+
+    fn departures_on(
+        &self,
+        stops: &[String],
+        service_day: Date,
+        from: i64,
+        filter: &DepartureFilter,
+        step_free_only: bool,
+        limit: usize,
+    ) -> Result<Vec<ScheduledDeparture>> {
+        // ?1 to ?3 are taken by the date, weekday and time
+        let placeholders = (4..4 + stops.len())
+            .map(|index| format!("?{index}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut statement = self.conn.prepare(&format!(
+            "WITH active AS ({ACTIVE_SERVICES})
+             SELECT st.departure, r.short_name, r.route_type, COALESCE(st.headsign, t.headsign, ''), t.trip_id,
+                    t.wheelchair_accessible, COALESCE(s.wheelchair_boarding, p.wheelchair_boarding)
+             FROM stop_times st
+             JOIN trips t ON t.trip_id = st.trip_id
+             JOIN routes r ON r.route_id = t.route_id
+             JOIN stops s ON s.stop_id = st.stop_id
+             LEFT JOIN stops p ON p.stop_id = s.parent_station
+             WHERE st.stop_id IN ({placeholders})
+               AND st.departure >= ?3
+               AND st.sequence < t.last_sequence
+               AND t.service_id IN active
+             ORDER BY st.departure"
+        ))?;
+
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = vec![
+            Box::new(date_to_int(service_day)),
+            Box::new(1i64 << service_day.weekday().number_days_from_monday()),
+            Box::new(from),
+        ];
+        values.extend(
+            stops
+                .iter()
+                .map(|stop| Box::new(stop.clone()) as Box<dyn rusqlite::ToSql>),
+        );
+        let mut rows = statement.query(rusqlite::params_from_iter(values.iter()))?;
+
+        let midnight = service_day.midnight();
+        let mut departures = Vec::new();
+        while departures.len() < limit
+            && let Some(row) = rows.next()?
+        {
+            let expected = sl_time(midnight + Duration::seconds(row.get(0)?));
+            let departure = Departure {
+                destination: row.get(3)?,
+                expected,
+                line: Line {
+                    designation: row.get(1)?,
+                    transport_mode: transport_mode(row.get(2)?),
+                    id: None,
+                    group_of_lines: None,
+                    transport_authority_id: None,
+                },
+                stop_point: None,
+                journey: None,
+                scheduled: Some(expected),
+                state: None,
+                display: None,
+                direction: None,
+                direction_code: None,
+                stop_area: None,
+            };
+            let scheduled = ScheduledDeparture {
+                trip_id: row.get(4)?,
+                departure,
+                wheelchair_accessible: row.get(5)?,
+                wheelchair_boarding: row.get(6)?,
+            };
+            if filter.matches(&scheduled.departure) && (!step_free_only || scheduled.is_step_free())
+            {
+                departures.push(scheduled);
+            }
+        }
+        Ok(departures)
+    }
+}
+
+/// Download the feed with a Trafiklab API key into `file`. Returns the
+/// number of bytes downloaded.
+pub fn download(client: &SlClient, key: &str, file: &mut File) -> Result<u64> {
+    let mut res = client
+        .http()
+        .get(FEED_URL)
+        .query(&[("key", key)])
+        .send()
+        .and_then(|res| res.error_for_status())
+        // The URL carries the API key, keep it out of error messages
+        .map_err(|err| err.without_url())
+        .context("failed to download the GTFS feed from Trafiklab")?;
+    io::copy(&mut res, file).context("failed to download the GTFS feed from Trafiklab")
+}
+
+fn import_into(path: &Path, feed: impl Read + Seek) -> Result<ImportStats> {
+    let mut archive = ZipArchive::new(feed).context("the GTFS feed is not a zip archive")?;
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")?;
+    conn.execute_batch(SCHEMA)?;
+
+    let tx = conn.transaction()?;
+    let mut stats = ImportStats::default();
+
+    {
+        let mut insert = tx.prepare(
+            "INSERT OR REPLACE INTO stops (stop_id, name, parent_station, lat, lon, wheelchair_boarding) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        stats.stops = read_file(&mut archive, "stops.txt", |row| {
+            insert.execute(params![
+                row.required("stop_id")?,
+                row.required("stop_name")?,
+                row.optional("parent_station"),
+                row.optional("stop_lat")
+                    .and_then(|lat| lat.parse::<f64>().ok()),
+                row.optional("stop_lon")
+                    .and_then(|lon| lon.parse::<f64>().ok()),
+                accessibility(row.optional("wheelchair_boarding")),
+            ])?;
+            Ok(())
+        })?;
+    }
+
+    {
+        let mut insert = tx.prepare(
+            "INSERT OR REPLACE INTO routes (route_id, short_name, route_type) VALUES (?1, ?2, ?3)",
+        )?;
+        stats.routes = read_file(&mut archive, "routes.txt", |row| {
+            let route_type: i64 = row
+                .required("route_type")?
+                .parse()
+                .context("route_type is not a number")?;
+            insert.execute(params![
+                row.required("route_id")?,
+                row.optional("route_short_name").unwrap_or_default(),
+                route_type,
+            ])?;
+            Ok(())
+        })?;
+    }
+
+    {
+        let mut insert = tx.prepare(
+            "INSERT OR REPLACE INTO trips (trip_id, route_id, service_id, headsign, direction_id, wheelchair_accessible) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        stats.trips = read_file(&mut archive, "trips.txt", |row| {
+            insert.execute(params![
+                row.required("trip_id")?,
+                row.required("route_id")?,
+                row.required("service_id")?,
+                row.optional("trip_headsign"),
+                row.optional("direction_id")
+                    .and_then(|direction| direction.parse::<i64>().ok()),
+                accessibility(row.optional("wheelchair_accessible")),
+            ])?;
+            Ok(())
+        })?;
+    }
+
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO stop_times (trip_id, stop_id, sequence, departure, headsign) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        // The last stop of a trip is where it ends, so nothing departs there
+        let mut last_sequences: HashMap<String, i64> = HashMap::new();
+        stats.stop_times = read_file(&mut archive, "stop_times.txt", |row| {
+            let trip_id = row.required("trip_id")?;
+            let sequence: i64 = row
+                .required("stop_sequence")?
+                .parse()
+                .context("stop_sequence is not a number")?;
+            let last = last_sequences
+                .entry(trip_id.to_string())
+                .or_insert(sequence);
+            *last = (*last).max(sequence);
+
+            // Times are optional between timepoints, SL publishes them all
+            let Some(departure) = row
+                .optional("departure_time")
+                .or_else(|| row.optional("arrival_time"))
+            else {
+                return Ok(());
+            };
+            insert.execute(params![
+                trip_id,
+                row.required("stop_id")?,
+                sequence,
+                parse_gtfs_time(departure)?,
+                row.optional("stop_headsign"),
+            ])?;
+            Ok(())
+        })?;
+
+        let mut update = tx.prepare("UPDATE trips SET last_sequence = ?2 WHERE trip_id = ?1")?;
+        for (trip_id, sequence) in &last_sequences {
+            update.execute(params![trip_id, sequence])?;
+        }
+    }
+
+    {
+        let mut insert = tx.prepare(
+            "INSERT OR REPLACE INTO calendar (service_id, days, start_date, end_date) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let days = [
+            "monday",
+            "tuesday",
+            "wednesday",
+            "thursday",
+            "friday",
+            "saturday",
+            "sunday",
+        ];
+        optional_file(&mut archive, "calendar.txt", |row| {
+            let mut mask = 0i64;
+            for (bit, day) in days.iter().enumerate() {
+                if row.required(day)? == "1" {
+                    mask |= 1 << bit;
+                }
+            }
+            insert.execute(params![
+                row.required("service_id")?,
+                mask,
+                parse_gtfs_date(row.required("start_date")?)?,
+                parse_gtfs_date(row.required("end_date")?)?,
+            ])?;
+            Ok(())
+        })?;
+    }
+
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO calendar_dates (service_id, date, exception_type) VALUES (?1, ?2, ?3)",
+        )?;
+        optional_file(&mut archive, "calendar_dates.txt", |row| {
+            let exception_type: i64 = row
+                .required("exception_type")?
+                .parse()
+                .context("exception_type is not a number")?;
+            insert.execute(params![
+                row.required("service_id")?,
+                parse_gtfs_date(row.required("date")?)?,
+                exception_type,
+            ])?;
+            Ok(())
+        })?;
+    }
+
+    tx.execute(
+        "INSERT INTO meta (key, value) VALUES ('imported_at', ?1)",
+        [OffsetDateTime::now_utc().format(&Rfc3339)?],
+    )?;
+    tx.commit()?;
+    conn.execute_batch(INDEXES)?;
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    debug!("imported {stats:?}");
+    Ok(stats)
+}
+
+/// `wheelchair_boarding` and `wheelchair_accessible` are `1` for yes, `2`
+/// for no and `0` or empty when nobody knows
+fn accessibility(value: Option<&str>) -> Option<bool> {
+    match value {
+        Some("1") => Some(true),
+        Some("2") => Some(false),
+        _ => None,
+    }
+}
+
+/// Feed every record of a required file in the archive to `row`. Returns
+/// the number of records.
+fn read_file<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+    row: impl FnMut(&Row) -> Result<()>,
+) -> Result<u64> {
+    let file = archive
+        .by_name(name)
+        .with_context(|| format!("the GTFS feed has no {name}"))?;
+    read_records(BufReader::new(file), row).with_context(|| format!("failed to import {name}"))
+}
+
+/// Like [`read_file`], for files the GTFS specification lets feeds leave out
+fn optional_file<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+    row: impl FnMut(&Row) -> Result<()>,
+) -> Result<u64> {
+    if archive.index_for_name(name).is_none() {
+        return Ok(0);
+    }
+    read_file(archive, name, row)
+}
+
+fn read_records(reader: impl BufRead, mut row: impl FnMut(&Row) -> Result<()>) -> Result<u64> {
+    let mut reader = CsvReader { reader };
+    let Some(mut header) = reader.record()? else {
+        return Ok(0);
+    };
+    if let Some(first) = header.first_mut() {
+        *first = first.trim_start_matches('\u{feff}').to_string();
+    }
+    let columns: HashMap<String, usize> = header
+        .into_iter()
+        .enumerate()
+        .map(|(index, name)| (name.trim().to_string(), index))
+        .collect();
+
+    let mut count = 0;
+    while let Some(fields) = reader.record()? {
+        // Blank lines at the end of a file
+        if fields.len() == 1 && fields[0].is_empty() {
+            continue;
+        }
+        row(&Row {
+            columns: &columns,
+            fields,
+        })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// A record of a GTFS file, with its fields looked up by column name
+struct Row<'a> {
+    columns: &'a HashMap<String, usize>,
+    fields: Vec<String>,
+}
+
+impl Row<'_> {
+    /// A field that is empty when it's missing
+    fn optional(&self, column: &str) -> Option<&str> {
+        self.columns
+            .get(column)
+            .and_then(|index| self.fields.get(*index))
+            .map(|field| field.as_str())
+            .filter(|field| !field.is_empty())
+    }
+
+    fn required(&self, column: &str) -> Result<&str> {
+        self.optional(column)
+            .with_context(|| format!("a record is missing {column}"))
+    }
+}
+
+/// Reads the CSV dialect of GTFS files: comma separated, with double quotes
+/// around fields that contain commas, quotes or line breaks.
+struct CsvReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    fn record(&mut self) -> io::Result<Option<Vec<String>>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        loop {
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if quoted && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if quoted => quoted = false,
+                    '"' if field.is_empty() => quoted = true,
+                    ',' if !quoted => fields.push(mem::take(&mut field)),
+                    '\r' | '\n' if !quoted => {}
+                    c => field.push(c),
+                }
+            }
+            // A quoted field continues on the next line
+            line.clear();
+            if !quoted || self.reader.read_line(&mut line)? == 0 {
+                break;
+            }
+        }
+        fields.push(field);
+        Ok(Some(fields))
+    }
+}
+
+/// Seconds after midnight of a GTFS time. Hours go past 23 for trips that
+/// run past midnight, e.g. `25:10:00`.
+fn parse_gtfs_time(time: &str) -> Result<i64> {
+    let parts = time
+        .trim()
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<Vec<i64>, _>>()
+        .with_context(|| format!("'{time}' is not a GTFS time"))?;
+    match parts[..] {
+        [hours, minutes, seconds] => Ok(hours * 3600 + minutes * 60 + seconds),
+        _ => bail!("'{time}' is not a GTFS time"),
+    }
+}
+
+/// A GTFS `YYYYMMDD` date, kept as that number so dates compare in order
+fn parse_gtfs_date(date: &str) -> Result<i64> {
+    let value: i64 = date
+        .trim()
+        .parse()
+        .with_context(|| format!("'{date}' is not a GTFS date"))?;
+    if date_from_int(value).is_none() {
+        bail!("'{date}' is not a GTFS date");
+    }
+    Ok(value)
+}
+
+fn date_to_int(date: Date) -> i64 {
+    i64::from(date.year()) * 10_000
+        + i64::from(u8::from(date.month())) * 100
+        + i64::from(date.day())
+}
+
+fn date_from_int(value: i64) -> Option<Date> {
+    let month = u8::try_from(value / 100 % 100).ok()?.try_into().ok()?;
+    Date::from_calendar_date(
+        i32::try_from(value / 10_000).ok()?,
+        month,
+        u8::try_from(value % 100).ok()?,
+    )
+    .ok()
+}
+
+/// Map a GTFS route type, including the extended types SL's feed uses, to a
+/// transport mode. Types slq has no mode for count as buses, which covers
+/// coaches and replacement services.
+fn transport_mode(route_type: i64) -> TransportMode {
+    match route_type {
+        0 | 900..=999 => TransportMode::Tram,
+        1 | 401..=402 | 500..=599 => TransportMode::Metro,
+        2 | 100..=199 | 400 | 403..=404 => TransportMode::Train,
+        4 | 1000..=1099 | 1200..=1299 => TransportMode::Ferry,
+        1500..=1599 => TransportMode::Taxi,
+        _ => TransportMode::Bus,
+    }
+}
+
+/// The starting point of a timetable lookup, from `--at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct At {
+    pub date: Option<Date>,
+    pub time: Time,
+}
+
+impl At {
+    /// The date and time to look up, with the date defaulting to `today`
+    pub fn on(self, today: Date) -> PrimitiveDateTime {
+        PrimitiveDateTime::new(self.date.unwrap_or(today), self.time)
+    }
+}
+
+/// Parse `--at`: a time like `08:30`, or a date and time like
+/// `2025-05-12 08:30`.
+pub fn parse_at(value: &str) -> Result<At, String> {
+    let time_format = format_description!("[hour]:[minute]");
+    let value = value.trim();
+    let (date, time) = match value.rsplit_once([' ', 'T']) {
+        Some((date, time)) => {
+            let date = Date::parse(date, format_description!("[year]-[month]-[day]"))
+                .map_err(|_| format!("'{date}' is not a date like 2025-05-12"))?;
+            (Some(date), time)
+        }
+        None => (None, value),
+    };
+    let time =
+        Time::parse(time, time_format).map_err(|_| format!("'{time}' is not a time like 08:30"))?;
+    Ok(At { date, time })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{date, datetime, time};
+
+    const FEED: &[(&str, &str)] = &[
+        (
+            "stops.txt",
+            "\u{feff}stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station,wheelchair_boarding\n\
+             1,Slussen,59.32,18.07,1,,1\n\
+             11,Slussen,59.32,18.07,0,1,0\n\
+             2,Gamla stan,59.32,18.06,0,,2\n",
+        ),
+        (
+            "routes.txt",
+            "route_id,route_short_name,route_type\nr14,14,401\nr43,43,700\n",
+        ),
+        (
+            "trips.txt",
+            "route_id,service_id,trip_id,trip_headsign,direction_id,wheelchair_accessible\n\
+             r14,weekday,t1,\"Mörby centrum\",0,1\n\
+             r14,weekday,t2,\"Fruängen, via Slussen\",1,1\n\
+             r43,weekend,t3,Hammarby sjöstad,0,\n\
+             r14,weekday,t4,Late,0,2\n",
+        ),
+        (
+            "stop_times.txt",
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             t1,08:00:00,08:00:00,11,1\n\
+             t1,08:02:00,08:02:00,2,2\n\
+             t2,07:58:00,07:58:00,2,1\n\
+             t2,08:00:00,08:00:00,11,2\n\
+             t3,08:05:00,08:05:00,11,1\n\
+             t3,08:10:00,08:10:00,2,2\n\
+             t4,24:30:00,24:30:00,11,1\n\
+             t4,24:32:00,24:32:00,2,2\n",
+        ),
+        (
+            "calendar.txt",
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             weekday,1,1,1,1,1,0,0,20250101,20251231\n\
+             weekend,0,0,0,0,0,1,1,20250101,20251231\n",
+        ),
+        (
+            "calendar_dates.txt",
+            "service_id,date,exception_type\nweekday,20250509,2\nweekend,20250509,1\n",
+        ),
+    ];
+
+    fn timetable(name: &str) -> Timetable {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (file, contents) in FEED {
+            zip.start_file(*file, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            io::Write::write_all(&mut zip, contents.as_bytes()).unwrap();
+        }
+        let feed = zip.finish().unwrap();
+
+        let dir = std::env::temp_dir().join(format!("slq-gtfs-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("gtfs.sqlite");
+        Timetable::import(&path, io::Cursor::new(feed.into_inner())).unwrap();
+        Timetable::open_at(&path).unwrap()
+    }
+
+    fn everything() -> DepartureFilter {
+        DepartureFilter::default()
+    }
+
+    #[test]
+    fn departures_follow_the_calendar() -> Result<()> {
+        let timetable = timetable("calendar");
+        let stops = timetable.stops_named("slussen")?;
+        assert_eq!(vec!["1", "11"], stops);
+
+        // A Thursday: weekday service, where trip 2 ends at Slussen
+        let departures =
+            timetable.departures(&stops, datetime!(2025-05-08 07:30), &everything(), false)?;
+        let times: Vec<(OffsetDateTime, &str)> = departures
+            .iter()
+            .map(|d| (d.departure.expected, d.departure.destination.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (datetime!(2025-05-08 08:00:00 +2), "Mörby centrum"),
+                (datetime!(2025-05-09 00:30:00 +2), "Late")
+            ],
+            times
+        );
+        assert_eq!(
+            TransportMode::Metro,
+            departures[0].departure.line.transport_mode
+        );
+        assert_eq!("t1", departures[0].trip_id);
+
+        // A Friday replaced by the weekend service, after Thursday's late trip
+        let departures =
+            timetable.departures(&stops, datetime!(2025-05-09 00:15), &everything(), false)?;
+        let times: Vec<OffsetDateTime> = departures.iter().map(|d| d.departure.expected).collect();
+        assert_eq!(
+            vec![
+                datetime!(2025-05-09 00:30:00 +2),
+                datetime!(2025-05-09 08:05:00 +2)
+            ],
+            times
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn step_free_departures_need_an_accessible_vehicle_and_stop() -> Result<()> {
+        let timetable = timetable("step-free");
+        let slussen = timetable.stops_named("Slussen")?;
+        let thursday = datetime!(2025-05-08 07:30);
+        let departures = timetable.departures(&slussen, thursday, &everything(), false)?;
+        let access: Vec<(&str, Option<bool>, Option<bool>)> = departures
+            .iter()
+            .map(|d| {
+                (
+                    d.trip_id.as_str(),
+                    d.wheelchair_accessible,
+                    d.wheelchair_boarding,
+                )
+            })
+            .collect();
+        // Stop 11 doesn't know, so its station's answer counts
+        assert_eq!(
+            vec![
+                ("t1", Some(true), Some(true)),
+                ("t4", Some(false), Some(true))
+            ],
+            access
+        );
+        let step_free = timetable.departures(&slussen, thursday, &everything(), true)?;
+        assert_eq!(
+            vec!["t1"],
+            step_free
+                .iter()
+                .map(|d| d.trip_id.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        // Trip 2 takes wheelchairs, but Gamla stan has steps
+        let gamla_stan = timetable.stops_named("Gamla stan")?;
+        assert_eq!(
+            1,
+            timetable
+                .departures(&gamla_stan, thursday, &everything(), false)?
+                .len()
+        );
+        assert!(
+            timetable
+                .departures(&gamla_stan, thursday, &everything(), true)?
+                .is_empty()
+        );
+
+        let stops = timetable.trip_stops("t2", date!(2025 - 05 - 08))?;
+        assert_eq!(
+            vec![Some(false), Some(true)],
+            stops
+                .iter()
+                .map(|stop| stop.wheelchair_boarding)
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn vehicles_are_matched_to_trips_and_stops() -> Result<()> {
+        let timetable = timetable("vehicles");
+        let trips = timetable.trips_on_line("14")?;
+        let mut ids: Vec<&str> = trips.keys().map(String::as_str).collect();
+        ids.sort();
+        assert_eq!(vec!["t1", "t2", "t4"], ids);
+        assert_eq!("Mörby centrum", trips["t1"].headsign);
+
+        let (name, distance) = timetable.nearest_stop(59.3201, 18.061)?.unwrap();
+        assert_eq!("Gamla stan", name);
+        assert!(distance < 100.0);
+        assert!(timetable.nearest_stop(59.9, 18.0)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn trips_list_their_stops_in_order() -> Result<()> {
+        let timetable = timetable("trip");
+        let trip = timetable.trip("t4")?.unwrap();
+        assert_eq!("14", trip.line.designation);
+        assert_eq!("Late", trip.headsign);
+        assert!(timetable.trip("t9")?.is_none());
+
+        let stops = timetable.trip_stops("t4", date!(2025 - 05 - 08))?;
+        let names: Vec<(&str, PrimitiveDateTime)> = stops
+            .iter()
+            .map(|stop| (stop.name.as_str(), stop.scheduled))
+            .collect();
+        assert_eq!(
+            vec![
+                ("Slussen", datetime!(2025-05-09 00:30)),
+                ("Gamla stan", datetime!(2025-05-09 00:32))
+            ],
+            names
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn routes_are_listed_per_direction() -> Result<()> {
+        let timetable = timetable("routes");
+        let routes = timetable.routes("14", None)?;
+        let summary: Vec<(Option<u8>, &str, Vec<&str>)> = routes
+            .iter()
+            .map(|route| {
+                let stops = route.stops.iter().map(|stop| stop.name.as_str()).collect();
+                (route.direction, route.headsign.as_str(), stops)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (Some(0), "Mörby centrum", vec!["Slussen", "Gamla stan"]),
+                (
+                    Some(1),
+                    "Fruängen, via Slussen",
+                    vec!["Gamla stan", "Slussen"]
+                ),
+            ],
+            summary
+        );
+
+        let routes = timetable.routes("14", Some(1))?;
+        assert_eq!(1, routes.len());
+        assert!(timetable.routes("99", None)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn departures_are_filtered_and_limited() -> Result<()> {
+        let timetable = timetable("filter");
+        let stops = timetable.stops_named("Slussen")?;
+        let filter = DepartureFilter::default()
+            .line(Some("43".to_string()))
+            .count(Some(1));
+        let departures =
+            timetable.departures(&stops, datetime!(2025-05-10 00:00), &filter, false)?;
+        assert_eq!(1, departures.len());
+        assert_eq!("43", departures[0].departure.line.designation);
+        assert_eq!(
+            TransportMode::Bus,
+            departures[0].departure.line.transport_mode
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn feed_info_reports_validity() -> Result<()> {
+        let info = timetable("info").info()?;
+        assert_eq!(
+            Some((date!(2025 - 01 - 01), date!(2025 - 12 - 31))),
+            info.validity
+        );
+        assert_eq!(3, info.stops);
+        assert_eq!(4, info.trips);
+        assert!(info.imported_at.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn quoted_fields_may_contain_commas_quotes_and_newlines() -> io::Result<()> {
+        let mut reader = CsvReader {
+            reader: "a,\"b, \"\"c\"\"\",\"d\ne\"\r\nf\n".as_bytes(),
+        };
+        assert_eq!(
+            Some(vec![
+                "a".to_string(),
+                "b, \"c\"".to_string(),
+                "d\ne".to_string()
+            ]),
+            reader.record()?
+        );
+        assert_eq!(Some(vec!["f".to_string()]), reader.record()?);
+        assert_eq!(None, reader.record()?);
+        Ok(())
+    }
+
+    #[test]
+    fn times_past_midnight_are_kept() -> Result<()> {
+        assert_eq!(25 * 3600 + 10 * 60, parse_gtfs_time("25:10:00")?);
+        assert!(parse_gtfs_time("8am").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn at_accepts_a_time_or_a_date_and_time() {
+        assert_eq!(
+            Ok(At {
+                date: None,
+                time: time!(08:30)
+            }),
+            parse_at("08:30")
+        );
+        assert_eq!(
+            Ok(At {
+                date: Some(date!(2025 - 05 - 12)),
+                time: time!(17:05)
+            }),
+            parse_at("2025-05-12 17:05")
+        );
+        assert!(parse_at("tomorrow").is_err());
+    }
+}
@@ -0,0 +1,706 @@
+//! Departure history recorded by `slq record`, kept in a SQLite database so
+//! the reliability of a line can be looked at over weeks and months.
+//!
+//! Every sample of a departure board updates one row per departure with its
+//! latest expected time. A departure that has left the board after its
+//! expected time gets that time as its actual departure, which is as close
+//! as the realtime API gets to when it really left.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{PrimitiveDateTime, UtcOffset};
+
+use crate::client::Departure;
+use crate::clock::Clock;
+use crate::config::data_dir;
+use crate::dates::format_sl_datetime;
+use crate::types::SiteId;
+
+/// Bumped whenever [`SCHEMA`] changes
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS departures (
+        site_id TEXT NOT NULL,
+        departure_key TEXT NOT NULL,
+        line TEXT NOT NULL,
+        transport_mode TEXT NOT NULL,
+        destination TEXT NOT NULL,
+        journey_id INTEGER,
+        scheduled TEXT,
+        expected TEXT NOT NULL,
+        actual TEXT,
+        state TEXT,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (site_id, departure_key)
+    );
+    CREATE INDEX IF NOT EXISTS departures_by_line ON departures (line, scheduled);
+";
+
+/// Departures that left at most this late count as on time, the same
+/// margin Trafikverket uses for punctuality
+pub const ON_TIME_SECONDS: i64 = 5 * 60;
+
+/// Hours of the day with fewer departed departures than this are left out
+/// of [`Stats::hours`], since a couple of bad days would dominate them
+pub const MIN_HOUR_DEPARTURES: usize = 5;
+
+/// What one sample of a departure board changed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Sample {
+    /// Departures on the board
+    pub seen: usize,
+    /// Departures that left the board since the last sample
+    pub departed: usize,
+}
+
+pub struct History {
+    conn: Connection,
+}
+
+/// How punctual recorded departures have been. Delays are in seconds, and
+/// departures that left early count as negative delays.
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    /// Departures scheduled in the period, whether or not they left
+    pub departures: usize,
+    pub departed: usize,
+    pub cancelled: usize,
+    pub mean_delay: Option<f64>,
+    pub median_delay: Option<i64>,
+    /// Share of departed departures at most [`ON_TIME_SECONDS`] late
+    pub on_time: Option<f64>,
+    /// Hours of the day, worst mean delay first
+    pub hours: Vec<HourStats>,
+}
+
+impl Stats {
+    /// Share of the departures that either left or were cancelled that
+    /// were cancelled
+    pub fn cancellation_rate(&self) -> Option<f64> {
+        let known = self.departed + self.cancelled;
+        (known > 0).then(|| self.cancelled as f64 / known as f64)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HourStats {
+    /// Local hour the departures were scheduled in, 0 to 23
+    pub hour: u8,
+    pub departed: usize,
+    pub mean_delay: f64,
+}
+
+/// Which recorded departures to look at
+#[derive(Default)]
+pub struct HistoryFilter<'a> {
+    pub line: Option<&'a str>,
+    pub site_id: Option<SiteId>,
+    /// Only departures scheduled between these local times. Departures
+    /// without a scheduled time are left out when either is set.
+    pub since: Option<PrimitiveDateTime>,
+    pub until: Option<PrimitiveDateTime>,
+}
+
+impl HistoryFilter<'_> {
+    const WHERE: &'static str =
+        "(?1 IS NULL OR scheduled >= ?1) AND (?2 IS NULL OR scheduled <= ?2)
+        AND (?3 IS NULL OR line = ?3) AND (?4 IS NULL OR site_id = ?4)";
+
+    fn params(&self) -> Result<[Option<String>; 4]> {
+        Ok([
+            self.since.map(local_time).transpose()?,
+            self.until.map(local_time).transpose()?,
+            self.line.map(str::to_string),
+            self.site_id.map(|site_id| site_id.to_string()),
+        ])
+    }
+}
+
+/// One recorded departure, as exported by `slq history export`. Times are
+/// local and written like the API writes them, except when it was first
+/// and last seen, which are RFC 3339 in UTC.
+#[derive(Debug, Serialize)]
+pub struct HistoryRow {
+    pub site_id: String,
+    pub line: String,
+    pub transport_mode: String,
+    pub destination: String,
+    pub journey_id: Option<i64>,
+    pub scheduled: Option<String>,
+    pub expected: String,
+    pub actual: Option<String>,
+    pub state: Option<String>,
+    /// Seconds between the scheduled and actual time
+    pub delay: Option<i64>,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+impl History {
+    /// `$SLQ_DATA_DIR/history.sqlite`, or `$XDG_DATA_HOME/slq/history.sqlite`
+    /// (falling back to `~/.local/share/slq/history.sqlite`).
+    pub fn path() -> Option<PathBuf> {
+        Some(data_dir()?.join("history.sqlite"))
+    }
+
+    /// Open the history, creating it when nothing has been recorded yet.
+    pub fn open() -> Result<History> {
+        let Some(path) = History::path() else {
+            bail!("no data directory for the departure history, set SLQ_DATA_DIR or HOME");
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        History::open_at(&path)
+    }
+
+    pub fn open_at(path: &Path) -> Result<History> {
+        let conn = Connection::open(path).with_context(|| {
+            format!("failed to open the departure history at {}", path.display())
+        })?;
+        History::with_connection(conn)
+    }
+
+    /// A history that only lives as long as the value, for tests
+    pub fn in_memory() -> Result<History> {
+        History::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<History> {
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            bail!("the departure history was recorded by a newer version of slq");
+        }
+        conn.execute_batch(SCHEMA)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(History { conn })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Store one sample of the departure board of `site_id`, taken now.
+    pub fn record(
+        &mut self,
+        site_id: SiteId,
+        departures: &[Departure],
+        clock: &dyn Clock,
+    ) -> Result<Sample> {
+        let site_id = site_id.to_string();
+        let now = clock.now().replace_nanosecond(0)?;
+        let sampled = now.to_offset(UtcOffset::UTC).format(&Rfc3339)?;
+        let local_now = now.to_offset(clock.local_offset());
+        let local_now = local_time(PrimitiveDateTime::new(local_now.date(), local_now.time()))?;
+
+        let tx = self.conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO departures (site_id, departure_key, line, transport_mode, destination,
+                    journey_id, scheduled, expected, state, first_seen, last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)
+                 ON CONFLICT (site_id, departure_key) DO UPDATE SET
+                    expected = excluded.expected,
+                    state = excluded.state,
+                    last_seen = excluded.last_seen",
+            )?;
+            for departure in departures {
+                let mode = serde_json::to_value(departure.line.transport_mode)?;
+                upsert.execute(params![
+                    site_id,
+                    key(departure),
+                    departure.line.designation,
+                    mode.as_str(),
+                    departure.destination,
+                    departure.journey.as_ref().map(|journey| journey.id as i64),
+                    departure.scheduled.map(format_sl_datetime),
+                    format_sl_datetime(departure.expected),
+                    state(departure),
+                    sampled,
+                ])?;
+            }
+        }
+        // Departures missing from this sample whose time has come have left.
+        // Cancelled ones never leave, so they keep no actual time.
+        let departed = tx.execute(
+            "UPDATE departures SET actual = expected
+             WHERE site_id = ?1 AND actual IS NULL AND last_seen < ?2 AND expected <= ?3
+               AND (state IS NULL OR state <> 'CANCELLED')",
+            params![site_id, sampled, local_now],
+        )?;
+        tx.commit()?;
+
+        Ok(Sample {
+            seen: departures.len(),
+            departed,
+        })
+    }
+}
+
+impl History {
+    /// Every recorded departure matching `filter`, in the order they were
+    /// scheduled
+    pub fn rows(&self, filter: &HistoryFilter) -> Result<Vec<HistoryRow>> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT site_id, line, transport_mode, destination, journey_id, scheduled, expected,
+                actual, state, first_seen, last_seen
+             FROM departures WHERE {} ORDER BY coalesce(scheduled, expected), site_id",
+            HistoryFilter::WHERE
+        ))?;
+        let rows = statement.query_map(filter.params()?, |row| {
+            let scheduled: Option<String> = row.get(5)?;
+            let actual: Option<String> = row.get(7)?;
+            Ok(HistoryRow {
+                site_id: row.get(0)?,
+                line: row.get(1)?,
+                transport_mode: row.get(2)?,
+                destination: row.get(3)?,
+                journey_id: row.get(4)?,
+                delay: delay(scheduled.as_deref(), actual.as_deref()),
+                scheduled,
+                expected: row.get(6)?,
+                actual,
+                state: row.get(8)?,
+                first_seen: row.get(9)?,
+                last_seen: row.get(10)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn stats(&self, filter: &HistoryFilter) -> Result<Stats> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT scheduled, actual, state FROM departures WHERE {}",
+            HistoryFilter::WHERE
+        ))?;
+        let rows = statement.query_map(filter.params()?, |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+
+        let mut stats = Stats::default();
+        let mut delays = Vec::new();
+        let mut hours: [Vec<i64>; 24] = Default::default();
+        for row in rows {
+            let (scheduled, actual, state) = row?;
+            stats.departures += 1;
+            if state.as_deref() == Some("CANCELLED") {
+                stats.cancelled += 1;
+                continue;
+            }
+            let (Some(scheduled), Some(delay)) = (
+                scheduled.as_deref().and_then(parse_local_time),
+                delay(scheduled.as_deref(), actual.as_deref()),
+            ) else {
+                continue;
+            };
+            delays.push(delay);
+            hours[usize::from(scheduled.hour())].push(delay);
+        }
+
+        stats.departed = delays.len();
+        if !delays.is_empty() {
+            stats.mean_delay = Some(mean(&delays));
+            delays.sort_unstable();
+            let middle = delays.len() / 2;
+            stats.median_delay = Some(if delays.len() % 2 == 0 {
+                (delays[middle - 1] + delays[middle]) / 2
+            } else {
+                delays[middle]
+            });
+            let on_time = delays.iter().filter(|&&delay| delay <= ON_TIME_SECONDS);
+            stats.on_time = Some(on_time.count() as f64 / delays.len() as f64);
+        }
+        stats.hours = (0u8..)
+            .zip(&hours)
+            .filter(|(_, delays)| delays.len() >= MIN_HOUR_DEPARTURES)
+            .map(|(hour, delays)| HourStats {
+                hour,
+                departed: delays.len(),
+                mean_delay: mean(delays),
+            })
+            .collect();
+        stats
+            .hours
+            .sort_by(|a, b| b.mean_delay.total_cmp(&a.mean_delay));
+        Ok(stats)
+    }
+}
+
+/// Parquet schema for [`HistoryRow`]. Local times are timestamps that
+/// aren't adjusted to UTC, as the format has it.
+#[cfg(feature = "parquet")]
+const PARQUET_SCHEMA: &str = "
+    message departures {
+        REQUIRED BYTE_ARRAY site_id (UTF8);
+        REQUIRED BYTE_ARRAY line (UTF8);
+        REQUIRED BYTE_ARRAY transport_mode (UTF8);
+        REQUIRED BYTE_ARRAY destination (UTF8);
+        OPTIONAL INT64 journey_id;
+        OPTIONAL INT64 scheduled (TIMESTAMP(MILLIS,false));
+        REQUIRED INT64 expected (TIMESTAMP(MILLIS,false));
+        OPTIONAL INT64 actual (TIMESTAMP(MILLIS,false));
+        OPTIONAL BYTE_ARRAY state (UTF8);
+        OPTIONAL INT64 delay;
+        REQUIRED INT64 first_seen (TIMESTAMP(MILLIS,true));
+        REQUIRED INT64 last_seen (TIMESTAMP(MILLIS,true));
+    }
+";
+
+/// The values of one Parquet column, in [`PARQUET_SCHEMA`] order
+#[cfg(feature = "parquet")]
+enum Column {
+    Text(Vec<Option<String>>),
+    Int(Vec<Option<i64>>),
+}
+
+/// Write `rows` as a Snappy compressed Parquet file, for notebooks and data
+/// warehouses.
+#[cfg(feature = "parquet")]
+pub fn write_parquet(rows: &[HistoryRow], out: impl std::io::Write + Send) -> Result<()> {
+    use std::sync::Arc;
+
+    use parquet::basic::Compression;
+    use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use time::OffsetDateTime;
+
+    let local = |time: Option<&str>| {
+        time.and_then(parse_local_time)
+            .map(|time| (time.assume_utc().unix_timestamp_nanos() / 1_000_000) as i64)
+    };
+    let utc = |time: &str| {
+        OffsetDateTime::parse(time, &Rfc3339)
+            .ok()
+            .map(|time| (time.unix_timestamp_nanos() / 1_000_000) as i64)
+    };
+    let text =
+        |value: fn(&HistoryRow) -> Option<String>| Column::Text(rows.iter().map(value).collect());
+    let columns = [
+        text(|row| Some(row.site_id.clone())),
+        text(|row| Some(row.line.clone())),
+        text(|row| Some(row.transport_mode.clone())),
+        text(|row| Some(row.destination.clone())),
+        Column::Int(rows.iter().map(|row| row.journey_id).collect()),
+        Column::Int(
+            rows.iter()
+                .map(|row| local(row.scheduled.as_deref()))
+                .collect(),
+        ),
+        Column::Int(rows.iter().map(|row| local(Some(&row.expected))).collect()),
+        Column::Int(
+            rows.iter()
+                .map(|row| local(row.actual.as_deref()))
+                .collect(),
+        ),
+        text(|row| row.state.clone()),
+        Column::Int(rows.iter().map(|row| row.delay).collect()),
+        Column::Int(rows.iter().map(|row| utc(&row.first_seen)).collect()),
+        Column::Int(rows.iter().map(|row| utc(&row.last_seen)).collect()),
+    ];
+
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = SerializedFileWriter::new(out, schema.clone(), Arc::new(properties))?;
+    let mut row_group = writer.next_row_group()?;
+    for (column, field) in columns.iter().zip(schema.get_fields()) {
+        let mut column_writer = row_group
+            .next_column()?
+            .context("the Parquet schema has fewer columns than a history row")?;
+        // Required columns take no definition levels. Their values are
+        // never missing, except for times that fail to parse, which are
+        // written as the epoch rather than failing the whole export.
+        let optional = field.is_optional();
+        match column {
+            Column::Text(values) => {
+                let levels = optional.then(|| definition_levels(values));
+                let values: Vec<ByteArray> = if optional {
+                    values
+                        .iter()
+                        .flatten()
+                        .map(|value| value.as_str().into())
+                        .collect()
+                } else {
+                    values
+                        .iter()
+                        .map(|value| value.as_deref().unwrap_or_default().into())
+                        .collect()
+                };
+                column_writer.typed::<ByteArrayType>().write_batch(
+                    &values,
+                    levels.as_deref(),
+                    None,
+                )?;
+            }
+            Column::Int(values) => {
+                let levels = optional.then(|| definition_levels(values));
+                let values: Vec<i64> = if optional {
+                    values.iter().flatten().copied().collect()
+                } else {
+                    values
+                        .iter()
+                        .map(|value| value.unwrap_or_default())
+                        .collect()
+                };
+                column_writer
+                    .typed::<Int64Type>()
+                    .write_batch(&values, levels.as_deref(), None)?;
+            }
+        }
+        column_writer.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+/// 1 for values that are there and 0 for those that are missing
+#[cfg(feature = "parquet")]
+fn definition_levels<T>(values: &[Option<T>]) -> Vec<i16> {
+    values
+        .iter()
+        .map(|value| i16::from(value.is_some()))
+        .collect()
+}
+
+/// Seconds from the scheduled to the actual time, when both are known
+fn delay(scheduled: Option<&str>, actual: Option<&str>) -> Option<i64> {
+    let scheduled = parse_local_time(scheduled?)?;
+    let actual = parse_local_time(actual?)?;
+    Some((actual - scheduled).whole_seconds())
+}
+
+fn mean(values: &[i64]) -> f64 {
+    values.iter().sum::<i64>() as f64 / values.len() as f64
+}
+
+fn parse_local_time(time: &str) -> Option<PrimitiveDateTime> {
+    PrimitiveDateTime::parse(
+        time,
+        format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"),
+    )
+    .ok()
+}
+
+/// Journey ids are reused from day to day, so the departure time is part of
+/// the key. Departures without a journey fall back to their line and
+/// destination.
+fn key(departure: &Departure) -> String {
+    let time = format_sl_datetime(departure.scheduled.unwrap_or(departure.expected));
+    match &departure.journey {
+        Some(journey) => format!("{}/{time}", journey.id),
+        None => format!(
+            "{}/{}/{time}",
+            departure.line.designation, departure.destination
+        ),
+    }
+}
+
+/// Cancellations are sometimes only flagged on the journey
+fn state(departure: &Departure) -> Option<&str> {
+    departure
+        .state
+        .as_deref()
+        .or_else(|| departure.journey.as_ref()?.state.as_deref())
+}
+
+/// A local time written like the API writes them, so the two compare as
+/// text
+fn local_time(time: PrimitiveDateTime) -> Result<String> {
+    Ok(time.format(format_description!(
+        "[year]-[month]-[day]T[hour]:[minute]:[second]"
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Journey, Line};
+    use crate::clock::FixedClock;
+    use crate::dates::parse_sl_datetime;
+    use crate::types::TransportMode;
+    use time::macros::datetime;
+
+    fn departure(journey: u64, scheduled: &str, expected: &str) -> Departure {
+        Departure {
+            destination: "Fruängen".to_string(),
+            expected: parse_sl_datetime(expected).unwrap(),
+            line: Line {
+                designation: "43".to_string(),
+                transport_mode: TransportMode::Bus,
+                id: None,
+                group_of_lines: None,
+                transport_authority_id: None,
+            },
+            stop_point: None,
+            journey: Some(Journey {
+                id: journey,
+                state: None,
+            }),
+            scheduled: parse_sl_datetime(scheduled),
+            state: None,
+            display: None,
+            direction: None,
+            direction_code: None,
+            stop_area: None,
+        }
+    }
+
+    fn actual(history: &History, journey: u64) -> Option<String> {
+        history
+            .connection()
+            .query_row(
+                "SELECT actual FROM departures WHERE journey_id = ?1",
+                [journey as i64],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn departures_get_their_last_expected_time_once_they_have_left() {
+        let mut history = History::in_memory().unwrap();
+        let board = [
+            departure(1, "2025-05-08T10:00:00", "2025-05-08T10:02:00"),
+            departure(2, "2025-05-08T10:10:00", "2025-05-08T10:10:00"),
+        ];
+        let clock = FixedClock(datetime!(2025-05-08 09:58:00 +2));
+        let sample = history.record(SiteId::from(9192), &board, &clock).unwrap();
+        assert_eq!(
+            Sample {
+                seen: 2,
+                departed: 0
+            },
+            sample
+        );
+
+        // The first one was delayed a little more, then left
+        let board = [departure(1, "2025-05-08T10:00:00", "2025-05-08T10:03:00")];
+        let clock = FixedClock(datetime!(2025-05-08 10:01:00 +2));
+        history.record(SiteId::from(9192), &board, &clock).unwrap();
+        let clock = FixedClock(datetime!(2025-05-08 10:04:00 +2));
+        let sample = history.record(SiteId::from(9192), &[], &clock).unwrap();
+
+        assert_eq!(1, sample.departed);
+        assert_eq!(Some("2025-05-08T10:03:00".to_string()), actual(&history, 1));
+        // Dropped off the board before its time, so it hasn't left yet
+        assert_eq!(None, actual(&history, 2));
+    }
+
+    #[test]
+    fn cancelled_departures_have_no_actual_time() {
+        let mut history = History::in_memory().unwrap();
+        let mut cancelled = departure(1, "2025-05-08T10:00:00", "2025-05-08T10:00:00");
+        cancelled.state = Some("CANCELLED".to_string());
+        let clock = FixedClock(datetime!(2025-05-08 09:58:00 +2));
+        history
+            .record(SiteId::from(9192), &[cancelled], &clock)
+            .unwrap();
+        let clock = FixedClock(datetime!(2025-05-08 10:05:00 +2));
+        history.record(SiteId::from(9192), &[], &clock).unwrap();
+
+        assert_eq!(None, actual(&history, 1));
+    }
+
+    #[test]
+    fn rows_carry_their_delay() {
+        let mut history = History::in_memory().unwrap();
+        let board = [departure(1, "2025-05-08T10:00:00", "2025-05-08T10:02:30")];
+        let clock = FixedClock(datetime!(2025-05-08 09:58:00 +2));
+        history.record(SiteId::from(9192), &board, &clock).unwrap();
+        let clock = FixedClock(datetime!(2025-05-08 10:05:00 +2));
+        history.record(SiteId::from(9192), &[], &clock).unwrap();
+
+        let rows = history.rows(&HistoryFilter::default()).unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!(Some(150), rows[0].delay);
+        assert_eq!("2025-05-08T07:58:00Z", rows[0].first_seen);
+        assert!(
+            history
+                .rows(&HistoryFilter {
+                    line: Some("14"),
+                    ..Default::default()
+                })
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_exports_read_back() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let mut history = History::in_memory().unwrap();
+        let board = [departure(1, "2025-05-08T10:00:00", "2025-05-08T10:02:30")];
+        let clock = FixedClock(datetime!(2025-05-08 09:58:00 +2));
+        history.record(SiteId::from(9192), &board, &clock).unwrap();
+        let rows = history.rows(&HistoryFilter::default()).unwrap();
+
+        let path = std::env::temp_dir().join(format!("slq-history-{}.parquet", std::process::id()));
+        write_parquet(&rows, fs::File::create(&path).unwrap()).unwrap();
+        let reader = SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(1, reader.metadata().file_metadata().num_rows());
+        let row = reader.get_row_iter(None).unwrap().next().unwrap().unwrap();
+        let row = row.to_string();
+        assert!(row.contains("line: \"43\""), "{row}");
+        assert!(row.contains("actual: null"), "{row}");
+    }
+
+    #[test]
+    fn stats_summarize_delays_cancellations_and_hours() {
+        let mut history = History::in_memory().unwrap();
+        let mut board = Vec::new();
+        // Five on time at eight, five late at nine, and one cancelled
+        for i in 0..5 {
+            let scheduled = format!("2025-05-08T08:{:02}:00", i * 10);
+            board.push(departure(i, &scheduled, &scheduled));
+            let scheduled = format!("2025-05-08T09:{:02}:00", i * 10);
+            let expected = format!("2025-05-08T09:{:02}:00", i * 10 + 6);
+            board.push(departure(10 + i, &scheduled, &expected));
+        }
+        let mut cancelled = departure(20, "2025-05-08T09:55:00", "2025-05-08T09:55:00");
+        cancelled.state = Some("CANCELLED".to_string());
+        board.push(cancelled);
+        let clock = FixedClock(datetime!(2025-05-08 07:00:00 +2));
+        history.record(SiteId::from(9192), &board, &clock).unwrap();
+        let clock = FixedClock(datetime!(2025-05-08 11:00:00 +2));
+        history.record(SiteId::from(9192), &[], &clock).unwrap();
+
+        let stats = history
+            .stats(&HistoryFilter {
+                line: Some("43"),
+                since: Some(datetime!(2025-05-08 00:00:00)),
+                until: Some(datetime!(2025-05-08 11:00:00)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(11, stats.departures);
+        assert_eq!(10, stats.departed);
+        assert_eq!(1, stats.cancelled);
+        assert_eq!(Some(180.0), stats.mean_delay);
+        assert_eq!(Some(180), stats.median_delay);
+        assert_eq!(Some(0.5), stats.on_time);
+        assert_eq!(9, stats.hours[0].hour);
+        assert_eq!(360.0, stats.hours[0].mean_delay);
+        assert_eq!(8, stats.hours[1].hour);
+    }
+}
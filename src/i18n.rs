@@ -0,0 +1,160 @@
+//! Words and phrases of human readable output in the languages slq speaks,
+//! including the status bar formats. What slq writes in machine readable
+//! output, like JSON and CSV, is never translated so scripts keep working
+//! whatever the user's locale.
+
+use std::env;
+
+use crate::types::{Column, Language, TransportMode};
+
+/// Labels used on departure boards and station lists
+pub struct Phrases {
+    pub now: &'static str,
+    pub departing: &'static str,
+    pub departed: &'static str,
+    pub departures_from: &'static str,
+    /// Follows the station in a board's title when SL couldn't be reached,
+    /// `{age}` is replaced by how old the board is
+    pub stale: &'static str,
+    pub unreachable: &'static str,
+    pub no_departures_from: &'static str,
+    pub distance: &'static str,
+    pub station: &'static str,
+    pub kind: &'static str,
+    pub earliest_at: &'static str,
+    /// Follows the minutes it takes to walk to a stop
+    pub walk: &'static str,
+    /// Heads the column of minutes it takes to walk to a stop
+    pub walking: &'static str,
+}
+
+const ENGLISH: Phrases = Phrases {
+    now: "now",
+    departing: "departing",
+    departed: "departed",
+    departures_from: "Departures from",
+    stale: "stale, fetched {age} ago, SL could not be reached",
+    unreachable: "SL could not be reached",
+    no_departures_from: "No departures from",
+    distance: "Distance",
+    station: "Station",
+    kind: "Type",
+    earliest_at: "Earliest arrival at",
+    walk: "min walk",
+    walking: "Walk",
+};
+
+const SWEDISH: Phrases = Phrases {
+    now: "nu",
+    departing: "avgår",
+    departed: "avgick",
+    departures_from: "Avgångar från",
+    stale: "inaktuell, hämtad för {age} sedan, SL gick inte att nå",
+    unreachable: "SL gick inte att nå",
+    no_departures_from: "Inga avgångar från",
+    distance: "Avstånd",
+    station: "Hållplats",
+    kind: "Typ",
+    earliest_at: "Tidigast framme vid",
+    walk: "min promenad",
+    walking: "Promenad",
+};
+
+pub fn phrases(language: Language) -> &'static Phrases {
+    match language {
+        Language::English => &ENGLISH,
+        Language::Swedish => &SWEDISH,
+    }
+}
+
+/// Name of a transport mode as shown on a departure board
+pub fn transport_mode_name(mode: TransportMode, language: Language) -> &'static str {
+    match (language, mode) {
+        (Language::English, TransportMode::Bus) => "Bus",
+        (Language::English, TransportMode::Tram) => "Tram",
+        (Language::English, TransportMode::Metro) => "Metro",
+        (Language::English, TransportMode::Train) => "Train",
+        (Language::English, TransportMode::Ferry) => "Ferry",
+        (Language::English, TransportMode::Ship) => "Ship",
+        (Language::English, TransportMode::Taxi) => "Taxi",
+        (Language::Swedish, TransportMode::Bus) => "Buss",
+        (Language::Swedish, TransportMode::Tram) => "Spårvagn",
+        (Language::Swedish, TransportMode::Metro) => "Tunnelbana",
+        (Language::Swedish, TransportMode::Train) => "Tåg",
+        (Language::Swedish, TransportMode::Ferry) => "Färja",
+        (Language::Swedish, TransportMode::Ship) => "Båt",
+        (Language::Swedish, TransportMode::Taxi) => "Taxi",
+        (Language::English, TransportMode::Other) => "Other",
+        (Language::Swedish, TransportMode::Other) => "Övrigt",
+    }
+}
+
+/// Heading of a departure board column in HTML and Markdown tables
+pub fn column_title(column: Column, language: Language) -> &'static str {
+    match (language, column) {
+        (Language::English, Column::Wait) => "Wait",
+        (Language::English, Column::Time) => "Time",
+        (Language::English, Column::Line) => "Line",
+        (Language::English, Column::Mode) => "Mode",
+        (Language::English, Column::Destination) => "Destination",
+        (Language::English, Column::Journey) => "Journey",
+        (Language::Swedish, Column::Wait) => "Om",
+        (Language::Swedish, Column::Time) => "Tid",
+        (Language::Swedish, Column::Line) => "Linje",
+        (Language::Swedish, Column::Mode) => "Trafikslag",
+        (Language::Swedish, Column::Destination) => "Destination",
+        (Language::Swedish, Column::Journey) => "Tur",
+    }
+}
+
+/// Name of a group of lines, translating the metro's colors and keeping the
+/// rest, like `Roslagsbanan`, as SL calls them
+pub fn line_group_name(group: &str, language: Language) -> &str {
+    match (language, group) {
+        (Language::English, "Tunnelbanans gröna linje") => "Green line",
+        (Language::English, "Tunnelbanans röda linje") => "Red line",
+        (Language::English, "Tunnelbanans blå linje") => "Blue line",
+        (Language::Swedish, "Tunnelbanans gröna linje") => "Gröna linjen",
+        (Language::Swedish, "Tunnelbanans röda linje") => "Röda linjen",
+        (Language::Swedish, "Tunnelbanans blå linje") => "Blå linjen",
+        _ => group,
+    }
+}
+
+/// The language of the locale in `LC_ALL`, `LC_MESSAGES` or `LANG` (first
+/// one set). Anything but Swedish gets English.
+pub fn detect_language() -> Language {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .map_or(Language::English, |locale| locale_language(&locale))
+}
+
+/// `sv`, `sv_SE.UTF-8` and `sv_FI` are Swedish
+fn locale_language(locale: &str) -> Language {
+    let code = locale
+        .split(['_', '-', '.', '@'])
+        .next()
+        .unwrap_or_default();
+    if code.eq_ignore_ascii_case("sv") {
+        Language::Swedish
+    } else {
+        Language::English
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swedish_locales_are_recognized() {
+        assert_eq!(Language::Swedish, locale_language("sv_SE.UTF-8"));
+        assert_eq!(Language::Swedish, locale_language("sv"));
+        assert_eq!(Language::Swedish, locale_language("sv_FI@euro"));
+        assert_eq!(Language::English, locale_language("en_US.UTF-8"));
+        assert_eq!(Language::English, locale_language("C"));
+        assert_eq!(Language::English, locale_language("POSIX"));
+    }
+}
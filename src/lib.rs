@@ -0,0 +1,52 @@
+//! Library behind the `slq` command line tool for querying stations and
+//! departures in Storstockholms Lokaltrafik (SL).
+//!
+//! ```no_run
+//! use slq::client::{ClientSettings, SlClient};
+//! use slq::types::{SiteId, TransportMode};
+//!
+//! let client = SlClient::new(ClientSettings::default())?;
+//! let slussen = SiteId::from(9192);
+//! for departure in client
+//!     .departures(slussen)
+//!     .line("43")
+//!     .mode(TransportMode::Bus)
+//!     .count(5)
+//!     .fetch()?
+//! {
+//!     println!("{} {}", departure.line.designation, departure.destination);
+//! }
+//! # Ok::<(), slq::client::ClientError>(())
+//! ```
+
+pub mod best;
+pub mod cache;
+pub mod check;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod daemon;
+pub mod dates;
+pub mod fixtures;
+pub mod geo;
+#[cfg(feature = "gtfs")]
+pub mod gtfs;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod i18n;
+pub mod mcp;
+pub mod mqtt;
+pub mod notify;
+pub mod output;
+pub mod path;
+pub mod query;
+pub mod question;
+#[cfg(feature = "gtfs")]
+pub mod realtime;
+pub mod sandbox;
+pub mod serve;
+pub mod status;
+pub mod theme;
+pub mod types;
+pub mod update;
+pub mod watch;
@@ -1,15 +1,76 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use time::{Duration, OffsetDateTime, PrimitiveDateTime, UtcOffset, format_description};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
 
-use crate::client::{
-    Departure, Site, get_departures, get_site_transport_modes, get_sites, search_for_sites,
-    site_has_transport_mode,
-};
-use crate::types::TransportMode;
+use anyhow::{Context, Result, bail};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use deunicode::deunicode;
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use thiserror::Error;
+#[cfg(any(feature = "gtfs", feature = "history"))]
+use time::PrimitiveDateTime;
+use time::{Duration, OffsetDateTime};
+use tracing::debug;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
 
-mod client;
-mod types;
+use slq::best::{CandidateStop, earliest_arrival, rank};
+use slq::cache::{Cache, Namespace, human_bytes};
+use slq::check::{Finding, Severity, check_config, doctor};
+use slq::client::{
+    ClientError, ClientSettings, DEFAULT_API_BASE, Departure, DepartureFilter, Deviation,
+    Freshness, HttpOptions, Journey, Line, RateLimit, RetryPolicy, Site, SiteDetails, SlClient,
+};
+use slq::clock::{Clock, SystemClock};
+use slq::config::{Config, NotifyConfig, ResolvedStation, StatusbarConfig};
+use slq::daemon::{self, supervise};
+#[cfg(any(feature = "gtfs", feature = "history"))]
+use slq::dates::sl_time;
+use slq::dates::{TimeFormat, Timezone, natural_datetime, timestamp};
+use slq::fixtures::Fixtures;
+#[cfg(feature = "gtfs")]
+use slq::geo::compass_point;
+use slq::geo::{BoundingBox, haversine_meters, walking_minutes};
+#[cfg(feature = "gtfs")]
+use slq::gtfs::{self, ScheduledDeparture, Timetable};
+#[cfg(feature = "history")]
+use slq::history::{History, HistoryFilter, ON_TIME_SECONDS};
+use slq::i18n::{column_title, detect_language, line_group_name, phrases, transport_mode_name};
+use slq::mcp;
+use slq::mqtt::{self, MqttSettings};
+use slq::notify::notifier;
+#[cfg(feature = "gtfs")]
+use slq::output::csv_flag;
+use slq::output::{
+    DEPARTURES_ONLY, color_enabled, coordinate, csv_field, escape_markup, finish_output,
+    finish_paging, fit_row, geojson, hass_sensor, headers_enabled, init_ascii, init_headers,
+    init_json_path, init_output, init_quiet, print_json, print_record, print_sites, site_type,
+    start_paging, terminal_columns, transport_mode_icon, write_output,
+};
+use slq::path::JsonPath;
+use slq::query::{MIN_POLL_SECONDS, Query};
+use slq::question::Question;
+#[cfg(feature = "gtfs")]
+use slq::realtime;
+use slq::sandbox::{Sandbox, init_sandbox};
+use slq::serve::serve;
+use slq::status::summarize;
+use slq::theme::{Color, Theme, paint};
+#[cfg(feature = "history")]
+use slq::types::ExportFormat;
+use slq::types::{ColorChoice, Column, Language, OutputFormat, SearchSort, SiteId, TransportMode};
+use slq::update::{self, CURRENT_VERSION, LATEST_RELEASE_URL};
+use slq::watch::{Change, SeenDeviations};
+use slq::{headerln, notice, outln};
 
 /// Storstockholms Lokaltrafik Query Tool
 #[derive(Parser)]
@@ -17,6 +78,149 @@ mod types;
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    /// Log requests, timings, cache hits and retries to stderr. Repeat (`-vv`) to also log
+    /// every departure that a filter dropped
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Transliterate station and destination names to plain ASCII (Älvsjö → Alvsjo). Enabled
+    /// automatically when the terminal's locale isn't UTF-8
+    #[arg(long, global = true)]
+    ascii: bool,
+
+    /// Color wait times by how soon they are and lines by transport mode. `auto` colors
+    /// only when writing to a terminal and `NO_COLOR` isn't set
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto, value_name = "WHEN")]
+    color: ColorChoice,
+
+    /// Show transport modes as pictograms (🚇 🚌 🚆 🚋 ⛴) instead of by name in text output
+    #[arg(long, global = true)]
+    icons: bool,
+
+    /// Cut long destinations short with `…` so departure rows fit in this many columns.
+    /// Defaults to the width of the terminal, and to not cutting anything when stdout isn't one
+    #[arg(long, global = true, value_parser = clap::value_parser!(u16).range(1..))]
+    width: Option<u16>,
+
+    /// Leave out banners like "Departures from Slussen:" and CSV header rows, so the output is
+    /// one line per record
+    #[arg(long, global = true)]
+    no_header: bool,
+
+    /// Print only part of the JSON output, picked by a jq-like path: `.[0].destination` for
+    /// the first destination, `.[].line.designation` for every line. Strings are printed
+    /// without quotes
+    #[arg(long, global = true, value_name = "PATH")]
+    query: Option<JsonPath>,
+
+    /// Write output to this file instead of stdout. The file is replaced in one go once the
+    /// command succeeds, so whatever reads it never sees half of it
+    #[arg(short, long, global = true, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Add to the end of the `--output` file as output comes instead of replacing it, e.g. to
+    /// keep a log from cron or from a command that keeps running
+    #[arg(long, global = true, requires = "output")]
+    append: bool,
+
+    /// Never show output taller than the terminal in `$PAGER`
+    #[arg(long, global = true)]
+    no_pager: bool,
+
+    /// Only print data and errors: no banners, headers or messages about what slq is doing
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Output for scripts that stays the same whatever the locale or terminal: no banners or
+    /// headers, no colors or icons, English labels and 24 hour times
+    #[arg(long, global = true, conflicts_with_all = ["color", "icons", "lang", "time_format"])]
+    porcelain: bool,
+
+    /// How many times to retry requests that fail because of network problems or server errors
+    #[arg(long, global = true, default_value_t = 2, value_parser = clap::value_parser!(u32).range(..=10))]
+    retries: u32,
+
+    /// Milliseconds to wait before the first retry. Doubles for every retry after it, with
+    /// some random jitter
+    #[arg(long, global = true, default_value_t = 500)]
+    retry_delay: u64,
+
+    /// Most requests per minute to send to the SL APIs
+    #[arg(long, global = true, default_value_t = 300, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: u32,
+
+    /// Requests that may be sent in a quick burst before `--rate-limit` kicks in
+    #[arg(long, global = true, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_burst: u32,
+
+    /// Proxy for all requests, e.g. `http://proxy.example.com:3128`. Without it the
+    /// `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables are used
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+
+    /// Base URL of the SL Transport API, e.g. a staging mirror, a caching proxy or a mock server
+    #[arg(long, global = true, env = "SLQ_API_BASE", default_value = DEFAULT_API_BASE)]
+    api_base_url: String,
+
+    /// Never send data over the network, e.g. notifications to ntfy, Pushover or Gotify.
+    /// Meant for shared deployments where the config file isn't trusted
+    #[arg(long, global = true, env = "SLQ_NO_NET_WRITE")]
+    no_net_write: bool,
+
+    /// Never start another program. The pager is the only one slq starts, so output is printed
+    /// without it. Meant for shared deployments where the config file isn't trusted
+    #[arg(long, global = true, env = "SLQ_NO_EXEC")]
+    no_exec: bool,
+
+    /// PEM file with extra CA certificates to trust, for networks that intercept TLS
+    #[arg(long, global = true)]
+    cacert: Option<PathBuf>,
+
+    /// Print the request to the SL API that the command would send, without sending it.
+    /// Notifications are described instead of delivered
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Send notifications to this URL instead of the configured backend: an ntfy.sh topic,
+    /// e.g. `https://ntfy.sh/my-commute`, or any other URL as a JSON POST
+    #[arg(long, global = true, env = "SLQ_NOTIFY_URL")]
+    notify_url: Option<String>,
+
+    /// Language of labels, dates and service messages in text output. Defaults to Swedish when
+    /// the locale (`LC_ALL`, `LC_MESSAGES` or `LANG`) is Swedish, and to English otherwise
+    #[arg(long, global = true, value_enum)]
+    lang: Option<Language>,
+
+    /// How times of day are shown in text output: `24h` (14:05), `12h` (2:05 PM) or a
+    /// strftime pattern such as `%H.%M`
+    #[arg(long, global = true, default_value = "24h", value_name = "FORMAT")]
+    time_format: TimeFormat,
+
+    /// Timezone times are shown in, from the tz database, e.g. `Europe/London`. SL's times
+    /// are always read as Stockholm time
+    #[arg(
+        long,
+        global = true,
+        default_value = "Europe/Stockholm",
+        value_name = "TZ"
+    )]
+    timezone: Timezone,
+
+    /// Save the raw response of every request to the SL APIs in this directory, to replay
+    /// later with `--replay-fixtures`. Responses aren't cached while recording
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        conflicts_with = "replay_fixtures"
+    )]
+    record_fixtures: Option<PathBuf>,
+
+    /// Answer requests to the SL APIs from responses saved with `--record-fixtures`, without
+    /// using the network. Requests that weren't recorded fail
+    #[arg(long, global = true, value_name = "DIR")]
+    replay_fixtures: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -25,19 +229,58 @@ enum Commands {
     Search {
         /// Station name
         station_name: String,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// Include coordinates and site type in text output. With `--near` the distance table
+        /// gets a site type column
+        #[arg(short, long)]
+        long: bool,
+
+        /// Maximum number of results. Defaults to what fits in the terminal for text output,
+        /// and to all results otherwise
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Order of the results: `relevance` puts exact matches first, then names starting
+        /// with the search, then names with a word starting with it. Defaults to `distance`
+        /// with `--near` and to `relevance` otherwise
+        #[arg(long, value_enum)]
+        sort: Option<SearchSort>,
+
+        /// Show how far each result is from this position and how long it takes to walk there,
+        /// nearest first. Sites without coordinates are left out
+        #[arg(long, num_args = 2, value_names = ["LAT", "LON"], allow_negative_numbers = true,
+              required_if_eq("sort", "distance"))]
+        near: Option<Vec<f64>>,
+
+        /// Only show sites in this municipality, e.g. `Solna`, for names like `Kyrkan` that
+        /// are found all over the region
+        #[arg(long, value_name = "MUNICIPALITY")]
+        area: Option<String>,
     },
     Departures {
-        /// Station name or identifier
-        station_name: String,
+        /// Station name or identifier, or `-` to read them from stdin
+        #[arg(required_unless_present = "stdin")]
+        station_name: Option<String>,
+
+        /// Show the departures of every station read from stdin, one per line, e.g.
+        /// `slq departures --stdin -f ndjson < stops.txt`. Blank lines and lines starting with
+        /// `#` are skipped
+        #[arg(long, conflicts_with_all = ["station_name", "raw", "watch"])]
+        stdin: bool,
         /// Filter by line number. Base line numbers (e.g., "28") will include variants like
         /// "28s"). Specific variants can be filtered with exact matches, sho if you search for
         /// "28s" you will only get that result
         #[arg(short, long)]
         line: Option<String>,
 
-        /// Maximum number of departures to show
-        #[arg(short, long)]
-        count: Option<usize>,
+        /// Maximum number of results. Defaults to what fits in the terminal for text output,
+        /// and to all results otherwise
+        #[arg(short = 'n', long, visible_alias = "count", short_alias = 'c')]
+        limit: Option<usize>,
 
         /// Filter results by their destination
         #[arg(short, long)]
@@ -46,285 +289,4625 @@ enum Commands {
         /// Filter by transport type
         #[arg(short, long)]
         transport_mode: Option<TransportMode>,
+
+        /// Only lines run by this operator: `SL`, or the numeric transport authority id of
+        /// another region's lines at a shared station. Other regions aren't known by name
+        #[arg(long, value_parser = parse_operator)]
+        operator: Option<String>,
+
+        /// Minutes it takes to walk to the station. Departures leaving sooner are hidden.
+        /// Overrides `walk_minutes` from the config file
+        #[arg(short, long, allow_negative_numbers = true)]
+        walk_minutes: Option<i64>,
+
+        /// Exit with status 3 when no departures match the filters
+        #[arg(long)]
+        fail_if_empty: bool,
+
+        /// Print the JSON response from the API untouched, e.g. to report a decoding problem
+        /// or to look at fields slq doesn't show
+        #[arg(long, conflicts_with = "fail_if_empty")]
+        raw: bool,
+
+        /// Show the journey id of each departure, to follow one with `slq track`
+        #[arg(long, conflicts_with = "raw")]
+        journey_ids: bool,
+
+        /// Keep departures that left more than two minutes ago, marked `departed`, instead of
+        /// hiding them
+        #[arg(long, conflicts_with = "raw")]
+        show_departed: bool,
+
+        /// Show the wait as SL's signs at the station do, e.g. `Nu` or `3 min`, instead of
+        /// working it out from the expected time
+        #[arg(long, conflicts_with = "raw")]
+        use_api_display: bool,
+
+        /// Columns to show and their order, e.g. `wait,line,destination`. Applies to text
+        /// output and the `statusbar`, `html` and `markdown` formats
+        #[arg(long, value_enum, value_delimiter = ',', conflicts_with = "raw")]
+        columns: Option<Vec<Column>>,
+
+        /// Output format. `hass` is a Home Assistant sensor with the minutes until the next
+        /// departure as its state, `waybar` a Waybar module, `statusbar` a single line for
+        /// polybar or i3blocks and `ndjson` the whole board on one line of JSON
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text, conflicts_with = "raw")]
+        format: OutputFormat,
+
+        /// Longest line `--format statusbar` prints, in characters. Longer lines are cut short
+        /// with `…`
+        #[arg(long)]
+        max_width: Option<usize>,
+
+        /// Keep showing the board, refreshed every `--interval`, as text or with `--format ndjson`
+        /// one line of JSON per refresh, to stream to another program
+        #[arg(long, conflicts_with_all = ["raw", "fail_if_empty"])]
+        watch: bool,
+
+        /// Seconds between refreshes with `--watch`
+        #[arg(long, default_value_t = 30, requires = "watch")]
+        interval: u64,
+
+        /// Send a heartbeat record on a `--format ndjson` stream from `--watch` or `--stdin`
+        /// that has been quiet this long, e.g. `10s`. A bare number is minutes
+        #[arg(long, value_parser = parse_duration)]
+        heartbeat: Option<Duration>,
+    },
+    /// Follow one specific bus or train on a station's departure board until it leaves,
+    /// printing its expected time whenever it changes. Journey ids are shown by
+    /// `slq departures --journey-ids`
+    Track {
+        /// Journey id of the departure to follow
+        journey: u64,
+
+        /// Station name or identifier of the stop to follow it at
+        #[arg(short, long)]
+        station: String,
+
+        /// Longest time in seconds between checks of the departure board
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Run a departures query saved under `[queries.<name>]` in the config file
+    Run {
+        /// Name of the saved query
+        name: String,
+
+        /// Exit with status 3 when no departures match the filters
+        #[arg(long)]
+        fail_if_empty: bool,
+    },
+    /// Check that aliases, saved queries and walking times in the config file still match
+    /// current SL data
+    CheckConfig,
+    /// Replace slq with the latest release from GitHub, if there is a newer one
+    SelfUpdate {
+        /// Only report whether a newer release is available
+        #[arg(long)]
+        check_only: bool,
+    },
+    /// Check connectivity to the SL APIs, the config file, the cache, and the clock and
+    /// timezone that wait times depend on
+    Doctor,
+    /// Inspect and prune the cache of API responses
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Print a shell completion script, e.g. `slq completions bash > /etc/bash_completion.d/slq`
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
     },
+    /// Print the man page in roff format, e.g. `slq man > /usr/local/share/man/man1/slq.1`
+    Man,
+    /// Print station names and aliases starting with a prefix, used by the completion scripts
+    #[command(name = "__complete-station", hide = true)]
+    CompleteStation {
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+    /// Find the stations closest to a position or an address
+    #[command(visible_alias = "nearby")]
     Closest {
         /// Latitude in decimal degrees
-        lat: f64,
+        #[arg(required_unless_present = "address", requires = "lon")]
+        lat: Option<f64>,
 
         /// Longitude in decimal degrees
-        lon: f64,
+        lon: Option<f64>,
+
+        /// Find the stations closest to an address instead, e.g. `"Sveavägen 100"`. It is looked
+        /// up with OpenStreetMap's Nominatim, or the geocoder under `[geocoder]` in the config
+        #[arg(long, conflicts_with_all = ["lat", "lon"])]
+        address: Option<String>,
 
-        /// Maximum number of stations to return
-        #[arg(short, long, default_value_t = 3)]
+        /// Maximum number of results. Each station in text output costs a request to find
+        /// out which transport modes stop there, so only a few are shown by default
+        #[arg(short = 'n', long, short_alias = 'l', default_value_t = CLOSEST_LIMIT)]
         limit: usize,
 
         /// Filter stations to those with departures for this transport mode
         #[arg(short = 't', long = "transport-mode", alias = "type")]
         transport_mode: Option<TransportMode>,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
     },
-}
+    /// Ask for departures in plain words, e.g. `slq q "next 4 to slussen from odenplan"`, for
+    /// when the flags don't come to mind. The same query as `slq departures` with flags is
+    /// shown on stderr, to learn them from
+    Q {
+        /// The question: a station after `from`, a destination after `to`, `next` and a
+        /// count, and a line or transport mode like `bus 43`. Quotes are optional
+        #[arg(required = true, num_args = 1..)]
+        question: Vec<String>,
 
-fn string_to_date(expected: &str) -> Result<PrimitiveDateTime> {
-    let fmt = format_description::parse("[year]-[month]-[day]T[hour]:[minute]:[second]")
-        .expect("static format description");
-    Ok(PrimitiveDateTime::parse(expected, &fmt)?)
-}
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Compare journeys from a few stops within walking distance and recommend the one to walk
+    /// to for the earliest arrival, e.g. `slq best --to Slussen --from-stops Skanstull,Ringvägen`
+    Best {
+        /// Station to get to
+        #[arg(long)]
+        to: String,
 
-/// Parse "2025-11-02T11:14:02" (no timezone) as local time and return a human wait string.
-pub fn wait_time(expected: &str) -> String {
-    let arrival = string_to_date(expected);
-    match arrival {
-        Ok(arrival) => {
-            let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
-            let local_off = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
-            let arrival: OffsetDateTime = arrival.assume_offset(local_off);
-            let delta: Duration = arrival - now;
+        /// Stops to compare, separated by commas. The walk to each is `walk_minutes` from the
+        /// config file, or none
+        #[arg(
+            long,
+            value_delimiter = ',',
+            required_unless_present = "near",
+            conflicts_with = "near"
+        )]
+        from_stops: Vec<String>,
 
-            if delta.is_negative() {
-                return "now".into();
-            }
+        /// Compare the stops closest to a position instead, with walks worked out from the
+        /// distance
+        #[arg(long, num_args = 2, value_names = ["LAT", "LON"], allow_negative_numbers = true)]
+        near: Option<Vec<f64>>,
 
-            human(delta)
-        }
-        Err(_) => "unknown".to_string(),
-    }
-}
+        /// Farthest to walk to a stop with `--near`, in meters
+        #[arg(long, default_value_t = 800, requires = "near")]
+        within: u32,
 
-fn human(d: Duration) -> String {
-    let s = d.whole_seconds();
-    let h = s / 3600;
-    let m = (s % 3600) / 60;
-    let sec = s % 60;
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Show details for a single site, including its stop points and the lines serving it.
+    Site {
+        /// Site identifier
+        id: SiteId,
+    },
+    /// Dump all known sites with their IDs, names and coordinates.
+    Sites {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
 
-    match (h, m, sec) {
-        (0, 0, sec) => format!("{sec}s"),
-        (0, m, _) => format!("{m}m"),
-        (h, m, _) => format!("{h}h {m}m"),
-    }
-}
+        /// Maximum number of results. Defaults to what fits in the terminal for text output,
+        /// and to all results otherwise
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
 
-fn print_departure(departure: &Departure) {
-    let wait = wait_time(&departure.expected.as_str());
-    let pd = string_to_date(departure.expected.as_str())
-        .expect("Could not parse date returned from API");
-    let time = format_time(&pd);
-    println!(
-        "{}\t{}\t{}\t{:?}\t{}",
-        wait,
-        time,
-        departure.line.designation,
-        departure.line.transport_mode,
-        departure.destination
-    );
-}
+        /// Only show sites inside this rectangle, e.g. `59.32,18.05,59.34,18.08`. Sites
+        /// without coordinates are left out
+        #[arg(long, value_name = "MINLAT,MINLON,MAXLAT,MAXLON")]
+        bbox: Option<BoundingBox>,
+    },
+    /// Watch for a service arriving at a station, e.g. someone you are meeting, and send a
+    /// notification when it is a few minutes away
+    Expect {
+        /// Station name or identifier
+        station_name: String,
 
-fn print_site(site: &Site) {
-    println!("{}\t{}", site.name, site.id)
-}
+        /// Line the service runs on
+        #[arg(short, long)]
+        line: Option<String>,
 
-const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
-const MAX_MODE_FILTER_PROBES: usize = 200;
-const DISTANCE_COL_WIDTH: usize = 10;
-const STATION_COL_WIDTH: usize = 34;
-const TYPE_COL_WIDTH: usize = 20;
+        /// Where the service is coming from. Departures heading back towards this place are
+        /// ignored
+        #[arg(long)]
+        from: Option<String>,
 
-#[derive(Clone)]
-struct RankedSite {
-    site: Site,
-    distance_meters: f64,
-}
+        /// Filter by transport type
+        #[arg(short, long)]
+        transport_mode: Option<TransportMode>,
 
-fn haversine_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    let d_lat = (lat2 - lat1).to_radians();
-    let d_lon = (lon2 - lon1).to_radians();
-    let a = (d_lat / 2.0).sin().powi(2)
-        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
-    EARTH_RADIUS_METERS * c
-}
+        /// Notify when the service is this many minutes away
+        #[arg(short, long, default_value_t = 5)]
+        minutes: i64,
 
-fn rank_sites_by_distance(sites: &[Site], lat: f64, lon: f64) -> Vec<RankedSite> {
-    let mut ranked: Vec<RankedSite> = sites
-        .iter()
-        .filter_map(|site| {
-            let site_lat = site.lat?;
-            let site_lon = site.lon?;
-            Some(RankedSite {
-                site: site.clone(),
-                distance_meters: haversine_meters(lat, lon, site_lat, site_lon),
-            })
-        })
-        .collect();
+        /// Seconds between checks of the departure board
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Send a notification when the next matching departure is a set time away, e.g.
+    /// `slq alarm home -l 43 --notify-before 10`. Notifications go to the desktop unless
+    /// another backend is configured
+    Alarm {
+        /// Station name or identifier
+        station_name: String,
 
-    ranked.sort_by(|a, b| {
-        a.distance_meters
-            .total_cmp(&b.distance_meters)
-            .then_with(|| a.site.id.cmp(&b.site.id))
-    });
+        /// Filter by line number, like for `departures`
+        #[arg(short, long)]
+        line: Option<String>,
 
-    ranked
-}
+        /// Filter results by their destination
+        #[arg(short, long)]
+        destination: Option<String>,
 
-fn print_closest_site(ranked_site: &RankedSite) {
-    let distance = format!("{:.0}m", ranked_site.distance_meters);
-    println!(
-        "{:<DISTANCE_COL_WIDTH$}{:<STATION_COL_WIDTH$}{}",
-        distance, ranked_site.site.name, ranked_site.site.id
-    );
-}
+        /// Filter by transport type
+        #[arg(short, long)]
+        transport_mode: Option<TransportMode>,
 
-fn transport_mode_rank(mode: TransportMode) -> u8 {
-    match mode {
-        TransportMode::Bus => 0,
-        TransportMode::Tram => 1,
-        TransportMode::Metro => 2,
-        TransportMode::Train => 3,
-        TransportMode::Ferry => 4,
-        TransportMode::Ship => 5,
-        TransportMode::Taxi => 6,
-    }
-}
+        /// How long before the departure to notify, e.g. `10`, `90s` or `1h`. A bare number is
+        /// minutes
+        #[arg(short, long, value_parser = parse_duration)]
+        notify_before: Duration,
 
-fn transport_mode_label(mode: TransportMode) -> &'static str {
-    match mode {
-        TransportMode::Bus => "bus",
-        TransportMode::Tram => "tram",
-        TransportMode::Metro => "metro",
-        TransportMode::Train => "train",
-        TransportMode::Ferry => "ferry",
-        TransportMode::Ship => "ship",
-        TransportMode::Taxi => "taxi",
-    }
-}
+        /// Keep going after the first notification, and notify for every following departure
+        #[arg(long)]
+        repeat: bool,
 
-fn closest_site_type_label(site_id: u32) -> String {
-    match get_site_transport_modes(site_id, 20) {
-        Ok(mut modes) if !modes.is_empty() => {
-            modes.sort_by_key(|mode| transport_mode_rank(*mode));
-            modes
-                .iter()
-                .map(|mode| transport_mode_label(*mode))
-                .collect::<Vec<_>>()
-                .join("/")
-        }
-        _ => "unknown".to_string(),
-    }
-}
+        /// Longest time in seconds between checks of the departure board
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Wait until the next matching departure is due within a time, then print it and exit.
+    /// Useful in scripts, e.g. `slq wait home -l 28 --until 7m && loginctl lock-session`
+    Wait {
+        /// Station name or identifier
+        station_name: String,
 
-fn print_closest_site_with_type(ranked_site: &RankedSite) {
-    let distance = format!("{:.0}m", ranked_site.distance_meters);
-    println!(
-        "{:<DISTANCE_COL_WIDTH$}{:<STATION_COL_WIDTH$}{:<TYPE_COL_WIDTH$}{}",
-        distance,
-        ranked_site.site.name,
-        closest_site_type_label(ranked_site.site.id),
-        ranked_site.site.id
-    );
-}
+        /// Filter by line number, like for `departures`
+        #[arg(short, long)]
+        line: Option<String>,
 
-fn print_closest_header(with_type: bool) {
-    if with_type {
-        println!(
-            "{:<DISTANCE_COL_WIDTH$}{:<STATION_COL_WIDTH$}{:<TYPE_COL_WIDTH$}ID",
-            "Distance", "Station", "Type"
-        );
-    } else {
-        println!(
-            "{:<DISTANCE_COL_WIDTH$}{:<STATION_COL_WIDTH$}ID",
-            "Distance", "Station"
-        );
-    }
-}
+        /// Filter results by their destination
+        #[arg(short, long)]
+        destination: Option<String>,
 
-fn format_time(date: &PrimitiveDateTime) -> String {
-    format!("{:02}:{:02}", date.hour(), date.minute())
-}
+        /// Filter by transport type
+        #[arg(short, long)]
+        transport_mode: Option<TransportMode>,
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+        /// Stop waiting when the departure is this close, e.g. `5m`, `90s` or `1h`. A bare
+        /// number is minutes
+        #[arg(short, long, value_parser = parse_duration)]
+        until: Duration,
 
-    match &args.command {
-        Commands::Search { station_name } => {
-            let sites = search_for_sites(station_name.as_str())?;
-            sites.iter().for_each(|s| print_site(s));
-        }
-        Commands::Departures {
-            station_name,
-            line,
-            count,
-            transport_mode,
-            destination,
-        } => {
-            let (site_id, site_name) = if station_name.parse::<u64>().is_ok() {
-                (station_name.clone(), station_name.clone())
-            } else {
-                let sites = search_for_sites(station_name.as_str())?;
-                if let Some(site) = sites.get(0) {
-                    (site.id.to_string(), site.name.clone())
-                } else {
-                    println!("Error: Station '{}' not found.", station_name);
-                    return Ok(());
-                }
-            };
+        /// Longest time in seconds between checks of the departure board
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Serve departures and station search as a small JSON API, so other machines share
+    /// one cache and rate limit: `GET /sites/<id>/departures` and `GET /search?q=<name>`
+    Serve {
+        /// Port to listen on
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
 
-            println!("Departures from {}:", site_name);
-            let departures = get_departures(&site_id, line, count, transport_mode, destination)?;
-            departures.iter().for_each(|d| print_departure(d));
-        }
-        Commands::Closest {
-            lat,
-            lon,
-            limit,
-            transport_mode,
-        } => {
-            let ranked_sites = rank_sites_by_distance(&get_sites()?, *lat, *lon);
-            if ranked_sites.is_empty() {
-                println!("Error: No stations with coordinates found.");
-                return Ok(());
-            }
+        /// Address to listen on. Use 0.0.0.0 to accept requests from other machines
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: IpAddr,
 
-            if let Some(mode) = transport_mode {
-                let mut filtered_results: Vec<RankedSite> = Vec::new();
-                print_closest_header(false);
-                for ranked_site in ranked_sites.iter().take(MAX_MODE_FILTER_PROBES) {
-                    if filtered_results.len() >= *limit {
-                        break;
-                    }
+        /// Send a keepalive on event streams that have been quiet this long, 20 seconds unless
+        /// given, so proxies don't close them and clients that went away are noticed. A bare
+        /// number is minutes
+        #[arg(long, value_parser = parse_duration)]
+        heartbeat: Option<Duration>,
+    },
+    /// Publish departures and deviations for one or more stations to an MQTT broker every
+    /// interval, e.g. to feed Home Assistant. Messages are retained JSON on
+    /// `slq/<site id>/departures` and `slq/<site id>/deviations` unless told otherwise
+    Mqtt {
+        /// Broker as `host` or `host:port`. Defaults to `broker` under `[mqtt]` in the config
+        /// file
+        #[arg(long)]
+        broker: Option<String>,
 
-                    if site_has_transport_mode(ranked_site.site.id, *mode)? {
-                        filtered_results.push(ranked_site.clone());
-                    }
-                }
-                filtered_results.iter().for_each(print_closest_site);
-            } else {
-                print_closest_header(true);
-                ranked_sites
-                    .iter()
-                    .take(*limit)
-                    .for_each(print_closest_site_with_type);
-            }
-        }
-    };
-    Ok(())
-}
+        /// Station name, alias or identifier to publish. Can be given several times
+        #[arg(short, long = "site", required = true)]
+        sites: Vec<String>,
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        /// Topic for departures, `{site}` is replaced by the site id
+        #[arg(long)]
+        departures_topic: Option<String>,
 
-    fn site(id: u32, name: &str, lat: f64, lon: f64) -> Site {
-        Site {
-            id,
-            name: name.to_string(),
-            lat: Some(lat),
-            lon: Some(lon),
-        }
+        /// Topic for deviations, `{site}` is replaced by the site id
+        #[arg(long)]
+        deviations_topic: Option<String>,
+
+        /// Seconds between publishes
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+
+        /// Only publish departures on this line
+        #[arg(short, long)]
+        line: Option<String>,
+
+        /// Maximum number of departures per station
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Only publish departures towards this destination
+        #[arg(short, long)]
+        destination: Option<String>,
+
+        /// Only publish departures of this transport type
+        #[arg(short, long)]
+        transport_mode: Option<TransportMode>,
+    },
+    /// Run a Model Context Protocol server on stdin and stdout, offering the `search_sites`,
+    /// `get_departures` and `plan_journey` tools to AI assistants
+    Mcp,
+    /// Summarize how each transport mode is running right now, from the deviations in effect
+    /// across the network
+    Status {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// List the deviations in effect right now on your lines, set with `lines` under
+    /// `[deviations]` in the config file, or on every line. With `--watch` or `--notify`, only
+    /// deviations that are new or have become more serious since the last run are reported
+    Deviations {
+        /// Only deviations affecting this line. Can be given several times, and replaces the
+        /// lines from the config file
+        #[arg(short, long = "line")]
+        lines: Vec<String>,
+
+        /// Keep checking, and report deviations as they appear or escalate
+        #[arg(long)]
+        watch: bool,
+
+        /// Send a notification for every new or escalated deviation
+        #[arg(long)]
+        notify: bool,
+
+        /// Seconds between checks with `--watch`
+        #[arg(long, default_value_t = 300)]
+        interval: u64,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// List planned works and other announced disruptions affecting a station in the coming days
+    Planned {
+        /// Station name or identifier
+        station_name: String,
+
+        /// Number of days ahead to look, starting today
+        #[arg(long, default_value_t = 7)]
+        days: i64,
+
+        /// Maximum number of results. Defaults to what fits in the terminal for text output,
+        /// and to all results otherwise
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Output format. Machine readable formats use RFC 3339 timestamps
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Scheduled departures from the offline timetable, without using the network. Download
+    /// the timetable with `slq gtfs update` first
+    #[cfg(feature = "gtfs")]
+    Timetable {
+        /// Station name or identifier
+        station_name: String,
+
+        /// Time to list departures from, e.g. `08:30` or `2025-05-12 08:30`. Defaults to now
+        #[arg(long, value_parser = gtfs::parse_at)]
+        at: Option<gtfs::At>,
+
+        /// Filter by line number, including variants like "28s" for "28"
+        #[arg(short, long)]
+        line: Option<String>,
+
+        /// Filter by transport type
+        #[arg(short, long)]
+        transport_mode: Option<TransportMode>,
+
+        /// Maximum number of results. Defaults to what fits in the terminal for text output,
+        /// and to all results otherwise
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Show the trip id of each departure, to list its stops with `slq journey-details`
+        #[arg(long)]
+        trip_ids: bool,
+
+        /// Only departures the timetable says are step free: the vehicle takes wheelchairs and
+        /// the stop isn't known to have steps
+        #[arg(long)]
+        accessible_only: bool,
+
+        /// Show how full each vehicle is, for those running and reporting it. Needs a
+        /// realtime key
+        #[arg(long)]
+        show_crowding: bool,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Every stop along a trip from the offline timetable, with scheduled and, given a
+    /// realtime key, expected times. Trip ids are shown by `slq timetable --trip-ids`
+    #[cfg(feature = "gtfs")]
+    JourneyDetails {
+        /// Trip id from the offline timetable
+        trip_id: String,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Every stop a line calls at, in order, from the offline timetable
+    #[cfg(feature = "gtfs")]
+    Route {
+        /// Line number, e.g. 43
+        line: String,
+
+        /// Only show one direction, 0 or 1 as numbered in the timetable
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=1))]
+        direction: Option<u8>,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Live positions of the vehicles on a line: the stop each one is closest to, which way
+    /// it's heading and how late it is. Uses the offline timetable to match vehicles to lines
+    #[cfg(feature = "gtfs")]
+    Vehicles {
+        /// Line number, e.g. 43
+        #[arg(short, long)]
+        line: String,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Download and inspect the offline GTFS timetable used by `slq timetable`
+    #[cfg(feature = "gtfs")]
+    Gtfs {
+        #[command(subcommand)]
+        command: GtfsCommand,
+    },
+    /// Send a notification through the backend configured in the config file. Useful for
+    /// checking notification settings or chaining after other commands in scripts.
+    Notify {
+        /// Message body
+        #[arg(default_value = "Notifications from slq are working")]
+        message: String,
+
+        /// Notification title
+        #[arg(long, default_value = "slq")]
+        title: String,
+    },
+    /// Sample the departure boards of one or more stations every interval and keep the
+    /// scheduled, expected and actual times in a local SQLite database, building up a history
+    /// of how reliable your lines are
+    #[cfg(feature = "history")]
+    Record {
+        /// Station names, aliases or identifiers
+        #[arg(required = true)]
+        stations: Vec<String>,
+
+        /// Only record departures on this line, including variants like "28s" for "28"
+        #[arg(short, long)]
+        line: Option<String>,
+
+        /// Only record departures of this transport type
+        #[arg(short, long)]
+        transport_mode: Option<TransportMode>,
+
+        /// Seconds between samples. Departures get the last expected time seen before they
+        /// left as their actual time, so shorter intervals give more accurate times
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+    },
+    /// How punctual the departures recorded by `slq record` have been: average and median
+    /// delay, how many were cancelled and the hours of the day that run worst
+    #[cfg(feature = "history")]
+    Stats {
+        /// Only departures on this line, e.g. 43
+        #[arg(short, long)]
+        line: Option<String>,
+
+        /// Only departures from this station, by name, alias or identifier
+        #[arg(short, long)]
+        station: Option<String>,
+
+        /// How far back to look, e.g. `30d`, `12h` or `90m`
+        #[arg(long, value_parser = parse_duration, default_value = "30d")]
+        since: Duration,
+
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Export the departure history recorded by `slq record`
+    #[cfg(feature = "history")]
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+    /// Run the alarms, deviation alerts, MQTT publisher, HTTP server and departure recording
+    /// set up under `[daemon]` in the config file in one long-lived process
+    Daemon {
+        /// Write a systemd user unit that starts the daemon at login, instead of running it
+        #[arg(long)]
+        install_service: bool,
+    },
+}
+
+#[cfg(feature = "gtfs")]
+#[derive(Subcommand)]
+enum GtfsCommand {
+    /// Download SL's GTFS feed from Trafiklab and import it. Needs a "GTFS Regional Static
+    /// data" key, set as `static_key` under `[trafiklab]` in the config file
+    Update,
+    /// Show when the timetable was imported and which days it covers
+    Info,
+}
+
+#[cfg(feature = "history")]
+#[derive(Subcommand)]
+enum HistoryCommand {
+    /// Write every recorded departure to stdout, to analyze in a notebook or load into a data
+    /// warehouse, e.g. `slq history export -f parquet > history.parquet`
+    Export {
+        /// File format. `parquet` needs slq built with the `parquet` feature
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+
+        /// Only departures on this line, e.g. 43
+        #[arg(short, long)]
+        line: Option<String>,
+
+        /// Only departures from this station, by name, alias or identifier
+        #[arg(short, long)]
+        station: Option<String>,
+
+        /// Only departures scheduled within this long ago, e.g. `30d` or `12h`
+        #[arg(long, value_parser = parse_duration)]
+        since: Option<Duration>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Show the size of the cache and how often it was used
+    Stats,
+    /// Remove every cached response
+    Clear,
+    /// Remove expired responses
+    Gc,
+}
+
+impl Commands {
+    /// Commands that print as things happen until they are stopped, rather
+    /// than once
+    fn keeps_running(&self) -> bool {
+        match self {
+            Commands::Departures { watch, .. } => *watch,
+            Commands::Track { .. }
+            | Commands::Expect { .. }
+            | Commands::Alarm { .. }
+            | Commands::Serve { .. }
+            | Commands::Mqtt { .. }
+            | Commands::Daemon { .. } => true,
+            Commands::Deviations { watch, .. } => *watch,
+            #[cfg(feature = "history")]
+            Commands::Record { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the output is finished when the command is, and can be held
+    /// back to show in a pager. Commands that keep running, or whose output
+    /// is meant for other programs, print as they go.
+    fn pageable(&self) -> bool {
+        match self {
+            Commands::Search { .. }
+            | Commands::Run { .. }
+            | Commands::CheckConfig
+            | Commands::Doctor
+            | Commands::Closest { .. }
+            | Commands::Best { .. }
+            | Commands::Q { .. }
+            | Commands::Site { .. }
+            | Commands::Sites { .. }
+            | Commands::Status { .. }
+            | Commands::Planned { .. } => true,
+            Commands::Departures {
+                station_name,
+                watch,
+                ..
+            } => !watch && !stations_from_stdin(station_name),
+            Commands::Deviations { watch, notify, .. } => !watch && !notify,
+            #[cfg(feature = "gtfs")]
+            Commands::Timetable { .. }
+            | Commands::JourneyDetails { .. }
+            | Commands::Route { .. }
+            | Commands::Vehicles { .. } => true,
+            #[cfg(feature = "history")]
+            Commands::Stats { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Lower the parsed arguments into the command independent query.
+    fn query(&self) -> Query {
+        match self {
+            Commands::Search {
+                format,
+                limit,
+                near,
+                ..
+            } => Query {
+                format: *format,
+                limit: *limit,
+                position: near.as_deref().map(|near| (near[0], near[1])),
+                ..Default::default()
+            },
+            Commands::Site { .. }
+            | Commands::Notify { .. }
+            | Commands::Run { .. }
+            | Commands::CheckConfig
+            | Commands::Mcp
+            | Commands::Daemon { .. }
+            | Commands::Doctor
+            | Commands::SelfUpdate { .. }
+            | Commands::Cache { .. }
+            | Commands::Completions { .. }
+            | Commands::Man
+            | Commands::CompleteStation { .. } => Query::default(),
+            Commands::Departures {
+                line,
+                limit,
+                destination,
+                transport_mode,
+                operator,
+                walk_minutes,
+                raw,
+                journey_ids,
+                show_departed,
+                use_api_display,
+                columns,
+                format,
+                max_width,
+                watch,
+                interval,
+                heartbeat,
+                station_name,
+                ..
+            } => Query {
+                format: *format,
+                max_width: *max_width,
+                poll_seconds: watch.then_some(*interval),
+                streaming: (*watch || stations_from_stdin(station_name))
+                    && *format == OutputFormat::Ndjson,
+                from_stdin: stations_from_stdin(station_name),
+                heartbeat: *heartbeat,
+                limit: *limit,
+                line: line.clone(),
+                destination: destination.clone(),
+                transport_mode: *transport_mode,
+                operator: operator.clone(),
+                walk_minutes: *walk_minutes,
+                raw: *raw,
+                journey_ids: *journey_ids,
+                show_departed: *show_departed,
+                use_api_display: *use_api_display,
+                columns: columns.clone(),
+                ..Default::default()
+            },
+            Commands::Track { interval, .. } => Query {
+                poll_seconds: Some(*interval),
+                ..Default::default()
+            },
+            Commands::Closest {
+                lat,
+                lon,
+                limit,
+                transport_mode,
+                format,
+                ..
+            } => Query {
+                format: *format,
+                limit: Some(*limit),
+                transport_mode: *transport_mode,
+                position: lat.zip(*lon),
+                ..Default::default()
+            },
+            Commands::Q { format, .. } => Query {
+                format: *format,
+                ..Default::default()
+            },
+            Commands::Best { near, format, .. } => Query {
+                format: *format,
+                position: near.as_deref().map(|near| (near[0], near[1])),
+                ..Default::default()
+            },
+            Commands::Sites { format, limit, .. } => Query {
+                format: *format,
+                limit: *limit,
+                ..Default::default()
+            },
+            Commands::Wait {
+                line,
+                destination,
+                transport_mode,
+                until,
+                interval,
+                ..
+            } => Query {
+                line: line.clone(),
+                destination: destination.clone(),
+                transport_mode: *transport_mode,
+                lead_time: Some(*until),
+                poll_seconds: Some(*interval),
+                ..Default::default()
+            },
+            Commands::Serve { heartbeat, .. } => Query {
+                streaming: true,
+                heartbeat: *heartbeat,
+                ..Default::default()
+            },
+            Commands::Mqtt {
+                interval,
+                line,
+                limit,
+                destination,
+                transport_mode,
+                ..
+            } => Query {
+                limit: *limit,
+                line: line.clone(),
+                destination: destination.clone(),
+                transport_mode: *transport_mode,
+                poll_seconds: Some(*interval),
+                ..Default::default()
+            },
+            Commands::Status { format } => Query {
+                format: *format,
+                ..Default::default()
+            },
+            Commands::Deviations {
+                watch,
+                interval,
+                format,
+                ..
+            } => Query {
+                format: *format,
+                poll_seconds: watch.then_some(*interval),
+                // Changes are printed one per line, and CSV gets one header
+                streaming: *watch,
+                ..Default::default()
+            },
+            Commands::Planned {
+                days,
+                format,
+                limit,
+                ..
+            } => Query {
+                format: *format,
+                limit: *limit,
+                days: Some(*days),
+                ..Default::default()
+            },
+            #[cfg(feature = "gtfs")]
+            Commands::Timetable {
+                line,
+                transport_mode,
+                limit,
+                trip_ids,
+                accessible_only,
+                show_crowding,
+                format,
+                ..
+            } => Query {
+                format: *format,
+                limit: *limit,
+                line: line.clone(),
+                transport_mode: *transport_mode,
+                journey_ids: *trip_ids,
+                accessible_only: *accessible_only,
+                show_crowding: *show_crowding,
+                ..Default::default()
+            },
+            #[cfg(feature = "gtfs")]
+            Commands::JourneyDetails { format, .. } => Query {
+                format: *format,
+                ..Default::default()
+            },
+            #[cfg(feature = "gtfs")]
+            Commands::Route { line, format, .. } => Query {
+                format: *format,
+                line: Some(line.clone()),
+                ..Default::default()
+            },
+            #[cfg(feature = "gtfs")]
+            Commands::Vehicles { line, format } => Query {
+                format: *format,
+                line: Some(line.clone()),
+                ..Default::default()
+            },
+            #[cfg(feature = "gtfs")]
+            Commands::Gtfs { .. } => Query::default(),
+            #[cfg(feature = "history")]
+            Commands::History {
+                command: HistoryCommand::Export { format, .. },
+            } => Query {
+                format: match format {
+                    ExportFormat::Json => OutputFormat::Json,
+                    _ => OutputFormat::Text,
+                },
+                ..Default::default()
+            },
+            #[cfg(feature = "history")]
+            Commands::Stats { line, format, .. } => Query {
+                line: line.clone(),
+                format: *format,
+                ..Default::default()
+            },
+            #[cfg(feature = "history")]
+            Commands::Record {
+                line,
+                transport_mode,
+                interval,
+                ..
+            } => Query {
+                line: line.clone(),
+                transport_mode: *transport_mode,
+                poll_seconds: Some(*interval),
+                ..Default::default()
+            },
+            Commands::Alarm {
+                line,
+                destination,
+                transport_mode,
+                notify_before,
+                interval,
+                ..
+            } => Query {
+                line: line.clone(),
+                destination: destination.clone(),
+                transport_mode: *transport_mode,
+                lead_time: Some(*notify_before),
+                poll_seconds: Some(*interval),
+                ..Default::default()
+            },
+            Commands::Expect {
+                line,
+                transport_mode,
+                minutes,
+                interval,
+                ..
+            } => Query {
+                line: line.clone(),
+                transport_mode: *transport_mode,
+                lead_time: Some(Duration::minutes(*minutes)),
+                poll_seconds: Some(*interval),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// How close to its expected time, either way, a departure shows as `now`
+const NOW_WINDOW: Duration = Duration::seconds(30);
+
+/// How long after its expected time a departure shows as `departing`. After
+/// that it has `departed`, and departure boards drop it unless asked not to.
+const DEPARTING_WINDOW: Duration = Duration::minutes(2);
+
+/// The wait until `expected` the way a person would say it, e.g. `14m`
+pub fn wait_time(expected: OffsetDateTime, clock: &dyn Clock) -> String {
+    wait_time_in(expected, clock, Language::English)
+}
+
+/// [`wait_time`] with `now`, `departing` and the like in `language`
+fn wait_time_in(expected: OffsetDateTime, clock: &dyn Clock, language: Language) -> String {
+    let phrases = phrases(language);
+    let delta: Duration = expected - clock.now();
+    if delta.abs() <= NOW_WINDOW {
+        phrases.now.into()
+    } else if delta.is_positive() {
+        human(delta)
+    } else if delta >= -DEPARTING_WINDOW {
+        phrases.departing.into()
+    } else {
+        phrases.departed.into()
+    }
+}
+
+/// Whether a departure leaves at least `minutes` from now
+fn leaves_after(departure: &Departure, minutes: i64, clock: &dyn Clock) -> bool {
+    departure.time_until(clock) >= Duration::minutes(minutes)
+}
+
+/// Whether a departure left longer ago than it shows as `departing`
+fn has_departed(departure: &Departure, clock: &dyn Clock) -> bool {
+    departure.time_until(clock) < -DEPARTING_WINDOW
+}
+
+/// Parse an operator for `--operator`: `SL`, or a transport authority id,
+/// since those are all the departures API says about who runs a line
+fn parse_operator(value: &str) -> Result<String, String> {
+    if value.eq_ignore_ascii_case("SL") || value.parse::<u32>().is_ok() {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "'{value}' isn't known, use SL or the transport authority id from the json format"
+        ))
+    }
+}
+
+/// Parse a duration such as "5m", "90s" or "1h". A bare number is minutes.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "m"),
+    };
+    let number: i64 = number
+        .parse()
+        .map_err(|_| format!("'{value}' is not a duration like 5m, 90s or 1h"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" | "min" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(format!("unknown unit '{unit}', use s, m, h or d")),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::seconds)
+        .ok_or_else(|| format!("'{value}' is too long"))
+}
+
+/// A duration in its two largest units, e.g. `45s`, `14m`, `2h 5m` or
+/// `1d 3h`, with a leading `-` when it is negative. Shorter units are cut
+/// off, not rounded.
+fn human(d: Duration) -> String {
+    let s = d.whole_seconds();
+    let sign = if s < 0 { "-" } else { "" };
+    let s = s.unsigned_abs();
+    let days = s / 86_400;
+    let h = (s % 86_400) / 3600;
+    let m = (s % 3600) / 60;
+    let sec = s % 60;
+
+    match (days, h, m, sec) {
+        (0, 0, 0, sec) => format!("{sign}{sec}s"),
+        (0, 0, m, _) => format!("{sign}{m}m"),
+        (0, h, m, _) => format!("{sign}{h}h {m}m"),
+        (days, h, _, _) => format!("{sign}{days}d {h}h"),
+    }
+}
+
+fn print_departure(departure: &Departure, query: &Query) {
+    outln!("{}", departure_row(departure, query, &SystemClock));
+}
+
+/// A departure as one tab separated line of text output
+fn departure_row(departure: &Departure, query: &Query, clock: &dyn Clock) -> String {
+    let columns = query.board_columns();
+    let cells: Vec<String> = columns
+        .iter()
+        .map(|column| column_text(*column, departure, query, clock))
+        .collect();
+    let destination = columns.iter().position(|c| *c == Column::Destination);
+    match (query.width, destination) {
+        (Some(width), Some(destination)) => fit_row(&cells, destination, width),
+        _ => cells.join("\t"),
+    }
+}
+
+/// One column of a departure as text output shows it
+fn column_text(column: Column, departure: &Departure, query: &Query, clock: &dyn Clock) -> String {
+    match column {
+        Column::Wait => {
+            // Departures from the timetable have no display time
+            let wait = match (query.use_api_display, &departure.display) {
+                (true, Some(display)) => display.clone(),
+                _ => wait_time_in(departure.expected, clock, query.language),
+            };
+            match &query.theme {
+                Some(theme) => {
+                    let spare = departure.time_until(clock).whole_minutes()
+                        - query.walk_minutes.unwrap_or(0);
+                    paint(&wait, theme.wait(spare))
+                }
+                None => wait,
+            }
+        }
+        Column::Time => painted(format_time(departure.expected, query), query, |theme| {
+            theme.time
+        }),
+        Column::Line => painted(departure.line.designation.clone(), query, |theme| {
+            theme.line(&departure.line)
+        }),
+        Column::Mode => painted(
+            transport_mode_text(departure.line.transport_mode, query).to_string(),
+            query,
+            |theme| theme.mode,
+        ),
+        Column::Destination => painted(departure.destination.clone(), query, |theme| {
+            theme.destination
+        }),
+        Column::Journey => departure
+            .journey
+            .as_ref()
+            .map_or("-".to_string(), |journey| journey.id.to_string()),
+    }
+}
+
+/// Text in the color the theme picks for it, when output is colored
+fn painted(text: String, query: &Query, color: impl Fn(&Theme) -> Color) -> String {
+    match &query.theme {
+        Some(theme) => paint(&text, color(theme)),
+        None => text,
+    }
+}
+
+/// The cells of a departure table, headings first. Tables are read outside
+/// the terminal, so they are never colored.
+fn departure_table(departures: &[Departure], query: &Query, clock: &dyn Clock) -> Vec<Vec<String>> {
+    let plain = Query {
+        theme: None,
+        ..query.clone()
+    };
+    let columns = query.board_columns();
+    let mut table = vec![
+        columns
+            .iter()
+            .map(|column| column_title(*column, query.language).to_string())
+            .collect(),
+    ];
+    table.extend(departures.iter().map(|departure| {
+        columns
+            .iter()
+            .map(|column| column_text(*column, departure, &plain, clock))
+            .collect()
+    }));
+    table
+}
+
+/// Departures as an HTML `<table>`, with the banner as its caption
+fn departures_html(
+    departures: &[Departure],
+    caption: Option<&str>,
+    query: &Query,
+    clock: &dyn Clock,
+) -> String {
+    let table = departure_table(departures, query, clock);
+    let row = |cells: &[String], tag: &str| {
+        let cells: String = cells
+            .iter()
+            .map(|cell| format!("<{tag}>{}</{tag}>", escape_markup(cell)))
+            .collect();
+        format!("    <tr>{cells}</tr>")
+    };
+    let mut lines = vec!["<table>".to_string()];
+    if let Some(caption) = caption {
+        lines.push(format!("  <caption>{}</caption>", escape_markup(caption)));
+    }
+    lines.push("  <thead>".to_string());
+    lines.push(row(&table[0], "th"));
+    lines.push("  </thead>".to_string());
+    lines.push("  <tbody>".to_string());
+    lines.extend(table[1..].iter().map(|cells| row(cells, "td")));
+    lines.push("  </tbody>".to_string());
+    lines.push("</table>".to_string());
+    lines.join("\n")
+}
+
+/// Departures as a Markdown table, with the banner in bold above it
+fn departures_markdown(
+    departures: &[Departure],
+    title: Option<&str>,
+    query: &Query,
+    clock: &dyn Clock,
+) -> String {
+    let table = departure_table(departures, query, clock);
+    let row = |cells: &[String]| {
+        let cells: Vec<String> = cells.iter().map(|cell| cell.replace('|', "\\|")).collect();
+        format!("| {} |", cells.join(" | "))
+    };
+    let mut lines = Vec::new();
+    if let Some(title) = title {
+        lines.push(format!("**{}**", title.replace('*', "\\*")));
+        lines.push(String::new());
+    }
+    lines.push(row(&table[0]));
+    lines.push(format!("|{}", "---|".repeat(table[0].len())));
+    lines.extend(table[1..].iter().map(|cells| row(cells)));
+    lines.join("\n")
+}
+
+/// Departures as CSV, with a header row unless `header` is off
+fn departures_csv(departures: &[Departure], header: bool) -> String {
+    let mut lines = Vec::new();
+    if header {
+        lines.push("expected,line,transport_mode,destination,journey_id,scheduled".to_string());
+    }
+    lines.extend(departures.iter().map(|departure| {
+        format!(
+            "{},{},{},{},{},{}",
+            timestamp(departure.expected),
+            csv_field(&departure.line.designation),
+            transport_mode_label(departure.line.transport_mode),
+            csv_field(&departure.destination),
+            departure
+                .journey
+                .as_ref()
+                .map(|journey| journey.id.to_string())
+                .unwrap_or_default(),
+            departure.scheduled.map(timestamp).unwrap_or_default()
+        )
+    }));
+    lines.join("\n")
+}
+
+const MAX_MODE_FILTER_PROBES: usize = 200;
+/// Stations `slq closest` shows unless `--limit` says otherwise
+const CLOSEST_LIMIT: usize = 3;
+const DISTANCE_COL_WIDTH: usize = 10;
+const WALK_COL_WIDTH: usize = 10;
+const STATION_COL_WIDTH: usize = 34;
+const TYPE_COL_WIDTH: usize = 20;
+
+#[derive(Clone)]
+struct RankedSite {
+    site: Site,
+    distance_meters: f64,
+}
+
+impl RankedSite {
+    fn walk_minutes(&self) -> i64 {
+        walking_minutes(self.distance_meters)
+    }
+}
+
+/// `sites` in the same order with their distance from a position. Sites
+/// without coordinates are left out.
+fn sites_with_distance(sites: &[Site], lat: f64, lon: f64) -> Vec<RankedSite> {
+    sites
+        .iter()
+        .filter_map(|site| {
+            let site_lat = site.lat?;
+            let site_lon = site.lon?;
+            Some(RankedSite {
+                site: site.clone(),
+                distance_meters: haversine_meters(lat, lon, site_lat, site_lon),
+            })
+        })
+        .collect()
+}
+
+fn rank_sites_by_distance(sites: &[Site], lat: f64, lon: f64) -> Vec<RankedSite> {
+    let mut ranked = sites_with_distance(sites, lat, lon);
+    ranked.sort_by(|a, b| {
+        a.distance_meters
+            .total_cmp(&b.distance_meters)
+            .then_with(|| a.site.id.cmp(&b.site.id))
+    });
+
+    ranked
+}
+
+/// Most stops `slq best --near` compares, closest first
+const MAX_BEST_STOPS: usize = 5;
+/// Journeys asked for from each stop, so some are left after dropping the
+/// ones leaving before the walk there is done
+const JOURNEYS_PER_STOP: usize = 4;
+
+/// The stops within `within` meters of a position, closest first
+fn stops_near(client: &SlClient, lat: f64, lon: f64, within: u32) -> Result<Vec<CandidateStop>> {
+    let stops: Vec<CandidateStop> = rank_sites_by_distance(&client.get_sites()?, lat, lon)
+        .into_iter()
+        .take_while(|ranked| ranked.distance_meters <= f64::from(within))
+        .take(MAX_BEST_STOPS)
+        .map(|ranked| CandidateStop {
+            walk_minutes: ranked.walk_minutes(),
+            site_id: ranked.site.id,
+            name: ranked.site.name,
+        })
+        .collect();
+    if stops.is_empty() {
+        bail!("no stops within {within}m of {lat}, {lon}");
+    }
+    Ok(stops)
+}
+
+/// Plan a journey to `to` from each of `stops` and print the one arriving
+/// first for each, best first
+fn show_best_stop(
+    client: &SlClient,
+    to: &ResolvedStation,
+    stops: &[CandidateStop],
+    query: &Query,
+) -> Result<()> {
+    let clock = SystemClock;
+    let mut choices = Vec::new();
+    for stop in stops.iter().filter(|stop| stop.site_id != to.id) {
+        let journeys = client.plan_journey(stop.site_id, to.id, JOURNEYS_PER_STOP)?;
+        match earliest_arrival(stop, &journeys, &clock) {
+            Some(choice) => choices.push(choice),
+            None => notice!(
+                "Nothing from {} leaves late enough to walk there in {} min",
+                stop.name,
+                stop.walk_minutes
+            ),
+        }
+    }
+    if choices.is_empty() {
+        bail!("no journey to {} can be caught from these stops", to.name);
+    }
+    rank(&mut choices);
+
+    match query.format {
+        OutputFormat::Text => {
+            let phrases = phrases(query.language);
+            headerln!("{} {}:", phrases.earliest_at, to.name);
+            for choice in &choices {
+                outln!(
+                    "{}\t{} {}\t{} {} → {}",
+                    choice.stop.name,
+                    choice.stop.walk_minutes,
+                    phrases.walk,
+                    choice.line.as_deref().unwrap_or("-"),
+                    format_time(choice.departs, query),
+                    format_time(choice.arrives, query)
+                );
+            }
+        }
+        OutputFormat::Json => print_json(&choices)?,
+        OutputFormat::Csv => {
+            headerln!("stop,site_id,walk_minutes,line,departs,arrives");
+            for choice in &choices {
+                outln!(
+                    "{},{},{},{},{},{}",
+                    csv_field(&choice.stop.name),
+                    choice.stop.site_id,
+                    choice.stop.walk_minutes,
+                    csv_field(choice.line.as_deref().unwrap_or_default()),
+                    timestamp(choice.departs),
+                    timestamp(choice.arrives)
+                );
+            }
+        }
+        OutputFormat::Geojson => bail!("journeys have no coordinates to put on a map"),
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+    }
+    Ok(())
+}
+
+fn print_closest_site(ranked_site: &RankedSite) {
+    outln!("{}", closest_site_row(ranked_site));
+}
+
+fn closest_site_row(ranked_site: &RankedSite) -> String {
+    format!(
+        "{}{:<STATION_COL_WIDTH$}{}",
+        distance_cells(ranked_site),
+        ranked_site.site.name,
+        ranked_site.site.id
+    )
+}
+
+/// The distance and walking time columns of a row, e.g. `350m      5 min`
+fn distance_cells(ranked_site: &RankedSite) -> String {
+    format!(
+        "{:<DISTANCE_COL_WIDTH$}{:<WALK_COL_WIDTH$}",
+        format!("{:.0}m", ranked_site.distance_meters),
+        format!("{} min", ranked_site.walk_minutes())
+    )
+}
+
+fn transport_mode_rank(mode: TransportMode) -> u8 {
+    match mode {
+        TransportMode::Bus => 0,
+        TransportMode::Tram => 1,
+        TransportMode::Metro => 2,
+        TransportMode::Train => 3,
+        TransportMode::Ferry => 4,
+        TransportMode::Ship => 5,
+        TransportMode::Taxi => 6,
+        TransportMode::Other => 7,
+    }
+}
+
+/// A transport mode as text output shows it: by name, or as a pictogram
+/// with `--icons`
+fn transport_mode_text(mode: TransportMode, query: &Query) -> &'static str {
+    query
+        .icons
+        .then(|| transport_mode_icon(mode))
+        .flatten()
+        .unwrap_or_else(|| transport_mode_name(mode, query.language))
+}
+
+fn transport_mode_label(mode: TransportMode) -> &'static str {
+    match mode {
+        TransportMode::Bus => "bus",
+        TransportMode::Tram => "tram",
+        TransportMode::Metro => "metro",
+        TransportMode::Train => "train",
+        TransportMode::Ferry => "ferry",
+        TransportMode::Ship => "ship",
+        TransportMode::Taxi => "taxi",
+        TransportMode::Other => "other",
+    }
+}
+
+fn closest_site_type_label(client: &SlClient, site_id: SiteId) -> String {
+    match client.get_site_transport_modes(site_id, 20) {
+        Ok(mut modes) if !modes.is_empty() => {
+            modes.sort_by_key(|mode| transport_mode_rank(*mode));
+            modes
+                .iter()
+                .map(|mode| transport_mode_label(*mode))
+                .collect::<Vec<_>>()
+                .join("/")
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+fn print_closest_site_with_type(client: &SlClient, ranked_site: &RankedSite) {
+    let kind = closest_site_type_label(client, ranked_site.site.id);
+    outln!("{}", closest_site_row_with_type(ranked_site, &kind));
+}
+
+fn closest_site_row_with_type(ranked_site: &RankedSite, kind: &str) -> String {
+    format!(
+        "{}{:<STATION_COL_WIDTH$}{kind:<TYPE_COL_WIDTH$}{}",
+        distance_cells(ranked_site),
+        ranked_site.site.name,
+        ranked_site.site.id
+    )
+}
+
+fn print_closest_header(with_type: bool, language: Language) {
+    headerln!("{}", closest_header(with_type, language));
+}
+
+fn closest_header(with_type: bool, language: Language) -> String {
+    let phrases = phrases(language);
+    if with_type {
+        format!(
+            "{:<DISTANCE_COL_WIDTH$}{:<WALK_COL_WIDTH$}{:<STATION_COL_WIDTH$}{:<TYPE_COL_WIDTH$}ID",
+            phrases.distance, phrases.walking, phrases.station, phrases.kind
+        )
+    } else {
+        format!(
+            "{:<DISTANCE_COL_WIDTH$}{:<WALK_COL_WIDTH$}{:<STATION_COL_WIDTH$}ID",
+            phrases.distance, phrases.walking, phrases.station
+        )
+    }
+}
+
+fn print_site_details(site: &Site, details: Option<&SiteDetails>, language: Language) {
+    outln!("Name:         {}", site.name);
+    outln!("ID:           {}", site.id);
+    if let (Some(lat), Some(lon)) = (site.lat, site.lon) {
+        outln!("Coordinates:  {lat}, {lon}");
+    }
+    if let Some(abbreviation) = &site.abbreviation {
+        outln!("Abbreviation: {abbreviation}");
+    }
+    if let Some(note) = &site.note {
+        outln!("Note:         {note}");
+    }
+
+    let Some(details) = details else {
+        outln!("Stop points:  unknown");
+        outln!("Lines:        unknown");
+        return;
+    };
+
+    outln!("Stop points:");
+    for stop_point in &details.stop_points {
+        outln!(
+            "  {:<8}{} {}",
+            stop_point.id,
+            stop_point.name,
+            stop_point.designation.as_deref().unwrap_or_default()
+        );
+    }
+
+    let mut modes: Vec<TransportMode> = Vec::new();
+    for line in &details.lines {
+        if !modes.contains(&line.transport_mode) {
+            modes.push(line.transport_mode);
+        }
+    }
+    modes.sort_by_key(|mode| transport_mode_rank(*mode));
+
+    outln!("Lines:");
+    for mode in modes {
+        outln!(
+            "  {:<8}{}",
+            transport_mode_label(mode),
+            line_groups(&details.lines, mode, language)
+        );
+    }
+}
+
+/// The lines of one transport mode, grouped like `17, 18, 19 (Green line)`
+fn line_groups(lines: &[Line], mode: TransportMode, language: Language) -> String {
+    let mut groups: Vec<(Option<&str>, Vec<&str>)> = Vec::new();
+    for line in lines.iter().filter(|line| line.transport_mode == mode) {
+        // Bus groups are just where they run, see `Line::name`
+        let group = line
+            .group_of_lines
+            .as_deref()
+            .filter(|_| mode != TransportMode::Bus);
+        match groups.iter_mut().find(|(name, _)| *name == group) {
+            Some((_, designations)) => designations.push(&line.designation),
+            None => groups.push((group, vec![&line.designation])),
+        }
+    }
+    groups
+        .iter()
+        .map(|(group, designations)| match group {
+            Some(group) => format!(
+                "{} ({})",
+                designations.join(", "),
+                line_group_name(group, language)
+            ),
+            None => designations.join(", "),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn print_ranked_sites(ranked_sites: &[RankedSite], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => ranked_sites.iter().for_each(print_closest_site),
+        OutputFormat::Json => {
+            let rows: Vec<Value> = ranked_sites
+                .iter()
+                .map(|ranked_site| {
+                    json!({
+                        "id": ranked_site.site.id,
+                        "name": ranked_site.site.name,
+                        "lat": ranked_site.site.lat,
+                        "lon": ranked_site.site.lon,
+                        "distance_meters": ranked_site.distance_meters.round(),
+                        "walk_minutes": ranked_site.walk_minutes(),
+                    })
+                })
+                .collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            headerln!("id,name,lat,lon,distance_meters,walk_minutes");
+            ranked_sites.iter().for_each(|ranked_site| {
+                outln!(
+                    "{},{},{},{},{:.0},{}",
+                    ranked_site.site.id,
+                    csv_field(&ranked_site.site.name),
+                    coordinate(ranked_site.site.lat),
+                    coordinate(ranked_site.site.lon),
+                    ranked_site.distance_meters,
+                    ranked_site.walk_minutes()
+                )
+            });
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+        OutputFormat::Geojson => {
+            let features = ranked_sites
+                .iter()
+                .map(|ranked_site| {
+                    let mut properties = Map::new();
+                    properties.insert(
+                        "distance_meters".to_string(),
+                        json!(ranked_site.distance_meters.round()),
+                    );
+                    properties.insert(
+                        "walk_minutes".to_string(),
+                        json!(ranked_site.walk_minutes()),
+                    );
+                    (&ranked_site.site, properties)
+                })
+                .collect();
+            print_json(&geojson(features))?;
+        }
+    }
+    Ok(())
+}
+
+/// Extra completion code that offers station names for the `departures`
+/// argument by calling back into `slq __complete-station`.
+fn station_completion_script(shell: Shell) -> Option<&'static str> {
+    match shell {
+        Shell::Bash => Some(
+            r#"
+_slq_with_stations() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    if [[ ${COMP_CWORD} -eq 2 && "${COMP_WORDS[1]}" == "departures" && "${cur}" != -* ]]; then
+        local IFS=$'\n'
+        compopt -o filenames
+        COMPREPLY=( $(slq __complete-station "${cur}" 2>/dev/null) )
+        return 0
+    fi
+    _slq "$@"
+}
+complete -F _slq_with_stations -o nosort -o bashdefault -o default slq
+"#,
+        ),
+        Shell::Fish => Some(
+            r#"
+complete -c slq -n "__fish_seen_subcommand_from departures" -f -a "(slq __complete-station (commandline -ct) 2>/dev/null)"
+"#,
+        ),
+        _ => None,
+    }
+}
+
+/// An instant as a time of day in `--timezone`
+fn format_time(instant: OffsetDateTime, query: &Query) -> String {
+    query.time_format.format(&query.timezone.local(instant))
+}
+
+/// Print the departure board for a station and return how many departures were shown.
+fn show_departures(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    query: &Query,
+) -> Result<usize> {
+    show_departures_into(client, config, station_name, query, None)
+}
+
+/// [`show_departures`], except that with `--format json` the departures are
+/// added to `merged` rather than printed, when given
+fn show_departures_into(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    query: &Query,
+    merged: Option<&mut Vec<Departure>>,
+) -> Result<usize> {
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+
+    let board = client.open_departures(station.id)?;
+    let text = query.format == OutputFormat::Text;
+    let phrases = phrases(query.language);
+    let (banner, stale) = match board.freshness {
+        Freshness::Fresh => (
+            format!("{} {}", phrases.departures_from, station.name),
+            false,
+        ),
+        Freshness::Stale { age } => {
+            let age = human(Duration::try_from(age).unwrap_or_default());
+            let stale = phrases.stale.replace("{age}", &age);
+            // Without the banner the board would pass for a fresh one
+            if text && !headers_enabled() {
+                eprintln!("Warning: {stale}");
+            }
+            (
+                format!("{} {} ({stale})", phrases.departures_from, station.name),
+                true,
+            )
+        }
+    };
+    if text {
+        headerln!("{banner}:");
+    }
+    let walk_minutes = query
+        .walk_minutes
+        .or_else(|| config.walk_minutes_for(station_name, &station));
+    // Rows are printed as they are decoded rather than collected first. The
+    // count is enforced here since the walking time filter comes on top.
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .mode(query.transport_mode)
+        .destination(query.destination.as_deref())
+        .operator(query.operator.clone());
+    let limit = query.result_limit();
+    let mut shown = 0;
+    let mut rows = Vec::new();
+    board.for_each(&filter, |departure| {
+        // A stale board still lists departures that have left since
+        let minutes = walk_minutes.or(stale.then_some(0));
+        let reachable = match minutes {
+            Some(minutes) => leaves_after(&departure, minutes, &SystemClock),
+            None => query.show_departed || !has_departed(&departure, &SystemClock),
+        };
+        if shown < limit && reachable {
+            if text {
+                print_departure(&departure, query);
+            } else {
+                rows.push(departure);
+            }
+            shown += 1;
+        } else if !reachable {
+            debug!(
+                "line {} towards {} at {} leaves too soon to reach",
+                departure.line.designation, departure.destination, departure.expected
+            );
+        }
+    })?;
+
+    match query.format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => match merged {
+            Some(merged) => merged.append(&mut rows),
+            None => print_json(&rows)?,
+        },
+        OutputFormat::Csv => {
+            let csv = departures_csv(&rows, headers_enabled());
+            if !csv.is_empty() {
+                outln!("{csv}");
+            }
+        }
+        OutputFormat::Geojson => bail!("departures have no coordinates to put on a map"),
+        OutputFormat::Hass => {
+            let sensor = hass_sensor(&station.name, &rows, stale, &SystemClock);
+            print_json(&sensor)?;
+        }
+        // Waybar reads one JSON object per line
+        OutputFormat::Waybar => {
+            outln!(
+                "{}",
+                waybar_module(&station.name, &rows, stale, &SystemClock, query.language)
+            );
+        }
+        OutputFormat::Statusbar => {
+            let max_width = query.max_width.or(config.statusbar.max_width);
+            outln!(
+                "{}",
+                statusbar_line(&rows, max_width, &config.statusbar, &SystemClock, query)
+            );
+        }
+        OutputFormat::Html => {
+            let caption = headers_enabled().then_some(banner.as_str());
+            outln!("{}", departures_html(&rows, caption, query, &SystemClock));
+        }
+        OutputFormat::Markdown => {
+            let title = headers_enabled().then_some(banner.as_str());
+            outln!("{}", departures_markdown(&rows, title, query, &SystemClock));
+        }
+        OutputFormat::Ndjson => print_record(&StreamRecord::Departures {
+            at: stream_time(&SystemClock)?,
+            site_id: station.id,
+            station: &station.name,
+            stale,
+            departures: &rows,
+        })?,
+    }
+    Ok(shown)
+}
+
+/// Whether `departures` reads its stations from stdin, with `--stdin` or
+/// `-` for the station
+fn stations_from_stdin(station_name: &Option<String>) -> bool {
+    station_name.as_deref().is_none_or(|name| name == "-")
+}
+
+/// Show the departures of every station named on stdin, as each line comes
+/// in, and return how many were shown in all. A station that can't be shown
+/// is a warning, so the rest of the list still gets done. JSON output is one
+/// array of every station's departures, printed once stdin ends.
+fn show_departures_from_stdin(client: &SlClient, config: &Config, query: &Query) -> Result<usize> {
+    // Read on a thread of its own, so heartbeats go out while stdin is quiet
+    let (send, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            if send.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let heartbeat = query.heartbeat.map(Duration::unsigned_abs);
+    let (mut shown, mut stations, mut failed) = (0, 0, 0);
+    let mut merged = (query.format == OutputFormat::Json).then(Vec::new);
+    loop {
+        let line = match heartbeat {
+            Some(heartbeat) => match lines.recv_timeout(heartbeat) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    print_record(&StreamRecord::Heartbeat {
+                        at: stream_time(&SystemClock)?,
+                    })?;
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match lines.recv() {
+                Ok(line) => line,
+                Err(_) => break,
+            },
+        };
+        let line = line.context("failed to read stations from stdin")?;
+        let station_name = line.trim();
+        if station_name.is_empty() || station_name.starts_with('#') {
+            continue;
+        }
+        // Boards in text output are kept apart by a blank line
+        if stations > 0 && query.format == OutputFormat::Text {
+            outln!();
+        }
+        stations += 1;
+        match show_departures_into(client, config, station_name, query, merged.as_mut()) {
+            Ok(count) => shown += count,
+            Err(err) => {
+                eprintln!("Warning: {station_name}: {err:#}");
+                failed += 1;
+            }
+        }
+    }
+    if let Some(merged) = &merged {
+        print_json(merged)?;
+    }
+    if failed > 0 {
+        bail!("{failed} of {stations} stations could not be shown");
+    }
+    Ok(shown)
+}
+
+/// One line of `--format ndjson`, told apart by its `type`
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamRecord<'a> {
+    Departures {
+        at: String,
+        site_id: SiteId,
+        station: &'a str,
+        stale: bool,
+        departures: &'a [Departure],
+    },
+    /// Sent on a quiet stream so whatever reads it knows it is still alive
+    Heartbeat { at: String },
+}
+
+/// When a stream record was written, to the second
+fn stream_time(clock: &dyn Clock) -> Result<String> {
+    Ok(timestamp(clock.now().replace_nanosecond(0)?))
+}
+
+/// Show a station's departures again every `--interval` until stopped. A
+/// failed refresh is only a warning, since the board is meant to be left
+/// running. Quiet NDJSON streams get a heartbeat record every `--heartbeat`.
+fn watch_departures(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    query: &Query,
+) -> Result<()> {
+    let interval = std::time::Duration::from_secs(query.poll_seconds.unwrap_or(MIN_POLL_SECONDS));
+    let heartbeat = query.heartbeat.map(Duration::unsigned_abs);
+    // Each refresh replaces the last one on a terminal, and follows it otherwise
+    let redraw = query.format == OutputFormat::Text && slq::output::stdout_is_terminal();
+    loop {
+        if redraw {
+            write_output(|out| write!(out, "\x1b[H\x1b[2J"));
+        }
+        if let Err(err) = show_departures(client, config, station_name, query) {
+            eprintln!("Warning: {err:#}");
+        }
+        let next = Instant::now() + interval;
+        while let Some(left) = next.checked_duration_since(Instant::now()) {
+            match heartbeat {
+                Some(heartbeat) if heartbeat < left => {
+                    thread::sleep(heartbeat);
+                    print_record(&StreamRecord::Heartbeat {
+                        at: stream_time(&SystemClock)?,
+                    })?;
+                }
+                _ => thread::sleep(left),
+            }
+        }
+    }
+}
+
+/// Departures on one line, e.g. `43→Ruddammen 4m`, cut short at
+/// `max_width` characters. The whole line is wrapped in the configured
+/// escapes when the first departure is soon, since cutting in the middle
+/// of an escape would break the bar's markup.
+fn statusbar_line(
+    departures: &[Departure],
+    max_width: Option<usize>,
+    config: &StatusbarConfig,
+    clock: &dyn Clock,
+    query: &Query,
+) -> String {
+    // Status bars style the line through the configured escapes instead
+    let plain = Query {
+        theme: None,
+        ..query.clone()
+    };
+    let mut line = departures
+        .iter()
+        .map(|departure| match &query.columns {
+            Some(columns) => columns
+                .iter()
+                .map(|column| column_text(*column, departure, &plain, clock))
+                .collect::<Vec<_>>()
+                .join(" "),
+            None => format!(
+                "{}→{} {}",
+                departure.line.designation,
+                departure.destination,
+                wait_time_in(departure.expected, clock, query.language)
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("  ");
+    if let Some(max_width) = max_width
+        && line.chars().count() > max_width
+    {
+        line = line.chars().take(max_width.saturating_sub(1)).collect();
+        line.push('…');
+    }
+
+    let soon = departures
+        .first()
+        .is_some_and(|next| next.time_until(clock) < Duration::minutes(config.soon_minutes));
+    if soon {
+        format!("{}{line}{}", config.soon_start, config.soon_end)
+    } else {
+        line
+    }
+}
+
+/// A Waybar custom module: the next departure as text, the upcoming ones
+/// in the tooltip, and a class to style the module by. Waybar hides modules
+/// with empty text, so a station without departures disappears from the bar.
+fn waybar_module(
+    station: &str,
+    departures: &[Departure],
+    stale: bool,
+    clock: &dyn Clock,
+    language: Language,
+) -> Value {
+    let phrases = phrases(language);
+    let Some(next) = departures.first() else {
+        return json!({
+            "text": "",
+            "tooltip": escape_markup(&format!("{} {station}", phrases.no_departures_from)),
+            "class": "empty",
+        });
+    };
+
+    let describe = |departure: &Departure| {
+        format!(
+            "{} {} {}",
+            departure.line.designation,
+            departure.destination,
+            wait_time_in(departure.expected, clock, language)
+        )
+    };
+    let mut tooltip = vec![if stale {
+        format!("{station} ({})", phrases.unreachable)
+    } else {
+        station.to_string()
+    }];
+    tooltip.extend(departures.iter().map(describe));
+
+    let due = next.time_until(clock) < Duration::minutes(1);
+    let class = match (stale, due) {
+        (true, _) => "stale",
+        (false, true) => "due",
+        (false, false) => "departures",
+    };
+    json!({
+        "text": escape_markup(&describe(next)),
+        "tooltip": escape_markup(&tooltip.join("\n")),
+        "class": class,
+    })
+}
+
+/// Copy a station's departures response to stdout without decoding it.
+fn dump_departures(client: &SlClient, config: &Config, station_name: &str) -> Result<()> {
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+
+    let board = client.open_departures(station.id)?;
+    if let Freshness::Stale { age } = board.freshness {
+        eprintln!(
+            "SL could not be reached, this response was fetched {} ago",
+            human(Duration::try_from(age).unwrap_or_default())
+        );
+    }
+    let mut copied = Ok(0);
+    write_output(|mut out| {
+        copied = board.copy_raw(&mut out);
+        Ok(())
+    });
+    copied.context("failed to copy the departures response")?;
+    Ok(())
+}
+
+/// Whether a service bound for `destination` can be coming from `origin`.
+///
+/// SL only publishes departures, so a service arriving from somewhere is
+/// approximated by one on the same line that isn't heading back there.
+fn heads_away_from(destination: &str, origin: &str) -> bool {
+    !deunicode(destination)
+        .to_lowercase()
+        .contains(deunicode(origin.trim()).to_lowercase().as_str())
+}
+
+/// Poll a station's departure board until the first upcoming departure
+/// passing `filter` and `accept` is due within `lead_time`. Between polls it
+/// sleeps until that departure should cross the threshold, but never longer
+/// than `interval` so that delays are picked up. `on_poll` sees the next
+/// matching departure, if any, after every poll that didn't finish.
+fn poll_until_due(
+    client: &SlClient,
+    station: &ResolvedStation,
+    filter: &DepartureFilter,
+    accept: impl Fn(&Departure) -> bool,
+    lead_time: Duration,
+    interval: std::time::Duration,
+    mut on_poll: impl FnMut(Option<&Departure>),
+) -> Result<Departure> {
+    let clock = SystemClock;
+    loop {
+        let mut next: Option<Departure> = None;
+        client.for_each_departure(station.id, filter, |departure| {
+            let upcoming =
+                next.is_none() && !departure.time_until(&clock).is_negative() && accept(&departure);
+            if upcoming {
+                next = Some(departure);
+            }
+        })?;
+
+        let remaining = next
+            .as_ref()
+            .map(|departure| departure.time_until(&clock) - lead_time);
+        if let Some(remaining) = remaining
+            && !remaining.is_positive()
+            && let Some(departure) = next
+        {
+            return Ok(departure);
+        }
+
+        on_poll(next.as_ref());
+        let pause = remaining
+            .and_then(|remaining| std::time::Duration::try_from(remaining).ok())
+            .map_or(interval, |remaining| {
+                remaining.clamp(std::time::Duration::from_secs(1), interval)
+            });
+        thread::sleep(pause);
+    }
+}
+
+/// Watch for a service coming from `from` and notify once it is due within
+/// the query's lead time.
+fn expect_service(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    from: Option<&str>,
+    query: &Query,
+) -> Result<()> {
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .mode(query.transport_mode);
+    let departure = poll_until_due(
+        client,
+        &station,
+        &filter,
+        |departure| from.is_none_or(|from| heads_away_from(&departure.destination, from)),
+        query.lead_time.unwrap_or_default(),
+        std::time::Duration::from_secs(query.poll_seconds.unwrap_or(MIN_POLL_SECONDS)),
+        |next| match next {
+            Some(departure) => outln!(
+                "Line {} towards {} is {} away",
+                departure.line.designation,
+                departure.destination,
+                wait_time(departure.expected, &SystemClock)
+            ),
+            None => notice!("Nothing matching on the board at {} yet", station.name),
+        },
+    )?;
+
+    let message = format!(
+        "Line {} towards {} reaches {} in {}",
+        departure.line.designation,
+        departure.destination,
+        station.name,
+        wait_time(departure.expected, &SystemClock)
+    );
+    outln!("{message}");
+    notifier(&config.notify, client).notify("slq", &message)?;
+    Ok(())
+}
+
+/// Notify once the next matching departure is due within the query's lead
+/// time. With `repeat` it carries on with the departure after that, for as
+/// long as slq runs.
+fn sound_alarm(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    repeat: bool,
+    query: &Query,
+) -> Result<()> {
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .mode(query.transport_mode)
+        .destination(query.destination.as_deref());
+    let notifier = notifier(&config.notify, client);
+    // Departures already notified about, so a repeating alarm moves on to
+    // the next one. Journey ids survive delays, expected times don't.
+    let mut notified: Vec<(Option<u64>, String)> = Vec::new();
+    let key = |departure: &Departure| match &departure.journey {
+        Some(journey) => (Some(journey.id), String::new()),
+        None => (
+            None,
+            format!("{}/{}", departure.line.designation, departure.expected),
+        ),
+    };
+    loop {
+        let departure = poll_until_due(
+            client,
+            &station,
+            &filter,
+            |departure| !notified.contains(&key(departure)),
+            query.lead_time.unwrap_or_default(),
+            std::time::Duration::from_secs(query.poll_seconds.unwrap_or(MIN_POLL_SECONDS)),
+            |_| {},
+        )?;
+
+        let message = format!(
+            "Line {} towards {} leaves {} in {}",
+            departure.line.designation,
+            departure.destination,
+            station.name,
+            wait_time(departure.expected, &SystemClock)
+        );
+        outln!("{message}");
+        notifier.notify("slq", &message)?;
+        if !repeat {
+            return Ok(());
+        }
+        notified.push(key(&departure));
+    }
+}
+
+/// Block until the next matching departure is due within the query's lead
+/// time, then print it.
+fn wait_for_departure(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    query: &Query,
+) -> Result<()> {
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .mode(query.transport_mode)
+        .destination(query.destination.as_deref());
+    let departure = poll_until_due(
+        client,
+        &station,
+        &filter,
+        |_| true,
+        query.lead_time.unwrap_or_default(),
+        std::time::Duration::from_secs(query.poll_seconds.unwrap_or(MIN_POLL_SECONDS)),
+        |_| {},
+    )?;
+    print_departure(&departure, query);
+    Ok(())
+}
+
+/// Follow one journey on a station's departure board until it is due,
+/// printing its expected time whenever it changes.
+fn track_journey(
+    client: &SlClient,
+    config: &Config,
+    journey: u64,
+    station_name: &str,
+    query: &Query,
+) -> Result<()> {
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+
+    let clock = SystemClock;
+    let interval = std::time::Duration::from_secs(query.poll_seconds.unwrap_or(MIN_POLL_SECONDS));
+    let filter = DepartureFilter::default();
+    let mut last: Option<Departure> = None;
+    loop {
+        let mut found: Option<Departure> = None;
+        client.for_each_departure(station.id, &filter, |departure| {
+            if departure.journey.as_ref().is_some_and(|j| j.id == journey) {
+                found = Some(departure);
+            }
+        })?;
+
+        let Some(departure) = found else {
+            // Departures drop off the board once they have left
+            return match last {
+                Some(last) => {
+                    outln!(
+                        "Line {} towards {} has left {}",
+                        last.line.designation,
+                        last.destination,
+                        station.name
+                    );
+                    Ok(())
+                }
+                None => Err(CliError::JourneyNotFound {
+                    journey,
+                    station: station.name,
+                }
+                .into()),
+            };
+        };
+        if departure
+            .journey
+            .as_ref()
+            .is_some_and(Journey::is_cancelled)
+        {
+            bail!(
+                "line {} towards {} has been cancelled",
+                departure.line.designation,
+                departure.destination
+            );
+        }
+
+        let until = departure.time_until(&clock);
+        if !until.is_positive() {
+            outln!(
+                "Line {} towards {} is leaving {} now",
+                departure.line.designation,
+                departure.destination,
+                station.name
+            );
+            return Ok(());
+        }
+        if last
+            .as_ref()
+            .is_none_or(|last| last.expected != departure.expected)
+        {
+            outln!(
+                "Line {} towards {} leaves {} at {}, in {}",
+                departure.line.designation,
+                departure.destination,
+                station.name,
+                format_time(departure.expected, query),
+                wait_time(departure.expected, &clock)
+            );
+        }
+
+        let pause = std::time::Duration::try_from(until).map_or(interval, |until| {
+            until.clamp(std::time::Duration::from_secs(1), interval)
+        });
+        last = Some(departure);
+        thread::sleep(pause);
+    }
+}
+
+/// Print the deviations affecting a station between now and `days` from now.
+fn show_planned(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    query: &Query,
+) -> Result<()> {
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+
+    let clock = SystemClock;
+    let days = query.days.unwrap_or(1);
+    let start = clock.now();
+    let end = start + Duration::days(days);
+    let deviations: Vec<Deviation> = client
+        .get_planned_deviations(station.id)?
+        .into_iter()
+        .filter(|deviation| deviation.overlaps(start, end))
+        .take(query.result_limit())
+        .collect();
+
+    let header = |deviation: &Deviation| {
+        deviation
+            .message(query.language.code())
+            .map(|message| message.header.clone())
+            .unwrap_or_default()
+    };
+
+    match query.format {
+        OutputFormat::Text => {
+            if deviations.is_empty() {
+                notice!(
+                    "No planned works at {} in the next {days} day(s).",
+                    station.name
+                );
+                return Ok(());
+            }
+
+            headerln!(
+                "Planned works at {} in the next {days} day(s):",
+                station.name
+            );
+            for deviation in &deviations {
+                let from = deviation
+                    .starts()
+                    .map(|from| natural_datetime(from, &clock, query.timezone, query.language))
+                    .unwrap_or_else(|| deviation.publish.from.clone());
+                let upto = deviation
+                    .ends()
+                    .map(|upto| natural_datetime(upto, &clock, query.timezone, query.language))
+                    .unwrap_or_else(|| "until further notice".to_string());
+                outln!(
+                    "{from} - {upto}\t{}\t{}",
+                    deviation.line_designations(),
+                    header(deviation)
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let rows: Vec<Value> = deviations
+                .iter()
+                .map(|deviation| {
+                    json!({
+                        "from": deviation.publish.from,
+                        "upto": deviation.publish.upto,
+                        "lines": deviation.line_designations(),
+                        "header": header(deviation),
+                    })
+                })
+                .collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            headerln!("from,upto,lines,header");
+            for deviation in &deviations {
+                outln!(
+                    "{},{},{},{}",
+                    deviation.publish.from,
+                    deviation.publish.upto.as_deref().unwrap_or_default(),
+                    csv_field(&deviation.line_designations()),
+                    csv_field(&header(deviation))
+                );
+            }
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+        OutputFormat::Geojson => bail!("planned works have no coordinates to put on a map"),
+    }
+    Ok(())
+}
+
+/// Deviations in effect right now on `lines`, most important first
+fn current_deviations(client: &SlClient, lines: &[String]) -> Result<Vec<Deviation>> {
+    let mut deviations: Vec<Deviation> = client
+        .get_current_deviations()?
+        .into_iter()
+        .filter(|deviation| deviation.affects_any(lines))
+        .collect();
+    deviations.sort_by_key(|deviation| std::cmp::Reverse(deviation.importance()));
+    Ok(deviations)
+}
+
+fn deviation_json(deviation: &Deviation, query: &Query) -> Value {
+    let message = deviation.message(query.language.code());
+    json!({
+        "id": deviation.deviation_case_id,
+        "lines": deviation.line_designations(),
+        "importance": deviation.importance(),
+        "from": deviation.publish.from,
+        "upto": deviation.publish.upto,
+        "header": message.map(|message| &message.header),
+        "details": message.and_then(|message| message.details.as_ref()),
+    })
+}
+
+fn deviation_csv(deviation: &Deviation, query: &Query) -> String {
+    let header = deviation
+        .message(query.language.code())
+        .map(|message| message.header.as_str())
+        .unwrap_or_default();
+    format!(
+        "{},{},{},{}",
+        deviation
+            .deviation_case_id
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+        csv_field(&deviation.line_designations()),
+        deviation.importance(),
+        csv_field(header)
+    )
+}
+
+fn show_deviations(client: &SlClient, lines: &[String], query: &Query) -> Result<()> {
+    let deviations = current_deviations(client, lines)?;
+    match query.format {
+        OutputFormat::Text => {
+            for deviation in &deviations {
+                let header = deviation
+                    .message(query.language.code())
+                    .map(|message| message.header.as_str())
+                    .unwrap_or_default();
+                outln!("{}\t{header}", deviation.line_designations());
+            }
+        }
+        OutputFormat::Json => {
+            let rows: Vec<Value> = deviations
+                .iter()
+                .map(|deviation| deviation_json(deviation, query))
+                .collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            headerln!("id,lines,importance,header");
+            for deviation in &deviations {
+                outln!("{}", deviation_csv(deviation, query));
+            }
+        }
+        OutputFormat::Geojson => bail!("deviations have no coordinates to put on a map"),
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+    }
+    Ok(())
+}
+
+/// Run everything set up under `[daemon]` in the config file, each on its
+/// own thread, until the process is stopped. Settings are checked before
+/// anything starts, so mistakes show up right away instead of in the logs.
+fn run_daemon(client: &SlClient, config: &Config) -> Result<()> {
+    let daemon = &config.daemon;
+    let mut tasks = Vec::new();
+
+    let alarms = daemon
+        .alarms
+        .iter()
+        .map(|alarm| {
+            let query = alarm.query();
+            query.validate()?;
+            tasks.push(format!("alarm at {}", alarm.station));
+            Ok((alarm, query))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let deviations = Query {
+        poll_seconds: Some(daemon.deviations_interval),
+        ..Default::default()
+    };
+    if daemon.deviations {
+        deviations.validate()?;
+        tasks.push("deviation alerts".to_string());
+    }
+
+    let mqtt = if daemon.mqtt_sites.is_empty() {
+        None
+    } else {
+        let interval = std::time::Duration::from_secs(daemon.mqtt_interval);
+        Query {
+            poll_seconds: Some(daemon.mqtt_interval),
+            ..Default::default()
+        }
+        .validate()?;
+        let settings = MqttSettings::new(&config.mqtt, None, None, None, interval)?;
+        let sites = resolve_stations(client, config, &daemon.mqtt_sites)?;
+        tasks.push("MQTT publisher".to_string());
+        Some((settings, sites))
+    };
+
+    #[cfg(feature = "history")]
+    let recording = Query {
+        poll_seconds: Some(daemon.record_interval),
+        ..Default::default()
+    };
+    #[cfg(feature = "history")]
+    let record_sites = if daemon.record_sites.is_empty() {
+        Vec::new()
+    } else {
+        recording.validate()?;
+        tasks.push("departure recording".to_string());
+        resolve_stations(client, config, &daemon.record_sites)?
+    };
+    #[cfg(not(feature = "history"))]
+    if !daemon.record_sites.is_empty() {
+        bail!(
+            "this slq was built without the departure history, remove record_sites from [daemon]"
+        );
+    }
+
+    if let Some(addr) = daemon.serve {
+        tasks.push(format!("HTTP server on {addr}"));
+    }
+    if tasks.is_empty() {
+        bail!(
+            "nothing to run, set up alarms, deviations, mqtt_sites or serve under [daemon] in the config file"
+        );
+    }
+    notice!("Running {}", tasks.join(", "));
+
+    thread::scope(|scope| {
+        for (alarm, query) in &alarms {
+            scope.spawn(move || {
+                supervise(&format!("the alarm at {}", alarm.station), || {
+                    sound_alarm(client, config, &alarm.station, true, query)
+                });
+            });
+        }
+        if daemon.deviations {
+            let deviations = &deviations;
+            scope.spawn(move || {
+                supervise("the deviation alerts", || {
+                    let lines = &config.deviations.lines;
+                    watch_deviations(client, config, lines, true, true, deviations)
+                });
+            });
+        }
+        if let Some((settings, sites)) = &mqtt {
+            scope.spawn(move || {
+                supervise("the MQTT publisher", || {
+                    mqtt::publish(client, settings, sites, &Query::default())
+                });
+            });
+        }
+        if let Some(addr) = daemon.serve {
+            scope.spawn(move || supervise("the HTTP server", || serve(client, addr, None)));
+        }
+        #[cfg(feature = "history")]
+        if !record_sites.is_empty() {
+            let (record_sites, recording) = (&record_sites, &recording);
+            scope.spawn(move || {
+                supervise("the departure recording", || {
+                    record_departures(client, record_sites, recording)
+                });
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Resolve every name in `names`, failing on the first that matches no station
+fn resolve_stations(
+    client: &SlClient,
+    config: &Config,
+    names: &[String],
+) -> Result<Vec<ResolvedStation>> {
+    names
+        .iter()
+        .map(|name| {
+            config
+                .resolve_station(client, name)?
+                .ok_or_else(|| CliError::StationNotFound(name.clone()).into())
+        })
+        .collect()
+}
+
+/// The station named by an optional `--station` option
+#[cfg(feature = "history")]
+fn resolve_optional_station(
+    client: &SlClient,
+    name: Option<&str>,
+) -> Result<Option<ResolvedStation>> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    let config = Config::load()?;
+    Ok(resolve_stations(client, &config, &[name.to_string()])?.pop())
+}
+
+/// Write the recorded departures matching the filters to stdout
+#[cfg(feature = "history")]
+fn export_history(
+    format: ExportFormat,
+    line: Option<&str>,
+    station: Option<&ResolvedStation>,
+    since: Option<Duration>,
+) -> Result<()> {
+    let clock = SystemClock;
+    let now = clock.now().to_offset(clock.local_offset());
+    let now = PrimitiveDateTime::new(now.date(), now.time());
+    let rows = History::open()?.rows(&HistoryFilter {
+        line,
+        site_id: station.map(|station| station.id),
+        since: since.map(|since| now - since),
+        until: None,
+    })?;
+
+    match format {
+        ExportFormat::Csv => {
+            headerln!(
+                "site_id,line,transport_mode,destination,journey_id,scheduled,expected,actual,state,delay,first_seen,last_seen"
+            );
+            let optional = |value: &Option<String>| csv_field(value.as_deref().unwrap_or_default());
+            let number =
+                |value: Option<i64>| value.map(|value| value.to_string()).unwrap_or_default();
+            for row in &rows {
+                outln!(
+                    "{},{},{},{},{},{},{},{},{},{},{},{}",
+                    csv_field(&row.site_id),
+                    csv_field(&row.line),
+                    row.transport_mode,
+                    csv_field(&row.destination),
+                    number(row.journey_id),
+                    optional(&row.scheduled),
+                    row.expected,
+                    optional(&row.actual),
+                    optional(&row.state),
+                    number(row.delay),
+                    row.first_seen,
+                    row.last_seen
+                );
+            }
+        }
+        ExportFormat::Json => print_json(&rows)?,
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => {
+            if slq::output::stdout_is_terminal() {
+                bail!("Parquet is a binary format, write it to a file: `-o history.parquet`");
+            }
+            let mut parquet = Vec::new();
+            slq::history::write_parquet(&rows, &mut parquet)?;
+            write_output(|out| out.write_all(&parquet));
+        }
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => {
+            bail!(
+                "this slq was built without Parquet support, install it with `cargo install slq --features parquet`"
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Delay statistics over the departure history, for `slq stats`
+#[cfg(feature = "history")]
+fn show_stats(station: Option<&ResolvedStation>, since: Duration, query: &Query) -> Result<()> {
+    if since <= Duration::ZERO {
+        bail!("--since must be longer than zero");
+    }
+    let clock = SystemClock;
+    let now = clock.now().to_offset(clock.local_offset());
+    let until = PrimitiveDateTime::new(now.date(), now.time());
+    let from = until - since;
+    let history = History::open()?;
+    let stats = history.stats(&HistoryFilter {
+        line: query.line.as_deref(),
+        site_id: station.map(|station| station.id),
+        since: Some(from),
+        until: Some(until),
+    })?;
+
+    match query.format {
+        OutputFormat::Text => {
+            let mut scope = match &query.line {
+                Some(line) => format!("Line {line}"),
+                None => "All lines".to_string(),
+            };
+            if let Some(station) = station {
+                scope.push_str(&format!(" at {}", station.name));
+            }
+            outln!(
+                "{scope} since {} {}",
+                from.date(),
+                format_time(sl_time(from), query)
+            );
+            if stats.departures == 0 {
+                notice!("No recorded departures, record some with `slq record`");
+                return Ok(());
+            }
+            outln!(
+                "Departures: {} recorded, {} departed, {} cancelled{}",
+                stats.departures,
+                stats.departed,
+                stats.cancelled,
+                stats
+                    .cancellation_rate()
+                    .map(|rate| format!(" ({})", percent(rate)))
+                    .unwrap_or_default()
+            );
+            if let (Some(mean), Some(median)) = (stats.mean_delay, stats.median_delay) {
+                outln!(
+                    "Delay: {} on average, {} median",
+                    delay(mean.round() as i64),
+                    delay(median)
+                );
+            }
+            if let Some(on_time) = stats.on_time {
+                outln!(
+                    "On time: {} left at most {} late",
+                    percent(on_time),
+                    human(Duration::seconds(ON_TIME_SECONDS))
+                );
+            }
+            if !stats.hours.is_empty() {
+                outln!("Worst hours:");
+                for hour in stats.hours.iter().take(3) {
+                    outln!(
+                        "  {:02}:00-{:02}:00  {} on average over {} departures",
+                        hour.hour,
+                        (hour.hour + 1) % 24,
+                        delay(hour.mean_delay.round() as i64),
+                        hour.departed
+                    );
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let mut json = serde_json::to_value(&stats)?;
+            json["line"] = json!(query.line);
+            json["site_id"] = json!(station.map(|station| &station.id));
+            json["since"] = json!(timestamp(sl_time(from)));
+            json["cancellation_rate"] = json!(stats.cancellation_rate());
+            print_json(&json)?;
+        }
+        OutputFormat::Csv | OutputFormat::Geojson => {
+            bail!("stats are only available as text or json")
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+    }
+    Ok(())
+}
+
+/// A delay in seconds, e.g. `2m late` or `30s early`
+#[cfg(feature = "history")]
+fn delay(seconds: i64) -> String {
+    match seconds {
+        0 => "on time".to_string(),
+        seconds if seconds < 0 => format!("{} early", human(Duration::seconds(-seconds))),
+        seconds => format!("{} late", human(Duration::seconds(seconds))),
+    }
+}
+
+#[cfg(feature = "history")]
+fn percent(share: f64) -> String {
+    format!("{:.1}%", share * 100.0)
+}
+
+/// Sample the boards of `stations` into the departure history every
+/// interval until the process is stopped. Failing to reach SL skips a
+/// sample rather than ending the recording.
+#[cfg(feature = "history")]
+fn record_departures(client: &SlClient, stations: &[ResolvedStation], query: &Query) -> Result<()> {
+    let mut history = History::open()?;
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .mode(query.transport_mode);
+    let interval = query.poll_seconds.unwrap_or(MIN_POLL_SECONDS);
+    notice!(
+        "Recording departures at {} every {interval}s to {}",
+        stations
+            .iter()
+            .map(|station| station.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        History::path().unwrap_or_default().display()
+    );
+    loop {
+        for station in stations {
+            let mut departures = Vec::new();
+            match client
+                .for_each_departure(station.id, &filter, |departure| departures.push(departure))
+            {
+                Ok(()) => {
+                    let sample = history.record(station.id, &departures, &SystemClock)?;
+                    debug!(
+                        "{}: {} departures on the board, {} left",
+                        station.name, sample.seen, sample.departed
+                    );
+                }
+                Err(err) => eprintln!("Warning: {err:#}"),
+            }
+        }
+        thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+/// Report deviations on `lines` that are new or more serious than when
+/// they were last seen, once or every interval with `watch`. What has
+/// been seen is kept in a state file between runs.
+fn watch_deviations(
+    client: &SlClient,
+    config: &Config,
+    lines: &[String],
+    watch: bool,
+    notify: bool,
+    query: &Query,
+) -> Result<()> {
+    match query.format {
+        OutputFormat::Text | OutputFormat::Json | OutputFormat::Csv => {}
+        OutputFormat::Geojson => bail!("deviations have no coordinates to put on a map"),
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+    }
+    let notifier = notify.then(|| notifier(&config.notify, client));
+    let mut seen = SeenDeviations::load();
+    if query.format == OutputFormat::Csv {
+        headerln!("change,id,lines,importance,header");
+    }
+    loop {
+        // A failed check is retried on the next round when watching, since
+        // the watcher is meant to run unattended
+        match current_deviations(client, lines) {
+            Ok(deviations) => {
+                for (change, deviation) in seen.update(&deviations) {
+                    let change_name = match change {
+                        Change::New => "new",
+                        Change::Escalated { .. } => "escalated",
+                    };
+                    let header = deviation
+                        .message(query.language.code())
+                        .map(|message| message.header.as_str())
+                        .unwrap_or_default();
+                    let message = match change {
+                        Change::New => format!("{}: {header}", deviation.line_designations()),
+                        Change::Escalated { .. } => format!(
+                            "Now more serious on {}: {header}",
+                            deviation.line_designations()
+                        ),
+                    };
+                    match query.format {
+                        OutputFormat::Json => {
+                            let mut row = deviation_json(deviation, query);
+                            row["change"] = json!(change_name);
+                            outln!("{row}");
+                        }
+                        OutputFormat::Csv => {
+                            outln!("{change_name},{}", deviation_csv(deviation, query));
+                        }
+                        _ => outln!("{message}"),
+                    }
+                    if let Some(notifier) = &notifier {
+                        notifier.notify("SL deviation", &message)?;
+                    }
+                }
+                seen.save()?;
+            }
+            Err(err) if watch => eprintln!("Warning: {err:#}"),
+            Err(err) => return Err(err),
+        }
+        if !watch {
+            return Ok(());
+        }
+        thread::sleep(std::time::Duration::from_secs(
+            query.poll_seconds.unwrap_or(MIN_POLL_SECONDS),
+        ));
+    }
+}
+
+/// Print how every transport mode is running, with the deviations behind
+/// anything short of good service.
+fn show_status(client: &SlClient, query: &Query) -> Result<()> {
+    let deviations = client.get_current_deviations()?;
+    let statuses = summarize(&deviations);
+    let header = |deviation: &Deviation| {
+        deviation
+            .message(query.language.code())
+            .map(|message| message.header.clone())
+            .unwrap_or_default()
+    };
+
+    match query.format {
+        OutputFormat::Text => {
+            for status in &statuses {
+                let mode = transport_mode_label(status.mode);
+                if status.affected.is_empty() {
+                    outln!("{mode}: {}", status.level.label());
+                    continue;
+                }
+                outln!(
+                    "{mode}: {} on {}",
+                    status.level.label(),
+                    status.affected.join(", ")
+                );
+                for deviation in &status.deviations {
+                    outln!("  {}\t{}", deviation.line_designations(), header(deviation));
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let rows: Vec<Value> = statuses
+                .iter()
+                .map(|status| {
+                    let deviations: Vec<Value> = status
+                        .deviations
+                        .iter()
+                        .map(|deviation| {
+                            json!({
+                                "lines": deviation.line_designations(),
+                                "header": header(deviation),
+                                "importance": deviation.priority.as_ref().map(|p| p.importance_level),
+                            })
+                        })
+                        .collect();
+                    json!({
+                        "transport_mode": status.mode,
+                        "status": status.level.label(),
+                        "affected": status.affected,
+                        "deviations": deviations,
+                    })
+                })
+                .collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            headerln!("transport_mode,status,lines,header");
+            for status in &statuses {
+                let mode = transport_mode_label(status.mode);
+                if status.deviations.is_empty() {
+                    outln!("{mode},{},,", status.level.label());
+                }
+                for deviation in &status.deviations {
+                    outln!(
+                        "{mode},{},{},{}",
+                        status.level.label(),
+                        csv_field(&deviation.line_designations()),
+                        csv_field(&header(deviation))
+                    );
+                }
+            }
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+        OutputFormat::Geojson => bail!("the service status has no coordinates to put on a map"),
+    }
+    Ok(())
+}
+
+/// Check GitHub for a newer release and, unless `check_only`, install it
+/// over the running binary.
+fn self_update(client: &SlClient, check_only: bool) -> Result<()> {
+    if client.dry_run() {
+        notice!("Would check {LATEST_RELEASE_URL} for a newer release");
+        return Ok(());
+    }
+
+    let release = update::latest_release(client.http())?;
+    if !release.is_newer() {
+        outln!("slq {CURRENT_VERSION} is up to date");
+        return Ok(());
+    }
+    outln!(
+        "slq {} is available, this is {CURRENT_VERSION}",
+        release.version()
+    );
+    if check_only {
+        return Ok(());
+    }
+
+    let Some(asset) = release.asset() else {
+        bail!(
+            "release {} has no {} binary, update with `cargo install slq` instead",
+            release.tag_name,
+            update::asset_name()
+        );
+    };
+    let Some(checksum) = release.checksum(asset) else {
+        bail!(
+            "release {} has no checksum for {}, so it can't be verified",
+            release.tag_name,
+            asset.name
+        );
+    };
+    let exe = env::current_exe().context("could not find the running slq binary")?;
+    update::install(client.http(), asset, checksum, &exe)?;
+    notice!("Updated {} to {}", exe.display(), release.version());
+    Ok(())
+}
+
+/// Print check results one per line and return the number of errors.
+fn print_findings(findings: &[Finding]) -> usize {
+    for finding in findings {
+        outln!(
+            "{:<9}{:<30}{}",
+            finding.severity,
+            finding.subject,
+            finding.message
+        );
+    }
+    findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count()
+}
+
+/// Print scheduled departures from the offline timetable.
+#[cfg(feature = "gtfs")]
+fn show_timetable(
+    client: &SlClient,
+    config: &Config,
+    station_name: &str,
+    at: Option<gtfs::At>,
+    query: &Query,
+) -> Result<()> {
+    let timetable = Timetable::open()?;
+    let Some(station) = config.resolve_station(client, station_name)? else {
+        return Err(CliError::StationNotFound(station_name.to_string()).into());
+    };
+    // Site ids resolve to themselves, but the timetable only knows names
+    let name = if station.name == station.id.to_string() {
+        client
+            .get_site(station.id)?
+            .map(|site| site.name)
+            .unwrap_or(station.name)
+    } else {
+        station.name
+    };
+    let stops = timetable.stops_named(&name)?;
+    if stops.is_empty() {
+        return Err(CliError::StationNotFound(name).into());
+    }
+
+    let clock = SystemClock;
+    let now = clock.now().to_offset(clock.local_offset());
+    let validity = timetable.info()?.validity;
+    Query {
+        at: at.map(|at| at.on(now.date())),
+        coverage: validity,
+        ..query.clone()
+    }
+    .validate()?;
+    let at = at.map_or(PrimitiveDateTime::new(now.date(), now.time()), |at| {
+        at.on(now.date())
+    });
+    if let Some((first, last)) = validity
+        && !(first..=last).contains(&at.date())
+    {
+        eprintln!(
+            "Warning: the offline timetable covers {first} to {last}, run `slq gtfs update` for a newer one"
+        );
+    }
+
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .count(Some(query.result_limit()))
+        .mode(query.transport_mode);
+    let departures = timetable.departures(&stops, at, &filter, query.accessible_only)?;
+
+    let crowding = if query.show_crowding {
+        let Some(key) = config.trafiklab.realtime_key() else {
+            bail!(
+                "--show-crowding needs a Trafiklab key for \"GTFS Regional Realtime\", set `realtime_key` under `[trafiklab]` in the config file or SLQ_TRAFIKLAB_REALTIME_KEY"
+            );
+        };
+        if client.dry_run() {
+            notice!("Would download {}", realtime::VEHICLE_POSITIONS_URL);
+            return Ok(());
+        }
+        realtime::crowding_by_trip(&realtime::fetch(
+            client,
+            realtime::VEHICLE_POSITIONS_URL,
+            &key,
+        )?)
+    } else {
+        std::collections::HashMap::new()
+    };
+
+    match query.format {
+        OutputFormat::Text => {
+            headerln!(
+                "Scheduled departures from {name}, {} from {}:",
+                slq::dates::natural_date(at.date(), now.date(), query.language),
+                format_time(sl_time(at), query)
+            );
+            for ScheduledDeparture {
+                trip_id, departure, ..
+            } in &departures
+            {
+                let trip = if query.journey_ids {
+                    format!("\t{trip_id}")
+                } else {
+                    String::new()
+                };
+                let crowding = if query.show_crowding {
+                    let indicator = crowding.get(trip_id).map_or("-", |c| c.indicator());
+                    format!("\t{indicator}")
+                } else {
+                    String::new()
+                };
+                outln!(
+                    "{}\t{}\t{}\t{}{trip}{crowding}",
+                    format_time(departure.expected, query),
+                    departure.line.designation,
+                    transport_mode_text(departure.line.transport_mode, query),
+                    departure.destination
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let rows: Vec<Value> = departures
+                .iter()
+                .map(|scheduled| {
+                    let departure = &scheduled.departure;
+                    let mut row = json!({
+                        "scheduled": timestamp(departure.expected),
+                        "line": departure.line.designation,
+                        "transport_mode": departure.line.transport_mode,
+                        "destination": departure.destination,
+                        "trip_id": scheduled.trip_id,
+                        "wheelchair_accessible": scheduled.wheelchair_accessible,
+                        "wheelchair_boarding": scheduled.wheelchair_boarding,
+                    });
+                    if query.show_crowding {
+                        row["crowding"] = json!(crowding.get(&scheduled.trip_id));
+                    }
+                    row
+                })
+                .collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            let crowding_column = if query.show_crowding { ",crowding" } else { "" };
+            headerln!(
+                "scheduled,line,transport_mode,destination,trip_id,wheelchair_accessible,wheelchair_boarding{crowding_column}"
+            );
+            for scheduled in &departures {
+                let departure = &scheduled.departure;
+                let crowding = if query.show_crowding {
+                    let label = crowding
+                        .get(&scheduled.trip_id)
+                        .map_or(String::new(), |c| c.to_string());
+                    format!(",{label}")
+                } else {
+                    String::new()
+                };
+                outln!(
+                    "{},{},{},{},{},{},{}{crowding}",
+                    timestamp(departure.expected),
+                    csv_field(&departure.line.designation),
+                    transport_mode_label(departure.line.transport_mode),
+                    csv_field(&departure.destination),
+                    csv_field(&scheduled.trip_id),
+                    csv_flag(scheduled.wheelchair_accessible),
+                    csv_flag(scheduled.wheelchair_boarding)
+                );
+            }
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+        OutputFormat::Geojson => bail!("the timetable has no coordinates to put on a map"),
+    }
+    Ok(())
+}
+
+/// Download the GTFS feed and import it as the offline timetable.
+#[cfg(feature = "gtfs")]
+fn update_timetable(client: &SlClient, config: &Config) -> Result<()> {
+    use std::io::Seek;
+
+    let Some(key) = config.trafiklab.static_key() else {
+        bail!(
+            "a Trafiklab key for \"GTFS Regional Static data\" is needed, set `static_key` under `[trafiklab]` in the config file or SLQ_TRAFIKLAB_STATIC_KEY"
+        );
+    };
+    let Some(path) = Timetable::path() else {
+        bail!("no data directory for the timetable, set SLQ_DATA_DIR or HOME");
+    };
+    if client.dry_run() {
+        notice!(
+            "Would download {} and import it into {}",
+            gtfs::FEED_URL,
+            path.display()
+        );
+        return Ok(());
+    }
+
+    let download = path.with_extension("zip.download");
+    if let Some(dir) = download.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    let result = fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&download)
+        .with_context(|| format!("failed to create {}", download.display()))
+        .and_then(|mut file| {
+            let bytes = gtfs::download(client, &key, &mut file)?;
+            notice!("Downloaded {} of GTFS data, importing", human_bytes(bytes));
+            file.rewind()?;
+            Timetable::import(&path, file)
+        });
+    let _ = fs::remove_file(&download);
+
+    let stats = result?;
+    notice!(
+        "Imported {} stops, {} routes, {} trips and {} stop times into {}",
+        stats.stops,
+        stats.routes,
+        stats.trips,
+        stats.stop_times,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Print the stops along a trip, with expected times when the realtime
+/// feed has predictions for it.
+#[cfg(feature = "gtfs")]
+fn show_journey_details(
+    client: &SlClient,
+    config: &Config,
+    trip_id: &str,
+    query: &Query,
+) -> Result<()> {
+    let timetable = Timetable::open()?;
+    let Some(trip) = timetable.trip(trip_id)? else {
+        bail!("trip {trip_id} is not in the offline timetable");
+    };
+
+    // Without a realtime key the schedule is all there is to show
+    let updates = match config.trafiklab.realtime_key() {
+        Some(_) if client.dry_run() => {
+            notice!("Would download {}", realtime::TRIP_UPDATES_URL);
+            return Ok(());
+        }
+        Some(key) => Some(realtime::fetch(client, realtime::TRIP_UPDATES_URL, &key)?),
+        None => None,
+    };
+    let update = updates
+        .as_ref()
+        .and_then(|updates| realtime::trip_update(updates, trip_id));
+
+    let clock = SystemClock;
+    let today = clock.now().date();
+    let service_day = update
+        .and_then(|update| update.service_day())
+        .unwrap_or(today);
+    let stops = timetable.trip_stops(trip_id, service_day)?;
+    let delays = match update {
+        Some(update) => update.delays(&stops),
+        None => vec![None; stops.len()],
+    };
+    let expected = |stop: &gtfs::TripStop, delay: Option<i64>| {
+        delay.map(|delay| sl_time(stop.scheduled) + Duration::seconds(delay))
+    };
+
+    match query.format {
+        OutputFormat::Text => {
+            headerln!(
+                "Line {} towards {}, {}:",
+                trip.line.designation,
+                trip.headsign,
+                slq::dates::natural_date(service_day, today, Default::default())
+            );
+            for (stop, delay) in stops.iter().zip(&delays) {
+                let expected = match (&updates, expected(stop, *delay)) {
+                    (None, _) => String::new(),
+                    (Some(_), Some(expected)) => format!("{}\t", format_time(expected, query)),
+                    (Some(_), None) => "-\t".to_string(),
+                };
+                outln!(
+                    "{}\t{expected}{}",
+                    format_time(sl_time(stop.scheduled), query),
+                    stop.name
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let rows: Vec<Value> = stops
+                .iter()
+                .zip(&delays)
+                .map(|(stop, delay)| {
+                    json!({
+                        "sequence": stop.sequence,
+                        "stop": stop.name,
+                        "scheduled": timestamp(sl_time(stop.scheduled)),
+                        "expected": expected(stop, *delay).map(timestamp),
+                        "wheelchair_boarding": stop.wheelchair_boarding,
+                    })
+                })
+                .collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            headerln!("sequence,stop,scheduled,expected,wheelchair_boarding");
+            for (stop, delay) in stops.iter().zip(&delays) {
+                outln!(
+                    "{},{},{},{},{}",
+                    stop.sequence,
+                    csv_field(&stop.name),
+                    timestamp(sl_time(stop.scheduled)),
+                    expected(stop, *delay).map(timestamp).unwrap_or_default(),
+                    csv_flag(stop.wheelchair_boarding)
+                );
+            }
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+        OutputFormat::Geojson => bail!("journey details have no coordinates to put on a map"),
+    }
+    Ok(())
+}
+
+/// Print the stops served by a line, per direction.
+#[cfg(feature = "gtfs")]
+fn show_route(line: &str, direction: Option<u8>, format: OutputFormat) -> Result<()> {
+    let routes = Timetable::open()?.routes(line, direction)?;
+    if routes.is_empty() {
+        bail!("line {line} is not in the offline timetable");
+    }
+    let direction =
+        |route: &gtfs::Route| route.direction.map_or("-".to_string(), |d| d.to_string());
+
+    match format {
+        OutputFormat::Text => {
+            for (index, route) in routes.iter().enumerate() {
+                if index > 0 {
+                    outln!();
+                }
+                outln!(
+                    "Line {} towards {} (direction {}):",
+                    route.line.designation,
+                    route.headsign,
+                    direction(route)
+                );
+                for (number, stop) in route.stops.iter().enumerate() {
+                    outln!("{}\t{}", number + 1, stop.name);
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let rows: Vec<Value> = routes
+                .iter()
+                .map(|route| {
+                    let stops: Vec<Value> = route
+                        .stops
+                        .iter()
+                        .map(|stop| json!({ "name": stop.name, "lat": stop.lat, "lon": stop.lon }))
+                        .collect();
+                    json!({
+                        "line": route.line.designation,
+                        "transport_mode": route.line.transport_mode,
+                        "direction": route.direction,
+                        "destination": route.headsign,
+                        "stops": stops,
+                    })
+                })
+                .collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            headerln!("direction,destination,sequence,stop,lat,lon");
+            for route in &routes {
+                for (number, stop) in route.stops.iter().enumerate() {
+                    outln!(
+                        "{},{},{},{},{},{}",
+                        route.direction.map_or(String::new(), |d| d.to_string()),
+                        csv_field(&route.headsign),
+                        number + 1,
+                        csv_field(&stop.name),
+                        coordinate(stop.lat),
+                        coordinate(stop.lon)
+                    );
+                }
+            }
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+        OutputFormat::Geojson => {
+            let features: Vec<Value> = routes
+                .iter()
+                .map(|route| {
+                    let coordinates: Vec<[f64; 2]> = route
+                        .stops
+                        .iter()
+                        .filter_map(|stop| Some([stop.lon?, stop.lat?]))
+                        .collect();
+                    json!({
+                        "type": "Feature",
+                        "geometry": { "type": "LineString", "coordinates": coordinates },
+                        "properties": {
+                            "line": route.line.designation,
+                            "direction": route.direction,
+                            "destination": route.headsign,
+                        },
+                    })
+                })
+                .collect();
+            let collection = json!({ "type": "FeatureCollection", "features": features });
+            print_json(&collection)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print where the vehicles on a line are, from the GTFS realtime feeds.
+#[cfg(feature = "gtfs")]
+fn show_vehicles(
+    client: &SlClient,
+    config: &Config,
+    line: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let Some(key) = config.trafiklab.realtime_key() else {
+        bail!(
+            "a Trafiklab key for \"GTFS Regional Realtime\" is needed, set `realtime_key` under `[trafiklab]` in the config file or SLQ_TRAFIKLAB_REALTIME_KEY"
+        );
+    };
+    let timetable = Timetable::open()?;
+    if client.dry_run() {
+        notice!("Would download {}", realtime::VEHICLE_POSITIONS_URL);
+        notice!("Would download {}", realtime::TRIP_UPDATES_URL);
+        return Ok(());
+    }
+
+    let positions = realtime::fetch(client, realtime::VEHICLE_POSITIONS_URL, &key)?;
+    let updates = realtime::fetch(client, realtime::TRIP_UPDATES_URL, &key)?;
+    let vehicles = realtime::vehicles_on_line(&timetable, &positions, &updates, line)?;
+    if vehicles.is_empty() && timetable.trips_on_line(line)?.is_empty() {
+        bail!("line {line} is not in the offline timetable");
+    }
+
+    match format {
+        OutputFormat::Text => {
+            if vehicles.is_empty() {
+                notice!("No vehicles on line {line} right now");
+            }
+            for vehicle in &vehicles {
+                let near = match &vehicle.nearest_stop {
+                    Some((name, distance)) => format!("{name} ({distance:.0}m)"),
+                    None => "-".to_string(),
+                };
+                outln!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    vehicle.id,
+                    vehicle.trip.headsign,
+                    near,
+                    vehicle.bearing.map_or("-", compass_point),
+                    vehicle.delay.map_or("-".to_string(), format_delay),
+                    vehicle.crowding.map_or("-", |c| c.indicator())
+                );
+            }
+        }
+        OutputFormat::Json => {
+            let rows: Vec<Value> = vehicles.iter().map(vehicle_properties).collect();
+            print_json(&rows)?;
+        }
+        OutputFormat::Csv => {
+            headerln!(
+                "vehicle,line,destination,lat,lon,bearing,nearest_stop,delay_seconds,crowding"
+            );
+            for vehicle in &vehicles {
+                outln!(
+                    "{},{},{},{:.6},{:.6},{},{},{},{}",
+                    csv_field(&vehicle.id),
+                    csv_field(&vehicle.trip.line.designation),
+                    csv_field(&vehicle.trip.headsign),
+                    vehicle.lat,
+                    vehicle.lon,
+                    vehicle.bearing.map_or(String::new(), |b| format!("{b:.0}")),
+                    csv_field(vehicle.nearest_stop.as_ref().map_or("", |(name, _)| name)),
+                    vehicle.delay.map_or(String::new(), |d| d.to_string()),
+                    vehicle.crowding.map_or(String::new(), |c| c.to_string())
+                );
+            }
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+        OutputFormat::Geojson => {
+            let features: Vec<Value> = vehicles
+                .iter()
+                .map(|vehicle| {
+                    json!({
+                        "type": "Feature",
+                        "geometry": { "type": "Point", "coordinates": [vehicle.lon, vehicle.lat] },
+                        "properties": vehicle_properties(vehicle),
+                    })
+                })
+                .collect();
+            let collection = json!({ "type": "FeatureCollection", "features": features });
+            print_json(&collection)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gtfs")]
+fn vehicle_properties(vehicle: &realtime::Vehicle) -> Value {
+    json!({
+        "vehicle": vehicle.id,
+        "trip_id": vehicle.trip_id,
+        "line": vehicle.trip.line.designation,
+        "transport_mode": vehicle.trip.line.transport_mode,
+        "destination": vehicle.trip.headsign,
+        "lat": vehicle.lat,
+        "lon": vehicle.lon,
+        "bearing": vehicle.bearing,
+        "nearest_stop": vehicle.nearest_stop.as_ref().map(|(name, _)| name),
+        "nearest_stop_meters": vehicle.nearest_stop.as_ref().map(|(_, distance)| distance.round()),
+        "delay_seconds": vehicle.delay,
+        "crowding": vehicle.crowding,
+        "timestamp": vehicle.timestamp.and_then(|at| at.format(&time::format_description::well_known::Rfc3339).ok()),
+    })
+}
+
+/// A delay as shown next to a vehicle, e.g. `+3 min` or `on time`
+#[cfg(feature = "gtfs")]
+fn format_delay(seconds: i64) -> String {
+    match seconds / 60 {
+        0 => "on time".to_string(),
+        minutes if minutes > 0 => format!("+{minutes} min"),
+        minutes => format!("{minutes} min"),
+    }
+}
+
+#[cfg(feature = "gtfs")]
+fn print_timetable_info() -> Result<()> {
+    let info = Timetable::open()?.info()?;
+    if let Some(path) = Timetable::path() {
+        outln!("Timetable:  {}", path.display());
+    }
+    if let Some(imported_at) = info.imported_at {
+        outln!(
+            "Imported:   {}",
+            natural_datetime(
+                imported_at,
+                &SystemClock,
+                Default::default(),
+                Default::default()
+            )
+        );
+    }
+    match info.validity {
+        Some((first, last)) => outln!("Covers:     {first} to {last}"),
+        None => outln!("Covers:     no service days"),
+    }
+    outln!("Stops:      {}", info.stops);
+    outln!("Trips:      {}", info.trips);
+    Ok(())
+}
+
+fn print_cache_stats(cache: &Cache) {
+    outln!("Cache directory: {}", cache.dir().display());
+    for namespace in Namespace::ALL {
+        let stats = cache.stats(namespace);
+        outln!(
+            "{:<11}{:>6} entries {:>11} {:>6} expired",
+            namespace.name(),
+            stats.entries,
+            human_bytes(stats.bytes),
+            stats.expired
+        );
+    }
+
+    let counters = cache.counters();
+    let lookups = counters.hits + counters.misses;
+    if lookups == 0 {
+        outln!("Hit rate:  no lookups yet");
+    } else {
+        outln!(
+            "Hit rate:  {:.0}% ({} hits, {} misses)",
+            counters.hits as f64 * 100.0 / lookups as f64,
+            counters.hits,
+            counters.misses
+        );
+    }
+}
+
+/// Failures with their own exit code
+#[derive(Debug, Error)]
+enum CliError {
+    #[error("Station '{0}' not found.")]
+    StationNotFound(String),
+    #[error("Site '{0}' not found.")]
+    SiteNotFound(SiteId),
+    #[error("No departures matched the filters.")]
+    NoDepartures,
+    #[error("Journey {journey} is not on the departure board at {station}.")]
+    JourneyNotFound { journey: u64, station: String },
+}
+
+/// Exit codes scripts can rely on
+mod exit_code {
+    pub const SUCCESS: u8 = 0;
+    pub const FAILURE: u8 = 1;
+    pub const NOT_FOUND: u8 = 2;
+    pub const NO_DEPARTURES: u8 = 3;
+    pub const NETWORK: u8 = 4;
+}
+
+/// The config file, with the notification backend replaced when
+/// `--notify-url` is given
+fn notify_config(args: &Args) -> Result<Config> {
+    let mut config = Config::load()?;
+    if let Some(url) = &args.notify_url {
+        config.notify = NotifyConfig::from_url(url);
+    }
+    Ok(config)
+}
+
+/// How much to log for the number of times `-v` was given
+fn log_level(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+fn exit_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<CliError>() {
+            return match err {
+                CliError::StationNotFound(_)
+                | CliError::SiteNotFound(_)
+                | CliError::JourneyNotFound { .. } => exit_code::NOT_FOUND,
+                CliError::NoDepartures => exit_code::NO_DEPARTURES,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<ClientError>() {
+            return match err {
+                ClientError::NotFound(_) => exit_code::NOT_FOUND,
+                ClientError::Network(_)
+                | ClientError::Unavailable { .. }
+                | ClientError::RateLimited => exit_code::NETWORK,
+                ClientError::Status { .. }
+                | ClientError::Decode(_)
+                | ClientError::Setup(_)
+                | ClientError::Fixture { .. } => exit_code::FAILURE,
+                ClientError::DryRun => exit_code::SUCCESS,
+            };
+        }
+    }
+    exit_code::FAILURE
+}
+
+fn main() -> ExitCode {
+    let result = run();
+    finish_paging();
+    let finished = finish_output(result.is_ok());
+    let result = result.and(finished);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // A dry run stops at the first request, after printing it
+        Err(err) if exit_code(&err) == exit_code::SUCCESS => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let args = Args::parse();
+    // Only slq's own events, the HTTP stack's are too noisy to be useful here
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(io::stderr)
+                .with_ansi(io::stderr().is_terminal())
+                .with_target(false)
+                .without_time(),
+        )
+        .with(Targets::new().with_target("slq", log_level(args.verbose)))
+        .init();
+    if let Some(path) = &args.output {
+        if matches!(args.command, Commands::Mcp) {
+            bail!("mcp talks to its client over stdout, it can't write to --output");
+        }
+        if !args.append && args.command.keeps_running() {
+            bail!("this command keeps printing until it is stopped, use --append with --output");
+        }
+        init_output(path, args.append)?;
+    }
+    init_ascii(args.ascii);
+    init_headers(!(args.no_header || args.porcelain));
+    init_quiet(args.quiet);
+    if !args.no_pager && !args.no_exec && args.command.pageable() {
+        start_paging();
+    }
+    init_sandbox(Sandbox {
+        no_net_write: args.no_net_write,
+        no_exec: args.no_exec,
+    });
+    let ca_certificates =
+        match &args.cacert {
+            Some(path) => Some(fs::read(path).with_context(|| {
+                format!("failed to read CA certificates from {}", path.display())
+            })?),
+            None => None,
+        };
+    let client = SlClient::new(ClientSettings {
+        api_base: args.api_base_url.clone(),
+        retry: RetryPolicy {
+            retries: args.retries,
+            delay: std::time::Duration::from_millis(args.retry_delay),
+        },
+        rate_limit: RateLimit {
+            per_minute: args.rate_limit,
+            burst: args.rate_burst,
+        },
+        http: HttpOptions {
+            proxy: args.proxy.clone(),
+            ca_certificates,
+        },
+        dry_run: args.dry_run,
+        fixtures: args
+            .record_fixtures
+            .clone()
+            .map(Fixtures::Record)
+            .or_else(|| args.replay_fixtures.clone().map(Fixtures::Replay)),
+    })?;
+    let language = match args.lang {
+        Some(language) => language,
+        None if args.porcelain => Language::English,
+        None => detect_language(),
+    };
+    let query = Query {
+        language,
+        time_format: args.time_format.clone(),
+        timezone: args.timezone,
+        theme: if !args.porcelain && color_enabled(args.color) {
+            Some(Config::load()?.theme)
+        } else {
+            None
+        },
+        icons: args.icons,
+        width: args.width.map(usize::from).or_else(terminal_columns),
+        json_query: args.query.is_some(),
+        ..args.command.query()
+    };
+    query.validate()?;
+    init_json_path(args.query.clone());
+
+    match &args.command {
+        Commands::Search {
+            station_name,
+            long,
+            sort,
+            area,
+            ..
+        } => {
+            let mut sites = match area {
+                Some(area) => {
+                    let found = client.search_for_sites_in(station_name, area)?;
+                    if found.sites.is_empty() && !found.areas.is_empty() {
+                        notice!(
+                            "No stops like '{station_name}' in {area}, the journey planner knows \
+                             some in {}",
+                            found.areas.join(", ")
+                        );
+                    }
+                    if found.unplaced > 0 {
+                        notice!(
+                            "{} more stops like '{station_name}' were left out, the journey \
+                             planner didn't say which municipality they are in",
+                            found.unplaced
+                        );
+                    }
+                    found.sites
+                }
+                None => client.search_for_sites(station_name)?,
+            };
+            if *sort == Some(SearchSort::Name) {
+                sites.sort_by_cached_key(|site| site.name.to_lowercase());
+            }
+            let Some((lat, lon)) = query.position else {
+                sites.truncate(query.result_limit());
+                return print_sites(&sites, query.format, *long);
+            };
+            let mut ranked = sites_with_distance(&sites, lat, lon);
+            if sort.unwrap_or(SearchSort::Distance) == SearchSort::Distance {
+                ranked.sort_by(|a, b| a.distance_meters.total_cmp(&b.distance_meters));
+            }
+            ranked.truncate(query.result_limit());
+            if query.format == OutputFormat::Text {
+                print_closest_header(*long, query.language);
+            }
+            if query.format == OutputFormat::Text && *long {
+                ranked.iter().for_each(|ranked_site| {
+                    let kind = site_type(&ranked_site.site);
+                    outln!("{}", closest_site_row_with_type(ranked_site, &kind));
+                });
+            } else {
+                print_ranked_sites(&ranked, query.format)?;
+            }
+        }
+        Commands::Departures {
+            station_name,
+            watch,
+            fail_if_empty,
+            ..
+        } => {
+            let config = Config::load()?;
+            let shown = match station_name.as_deref() {
+                _ if stations_from_stdin(station_name) => {
+                    show_departures_from_stdin(&client, &config, &query)?
+                }
+                Some(station_name) if query.raw => {
+                    return dump_departures(&client, &config, station_name);
+                }
+                Some(station_name) if *watch => {
+                    return watch_departures(&client, &config, station_name, &query);
+                }
+                Some(station_name) => show_departures(&client, &config, station_name, &query)?,
+                None => unreachable!("clap asks for a station without --stdin"),
+            };
+            if *fail_if_empty && shown == 0 {
+                return Err(CliError::NoDepartures.into());
+            }
+        }
+        Commands::Track {
+            journey, station, ..
+        } => {
+            track_journey(&client, &Config::load()?, *journey, station, &query)?;
+        }
+        Commands::Run {
+            name,
+            fail_if_empty,
+        } => {
+            let config = Config::load()?;
+            let Some(saved) = config.queries.get(name) else {
+                bail!("no saved query named '{name}' in the config file");
+            };
+            let saved_query = saved.query();
+            let query = Query {
+                limit: saved_query.limit,
+                line: saved_query.line,
+                destination: saved_query.destination,
+                transport_mode: saved_query.transport_mode,
+                ..query
+            };
+            query.validate()?;
+            let shown = show_departures(&client, &config, &saved.station, &query)?;
+            if *fail_if_empty && shown == 0 {
+                return Err(CliError::NoDepartures.into());
+            }
+        }
+        Commands::CheckConfig => {
+            let findings = check_config(&client, &Config::load()?);
+            if findings.is_empty() {
+                notice!(
+                    "Nothing to check, the config file has no aliases, queries or walking times."
+                );
+            }
+            let errors = print_findings(&findings);
+            if errors > 0 {
+                bail!("{errors} problem(s) found in the config file");
+            }
+        }
+        Commands::SelfUpdate { check_only } => self_update(&client, *check_only)?,
+        Commands::Doctor => {
+            let errors = print_findings(&doctor(&client));
+            if errors > 0 {
+                bail!("{errors} problem(s) found");
+            }
+        }
+        Commands::Cache { command } => {
+            let Some(cache) = Cache::open() else {
+                bail!("no cache directory, set SLQ_CACHE_DIR or HOME");
+            };
+            match command {
+                CacheCommand::Stats => print_cache_stats(&cache),
+                CacheCommand::Clear => {
+                    let freed = cache.clear()?;
+                    notice!("Removed all cached responses, freed {}", human_bytes(freed));
+                }
+                CacheCommand::Gc => {
+                    let (removed, freed) = cache.gc()?;
+                    notice!(
+                        "Removed {removed} expired response(s), freed {}",
+                        human_bytes(freed)
+                    );
+                }
+            }
+        }
+        Commands::Q { question, .. } => {
+            let question: Question = question.join(" ").parse().map_err(anyhow::Error::msg)?;
+            notice!("= {}", question.command());
+            let query = Query {
+                limit: question.limit,
+                line: question.line,
+                transport_mode: question.transport_mode,
+                destination: question.destination,
+                ..query
+            };
+            query.validate()?;
+            show_departures(&client, &Config::load()?, &question.station, &query)?;
+        }
+        Commands::Best {
+            to,
+            from_stops,
+            within,
+            ..
+        } => {
+            let config = Config::load()?;
+            let Some(to) = config.resolve_station(&client, to)? else {
+                return Err(CliError::StationNotFound(to.clone()).into());
+            };
+            let stops = match query.position {
+                Some((lat, lon)) => stops_near(&client, lat, lon, *within)?,
+                None => from_stops
+                    .iter()
+                    .map(|name| {
+                        let Some(station) = config.resolve_station(&client, name)? else {
+                            return Err(CliError::StationNotFound(name.clone()).into());
+                        };
+                        Ok(CandidateStop {
+                            site_id: station.id,
+                            walk_minutes: config.walk_minutes_for(name, &station).unwrap_or(0),
+                            name: station.name,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            };
+            show_best_stop(&client, &to, &stops, &query)?;
+        }
+        Commands::Closest { address, .. } => {
+            let (lat, lon) = match (address, query.position) {
+                (Some(address), _) => {
+                    let place = client.geocode(&Config::load()?.geocoder.url()?, address)?;
+                    notice!("Closest to {}", place.name);
+                    (place.lat, place.lon)
+                }
+                (None, Some(position)) => position,
+                (None, None) => bail!("give a latitude and longitude, or --address"),
+            };
+            let limit = query.result_limit();
+            let ranked_sites = rank_sites_by_distance(&client.get_sites()?, lat, lon);
+            if ranked_sites.is_empty() {
+                bail!("No stations with coordinates found.");
+            }
+
+            if let Some(mode) = query.transport_mode {
+                let mut filtered_results: Vec<RankedSite> = Vec::new();
+                for ranked_site in ranked_sites.iter().take(MAX_MODE_FILTER_PROBES) {
+                    if filtered_results.len() >= limit {
+                        break;
+                    }
+
+                    if client.site_has_transport_mode(ranked_site.site.id, mode)? {
+                        filtered_results.push(ranked_site.clone());
+                    }
+                }
+                if query.format == OutputFormat::Text {
+                    print_closest_header(false, query.language);
+                }
+                print_ranked_sites(&filtered_results, query.format)?;
+            } else if query.format == OutputFormat::Text {
+                print_closest_header(true, query.language);
+                ranked_sites
+                    .iter()
+                    .take(limit)
+                    .for_each(|ranked_site| print_closest_site_with_type(&client, ranked_site));
+            } else {
+                let nearest: Vec<RankedSite> = ranked_sites.into_iter().take(limit).collect();
+                print_ranked_sites(&nearest, query.format)?;
+            }
+        }
+        Commands::Site { id } => {
+            let Some(site) = client.get_site(*id)? else {
+                return Err(CliError::SiteNotFound(*id).into());
+            };
+            let details = client.get_site_details(site.id).ok();
+            print_site_details(&site, details.as_ref(), query.language);
+        }
+        Commands::Sites { bbox, .. } => {
+            let mut sites = client.get_sites()?;
+            if let Some(area) = bbox {
+                sites.retain(|site| match (site.lat, site.lon) {
+                    (Some(lat), Some(lon)) => area.contains(lat, lon),
+                    _ => false,
+                });
+            }
+            sites.sort_by_key(|site| site.id);
+            sites.truncate(query.result_limit());
+            print_sites(&sites, query.format, true)?;
+        }
+        Commands::Completions { shell } => {
+            let mut script = Vec::new();
+            clap_complete::generate(*shell, &mut Args::command(), "slq", &mut script);
+            if let Some(extra) = station_completion_script(*shell) {
+                script.extend_from_slice(extra.as_bytes());
+            }
+            write_output(|out| out.write_all(&script));
+        }
+        Commands::Man => {
+            let mut page = Vec::new();
+            clap_mangen::Man::new(Args::command()).render(&mut page)?;
+            write_output(|out| out.write_all(&page));
+        }
+        Commands::CompleteStation { prefix } => {
+            let config = Config::load().unwrap_or_default();
+            config
+                .aliases
+                .keys()
+                .filter(|alias| alias.starts_with(prefix.as_str()))
+                .for_each(|alias| outln!("{alias}"));
+            client
+                .station_names_with_prefix(prefix)?
+                .iter()
+                .for_each(|name| outln!("{name}"));
+        }
+        Commands::Wait { station_name, .. } => {
+            wait_for_departure(&client, &Config::load()?, station_name, &query)?;
+        }
+        Commands::Status { .. } => show_status(&client, &query)?,
+        Commands::Deviations {
+            lines,
+            watch,
+            notify,
+            ..
+        } => {
+            let config = notify_config(&args)?;
+            let lines = if lines.is_empty() {
+                &config.deviations.lines
+            } else {
+                lines
+            };
+            if *watch || *notify {
+                watch_deviations(&client, &config, lines, *watch, *notify, &query)?;
+            } else {
+                show_deviations(&client, lines, &query)?;
+            }
+        }
+        Commands::Mcp => mcp::serve_stdio(&client, &Config::load()?)?,
+        Commands::Mqtt {
+            broker,
+            sites,
+            departures_topic,
+            deviations_topic,
+            ..
+        } => {
+            let config = Config::load()?;
+            let settings = MqttSettings::new(
+                &config.mqtt,
+                broker.as_deref(),
+                departures_topic.as_deref(),
+                deviations_topic.as_deref(),
+                std::time::Duration::from_secs(query.poll_seconds.unwrap_or(MIN_POLL_SECONDS)),
+            )?;
+            let sites = resolve_stations(&client, &config, sites)?;
+            mqtt::publish(&client, &settings, &sites, &query)?;
+        }
+        Commands::Serve { port, bind, .. } => serve(
+            &client,
+            SocketAddr::new(*bind, *port),
+            query.heartbeat.map(Duration::unsigned_abs),
+        )?,
+        Commands::Planned { station_name, .. } => {
+            show_planned(&client, &Config::load()?, station_name, &query)?;
+        }
+        #[cfg(feature = "gtfs")]
+        Commands::Timetable {
+            station_name, at, ..
+        } => {
+            show_timetable(&client, &Config::load()?, station_name, *at, &query)?;
+        }
+        #[cfg(feature = "gtfs")]
+        Commands::JourneyDetails { trip_id, .. } => {
+            show_journey_details(&client, &Config::load()?, trip_id, &query)?;
+        }
+        #[cfg(feature = "gtfs")]
+        Commands::Route {
+            line, direction, ..
+        } => {
+            show_route(line, *direction, query.format)?;
+        }
+        #[cfg(feature = "gtfs")]
+        Commands::Vehicles { line, .. } => {
+            show_vehicles(&client, &Config::load()?, line, query.format)?;
+        }
+        #[cfg(feature = "gtfs")]
+        Commands::Gtfs { command } => match command {
+            GtfsCommand::Update => update_timetable(&client, &Config::load()?)?,
+            GtfsCommand::Info => print_timetable_info()?,
+        },
+        Commands::Expect {
+            station_name, from, ..
+        } => {
+            expect_service(
+                &client,
+                &notify_config(&args)?,
+                station_name,
+                from.as_deref(),
+                &query,
+            )?;
+        }
+        Commands::Alarm {
+            station_name,
+            repeat,
+            ..
+        } => {
+            sound_alarm(
+                &client,
+                &notify_config(&args)?,
+                station_name,
+                *repeat,
+                &query,
+            )?;
+        }
+        #[cfg(feature = "history")]
+        Commands::Stats { station, since, .. } => {
+            let station = resolve_optional_station(&client, station.as_deref())?;
+            show_stats(station.as_ref(), *since, &query)?;
+        }
+        #[cfg(feature = "history")]
+        Commands::History {
+            command:
+                HistoryCommand::Export {
+                    format,
+                    line,
+                    station,
+                    since,
+                },
+        } => {
+            let station = resolve_optional_station(&client, station.as_deref())?;
+            export_history(*format, line.as_deref(), station.as_ref(), *since)?;
+        }
+        #[cfg(feature = "history")]
+        Commands::Record { stations, .. } => {
+            let config = Config::load()?;
+            let stations = resolve_stations(&client, &config, stations)?;
+            record_departures(&client, &stations, &query)?;
+        }
+        Commands::Daemon { install_service } => {
+            if *install_service {
+                daemon::install_service()?;
+            } else {
+                run_daemon(&client, &notify_config(&args)?)?;
+            }
+        }
+        Commands::Notify { message, title } => {
+            let config = notify_config(&args)?;
+            notifier(&config.notify, &client).notify(title, message)?;
+        }
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use slq::clock::FixedClock;
+    use time::macros::datetime;
+
+    #[test]
+    fn waybar_module_shows_the_next_departure() {
+        let departures: Vec<Departure> = serde_json::from_str(
+            r#"[
+                { "destination": "Ruddammen", "expected": "2025-11-02T11:04:00",
+                  "line": { "designation": "43", "transport_mode": "BUS" } },
+                { "destination": "R&D park", "expected": "2025-11-02T11:10:00",
+                  "line": { "designation": "43", "transport_mode": "BUS" } }
+            ]"#,
+        )
+        .unwrap();
+        let clock = FixedClock(datetime!(2025-11-02 11:00:00 +1));
+
+        let module = waybar_module("Slussen", &departures, false, &clock, Language::English);
+        assert_eq!("43 Ruddammen 4m", module["text"]);
+        assert_eq!(
+            "Slussen\n43 Ruddammen 4m\n43 R&amp;D park 10m",
+            module["tooltip"]
+        );
+        assert_eq!("departures", module["class"]);
+
+        assert_eq!(
+            "stale",
+            waybar_module("Slussen", &departures, true, &clock, Language::English)["class"]
+        );
+        assert_eq!(
+            "empty",
+            waybar_module("Slussen", &[], false, &clock, Language::English)["class"]
+        );
+    }
+
+    #[test]
+    fn statusbar_line_is_cut_short_and_colored_when_soon() {
+        let departures: Vec<Departure> = serde_json::from_str(
+            r#"[
+                { "destination": "Ruddammen", "expected": "2025-11-02T11:04:00",
+                  "line": { "designation": "43", "transport_mode": "BUS" } },
+                { "destination": "Hammarby sjöstad", "expected": "2025-11-02T11:10:00",
+                  "line": { "designation": "43", "transport_mode": "BUS" } }
+            ]"#,
+        )
+        .unwrap();
+        let clock = FixedClock(datetime!(2025-11-02 11:00:00 +1));
+        let config = StatusbarConfig::default();
+
+        assert_eq!(
+            "43→Ruddammen 4m",
+            statusbar_line(&departures[..1], None, &config, &clock, &Query::default())
+        );
+        assert_eq!(
+            "43→Ruddammen 4m  43→Ham…",
+            statusbar_line(&departures, Some(24), &config, &clock, &Query::default())
+        );
+
+        let config = StatusbarConfig {
+            soon_minutes: 5,
+            soon_start: "%{F#f00}".to_string(),
+            soon_end: "%{F-}".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            "%{F#f00}43→Ruddammen 4m%{F-}",
+            statusbar_line(&departures[..1], None, &config, &clock, &Query::default())
+        );
+        assert_eq!(
+            "43→Hammarby sjöstad 10m",
+            statusbar_line(&departures[1..], None, &config, &clock, &Query::default())
+        );
+
+        let columns = Query {
+            columns: Some(vec![Column::Line, Column::Wait]),
+            ..Query::default()
+        };
+        assert_eq!(
+            "43 10m",
+            statusbar_line(&departures[1..], None, &config, &clock, &columns)
+        );
+    }
+
+    /// The departures in `tests/fixtures`, and a clock frozen just before the first one
+    fn fixture_board() -> (Vec<Departure>, FixedClock) {
+        let response: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/departures-9600.json")).unwrap();
+        let departures = serde_json::from_value(response["departures"].clone()).unwrap();
+        (departures, FixedClock(datetime!(2025-05-16 08:00:00 +2)))
+    }
+
+    #[test]
+    fn text_board_snapshot() {
+        let (departures, clock) = fixture_board();
+        let render = |query: &Query, clock: &dyn Clock| {
+            departures
+                .iter()
+                .map(|departure| departure_row(departure, query, clock))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        insta::assert_snapshot!("text_board", render(&Query::default(), &clock));
+        let journey_ids = Query {
+            journey_ids: true,
+            ..Query::default()
+        };
+        insta::assert_snapshot!("text_board_with_journey_ids", render(&journey_ids, &clock));
+        // Late enough for the first two to have left
+        let later = FixedClock(datetime!(2025-05-16 08:05:40 +2));
+        let swedish = Query {
+            language: Language::Swedish,
+            ..Query::default()
+        };
+        insta::assert_snapshot!("text_board_swedish", render(&swedish, &later));
+        let twelve_hour = Query {
+            time_format: TimeFormat::TwelveHour,
+            ..Query::default()
+        };
+        insta::assert_snapshot!("text_board_12h", render(&twelve_hour, &clock));
+        let api_display = Query {
+            use_api_display: true,
+            ..Query::default()
+        };
+        insta::assert_snapshot!("text_board_api_display", render(&api_display, &clock));
+        let icons = Query {
+            icons: true,
+            ..Query::default()
+        };
+        insta::assert_snapshot!("text_board_icons", render(&icons, &clock));
+        let columns = Query {
+            columns: Some(vec![Column::Wait, Column::Line, Column::Destination]),
+            ..Query::default()
+        };
+        insta::assert_snapshot!("text_board_columns", render(&columns, &clock));
+    }
+
+    #[test]
+    fn html_and_markdown_board_snapshots() {
+        let (departures, clock) = fixture_board();
+        let query = Query::default();
+        insta::assert_snapshot!(
+            "html_board",
+            departures_html(
+                &departures[..3],
+                Some("Departures from Stockholm Östra <S>"),
+                &query,
+                &clock
+            )
+        );
+        let columns = Query {
+            columns: Some(vec![Column::Line, Column::Destination, Column::Wait]),
+            language: Language::Swedish,
+            ..Query::default()
+        };
+        insta::assert_snapshot!(
+            "markdown_board",
+            departures_markdown(
+                &departures[..3],
+                Some("Avgångar från T-Centralen"),
+                &columns,
+                &clock
+            )
+        );
+        assert!(!departures_html(&departures, None, &query, &clock).contains("caption"));
+    }
+
+    #[test]
+    fn colored_rows_paint_the_wait_and_the_line() {
+        let (departures, clock) = fixture_board();
+        let colored = Query {
+            theme: Some(Theme::default()),
+            ..Query::default()
+        };
+        assert_eq!(
+            "\x1b[33m3m\x1b[0m\t08:03\t\x1b[35m27\x1b[0m\tTrain\tKårsta",
+            departure_row(&departures[0], &colored, &clock)
+        );
+        // Three minutes is no time at all with a five minute walk
+        let walking = Query {
+            walk_minutes: Some(5),
+            ..colored
+        };
+        assert!(departure_row(&departures[0], &walking, &clock).starts_with("\x1b[31m3m"));
+    }
+
+    #[test]
+    fn json_board_snapshot() {
+        let (departures, _) = fixture_board();
+        insta::assert_snapshot!(serde_json::to_string_pretty(&departures).unwrap());
+    }
+
+    #[test]
+    fn csv_board_snapshot() {
+        let (departures, _) = fixture_board();
+        insta::assert_snapshot!(departures_csv(&departures, true));
+    }
+
+    #[test]
+    fn statusbar_snapshot() {
+        let (departures, clock) = fixture_board();
+        let config = StatusbarConfig {
+            soon_minutes: 5,
+            soon_start: "<span color='red'>".to_string(),
+            soon_end: "</span>".to_string(),
+            ..Default::default()
+        };
+        let lines = [
+            statusbar_line(
+                &departures[..1],
+                None,
+                &StatusbarConfig::default(),
+                &clock,
+                &Query::default(),
+            ),
+            statusbar_line(&departures[..3], None, &config, &clock, &Query::default()),
+            statusbar_line(&departures, Some(60), &config, &clock, &Query::default()),
+        ];
+        insta::assert_snapshot!(lines.join("\n"));
+    }
+
+    #[test]
+    fn waybar_and_hass_snapshots() {
+        let (departures, clock) = fixture_board();
+        let module = waybar_module(
+            "Stockholms östra",
+            &departures[..3],
+            false,
+            &clock,
+            Language::English,
+        );
+        insta::assert_snapshot!("waybar", module.to_string());
+        let sensor = hass_sensor("Stockholms östra", &departures[..3], false, &clock);
+        insta::assert_snapshot!("hass", serde_json::to_string_pretty(&sensor).unwrap());
+    }
+
+    #[test]
+    fn nearby_table_snapshot() {
+        let sites = [
+            site(9600, "Stockholms östra", 59.3455, 18.0717),
+            site(9204, "Tekniska högskolan", 59.3458, 18.0713),
+            site(9203, "Stadion", 59.3425, 18.0818),
+        ];
+        let mut table = vec![closest_header(false, Language::English)];
+        table.extend(
+            rank_sites_by_distance(&sites, 59.3456, 18.0716)
+                .iter()
+                .map(closest_site_row),
+        );
+        insta::assert_snapshot!(table.join("\n"));
+    }
+
+    fn site(id: u32, name: &str, lat: f64, lon: f64) -> Site {
+        Site {
+            id: id.into(),
+            name: name.to_string(),
+            lat: Some(lat),
+            lon: Some(lon),
+            abbreviation: None,
+            note: None,
+            stop_areas: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn wait_time_counts_down_to_expected_time() {
+        let clock = FixedClock(datetime!(2025-11-02 11:00:00 +1));
+        assert_eq!("45s", wait_time(datetime!(2025-11-02 11:00:45 +1), &clock));
+        assert_eq!("14m", wait_time(datetime!(2025-11-02 11:14:02 +1), &clock));
+        assert_eq!(
+            "2h 5m",
+            wait_time(datetime!(2025-11-02 13:05:00 +1), &clock)
+        );
+    }
+
+    #[test]
+    fn wait_time_handles_past_times() {
+        let clock = FixedClock(datetime!(2025-11-02 11:00:00 +1));
+        assert_eq!("now", wait_time(datetime!(2025-11-02 11:00:30 +1), &clock));
+        assert_eq!("now", wait_time(datetime!(2025-11-02 10:59:30 +1), &clock));
+        assert_eq!(
+            "departing",
+            wait_time(datetime!(2025-11-02 10:59:00 +1), &clock)
+        );
+        assert_eq!(
+            "departing",
+            wait_time(datetime!(2025-11-02 10:58:00 +1), &clock)
+        );
+        assert_eq!(
+            "departed",
+            wait_time(datetime!(2025-11-02 10:57:59 +1), &clock)
+        );
+    }
+
+    #[test]
+    fn leaves_after_respects_walking_time() {
+        let clock = FixedClock(datetime!(2025-11-02 11:00:00 +1));
+        let leaving_at = |expected| -> Departure {
+            serde_json::from_value(json!({
+                "destination": "Fruängen",
+                "expected": expected,
+                "line": {"designation": "43", "transport_mode": "BUS"},
+            }))
+            .unwrap()
+        };
+        assert!(leaves_after(&leaving_at("2025-11-02T11:10:00"), 5, &clock));
+        assert!(!leaves_after(&leaving_at("2025-11-02T11:03:00"), 5, &clock));
+    }
+
+    #[test]
+    fn site_lines_are_grouped() {
+        let line = |designation: &str, mode, group: &str| Line {
+            designation: designation.to_string(),
+            transport_mode: mode,
+            id: None,
+            group_of_lines: Some(group.to_string()),
+            transport_authority_id: None,
+        };
+        let lines = [
+            line("13", TransportMode::Metro, "Tunnelbanans röda linje"),
+            line("17", TransportMode::Metro, "Tunnelbanans gröna linje"),
+            line("14", TransportMode::Metro, "Tunnelbanans röda linje"),
+            line("43", TransportMode::Bus, "Innerstadsbussar"),
+            line("55", TransportMode::Bus, "Innerstadsbussar"),
+        ];
+        assert_eq!(
+            "13, 14 (Red line); 17 (Green line)",
+            line_groups(&lines, TransportMode::Metro, Language::English)
+        );
+        assert_eq!(
+            "43, 55",
+            line_groups(&lines, TransportMode::Bus, Language::English)
+        );
+    }
+
+    #[test]
+    fn repeated_verbose_flags_log_more() {
+        assert_eq!(LevelFilter::WARN, log_level(0));
+        assert_eq!(LevelFilter::DEBUG, log_level(1));
+        assert_eq!(LevelFilter::TRACE, log_level(2));
+        assert_eq!(LevelFilter::TRACE, log_level(5));
+    }
+
+    #[test]
+    fn durations_accept_common_units() {
+        assert_eq!(Ok(Duration::minutes(5)), parse_duration("5m"));
+        assert_eq!(Ok(Duration::minutes(7)), parse_duration("7"));
+        assert_eq!(Ok(Duration::seconds(90)), parse_duration("90s"));
+        assert_eq!(Ok(Duration::hours(1)), parse_duration("1h"));
+        assert_eq!(Ok(Duration::days(30)), parse_duration("30d"));
+        assert!(parse_duration("5 parsecs").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("999999999999999d").is_err());
+    }
+
+    #[test]
+    fn human_durations_use_the_two_largest_units() {
+        assert_eq!("0s", human(Duration::ZERO));
+        assert_eq!("45s", human(Duration::seconds(45)));
+        assert_eq!("14m", human(Duration::seconds(14 * 60 + 2)));
+        assert_eq!("2h 5m", human(Duration::minutes(125)));
+        assert_eq!("23h 59m", human(Duration::seconds(86_399)));
+        assert_eq!("1d 0h", human(Duration::days(1)));
+        assert_eq!("30d 0h", human(Duration::days(30)));
+        assert_eq!("1d 3h", human(Duration::hours(27) + Duration::minutes(59)));
+        assert_eq!("-1m", human(Duration::seconds(-90)));
+        assert_eq!("-1d 3h", human(-Duration::hours(27)));
+        assert_eq!("0s", human(Duration::milliseconds(-500)));
+    }
+
+    /// The units of a [`human`] duration added back up, e.g. `1d 3h` is 27 hours
+    fn parse_human(text: &str) -> Duration {
+        let (sign, text) = match text.strip_prefix('-') {
+            Some(text) => (-1, text),
+            None => (1, text),
+        };
+        sign * text
+            .split(' ')
+            .map(|part| parse_duration(part).unwrap())
+            .sum::<Duration>()
+    }
+
+    proptest! {
+        #[test]
+        fn human_durations_are_accurate_to_their_smallest_unit(seconds in -10_000_000i64..10_000_000) {
+            let d = Duration::seconds(seconds);
+            let text = human(d);
+            let shown = parse_human(&text);
+            let unit = match text.rsplit(' ').next().unwrap().chars().last().unwrap() {
+                's' => Duration::SECOND,
+                'm' => Duration::MINUTE,
+                'h' => Duration::HOUR,
+                _ => Duration::DAY,
+            };
+            // Cut off towards zero, never by a whole unit or more
+            prop_assert!(shown.abs() <= d.abs() && d.abs() - shown.abs() < unit, "{text} for {d}");
+            prop_assert_eq!(seconds < 0, text.starts_with('-'));
+        }
+
+        #[test]
+        fn wait_time_counts_down_from_any_expected_time(seconds in -1_000_000i64..10_000_000) {
+            let clock = FixedClock(datetime!(2025-11-02 11:00:00 +1));
+            let wait = wait_time(clock.now() + Duration::seconds(seconds), &clock);
+            match seconds {
+                -30..=30 => prop_assert_eq!("now", wait),
+                -120..=-31 => prop_assert_eq!("departing", wait),
+                ..=-121 => prop_assert_eq!("departed", wait),
+                _ => prop_assert_eq!(human(Duration::seconds(seconds)), wait),
+            }
+        }
+
+        #[test]
+        fn durations_parse_with_every_unit(number in 0i64..1_000_000, unit in "s|m|min|h|d") {
+            let seconds = match unit.as_str() {
+                "s" => 1,
+                "m" | "min" => 60,
+                "h" => 3600,
+                _ => 86_400,
+            };
+            prop_assert_eq!(Ok(Duration::seconds(number * seconds)), parse_duration(&format!("{number}{unit}")));
+        }
+
+        #[test]
+        fn parsing_durations_never_panics(value in "\\PC*") {
+            let _ = parse_duration(&value);
+        }
+    }
+
+    #[test]
+    fn services_heading_back_to_the_origin_are_skipped() {
+        assert!(heads_away_from("Märsta", "Södertälje"));
+        assert!(!heads_away_from("Södertälje centrum", "södertälje"));
+        assert!(!heads_away_from("Södertälje centrum", "Sodertalje"));
+    }
+
+    #[test]
+    fn exit_codes_follow_the_documented_contract() {
+        let not_found = anyhow::Error::from(CliError::StationNotFound("x".to_string()));
+        assert_eq!(2, exit_code(&not_found));
+
+        let no_departures = anyhow::Error::from(CliError::NoDepartures);
+        assert_eq!(3, exit_code(&no_departures));
+
+        let unavailable = anyhow::Error::from(ClientError::Unavailable { status: 503 });
+        assert_eq!(4, exit_code(&unavailable.context("fetching departures")));
+
+        assert_eq!(1, exit_code(&anyhow::anyhow!("something else")));
+
+        let dry_run = anyhow::Error::from(ClientError::DryRun);
+        assert_eq!(0, exit_code(&dry_run.context("fetching departures")));
     }
 
+    #[cfg(feature = "gtfs")]
     #[test]
-    fn haversine_is_zero_for_identical_points() {
-        let distance = haversine_meters(59.331, 18.06, 59.331, 18.06);
-        assert!(distance < 0.001);
+    fn delays_are_shown_in_whole_minutes() {
+        assert_eq!("on time", format_delay(45));
+        assert_eq!("on time", format_delay(-30));
+        assert_eq!("+3 min", format_delay(200));
+        assert_eq!("-2 min", format_delay(-125));
     }
 
     #[test]
@@ -337,8 +4920,8 @@ mod tests {
 
         let ranked = rank_sites_by_distance(&sites, 59.331, 18.060);
 
-        assert_eq!(ranked[0].site.id, 1);
-        assert_eq!(ranked[1].site.id, 2);
-        assert_eq!(ranked[2].site.id, 10);
+        assert_eq!(ranked[0].site.id.get(), 1);
+        assert_eq!(ranked[1].site.id.get(), 2);
+        assert_eq!(ranked[2].site.id.get(), 10);
     }
 }
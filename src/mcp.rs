@@ -0,0 +1,356 @@
+//! `slq mcp`: a Model Context Protocol server on stdin and stdout, so AI
+//! assistants can look up stations, departures and journeys through slq.
+//!
+//! Messages are JSON-RPC 2.0, one per line. Only tools are offered.
+
+use std::io::{self, BufRead, Write};
+
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
+use serde_json::{Value, json};
+use tracing::debug;
+
+use crate::client::{DepartureFilter, SlClient};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{Config, ResolvedStation};
+use crate::dates::timestamp;
+use crate::query::Query;
+use crate::types::TransportMode;
+use crate::update::CURRENT_VERSION;
+
+/// Protocol versions slq speaks, newest first. They only differ in parts
+/// slq doesn't use.
+const PROTOCOL_VERSIONS: [&str; 3] = ["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// Departures and journeys returned unless the caller asks for another number
+const DEFAULT_DEPARTURES: usize = 10;
+const DEFAULT_JOURNEYS: usize = 3;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Answer requests from stdin until it is closed. Every response is
+/// flushed on its own, since the client waits for it before sending more.
+pub fn serve_stdio(client: &SlClient, config: &Config) -> Result<()> {
+    let stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    for line in stdin.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(message) => handle(client, config, &message),
+            Err(err) => Some(error(Value::Null, PARSE_ERROR, err.to_string())),
+        };
+        if let Some(response) = response {
+            writeln!(stdout, "{response}")?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// The response to one JSON-RPC message, `None` for notifications.
+pub fn handle(client: &SlClient, config: &Config, message: &Value) -> Option<Value> {
+    let method = message["method"].as_str().unwrap_or_default();
+    let Some(id) = message.get("id").cloned() else {
+        debug!("mcp notification {method}");
+        return None;
+    };
+    let params = &message["params"];
+    debug!("mcp request {method}");
+
+    let result = match method {
+        "initialize" => initialize(params),
+        "ping" => json!({}),
+        "tools/list" => json!({ "tools": tools() }),
+        "tools/call" => {
+            let name = params["name"].as_str().unwrap_or_default();
+            let arguments = &params["arguments"];
+            let output = match name {
+                "search_sites" => search_sites(client, arguments),
+                "get_departures" => get_departures(client, config, arguments),
+                "plan_journey" => plan_journey(client, config, arguments),
+                _ => return Some(error(id, INVALID_PARAMS, format!("unknown tool '{name}'"))),
+            };
+            // Failures are reported to the model as tool results, so it can
+            // correct itself, rather than as protocol errors
+            match output {
+                Ok(output) => json!({
+                    "content": [{ "type": "text", "text": output.to_string() }],
+                    "isError": false,
+                }),
+                Err(err) => json!({
+                    "content": [{ "type": "text", "text": format!("{err:#}") }],
+                    "isError": true,
+                }),
+            }
+        }
+        _ => {
+            return Some(error(
+                id,
+                METHOD_NOT_FOUND,
+                format!("unknown method '{method}'"),
+            ));
+        }
+    };
+    Some(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+}
+
+fn error(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+fn initialize(params: &Value) -> Value {
+    let requested = params["protocolVersion"].as_str().unwrap_or_default();
+    let version = PROTOCOL_VERSIONS
+        .into_iter()
+        .find(|version| *version == requested)
+        .unwrap_or(PROTOCOL_VERSIONS[0]);
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {} },
+        "serverInfo": { "name": "slq", "version": CURRENT_VERSION },
+        "instructions": "Public transport in Stockholm (SL). Stations can be given by name or site id. Times are local Stockholm time.",
+    })
+}
+
+fn tools() -> Value {
+    let station = json!({
+        "type": "string",
+        "description": "Station name, alias from the slq config file or SL site id",
+    });
+    json!([
+        {
+            "name": "search_sites",
+            "description": "Find SL stations by name and get their site ids and coordinates",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "Part of the station name" },
+                    "limit": { "type": "integer", "minimum": 1 },
+                },
+                "required": ["query"],
+            },
+        },
+        {
+            "name": "get_departures",
+            "description": "Next departures from a station, with the minutes until each one leaves",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "station": station,
+                    "line": { "type": "string", "description": "Line number, e.g. 43 or 14" },
+                    "destination": { "type": "string" },
+                    "transport_mode": {
+                        "type": "string",
+                        "enum": ["bus", "tram", "metro", "train", "ferry", "ship", "taxi"],
+                    },
+                    "limit": { "type": "integer", "minimum": 1, "default": DEFAULT_DEPARTURES },
+                },
+                "required": ["station"],
+            },
+        },
+        {
+            "name": "plan_journey",
+            "description": "Ways to travel between two stations leaving now, with every leg and change",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "from": station,
+                    "to": station,
+                    "limit": { "type": "integer", "minimum": 1, "default": DEFAULT_JOURNEYS },
+                },
+                "required": ["from", "to"],
+            },
+        },
+    ])
+}
+
+fn string(arguments: &Value, name: &str) -> Result<Option<String>> {
+    match &arguments[name] {
+        Value::Null => Ok(None),
+        Value::String(value) => Ok(Some(value.clone())),
+        _ => bail!("`{name}` must be a string"),
+    }
+}
+
+fn required(arguments: &Value, name: &str) -> Result<String> {
+    string(arguments, name)?.ok_or_else(|| anyhow!("`{name}` is required"))
+}
+
+fn limit(arguments: &Value) -> Result<Option<usize>> {
+    match &arguments["limit"] {
+        Value::Null => Ok(None),
+        value => value
+            .as_u64()
+            .map(|limit| Some(limit as usize))
+            .ok_or_else(|| anyhow!("`limit` must be a positive integer")),
+    }
+}
+
+fn station(client: &SlClient, config: &Config, name: &str) -> Result<ResolvedStation> {
+    config
+        .resolve_station(client, name)?
+        .ok_or_else(|| anyhow!("no station matches '{name}', try search_sites"))
+}
+
+fn search_sites(client: &SlClient, arguments: &Value) -> Result<Value> {
+    let query = Query {
+        limit: limit(arguments)?,
+        ..Default::default()
+    };
+    query.validate()?;
+    let mut sites = client.search_for_sites(&required(arguments, "query")?)?;
+    sites.truncate(query.result_limit());
+    let sites: Vec<Value> = sites
+        .iter()
+        .map(|site| json!({ "id": site.id, "name": site.name, "lat": site.lat, "lon": site.lon }))
+        .collect();
+    Ok(json!(sites))
+}
+
+fn get_departures(client: &SlClient, config: &Config, arguments: &Value) -> Result<Value> {
+    let transport_mode = string(arguments, "transport_mode")?
+        .map(|mode| {
+            TransportMode::from_str(&mode, true)
+                .map_err(|_| anyhow!("unknown transport_mode '{mode}'"))
+        })
+        .transpose()?;
+    let query = Query {
+        limit: Some(limit(arguments)?.unwrap_or(DEFAULT_DEPARTURES)),
+        line: string(arguments, "line")?,
+        destination: string(arguments, "destination")?,
+        transport_mode,
+        ..Default::default()
+    };
+    query.validate()?;
+
+    let station = station(client, config, &required(arguments, "station")?)?;
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .count(query.limit)
+        .mode(query.transport_mode)
+        .destination(query.destination.as_deref());
+    let clock = SystemClock;
+    let mut departures = Vec::new();
+    client.for_each_departure(station.id, &filter, |departure| {
+        departures.push(json!({
+            "line": departure.line.designation,
+            "line_name": departure.line.name(query.language),
+            "transport_mode": departure.line.transport_mode,
+            "destination": departure.destination,
+            "expected": timestamp(departure.expected),
+            "minutes": departure.time_until(&clock).whole_minutes().max(0),
+        }));
+    })?;
+    Ok(json!({
+        "station": station.name,
+        "now": now(&clock),
+        "departures": departures,
+    }))
+}
+
+fn plan_journey(client: &SlClient, config: &Config, arguments: &Value) -> Result<Value> {
+    let count = limit(arguments)?.unwrap_or(DEFAULT_JOURNEYS);
+    if count == 0 {
+        bail!("`limit` must be at least 1");
+    }
+    let from = station(client, config, &required(arguments, "from")?)?;
+    let to = station(client, config, &required(arguments, "to")?)?;
+    let journeys = client.plan_journey(from.id, to.id, count)?;
+    Ok(json!({
+        "from": from.name,
+        "to": to.name,
+        "now": now(&SystemClock),
+        "journeys": journeys,
+    }))
+}
+
+/// The current local time, for the model to compare departures against
+fn now(clock: &dyn Clock) -> String {
+    let now = clock.now().to_offset(clock.local_offset());
+    format!(
+        "{}T{:02}:{:02}:{:02}",
+        now.date(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientSettings;
+
+    fn call(message: Value) -> Value {
+        let client = SlClient::new(ClientSettings::default()).unwrap();
+        handle(&client, &Config::default(), &message).expect("requests get a response")
+    }
+
+    #[test]
+    fn initialize_agrees_on_a_protocol_version() {
+        let response = call(json!({
+            "jsonrpc": "2.0", "id": 1, "method": "initialize",
+            "params": { "protocolVersion": "2025-03-26" },
+        }));
+        assert_eq!(json!(1), response["id"]);
+        assert_eq!("2025-03-26", response["result"]["protocolVersion"]);
+        assert_eq!("slq", response["result"]["serverInfo"]["name"]);
+
+        let response = call(json!({
+            "jsonrpc": "2.0", "id": 2, "method": "initialize",
+            "params": { "protocolVersion": "1999-01-01" },
+        }));
+        assert_eq!(PROTOCOL_VERSIONS[0], response["result"]["protocolVersion"]);
+    }
+
+    #[test]
+    fn notifications_get_no_response() {
+        let client = SlClient::new(ClientSettings::default()).unwrap();
+        let message = json!({ "jsonrpc": "2.0", "method": "notifications/initialized" });
+        assert!(handle(&client, &Config::default(), &message).is_none());
+    }
+
+    #[test]
+    fn tools_are_listed_and_called() {
+        let response = call(json!({ "jsonrpc": "2.0", "id": 1, "method": "tools/list" }));
+        let tools = response["result"]["tools"].as_array().unwrap();
+        assert_eq!(3, tools.len());
+
+        let response = call(json!({
+            "jsonrpc": "2.0", "id": 2, "method": "tools/call",
+            "params": { "name": "search_sites", "arguments": { "query": "t-centralen", "limit": 1 } },
+        }));
+        assert_eq!(false, response["result"]["isError"]);
+        let text = response["result"]["content"][0]["text"].as_str().unwrap();
+        let sites: Value = serde_json::from_str(text).unwrap();
+        assert_eq!("T-Centralen", sites[0]["name"]);
+    }
+
+    #[test]
+    fn bad_arguments_are_tool_errors() {
+        let response = call(json!({
+            "jsonrpc": "2.0", "id": 1, "method": "tools/call",
+            "params": { "name": "get_departures", "arguments": { "station": "9192", "limit": 0 } },
+        }));
+        assert_eq!(true, response["result"]["isError"]);
+
+        let response = call(json!({
+            "jsonrpc": "2.0", "id": 2, "method": "tools/call",
+            "params": { "name": "launch_rocket", "arguments": {} },
+        }));
+        assert_eq!(INVALID_PARAMS, response["error"]["code"]);
+
+        let response = call(json!({ "jsonrpc": "2.0", "id": 3, "method": "resources/list" }));
+        assert_eq!(METHOD_NOT_FOUND, response["error"]["code"]);
+    }
+}
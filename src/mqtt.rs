@@ -0,0 +1,278 @@
+//! `slq mqtt`: publish the departures and deviations of a few stations to
+//! an MQTT broker, the usual way to feed Home Assistant and other home
+//! automation.
+
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use rumqttc::{Client, MqttOptions, QoS};
+use serde_json::{Value, json};
+use time::format_description::well_known::Rfc3339;
+use tracing::debug;
+
+use crate::client::{DepartureFilter, SlClient};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{MqttConfig, ResolvedStation};
+use crate::notice;
+use crate::query::Query;
+use crate::sandbox::ensure_net_write;
+
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// Topics unless the command line or config file name others. `{site}` is
+/// replaced by the site id.
+pub const DEFAULT_DEPARTURES_TOPIC: &str = "slq/{site}/departures";
+pub const DEFAULT_DEVIATIONS_TOPIC: &str = "slq/{site}/deviations";
+
+/// How long to wait before reconnecting to a broker that dropped us
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Where and how to publish
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub departures_topic: String,
+    pub deviations_topic: String,
+    pub interval: Duration,
+}
+
+impl MqttSettings {
+    /// Settings from the command line, falling back to `[mqtt]` in the
+    /// config file and then to the defaults.
+    pub fn new(
+        config: &MqttConfig,
+        broker: Option<&str>,
+        departures_topic: Option<&str>,
+        deviations_topic: Option<&str>,
+        interval: Duration,
+    ) -> Result<MqttSettings> {
+        let broker = broker.or(config.broker.as_deref()).ok_or_else(|| {
+            anyhow!(
+                "no MQTT broker given, use --broker or set `broker` under [mqtt] in the config file"
+            )
+        })?;
+        let (host, port) = parse_broker(broker)?;
+        Ok(MqttSettings {
+            host,
+            port,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            departures_topic: departures_topic
+                .or(config.departures_topic.as_deref())
+                .unwrap_or(DEFAULT_DEPARTURES_TOPIC)
+                .to_string(),
+            deviations_topic: deviations_topic
+                .or(config.deviations_topic.as_deref())
+                .unwrap_or(DEFAULT_DEVIATIONS_TOPIC)
+                .to_string(),
+            interval,
+        })
+    }
+}
+
+/// Split `host`, `host:port` or `mqtt://host:port` into host and port.
+pub fn parse_broker(broker: &str) -> Result<(String, u16)> {
+    let address = broker.strip_prefix("mqtt://").unwrap_or(broker);
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .with_context(|| format!("'{port}' is not a port number"))?;
+            (host, port)
+        }
+        None => (address, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        anyhow::bail!("'{broker}' doesn't name a broker host");
+    }
+    Ok((host.to_string(), port))
+}
+
+/// The topic for a site, with `{site}` in `template` replaced by its id
+pub fn topic(template: &str, site: &ResolvedStation) -> String {
+    template.replace("{site}", &site.id.to_string())
+}
+
+/// Publish departures and deviations for `sites` every interval until the
+/// process is stopped. Messages are retained, so subscribers that connect
+/// later get the latest board right away. Failing to reach SL is reported
+/// and retried on the next round rather than ending the publisher.
+pub fn publish(
+    client: &SlClient,
+    settings: &MqttSettings,
+    sites: &[ResolvedStation],
+    query: &Query,
+) -> Result<()> {
+    let publisher = if client.dry_run() {
+        None
+    } else {
+        ensure_net_write("departures to an MQTT broker")?;
+        Some(connect(settings))
+    };
+    notice!(
+        "Publishing {} to {}:{} every {}s",
+        sites
+            .iter()
+            .map(|site| site.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        settings.host,
+        settings.port,
+        settings.interval.as_secs()
+    );
+
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .count(query.limit)
+        .mode(query.transport_mode)
+        .destination(query.destination.as_deref());
+    loop {
+        for site in sites {
+            let messages = [
+                (
+                    &settings.departures_topic,
+                    departures(client, site, &filter),
+                ),
+                (&settings.deviations_topic, deviations(client, site, query)),
+            ];
+            for (template, payload) in messages {
+                let payload = match payload {
+                    Ok(payload) => payload.to_string(),
+                    Err(err) => {
+                        eprintln!("Warning: {err:#}");
+                        continue;
+                    }
+                };
+                let topic = topic(template, site);
+                match &publisher {
+                    Some(publisher) => publisher
+                        .publish(&topic, QoS::AtLeastOnce, true, payload)
+                        .context("the MQTT connection has closed")?,
+                    None => notice!("Would publish to {topic}: {payload}"),
+                }
+            }
+        }
+        thread::sleep(settings.interval);
+    }
+}
+
+/// Connect to the broker. The connection is driven on its own thread, which
+/// reconnects whenever the broker goes away.
+fn connect(settings: &MqttSettings) -> Client {
+    let client_id = format!("slq-{}", std::process::id());
+    let mut options = MqttOptions::new(client_id, &settings.host, settings.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &settings.username {
+        options.set_credentials(username, settings.password.as_deref().unwrap_or_default());
+    }
+
+    let (client, mut connection) = Client::new(options, 16);
+    let broker = format!("{}:{}", settings.host, settings.port);
+    thread::spawn(move || {
+        for event in connection.iter() {
+            match event {
+                Ok(event) => debug!("mqtt: {event:?}"),
+                Err(err) => {
+                    eprintln!("Warning: lost the connection to {broker}: {err}");
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    });
+    client
+}
+
+fn departures(
+    client: &SlClient,
+    site: &ResolvedStation,
+    filter: &DepartureFilter,
+) -> Result<Value> {
+    let mut departures = Vec::new();
+    client.for_each_departure(site.id, filter, |departure| departures.push(departure))?;
+    Ok(json!({
+        "site_id": site.id,
+        "site": site.name,
+        "updated": timestamp(),
+        "departures": departures,
+    }))
+}
+
+/// Deviations in effect at the site right now
+fn deviations(client: &SlClient, site: &ResolvedStation, query: &Query) -> Result<Value> {
+    let now = SystemClock.now();
+    let deviations: Vec<Value> = client
+        .get_planned_deviations(site.id)?
+        .iter()
+        .filter(|deviation| deviation.overlaps(now, now))
+        .map(|deviation| {
+            let message = deviation.message(query.language.code());
+            json!({
+                "from": deviation.publish.from,
+                "upto": deviation.publish.upto,
+                "lines": deviation.line_designations(),
+                "importance": deviation.priority.as_ref().map(|priority| priority.importance_level),
+                "header": message.map(|message| &message.header),
+                "details": message.and_then(|message| message.details.as_ref()),
+            })
+        })
+        .collect();
+    Ok(json!({
+        "site_id": site.id,
+        "site": site.name,
+        "updated": timestamp(),
+        "deviations": deviations,
+    }))
+}
+
+fn timestamp() -> String {
+    SystemClock.now().format(&Rfc3339).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SiteId;
+
+    #[test]
+    fn brokers_default_to_the_mqtt_port() {
+        assert_eq!(
+            ("broker.local".to_string(), 1883),
+            parse_broker("broker.local").unwrap()
+        );
+        assert_eq!(
+            ("10.0.0.2".to_string(), 1884),
+            parse_broker("mqtt://10.0.0.2:1884").unwrap()
+        );
+        assert!(parse_broker("broker.local:mqtt").is_err());
+        assert!(parse_broker(":1883").is_err());
+    }
+
+    #[test]
+    fn topics_are_filled_in_per_site() {
+        let site = ResolvedStation {
+            id: SiteId::from(9192),
+            name: "Slussen".to_string(),
+        };
+        assert_eq!(
+            "home/sl/9192/departures",
+            topic("home/sl/{site}/departures", &site)
+        );
+        let settings = MqttSettings::new(
+            &MqttConfig {
+                broker: Some("broker.local".to_string()),
+                deviations_topic: Some("sl/{site}".to_string()),
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert_eq!(DEFAULT_DEPARTURES_TOPIC, settings.departures_topic);
+        assert_eq!("sl/{site}", settings.deviations_topic);
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use serde_json::json;
+
+use crate::client::SlClient;
+use crate::config::NotifyConfig;
+use crate::notice;
+use crate::sandbox::ensure_net_write;
+
+/// Something that can deliver a short message to the user
+pub trait Notifier {
+    fn notify(&self, title: &str, message: &str) -> Result<()>;
+}
+
+/// Build the notifier selected in the configuration. Remote backends send
+/// their requests through the client's proxy and certificate settings, and a
+/// client in dry run mode only describes the notification.
+pub fn notifier(config: &NotifyConfig, client: &SlClient) -> Box<dyn Notifier> {
+    if client.dry_run() {
+        return Box::new(DryRun {
+            backend: config.backend_name(),
+        });
+    }
+
+    let http = client.http().clone();
+    match config {
+        NotifyConfig::Desktop => Box::new(Desktop),
+        NotifyConfig::Ntfy { url, token } => Box::new(Ntfy {
+            http,
+            url: url.clone(),
+            token: token.clone(),
+        }),
+        NotifyConfig::Pushover { token, user } => Box::new(Pushover {
+            http,
+            token: token.clone(),
+            user: user.clone(),
+        }),
+        NotifyConfig::Gotify { url, token } => Box::new(Gotify {
+            http,
+            url: url.clone(),
+            token: token.clone(),
+        }),
+        NotifyConfig::Webhook { url, token } => Box::new(Webhook {
+            http,
+            url: url.clone(),
+            token: token.clone(),
+        }),
+    }
+}
+
+struct DryRun {
+    backend: &'static str,
+}
+
+impl Notifier for DryRun {
+    fn notify(&self, title: &str, message: &str) -> Result<()> {
+        notice!(
+            "Would send a {} notification: {title}: {message}",
+            self.backend
+        );
+        Ok(())
+    }
+}
+
+struct Desktop;
+
+impl Notifier for Desktop {
+    #[cfg(feature = "desktop-notifications")]
+    fn notify(&self, title: &str, message: &str) -> Result<()> {
+        notify_rust::Notification::new()
+            .appname("slq")
+            .summary(title)
+            .body(message)
+            .show()
+            .context("failed to show desktop notification")?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "desktop-notifications"))]
+    fn notify(&self, _title: &str, _message: &str) -> Result<()> {
+        anyhow::bail!(
+            "slq was built without desktop notifications, configure the ntfy, pushover or gotify backend instead"
+        )
+    }
+}
+
+struct Ntfy {
+    http: Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl Notifier for Ntfy {
+    fn notify(&self, title: &str, message: &str) -> Result<()> {
+        ensure_net_write("an ntfy notification")?;
+        let mut request = self
+            .http
+            .post(&self.url)
+            .header("Title", title)
+            .body(message.to_string());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+            .send()
+            .and_then(|res| res.error_for_status())
+            .context("failed to publish ntfy notification")?;
+        Ok(())
+    }
+}
+
+struct Pushover {
+    http: Client,
+    token: String,
+    user: String,
+}
+
+impl Notifier for Pushover {
+    fn notify(&self, title: &str, message: &str) -> Result<()> {
+        ensure_net_write("a Pushover notification")?;
+        let params = [
+            ("token", self.token.as_str()),
+            ("user", self.user.as_str()),
+            ("title", title),
+            ("message", message),
+        ];
+        self.http
+            .post("https://api.pushover.net/1/messages.json")
+            .form(&params)
+            .send()
+            .and_then(|res| res.error_for_status())
+            .context("failed to send Pushover notification")?;
+        Ok(())
+    }
+}
+
+struct Gotify {
+    http: Client,
+    url: String,
+    token: String,
+}
+
+impl Notifier for Gotify {
+    fn notify(&self, title: &str, message: &str) -> Result<()> {
+        ensure_net_write("a Gotify notification")?;
+        let url = format!("{}/message", self.url.trim_end_matches('/'));
+        self.http
+            .post(url)
+            .header("X-Gotify-Key", &self.token)
+            .json(&json!({ "title": title, "message": message }))
+            .send()
+            .and_then(|res| res.error_for_status())
+            .context("failed to send Gotify notification")?;
+        Ok(())
+    }
+}
+
+struct Webhook {
+    http: Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl Notifier for Webhook {
+    fn notify(&self, title: &str, message: &str) -> Result<()> {
+        ensure_net_write("a webhook notification")?;
+        let mut request = self
+            .http
+            .post(&self.url)
+            .json(&json!({ "title": title, "message": message }));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+            .send()
+            .and_then(|res| res.error_for_status())
+            .context("failed to send webhook notification")?;
+        Ok(())
+    }
+}
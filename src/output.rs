@@ -0,0 +1,656 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result, anyhow, bail};
+use deunicode::deunicode_char;
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use terminal_size::{Height, Width, terminal_size};
+use unicode_width::UnicodeWidthChar;
+
+use crate::client::{Departure, Site};
+use crate::clock::Clock;
+use crate::dates::timestamp;
+use crate::path::JsonPath;
+use crate::types::{ColorChoice, OutputFormat, TransportMode};
+
+static ASCII_ONLY: AtomicBool = AtomicBool::new(false);
+static HEADERS: AtomicBool = AtomicBool::new(true);
+static QUIET: AtomicBool = AtomicBool::new(false);
+/// What `--query` picks out of JSON output
+static JSON_PATH: Mutex<Option<JsonPath>> = Mutex::new(None);
+/// Output held back while paging, to see whether it fits the terminal
+static PAGED: Mutex<Option<Vec<String>>> = Mutex::new(None);
+/// Where `--output` sends what would otherwise go to stdout
+static OUTPUT_FILE: Mutex<Option<OutputFile>> = Mutex::new(None);
+
+struct OutputFile {
+    path: PathBuf,
+    /// The file written instead of `path` until it is moved over it, unless
+    /// appending
+    staging: Option<PathBuf>,
+    file: File,
+    /// The first write that failed, reported by [`finish_output`]
+    error: Option<io::Error>,
+}
+
+/// Print a line to stdout, transliterated to plain ASCII (Älvsjö → Alvsjo)
+/// when ASCII output is enabled.
+#[macro_export]
+macro_rules! outln {
+    () => {
+        $crate::output::write_line("")
+    };
+    ($($arg:tt)*) => {
+        $crate::output::write_line(&format!($($arg)*))
+    };
+}
+
+/// Print a banner or CSV header row like [`outln!`], unless headers are
+/// turned off with `--no-header` so only records are left.
+#[macro_export]
+macro_rules! headerln {
+    ($($arg:tt)*) => {
+        if $crate::output::headers_enabled() {
+            $crate::outln!($($arg)*)
+        }
+    };
+}
+
+/// Print a message for whoever is watching, like progress or "nothing
+/// found", to stderr so stdout only carries data. `--quiet` silences them.
+#[macro_export]
+macro_rules! notice {
+    ($($arg:tt)*) => {
+        $crate::output::write_notice(&format!($($arg)*))
+    };
+}
+
+pub fn write_line(line: &str) {
+    let line = if ASCII_ONLY.load(Ordering::Relaxed) {
+        transliterate(line)
+    } else {
+        line.to_string()
+    };
+    if let Some(paged) = PAGED.lock().unwrap().as_mut() {
+        paged.push(line);
+        return;
+    }
+    write_output(|out| writeln!(out, "{line}"));
+}
+
+/// Write to the `--output` file, or to stdout without one
+pub fn write_output(write: impl FnOnce(&mut dyn Write) -> io::Result<()>) {
+    match OUTPUT_FILE.lock().unwrap().as_mut() {
+        Some(output) => {
+            if output.error.is_none()
+                && let Err(err) = write(&mut output.file)
+            {
+                output.error = Some(err);
+            }
+        }
+        // As `println!` does
+        None => write(&mut io::stdout().lock()).expect("failed printing to stdout"),
+    }
+}
+
+/// Send output to `path` instead of stdout from now on. Appended output is
+/// written as it comes. Otherwise it goes to a file next to `path` that
+/// [`finish_output`] moves over it, so readers never see half a file.
+pub fn init_output(path: &Path, append: bool) -> Result<()> {
+    let (file, staging) = if append {
+        let file = OpenOptions::new().create(true).append(true).open(path);
+        (file, None)
+    } else {
+        let staging = staging_path(path);
+        (File::create(&staging), Some(staging))
+    };
+    let file = file.with_context(|| format!("failed to open {}", path.display()))?;
+    *OUTPUT_FILE.lock().unwrap() = Some(OutputFile {
+        path: path.to_path_buf(),
+        staging,
+        file,
+        error: None,
+    });
+    Ok(())
+}
+
+/// A hidden file in the same directory as `path`, since a rename only
+/// replaces a file atomically within one file system
+fn staging_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{}.tmp", process::id()))
+}
+
+/// Finish writing the `--output` file. The file is only replaced when the
+/// command `succeeded`, so a failed run leaves the last good output behind.
+pub fn finish_output(succeeded: bool) -> Result<()> {
+    let Some(mut output) = OUTPUT_FILE.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let written = match output.error.take() {
+        Some(err) => Err(err),
+        None => output.file.sync_all(),
+    };
+    let Some(staging) = output.staging else {
+        return written.with_context(|| format!("failed to write {}", output.path.display()));
+    };
+    if let Err(err) = written {
+        let _ = fs::remove_file(&staging);
+        return Err(err).with_context(|| format!("failed to write {}", output.path.display()));
+    }
+    if !succeeded {
+        let _ = fs::remove_file(&staging);
+        return Ok(());
+    }
+    fs::rename(&staging, &output.path)
+        .with_context(|| format!("failed to replace {}", output.path.display()))
+}
+
+/// Whether output ends up on a terminal, rather than in a pipe or an
+/// `--output` file
+pub fn stdout_is_terminal() -> bool {
+    OUTPUT_FILE.lock().unwrap().is_none() && io::stdout().is_terminal()
+}
+
+/// Print only what `path` picks out of JSON output from now on
+pub fn init_json_path(path: Option<JsonPath>) {
+    *JSON_PATH.lock().unwrap() = path;
+}
+
+/// Print a value as pretty JSON, or what `--query` picks out of it: strings
+/// as they are and anything else as JSON, one value per line.
+pub fn print_json(value: &impl Serialize) -> Result<()> {
+    let path = JSON_PATH.lock().unwrap().clone();
+    let Some(path) = path else {
+        outln!("{}", serde_json::to_string_pretty(value)?);
+        return Ok(());
+    };
+    let selected = path
+        .select(&serde_json::to_value(value)?)
+        .map_err(|err| anyhow!("--query: {err}"))?;
+    for value in selected {
+        match value {
+            Value::String(text) => outln!("{text}"),
+            value => outln!("{}", serde_json::to_string_pretty(&value)?),
+        }
+    }
+    Ok(())
+}
+
+/// Print a record of a stream as one line of JSON, flushed straight away
+/// so whoever reads the stream gets it as soon as it is written
+pub fn print_record(value: &impl Serialize) -> Result<()> {
+    outln!("{}", serde_json::to_string(value)?);
+    write_output(|out| out.flush());
+    Ok(())
+}
+
+/// Hold output back until [`finish_paging`], so output taller than the
+/// terminal can be shown in a pager. Does nothing when stdout isn't a
+/// terminal.
+pub fn start_paging() {
+    if stdout_is_terminal() {
+        *PAGED.lock().unwrap() = Some(Vec::new());
+    }
+}
+
+/// Print the output held back since [`start_paging`]: through `$PAGER`,
+/// or `less`, when it is taller than the terminal, and straight to stdout
+/// otherwise or when the pager can't be started.
+pub fn finish_paging() {
+    let Some(lines) = PAGED.lock().unwrap().take() else {
+        return;
+    };
+    let height = terminal_size().map_or(usize::MAX, |(_, Height(height))| height as usize);
+    let paged = lines.len() >= height
+        && pager_command(env::var("PAGER").ok().as_deref())
+            .is_some_and(|pager| page(&lines, &pager).is_ok());
+    if !paged {
+        lines.iter().for_each(|line| println!("{line}"));
+    }
+}
+
+/// The pager from `$PAGER` split into the program and its arguments, `less`
+/// when it isn't set, and `None` when it is set to nothing or `cat`
+fn pager_command(pager: Option<&str>) -> Option<Vec<String>> {
+    let words: Vec<String> = pager
+        .unwrap_or("less")
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    match words.first().map(String::as_str) {
+        None | Some("cat") => None,
+        Some(_) => Some(words),
+    }
+}
+
+fn page(lines: &[String], pager: &[String]) -> io::Result<()> {
+    let mut command = Command::new(&pager[0]);
+    command.args(&pager[1..]).stdin(Stdio::piped());
+    // As git does: quit when it all fits, pass colors through, and leave
+    // the text on screen afterwards
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    let mut child = command.spawn()?;
+    let mut stdin = child.stdin.take().expect("the pager's stdin is piped");
+    for line in lines {
+        // The pager was quit before reading everything
+        if writeln!(stdin, "{line}").is_err() {
+            break;
+        }
+    }
+    drop(stdin);
+    child.wait()?;
+    Ok(())
+}
+
+pub fn write_notice(line: &str) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    if ASCII_ONLY.load(Ordering::Relaxed) {
+        eprintln!("{}", transliterate(line));
+    } else {
+        eprintln!("{line}");
+    }
+}
+
+/// Replace non-ASCII characters with their closest ASCII spelling, leaving
+/// tabs and other ASCII control characters alone.
+fn transliterate(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii() {
+                c.to_string()
+            } else {
+                deunicode_char(c).unwrap_or("?").to_string()
+            }
+        })
+        .collect()
+}
+
+/// Transliterate all output when asked to, or when stdout is a terminal
+/// whose locale can't display anything but ASCII.
+pub fn init_ascii(requested: bool) {
+    let enabled = requested || (stdout_is_terminal() && !locale_supports_utf8());
+    ASCII_ONLY.store(enabled, Ordering::Relaxed);
+}
+
+/// Print banners and CSV header rows, or leave them out for output that is
+/// nothing but records
+pub fn init_headers(enabled: bool) {
+    HEADERS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn headers_enabled() -> bool {
+    HEADERS.load(Ordering::Relaxed)
+}
+
+/// Leave out notices, and with them banners and headers, so only data and
+/// errors are printed
+pub fn init_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+    if quiet {
+        init_headers(false);
+    }
+}
+
+/// Whether the locale from `LC_ALL`, `LC_CTYPE` or `LANG` (first one set)
+/// uses UTF-8. An unset locale is assumed to be UTF-8 capable.
+fn locale_supports_utf8() -> bool {
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty());
+    match locale {
+        Some(locale) => is_utf8_locale(&locale),
+        None => true,
+    }
+}
+
+fn is_utf8_locale(locale: &str) -> bool {
+    let locale = locale.to_lowercase();
+    locale.contains("utf-8") || locale.contains("utf8")
+}
+
+/// Whether text output gets colored. `auto` colors only what's written to a
+/// terminal, and never when `NO_COLOR` is set to anything.
+pub fn color_enabled(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            stdout_is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+    }
+}
+
+/// A pictogram for a transport mode, for boards shown with `--icons`. Modes
+/// without one keep their name.
+pub fn transport_mode_icon(mode: TransportMode) -> Option<&'static str> {
+    match mode {
+        TransportMode::Metro => Some("🚇"),
+        TransportMode::Bus => Some("🚌"),
+        TransportMode::Train => Some("🚆"),
+        TransportMode::Tram => Some("🚋"),
+        TransportMode::Ferry | TransportMode::Ship => Some("⛴"),
+        TransportMode::Taxi => Some("🚕"),
+        TransportMode::Other => None,
+    }
+}
+
+/// Rows taken up by things other than departures: the board's title line and
+/// the shell prompt that follows it.
+const BOARD_CHROME_ROWS: usize = 2;
+
+/// Number of departures that fit in the terminal, or `None` when stdout
+/// isn't a terminal and the output shouldn't be sized to one.
+pub fn board_rows() -> Option<usize> {
+    if !stdout_is_terminal() {
+        return None;
+    }
+    let (_, Height(height)) = terminal_size()?;
+    Some((height as usize).saturating_sub(BOARD_CHROME_ROWS).max(1))
+}
+
+/// Width of the terminal stdout writes to, or `None` when it isn't one
+pub fn terminal_columns() -> Option<usize> {
+    if !stdout_is_terminal() {
+        return None;
+    }
+    let (Width(width), _) = terminal_size()?;
+    Some(width as usize)
+}
+
+/// Columns text takes up on a terminal: wide characters like 🚇 take two,
+/// color escapes none, and tabs reach to the next multiple of eight.
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                chars.by_ref().find(|&c| c == 'm');
+            }
+            '\t' => width = (width / 8 + 1) * 8,
+            c => width += c.width().unwrap_or(0),
+        }
+    }
+    width
+}
+
+/// Join the cells of a row with tabs, cutting the cell at `shrink` short
+/// with `…` until the row fits in `width` columns. A row that can't be made
+/// to fit keeps just the `…` of that cell.
+pub fn fit_row(cells: &[String], shrink: usize, width: usize) -> String {
+    let row = cells.join("\t");
+    if display_width(&row) <= width {
+        return row;
+    }
+    let mut cells = cells.to_vec();
+    let text: Vec<char> = cells[shrink].chars().collect();
+    for keep in (0..text.len()).rev() {
+        cells[shrink] = text[..keep].iter().collect::<String>() + "…";
+        let row = cells.join("\t");
+        if keep == 0 || display_width(&row) <= width {
+            return row;
+        }
+    }
+    row
+}
+
+pub fn coordinate(value: Option<f64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Escape text for HTML, or for the Pango markup used by status bars
+pub fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `true`, `false`, or empty when it isn't known
+pub fn csv_flag(value: Option<bool>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+pub fn site_type(site: &Site) -> String {
+    let types = site.types();
+    if types.is_empty() {
+        "unknown".to_string()
+    } else {
+        types.join("/")
+    }
+}
+
+pub fn print_sites(sites: &[Site], format: OutputFormat, verbose: bool) -> Result<()> {
+    match format {
+        OutputFormat::Text if verbose => sites.iter().for_each(|site| {
+            outln!(
+                "{}\t{}\t{}\t{}\t{}",
+                site.name,
+                site.id,
+                coordinate(site.lat),
+                coordinate(site.lon),
+                site_type(site)
+            )
+        }),
+        OutputFormat::Text => sites
+            .iter()
+            .for_each(|site| outln!("{}\t{}", site.name, site.id)),
+        OutputFormat::Json => print_json(&sites)?,
+        OutputFormat::Csv => {
+            headerln!("id,name,lat,lon,type");
+            sites.iter().for_each(|site| {
+                outln!(
+                    "{},{},{},{},{}",
+                    site.id,
+                    csv_field(&site.name),
+                    coordinate(site.lat),
+                    coordinate(site.lon),
+                    site_type(site)
+                )
+            });
+        }
+        OutputFormat::Geojson => {
+            let features = sites.iter().map(|site| (site, Map::new())).collect();
+            print_json(&geojson(features))?;
+        }
+        OutputFormat::Hass
+        | OutputFormat::Waybar
+        | OutputFormat::Statusbar
+        | OutputFormat::Html
+        | OutputFormat::Markdown
+        | OutputFormat::Ndjson => {
+            bail!(DEPARTURES_ONLY)
+        }
+    }
+    Ok(())
+}
+
+/// Build a GeoJSON FeatureCollection with one point per site. Each site can
+/// carry extra properties on top of its id and name. Sites without
+/// coordinates are left out.
+pub fn geojson(sites: Vec<(&Site, Map<String, Value>)>) -> Value {
+    let features: Vec<Value> = sites
+        .into_iter()
+        .filter_map(|(site, mut properties)| {
+            let (lat, lon) = (site.lat?, site.lon?);
+            properties.insert("id".to_string(), json!(site.id));
+            properties.insert("name".to_string(), json!(site.name));
+            let types = site.types();
+            if !types.is_empty() {
+                properties.insert("types".to_string(), json!(types));
+            }
+            Some(json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [lon, lat] },
+                "properties": properties,
+            }))
+        })
+        .collect();
+
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+/// Error for commands asked for a format that only makes sense for a
+/// departure board, such as `hass`, `waybar` or `statusbar`
+pub const DEPARTURES_ONLY: &str =
+    "this format is made for departure boards, use it with `slq departures`";
+
+/// Departures on a status bar line unless `--limit` says otherwise
+pub const STATUSBAR_ROWS: usize = 1;
+
+/// Departures in a Waybar tooltip unless `--limit` says otherwise
+pub const WAYBAR_ROWS: usize = 5;
+
+/// Departures in a Home Assistant sensor unless `--limit` says otherwise.
+/// Home Assistant won't record attributes larger than 16 KiB.
+pub const HASS_ROWS: usize = 10;
+
+/// A departure board shaped for Home Assistant's RESTful and command line
+/// sensors: minutes until the next departure as the state, and the upcoming
+/// departures as attributes.
+pub fn hass_sensor(
+    station: &str,
+    departures: &[Departure],
+    stale: bool,
+    clock: &dyn Clock,
+) -> Value {
+    let minutes = |departure: &Departure| departure.time_until(clock).whole_minutes().max(0);
+    let upcoming: Vec<Value> = departures
+        .iter()
+        .map(|departure| {
+            json!({
+                "line": departure.line.designation,
+                "transport_mode": departure.line.transport_mode,
+                "destination": departure.destination,
+                "expected": timestamp(departure.expected),
+                "minutes": minutes(departure),
+            })
+        })
+        .collect();
+    json!({
+        "state": departures.first().map(minutes),
+        "attributes": {
+            "station": station,
+            "stale": stale,
+            "upcoming": upcoming,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use time::macros::datetime;
+
+    #[test]
+    fn csv_field_quotes_only_when_needed() {
+        assert_eq!(csv_field("Slussen"), "Slussen");
+        assert_eq!(csv_field("Kista, centrum"), "\"Kista, centrum\"");
+        assert_eq!(csv_field("Say \"hi\""), "\"Say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn transliterate_keeps_ascii_control_characters() {
+        assert_eq!("Alvsjo\t9529", transliterate("Älvsjö\t9529"));
+    }
+
+    #[test]
+    fn pager_comes_from_the_environment_or_is_less() {
+        assert_eq!(Some(vec!["less".to_string()]), pager_command(None));
+        assert_eq!(
+            Some(vec!["less".to_string(), "-R".to_string()]),
+            pager_command(Some("less -R"))
+        );
+        assert_eq!(None, pager_command(Some("")));
+        assert_eq!(None, pager_command(Some("cat")));
+    }
+
+    #[test]
+    fn utf8_locales_are_detected() {
+        assert!(is_utf8_locale("sv_SE.UTF-8"));
+        assert!(is_utf8_locale("en_US.utf8"));
+        assert!(!is_utf8_locale("C"));
+        assert!(!is_utf8_locale("sv_SE.ISO-8859-1"));
+    }
+
+    #[test]
+    fn rows_are_cut_short_to_fit_the_terminal() {
+        let cells: Vec<String> = ["3m", "08:03", "\x1b[35m27\x1b[0m", "🚆", "Stockholms östra"]
+            .map(str::to_string)
+            .into();
+        // Four tab stops, then the destination
+        assert_eq!(32 + 16, display_width(&cells.join("\t")));
+        assert_eq!(cells.join("\t"), fit_row(&cells, 4, 48));
+        assert_eq!(
+            "3m\t08:03\t\x1b[35m27\x1b[0m\t🚆\tStockho…",
+            fit_row(&cells, 4, 40)
+        );
+        assert_eq!(
+            "3m\t08:03\t\x1b[35m27\x1b[0m\t🚆\t…",
+            fit_row(&cells, 4, 10)
+        );
+    }
+
+    #[test]
+    fn geojson_uses_lon_lat_order_and_skips_sites_without_coordinates() {
+        let with_coordinates: Site =
+            serde_json::from_str(r#"{ "id": 1, "name": "Slussen", "lat": 59.3, "lon": 18.07 }"#)
+                .unwrap();
+        let without_coordinates: Site =
+            serde_json::from_str(r#"{ "id": 2, "name": "Nowhere" }"#).unwrap();
+
+        let collection = geojson(vec![
+            (&with_coordinates, Map::new()),
+            (&without_coordinates, Map::new()),
+        ]);
+
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(1, features.len());
+        assert_eq!(json!([18.07, 59.3]), features[0]["geometry"]["coordinates"]);
+        assert_eq!(json!("Slussen"), features[0]["properties"]["name"]);
+    }
+
+    #[test]
+    fn hass_sensor_state_is_minutes_to_the_next_departure() {
+        let departures: Vec<Departure> = serde_json::from_str(
+            r#"[
+                { "destination": "Fruängen", "expected": "2025-11-02T11:04:30",
+                  "line": { "designation": "14", "transport_mode": "METRO" } },
+                { "destination": "Mörby centrum", "expected": "2025-11-02T11:10:00",
+                  "line": { "designation": "14", "transport_mode": "METRO" } }
+            ]"#,
+        )
+        .unwrap();
+        let clock = FixedClock(datetime!(2025-11-02 11:00:00 +1));
+
+        let sensor = hass_sensor("Slussen", &departures, false, &clock);
+        assert_eq!(json!(4), sensor["state"]);
+        assert_eq!(json!("Slussen"), sensor["attributes"]["station"]);
+        assert_eq!(json!(10), sensor["attributes"]["upcoming"][1]["minutes"]);
+
+        let empty = hass_sensor("Slussen", &[], false, &clock);
+        assert!(empty["state"].is_null());
+    }
+}
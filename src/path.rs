@@ -0,0 +1,168 @@
+//! A small jq-like language for picking values out of JSON output, for
+//! `--query`: `.[0].line.designation`, `.[].destination` or `.upcoming[-1]`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+/// A path through a JSON value, e.g. `.[0].destination`
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath(Vec<Step>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    /// `.name` or `."a name"`
+    Field(String),
+    /// `[2]`, or `[-1]` counting from the end
+    Index(i64),
+    /// `[]`, every element of an array or value of an object
+    Each,
+}
+
+impl FromStr for JsonPath {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| format!("'{path}' is not a path like `.[0].destination`: {why}");
+        let mut chars = path.trim().chars().peekable();
+        if chars.peek() != Some(&'.') {
+            return Err(invalid("it should start with `.`"));
+        }
+        let mut steps = Vec::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => match chars.peek() {
+                    None | Some('[') => {}
+                    Some('"') => {
+                        chars.next();
+                        let name: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                        steps.push(Step::Field(name));
+                    }
+                    Some(c) if c.is_alphabetic() || *c == '_' => {
+                        let mut name = String::new();
+                        while let Some(&c) = chars.peek() {
+                            if !(c.is_alphanumeric() || c == '_') {
+                                break;
+                            }
+                            name.push(c);
+                            chars.next();
+                        }
+                        steps.push(Step::Field(name));
+                    }
+                    Some(c) => return Err(invalid(&format!("unexpected `{c}` after `.`"))),
+                },
+                '[' => {
+                    let inside: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                    if inside.trim().is_empty() {
+                        steps.push(Step::Each);
+                    } else {
+                        let index = inside
+                            .trim()
+                            .parse()
+                            .map_err(|_| invalid(&format!("`[{inside}]` is not an index")))?;
+                        steps.push(Step::Index(index));
+                    }
+                }
+                c => return Err(invalid(&format!("unexpected `{c}`"))),
+            }
+        }
+        Ok(JsonPath(steps))
+    }
+}
+
+impl JsonPath {
+    /// The values at the end of the path. A field or index that isn't there
+    /// is `null`, as in jq, but looking up a field of a list is an error.
+    pub fn select(&self, value: &Value) -> Result<Vec<Value>, String> {
+        let mut values = vec![value.clone()];
+        for step in &self.0 {
+            let mut next = Vec::new();
+            for value in values {
+                match (step, value) {
+                    (_, Value::Null) => next.push(Value::Null),
+                    (Step::Field(name), Value::Object(mut object)) => {
+                        next.push(object.remove(name).unwrap_or(Value::Null));
+                    }
+                    (Step::Index(index), Value::Array(array)) => {
+                        let at = if *index < 0 {
+                            array.len().checked_sub(index.unsigned_abs() as usize)
+                        } else {
+                            Some(*index as usize)
+                        };
+                        next.push(at.and_then(|at| array.get(at).cloned()).unwrap_or_default());
+                    }
+                    (Step::Each, Value::Array(array)) => next.extend(array),
+                    (Step::Each, Value::Object(object)) => next.extend(object.into_values()),
+                    (step, value) => {
+                        return Err(format!("can't take {step} of {}", kind(&value)));
+                    }
+                }
+            }
+            values = next;
+        }
+        Ok(values)
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Field(name) => write!(f, "field \"{name}\""),
+            Step::Index(index) => write!(f, "[{index}]"),
+            Step::Each => f.write_str("[]"),
+        }
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn select(path: &str, value: &Value) -> Result<Vec<Value>, String> {
+        path.parse::<JsonPath>()?.select(value)
+    }
+
+    #[test]
+    fn paths_pick_fields_indexes_and_every_element() {
+        let board = json!([
+            { "destination": "Fruängen", "line": { "designation": "14" } },
+            { "destination": "Mörby centrum", "line": { "designation": "14" } },
+        ]);
+        assert_eq!(Ok(vec![board.clone()]), select(".", &board));
+        assert_eq!(
+            Ok(vec![json!("14")]),
+            select(".[0].line.designation", &board)
+        );
+        assert_eq!(
+            Ok(vec![json!("Mörby centrum")]),
+            select(".[-1].\"destination\"", &board)
+        );
+        assert_eq!(
+            Ok(vec![json!("Fruängen"), json!("Mörby centrum")]),
+            select(".[].destination", &board)
+        );
+        assert_eq!(Ok(vec![Value::Null]), select(".[5].line", &board));
+        assert_eq!(Ok(vec![Value::Null]), select(".[0].platform", &board));
+        assert!(select(".destination", &board).is_err());
+    }
+
+    #[test]
+    fn malformed_paths_are_rejected() {
+        assert!("destination".parse::<JsonPath>().is_err());
+        assert!(".[first]".parse::<JsonPath>().is_err());
+        assert!(".line-1".parse::<JsonPath>().is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use anyhow::{Result, bail};
+use time::{Date, Duration, PrimitiveDateTime};
+
+use crate::dates::{TimeFormat, Timezone};
+use crate::output::{HASS_ROWS, STATUSBAR_ROWS, WAYBAR_ROWS, board_rows};
+use crate::theme::Theme;
+use crate::types::{Column, Language, OutputFormat, TransportMode};
+
+/// Longest walk to a station that slq takes seriously. Departure boards
+/// rarely reach further into the future than this.
+pub const MAX_WALK_MINUTES: i64 = 120;
+
+/// How far ahead planned works are listed at most. The deviations API
+/// rarely publishes anything further out.
+pub const MAX_PLANNED_DAYS: i64 = 60;
+
+/// Shortest interval between two polls of the SL API.
+pub const MIN_POLL_SECONDS: u64 = 10;
+
+/// Command independent description of what the user asked for.
+///
+/// Every subcommand lowers its arguments into a `Query` before doing any
+/// work, so rules that span several flags only have to be written once.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pub format: OutputFormat,
+    /// Maximum number of results, see [`Query::result_limit`]
+    pub limit: Option<usize>,
+    /// Language for dates and messages in human readable output
+    pub language: Language,
+    /// How times of day are shown in human readable output
+    pub time_format: TimeFormat,
+    /// Timezone times are shown in
+    pub timezone: Timezone,
+    pub line: Option<String>,
+    pub destination: Option<String>,
+    pub transport_mode: Option<TransportMode>,
+    /// Who runs the line, `SL` or a transport authority id
+    pub operator: Option<String>,
+    /// Latitude and longitude in decimal degrees
+    pub position: Option<(f64, f64)>,
+    /// Hide departures leaving sooner than this many minutes from now
+    pub walk_minutes: Option<i64>,
+    /// How long before a departure is due to notify or stop waiting
+    pub lead_time: Option<Duration>,
+    /// Seconds between polls for commands that keep watching the board
+    pub poll_seconds: Option<u64>,
+    /// Number of days ahead to look, starting today
+    pub days: Option<i64>,
+    /// Print the API response untouched instead of decoding it
+    pub raw: bool,
+    /// Show the journey id of each departure, or the trip id for scheduled
+    /// ones, to follow it with `slq track` or `slq journey-details`
+    pub journey_ids: bool,
+    /// Keep departures that have already left, marked as departed, instead
+    /// of dropping them
+    pub show_departed: bool,
+    /// Show the time as SL's own signs put it, e.g. `Nu` or `3 min`, instead
+    /// of working out the wait locally
+    pub use_api_display: bool,
+    /// Only departures known to be step free, from vehicles that take
+    /// wheelchairs at stops without steps
+    pub accessible_only: bool,
+    /// Show how full each vehicle is, where the realtime feeds say
+    pub show_crowding: bool,
+    /// Colors of wait times, lines and the other columns in text output,
+    /// `None` when it isn't colored
+    pub theme: Option<Theme>,
+    /// Columns of the departure board picked with `--columns`, in order
+    pub columns: Option<Vec<Column>>,
+    /// Show transport modes as pictograms, e.g. 🚇, instead of by name
+    pub icons: bool,
+    /// Columns text rows are cut to fit in, from `--width` or the terminal
+    pub width: Option<usize>,
+    /// Longest line `--format statusbar` prints, in characters
+    pub max_width: Option<usize>,
+    /// Whether the command keeps writing records until it is stopped
+    pub streaming: bool,
+    /// Send a keepalive when a stream has been quiet this long
+    pub heartbeat: Option<Duration>,
+    /// Stations are read from stdin, one per line
+    pub from_stdin: bool,
+    /// Whether `--query` picks values out of the output
+    pub json_query: bool,
+    /// When to look up scheduled departures, from `timetable --at`
+    pub at: Option<PrimitiveDateTime>,
+    /// First and last day the offline timetable covers, once it is open
+    pub coverage: Option<(Date, Date)>,
+}
+
+impl Query {
+    /// How many results a command shows. An explicit `--limit` always wins.
+    /// Otherwise text output is sized to fit the terminal when writing to
+    /// one, and everything else gets all results. Limits apply after every
+    /// filter, so `--limit 5` always means five rows when there are five to
+    /// show.
+    pub fn result_limit(&self) -> usize {
+        self.limit
+            .or_else(|| match self.format {
+                OutputFormat::Text => board_rows(),
+                OutputFormat::Hass => Some(HASS_ROWS),
+                OutputFormat::Waybar => Some(WAYBAR_ROWS),
+                OutputFormat::Statusbar => Some(STATUSBAR_ROWS),
+                _ => None,
+            })
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Whether the command prints JSON, which `--query` can pick from
+    pub fn prints_json(&self) -> bool {
+        matches!(
+            self.format,
+            OutputFormat::Json | OutputFormat::Geojson | OutputFormat::Hass
+        )
+    }
+
+    /// The columns of a departure board in text output, in order. The
+    /// journey id is added at the end with `--journey-ids` unless
+    /// `--columns` already has it.
+    pub fn board_columns(&self) -> Vec<Column> {
+        let mut columns = self
+            .columns
+            .clone()
+            .unwrap_or_else(|| Column::DEFAULT.to_vec());
+        if self.journey_ids && !columns.contains(&Column::Journey) {
+            columns.push(Column::Journey);
+        }
+        columns
+    }
+
+    /// Check the query for values and flag combinations that can never
+    /// produce a meaningful result.
+    pub fn validate(&self) -> Result<()> {
+        if self.raw
+            && (self.limit.is_some()
+                || self.line.is_some()
+                || self.destination.is_some()
+                || self.transport_mode.is_some()
+                || self.operator.is_some()
+                || self.walk_minutes.is_some())
+        {
+            bail!(
+                "--raw prints the API response untouched and can't be combined with filters or --limit"
+            );
+        }
+
+        if self.limit == Some(0) {
+            bail!("the result limit must be at least 1");
+        }
+
+        if let Some(line) = &self.line
+            && line.trim().is_empty()
+        {
+            bail!("--line cannot be empty");
+        }
+
+        if let Some(destination) = &self.destination
+            && destination.trim().is_empty()
+        {
+            bail!("--destination cannot be empty");
+        }
+
+        if let Some((lat, lon)) = self.position {
+            if !(-90.0..=90.0).contains(&lat) {
+                bail!("latitude {lat} is out of range, expected a value between -90 and 90");
+            }
+            if !(-180.0..=180.0).contains(&lon) {
+                bail!("longitude {lon} is out of range, expected a value between -180 and 180");
+            }
+        }
+
+        if let Some(minutes) = self.walk_minutes {
+            if minutes < 0 {
+                bail!("--walk-minutes cannot be negative, got {minutes}");
+            }
+            if minutes > MAX_WALK_MINUTES {
+                bail!(
+                    "--walk-minutes {minutes} is longer than departure boards reach, use at most {MAX_WALK_MINUTES}"
+                );
+            }
+        }
+
+        if let Some(lead_time) = self.lead_time
+            && !(Duration::ZERO..=Duration::minutes(MAX_WALK_MINUTES)).contains(&lead_time)
+        {
+            bail!(
+                "the lead time must be between 0 and {MAX_WALK_MINUTES} minutes, got {lead_time}"
+            );
+        }
+
+        if let Some(seconds) = self.poll_seconds
+            && seconds < MIN_POLL_SECONDS
+        {
+            bail!("--interval must be at least {MIN_POLL_SECONDS} seconds, got {seconds}");
+        }
+
+        if self.poll_seconds.is_some()
+            && !self.streaming
+            && !matches!(self.format, OutputFormat::Text | OutputFormat::Ndjson)
+        {
+            bail!(
+                "--watch repeats the whole output on every refresh, use text output or --format ndjson"
+            );
+        }
+
+        if self.from_stdin && (self.raw || self.poll_seconds.is_some()) {
+            bail!("--raw and --watch take one station, not a list from stdin");
+        }
+
+        if self.json_query && !self.prints_json() {
+            bail!("--query picks from JSON output, use it with --format json");
+        }
+
+        if let (Some(at), Some((first, last))) = (self.at, self.coverage)
+            && !(first..=last).contains(&at.date())
+        {
+            bail!(
+                "--at {} is outside the offline timetable, which covers {first} to {last}. Run `slq gtfs update` for a newer one",
+                at.date()
+            );
+        }
+
+        if let Some(days) = self.days
+            && !(1..=MAX_PLANNED_DAYS).contains(&days)
+        {
+            bail!("--days must be between 1 and {MAX_PLANNED_DAYS}, got {days}");
+        }
+
+        if self.columns.is_some()
+            && !matches!(
+                self.format,
+                OutputFormat::Text
+                    | OutputFormat::Statusbar
+                    | OutputFormat::Html
+                    | OutputFormat::Markdown
+            )
+        {
+            bail!(
+                "--columns only applies to text output and the statusbar, html and markdown formats"
+            );
+        }
+
+        if let Some(width) = self.max_width {
+            if self.format != OutputFormat::Statusbar {
+                bail!("--max-width only applies to --format statusbar");
+            }
+            if width == 0 {
+                bail!("--max-width must be at least 1");
+            }
+        }
+
+        if let Some(heartbeat) = self.heartbeat {
+            if !self.streaming {
+                bail!("--heartbeat only applies to streaming output");
+            }
+            if heartbeat <= Duration::ZERO {
+                bail!("--heartbeat must be longer than zero, got {heartbeat}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{date, datetime};
+
+    #[test]
+    fn default_query_is_valid() {
+        assert!(Query::default().validate().is_ok());
+    }
+
+    #[test]
+    fn zero_limit_is_rejected() {
+        let query = Query {
+            limit: Some(0),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+    }
+
+    #[test]
+    fn explicit_limit_wins_and_machine_formats_are_unlimited() {
+        let query = Query {
+            limit: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(4, query.result_limit());
+
+        let query = Query {
+            format: OutputFormat::Json,
+            ..Default::default()
+        };
+        assert_eq!(usize::MAX, query.result_limit());
+    }
+
+    #[test]
+    fn raw_responses_cannot_be_filtered() {
+        let query = Query {
+            raw: true,
+            line: Some("14".to_string()),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            raw: true,
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn blank_line_is_rejected() {
+        let query = Query {
+            line: Some("  ".to_string()),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+    }
+
+    #[test]
+    fn negative_or_huge_walk_minutes_are_rejected() {
+        for minutes in [-5, MAX_WALK_MINUTES + 1] {
+            let query = Query {
+                walk_minutes: Some(minutes),
+                ..Default::default()
+            };
+            assert!(query.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn impatient_polling_is_rejected() {
+        let query = Query {
+            poll_seconds: Some(MIN_POLL_SECONDS - 1),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+    }
+
+    #[test]
+    fn out_of_range_coordinates_are_rejected() {
+        let query = Query {
+            position: Some((91.0, 18.0)),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            position: Some((59.3, 181.0)),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            position: Some((59.3, 18.0)),
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn heartbeat_needs_a_stream() {
+        let query = Query {
+            heartbeat: Some(Duration::seconds(30)),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            heartbeat: Some(Duration::ZERO),
+            streaming: true,
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            heartbeat: Some(Duration::seconds(30)),
+            streaming: true,
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn watching_needs_text_or_a_stream() {
+        let query = Query {
+            poll_seconds: Some(30),
+            format: OutputFormat::Csv,
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            poll_seconds: Some(30),
+            format: OutputFormat::Json,
+            streaming: true,
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn stdin_takes_neither_raw_nor_watch() {
+        let query = Query {
+            from_stdin: true,
+            raw: true,
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            from_stdin: true,
+            poll_seconds: Some(30),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+    }
+
+    #[test]
+    fn json_query_needs_json_output() {
+        let query = Query {
+            json_query: true,
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            json_query: true,
+            format: OutputFormat::Hass,
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn times_outside_the_timetable_are_rejected() {
+        let coverage = Some((date!(2025 - 05 - 01), date!(2025 - 05 - 31)));
+        let query = Query {
+            at: Some(datetime!(2025-06-02 08:30)),
+            coverage,
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            at: Some(datetime!(2025-05-31 23:45)),
+            coverage,
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn max_width_needs_the_statusbar_format() {
+        let query = Query {
+            max_width: Some(30),
+            ..Default::default()
+        };
+        assert!(query.validate().is_err());
+
+        let query = Query {
+            format: OutputFormat::Statusbar,
+            max_width: Some(30),
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn picked_columns_keep_their_order_and_journey_ids_still_apply() {
+        let query = Query {
+            columns: Some(vec![Column::Line, Column::Wait]),
+            journey_ids: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![Column::Line, Column::Wait, Column::Journey],
+            query.board_columns()
+        );
+        assert!(query.validate().is_ok());
+
+        let json = Query {
+            format: OutputFormat::Json,
+            ..query
+        };
+        assert!(json.validate().is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Departure questions in plain words, like `next 4 to slussen from
+//! odenplan`, for `slq q`. Only a handful of patterns are understood, in
+//! English and Swedish:
+//!
+//! - `from` / `at` / `från` / `vid` and a station, the board to look at
+//! - `to` / `towards` / `till` / `mot` and a destination
+//! - `next` / `nästa` with an optional count
+//! - `line` / `linje` and a line, or a bare line number like `43`
+//! - a transport mode like `bus` or `tunnelbana`, optionally with its line
+//!
+//! Without `from`, whatever words are left over name the station.
+
+use std::str::FromStr;
+
+use clap::ValueEnum;
+
+use crate::types::TransportMode;
+
+/// What `slq q` was asked, as a departure board query
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Question {
+    pub station: String,
+    pub destination: Option<String>,
+    pub limit: Option<usize>,
+    pub line: Option<String>,
+    pub transport_mode: Option<TransportMode>,
+}
+
+impl Question {
+    /// The same query with `slq departures` flags, to learn them from
+    pub fn command(&self) -> String {
+        let mut words = vec!["slq departures".to_string(), quoted(&self.station)];
+        if let Some(line) = &self.line {
+            words.push(format!("--line {line}"));
+        }
+        if let Some(mode) = self.transport_mode {
+            let mode = mode.to_possible_value().expect("no mode is skipped");
+            words.push(format!("--transport-mode {}", mode.get_name()));
+        }
+        if let Some(destination) = &self.destination {
+            words.push(format!("--destination {}", quoted(destination)));
+        }
+        if let Some(limit) = self.limit {
+            words.push(format!("--limit {limit}"));
+        }
+        words.join(" ")
+    }
+}
+
+fn quoted(text: &str) -> String {
+    if text.contains(char::is_whitespace) {
+        format!("'{text}'")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Where the words that follow a keyword end up
+#[derive(Clone, Copy, PartialEq)]
+enum Slot {
+    Station,
+    Destination,
+    Leftover,
+}
+
+impl FromStr for Question {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let mut question = Question::default();
+        let (mut station, mut destination, mut leftover) = (Vec::new(), Vec::new(), Vec::new());
+        let mut slot = Slot::Leftover;
+        let mut i = 0;
+        while i < words.len() {
+            let word = words[i];
+            let next = words.get(i + 1).copied();
+            i += 1;
+            match word.to_lowercase().as_str() {
+                "from" | "at" | "från" | "vid" => slot = Slot::Station,
+                "to" | "towards" | "till" | "mot" => slot = Slot::Destination,
+                "next" | "nästa" => {
+                    slot = Slot::Leftover;
+                    if let Some(count) = next.and_then(|next| next.parse::<usize>().ok()) {
+                        if count == 0 {
+                            return Err("ask for at least one departure".to_string());
+                        }
+                        question.limit = Some(count);
+                        i += 1;
+                    } else if question.limit.is_none() {
+                        question.limit = Some(1);
+                    }
+                }
+                "line" | "linje" => {
+                    slot = Slot::Leftover;
+                    let Some(line) = next else {
+                        return Err("say which line, e.g. `line 43`".to_string());
+                    };
+                    question.line = Some(line.to_string());
+                    i += 1;
+                }
+                lower => {
+                    if let Some(mode) = transport_mode(lower) {
+                        question.transport_mode = Some(mode);
+                        if let Some(line) = next.filter(|next| is_line(next)) {
+                            question.line = Some(line.to_string());
+                            i += 1;
+                        }
+                        slot = Slot::Leftover;
+                    } else if slot == Slot::Leftover && is_line(word) {
+                        question.line = Some(word.to_string());
+                    } else {
+                        match slot {
+                            Slot::Station => station.push(word),
+                            Slot::Destination => destination.push(word),
+                            Slot::Leftover => leftover.push(word),
+                        }
+                    }
+                }
+            }
+        }
+
+        if !destination.is_empty() {
+            question.destination = Some(destination.join(" "));
+        }
+        question.station = match (station.is_empty(), leftover.is_empty()) {
+            (false, true) => station.join(" "),
+            (true, false) => leftover.join(" "),
+            (false, false) => {
+                return Err(format!(
+                    "'{}' isn't part of the question, ask like `next 4 to Slussen from Odenplan`",
+                    leftover.join(" ")
+                ));
+            }
+            (true, true) => {
+                return Err("say which station, e.g. `next 4 to Slussen from Odenplan`".to_string());
+            }
+        };
+        Ok(question)
+    }
+}
+
+/// A transport mode by one of the names people call it, singular or plural
+fn transport_mode(word: &str) -> Option<TransportMode> {
+    let mode = match word {
+        "bus" | "buses" | "buss" | "bussar" => TransportMode::Bus,
+        "metro" | "metros" | "subway" | "tube" | "tunnelbana" | "tunnelbanor" | "t-bana"
+        | "t-banor" => TransportMode::Metro,
+        "train" | "trains" | "tåg" | "pendeltåg" => TransportMode::Train,
+        "tram" | "trams" | "spårvagn" | "spårvagnar" => TransportMode::Tram,
+        "ferry" | "ferries" | "boat" | "boats" | "båt" | "båtar" | "färja" | "färjor" => {
+            TransportMode::Ferry
+        }
+        _ => return None,
+    };
+    Some(mode)
+}
+
+/// Whether a word looks like a line, e.g. `43`, `28s` or `4`
+fn is_line(word: &str) -> bool {
+    let digits = word.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    !digits.is_empty()
+        && digits.len() <= 3
+        && digits.chars().all(|c| c.is_ascii_digit())
+        && word.len() - digits.len() <= 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ask(text: &str) -> Question {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn the_example_from_the_help_is_understood() {
+        assert_eq!(
+            Question {
+                station: "odenplan".to_string(),
+                destination: Some("slussen".to_string()),
+                limit: Some(4),
+                ..Question::default()
+            },
+            ask("next 4 to slussen from odenplan")
+        );
+    }
+
+    #[test]
+    fn stations_and_destinations_can_be_several_words() {
+        let question = ask("from Gamla stan towards Mörby centrum");
+        assert_eq!("Gamla stan", question.station);
+        assert_eq!(Some("Mörby centrum".to_string()), question.destination);
+        assert_eq!(None, question.limit);
+    }
+
+    #[test]
+    fn lines_and_modes_are_picked_out() {
+        let question = ask("next bus 43 at Slussen");
+        assert_eq!(Some(TransportMode::Bus), question.transport_mode);
+        assert_eq!(Some("43".to_string()), question.line);
+        assert_eq!(Some(1), question.limit);
+        assert_eq!("Slussen", question.station);
+
+        let question = ask("28s Stockholms östra");
+        assert_eq!(Some("28s".to_string()), question.line);
+        assert_eq!("Stockholms östra", question.station);
+
+        let question = ask("nästa 3 tunnelbanor från Odenplan till Skarpnäck");
+        assert_eq!(Some(TransportMode::Metro), question.transport_mode);
+        assert_eq!(Some(3), question.limit);
+        assert_eq!(Some("Skarpnäck".to_string()), question.destination);
+    }
+
+    #[test]
+    fn questions_without_a_station_are_refused() {
+        assert!("next 4 to slussen".parse::<Question>().is_err());
+        assert!("hello from Slussen".parse::<Question>().is_err());
+        assert!("next 0 from Slussen".parse::<Question>().is_err());
+    }
+
+    #[test]
+    fn the_command_spells_out_the_flags() {
+        assert_eq!(
+            "slq departures 'Gamla stan' --line 43 --transport-mode bus --destination Slussen --limit 2",
+            ask("next 2 buses 43 to Slussen from Gamla stan").command()
+        );
+    }
+}
@@ -0,0 +1,491 @@
+//! Live vehicle positions from Trafiklab's GTFS Regional Realtime feeds.
+//!
+//! The feeds only carry trip ids, so vehicles are matched to lines and stops
+//! through the offline timetable from [`crate::gtfs`].
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use prost::Message;
+use serde::Serialize;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
+
+use crate::client::SlClient;
+use crate::dates::sl_time;
+use crate::gtfs::{Timetable, Trip, TripStop};
+
+/// Where every vehicle in SL's fleet is. Needs a key for "GTFS Regional
+/// Realtime" from <https://www.trafiklab.se>.
+pub const VEHICLE_POSITIONS_URL: &str =
+    "https://opendata.samtrafiken.se/gtfs-rt/sl/VehiclePositions.pb";
+
+/// Predicted delays for every running trip, same key as the positions.
+pub const TRIP_UPDATES_URL: &str = "https://opendata.samtrafiken.se/gtfs-rt/sl/TripUpdates.pb";
+
+/// A vehicle running on a line, matched against the timetable
+pub struct Vehicle {
+    /// The label shown on the vehicle, or its id when it has none
+    pub id: String,
+    pub trip_id: String,
+    pub trip: Trip,
+    pub lat: f64,
+    pub lon: f64,
+    /// Degrees clockwise from north
+    pub bearing: Option<f64>,
+    /// Name of and distance in meters to the closest stop
+    pub nearest_stop: Option<(String, f64)>,
+    /// Seconds behind the timetable, negative when early
+    pub delay: Option<i64>,
+    /// When the position was measured
+    pub timestamp: Option<OffsetDateTime>,
+    /// How full the vehicle is, when it reports it
+    pub crowding: Option<Crowding>,
+}
+
+/// How full a vehicle is, from the GTFS realtime occupancy status with the
+/// finer steps folded together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Crowding {
+    ManySeats,
+    FewSeats,
+    StandingOnly,
+    Full,
+}
+
+impl Crowding {
+    /// Read an `OccupancyStatus`. Statuses that say nothing about how full
+    /// the vehicle is, like `NO_DATA_AVAILABLE`, are `None`.
+    pub fn from_occupancy_status(status: i32) -> Option<Crowding> {
+        match status {
+            0 | 1 => Some(Crowding::ManySeats),
+            2 => Some(Crowding::FewSeats),
+            3 | 4 => Some(Crowding::StandingOnly),
+            5 | 6 => Some(Crowding::Full),
+            _ => None,
+        }
+    }
+
+    /// A short bar for tables, fuller the more crowded the vehicle is
+    pub fn indicator(self) -> &'static str {
+        match self {
+            Crowding::ManySeats => "●○○○",
+            Crowding::FewSeats => "●●○○",
+            Crowding::StandingOnly => "●●●○",
+            Crowding::Full => "●●●●",
+        }
+    }
+}
+
+/// The same names as in JSON, for CSV
+impl std::fmt::Display for Crowding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Crowding::ManySeats => "many_seats",
+            Crowding::FewSeats => "few_seats",
+            Crowding::StandingOnly => "standing_only",
+            Crowding::Full => "full",
+        })
+    }
+}
+
+/// Download and decode one of the realtime feeds.
+pub fn fetch(client: &SlClient, url: &str, key: &str) -> Result<FeedMessage> {
+    let bytes = client
+        .http()
+        .get(url)
+        .query(&[("key", key)])
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|res| res.bytes())
+        // The URL carries the API key, keep it out of error messages
+        .map_err(|err| err.without_url())
+        .context("failed to download the GTFS realtime feed from Trafiklab")?;
+    FeedMessage::decode(bytes).context("the GTFS realtime feed could not be decoded")
+}
+
+/// Vehicles currently running trips of `line`, ordered by destination.
+pub fn vehicles_on_line(
+    timetable: &Timetable,
+    positions: &FeedMessage,
+    updates: &FeedMessage,
+    line: &str,
+) -> Result<Vec<Vehicle>> {
+    let trips = timetable.trips_on_line(line)?;
+    let mut vehicles = Vec::new();
+    for position in positions.entity.iter().filter_map(|e| e.vehicle.as_ref()) {
+        let (Some(trip_id), Some(point)) = (
+            position.trip.as_ref().and_then(|t| t.trip_id.as_ref()),
+            &position.position,
+        ) else {
+            continue;
+        };
+        let Some(trip) = trips.get(trip_id) else {
+            continue;
+        };
+
+        let descriptor = position.vehicle.as_ref();
+        let id = descriptor
+            .and_then(|v| v.label.clone().or_else(|| v.id.clone()))
+            .unwrap_or_else(|| trip_id.clone());
+        let (lat, lon) = (f64::from(point.latitude), f64::from(point.longitude));
+        let delay = trip_update(updates, trip_id)
+            .and_then(|update| delay_at(update, position.current_stop_sequence));
+
+        vehicles.push(Vehicle {
+            id,
+            trip_id: trip_id.clone(),
+            trip: trip.clone(),
+            lat,
+            lon,
+            bearing: point.bearing.map(f64::from),
+            nearest_stop: timetable.nearest_stop(lat, lon)?,
+            delay,
+            timestamp: position
+                .timestamp
+                .and_then(|at| OffsetDateTime::from_unix_timestamp(at as i64).ok()),
+            crowding: position.crowding(),
+        });
+    }
+
+    vehicles.sort_by(|a, b| {
+        a.trip
+            .headsign
+            .cmp(&b.trip.headsign)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    Ok(vehicles)
+}
+
+/// How full the vehicle running each trip is, for the trips whose vehicles
+/// report it.
+pub fn crowding_by_trip(positions: &FeedMessage) -> HashMap<String, Crowding> {
+    positions
+        .entity
+        .iter()
+        .filter_map(|e| e.vehicle.as_ref())
+        .filter_map(|position| {
+            let trip_id = position.trip.as_ref()?.trip_id.clone()?;
+            Some((trip_id, position.crowding()?))
+        })
+        .collect()
+}
+
+impl VehiclePosition {
+    /// How full the vehicle is, if it says
+    pub fn crowding(&self) -> Option<Crowding> {
+        self.occupancy_status
+            .and_then(Crowding::from_occupancy_status)
+    }
+}
+
+/// The predictions for a trip, if the feed has any
+pub fn trip_update<'a>(feed: &'a FeedMessage, trip_id: &str) -> Option<&'a TripUpdate> {
+    feed.entity
+        .iter()
+        .filter_map(|e| e.trip_update.as_ref())
+        .find(|update| update.trip.trip_id.as_deref() == Some(trip_id))
+}
+
+impl TripUpdate {
+    /// The day the trip's service runs on, which for trips past midnight is
+    /// the day before
+    pub fn service_day(&self) -> Option<Date> {
+        let start_date = self.trip.start_date.as_deref()?;
+        Date::parse(start_date, format_description!("[year][month][day]")).ok()
+    }
+
+    /// Seconds behind the timetable at each of `stops`, where the feed
+    /// predicts anything. A prediction holds for the stops after it until
+    /// the next one, as the GTFS realtime spec has it.
+    pub fn delays(&self, stops: &[TripStop]) -> Vec<Option<i64>> {
+        let mut delay = None;
+        stops
+            .iter()
+            .map(|stop| {
+                let update = self.stop_time_update.iter().find(|update| {
+                    update.stop_sequence == Some(stop.sequence)
+                        || (update.stop_sequence.is_none()
+                            && update.stop_id.as_deref() == Some(stop.stop_id.as_str()))
+                });
+                let event =
+                    update.and_then(|update| update.departure.as_ref().or(update.arrival.as_ref()));
+                if let Some(event) = event {
+                    let scheduled = sl_time(stop.scheduled).unix_timestamp();
+                    delay = event
+                        .delay
+                        .map(i64::from)
+                        .or(event.time.map(|time| time - scheduled))
+                        .or(delay);
+                }
+                delay
+            })
+            .collect()
+    }
+}
+
+/// The delay predicted for the next stop the vehicle reaches, or for the
+/// whole trip when the feed has no stop level predictions.
+fn delay_at(update: &TripUpdate, current_sequence: Option<u32>) -> Option<i64> {
+    update
+        .stop_time_update
+        .iter()
+        .filter(|stop| match (stop.stop_sequence, current_sequence) {
+            (Some(sequence), Some(current)) => sequence >= current,
+            _ => true,
+        })
+        .find_map(|stop| {
+            stop.arrival
+                .as_ref()
+                .or(stop.departure.as_ref())
+                .and_then(|event| event.delay)
+        })
+        .or(update.delay)
+        .map(i64::from)
+}
+
+// The parts of gtfs-realtime.proto slq reads, see
+// <https://gtfs.org/documentation/realtime/proto/>. Fields not listed here
+// are skipped while decoding.
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedMessage {
+    #[prost(message, required, tag = "1")]
+    pub header: FeedHeader,
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedHeader {
+    #[prost(string, required, tag = "1")]
+    pub gtfs_realtime_version: String,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FeedEntity {
+    #[prost(string, required, tag = "1")]
+    pub id: String,
+    #[prost(message, optional, tag = "3")]
+    pub trip_update: Option<TripUpdate>,
+    #[prost(message, optional, tag = "4")]
+    pub vehicle: Option<VehiclePosition>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripUpdate {
+    #[prost(message, required, tag = "1")]
+    pub trip: TripDescriptor,
+    #[prost(message, repeated, tag = "2")]
+    pub stop_time_update: Vec<StopTimeUpdate>,
+    #[prost(int32, optional, tag = "5")]
+    pub delay: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub stop_sequence: Option<u32>,
+    #[prost(message, optional, tag = "2")]
+    pub arrival: Option<StopTimeEvent>,
+    #[prost(message, optional, tag = "3")]
+    pub departure: Option<StopTimeEvent>,
+    #[prost(string, optional, tag = "4")]
+    pub stop_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StopTimeEvent {
+    #[prost(int32, optional, tag = "1")]
+    pub delay: Option<i32>,
+    #[prost(int64, optional, tag = "2")]
+    pub time: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct VehiclePosition {
+    #[prost(message, optional, tag = "1")]
+    pub trip: Option<TripDescriptor>,
+    #[prost(message, optional, tag = "2")]
+    pub position: Option<Position>,
+    #[prost(uint32, optional, tag = "3")]
+    pub current_stop_sequence: Option<u32>,
+    #[prost(uint64, optional, tag = "5")]
+    pub timestamp: Option<u64>,
+    #[prost(string, optional, tag = "7")]
+    pub stop_id: Option<String>,
+    #[prost(message, optional, tag = "8")]
+    pub vehicle: Option<VehicleDescriptor>,
+    /// An `OccupancyStatus`, read through [`VehiclePosition::crowding`]
+    #[prost(int32, optional, tag = "9")]
+    pub occupancy_status: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    /// `YYYYMMDD`
+    #[prost(string, optional, tag = "3")]
+    pub start_date: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct VehicleDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub label: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Position {
+    #[prost(float, required, tag = "1")]
+    pub latitude: f32,
+    #[prost(float, required, tag = "2")]
+    pub longitude: f32,
+    #[prost(float, optional, tag = "3")]
+    pub bearing: Option<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{date, datetime};
+
+    fn stop(sequence: u32, delay: i32) -> StopTimeUpdate {
+        StopTimeUpdate {
+            stop_sequence: Some(sequence),
+            arrival: Some(StopTimeEvent {
+                delay: Some(delay),
+                time: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delay_is_taken_from_the_next_stop() {
+        let update = TripUpdate {
+            trip: TripDescriptor {
+                trip_id: Some("t1".to_string()),
+                ..Default::default()
+            },
+            stop_time_update: vec![stop(3, 60), stop(5, 180), stop(9, 240)],
+            delay: Some(30),
+        };
+        assert_eq!(Some(180), delay_at(&update, Some(4)));
+        assert_eq!(Some(60), delay_at(&update, None));
+        assert_eq!(Some(30), delay_at(&update, Some(10)));
+    }
+
+    #[test]
+    fn delays_carry_over_to_later_stops() {
+        let trip_stop = |sequence: u32, scheduled| TripStop {
+            sequence,
+            stop_id: format!("s{sequence}"),
+            name: format!("Stop {sequence}"),
+            scheduled,
+            wheelchair_boarding: None,
+        };
+        let stops = vec![
+            trip_stop(1, datetime!(2025-05-08 08:00)),
+            trip_stop(2, datetime!(2025-05-08 08:02)),
+            trip_stop(3, datetime!(2025-05-08 08:05)),
+            trip_stop(4, datetime!(2025-05-08 08:09)),
+        ];
+        let at_time = StopTimeUpdate {
+            stop_sequence: Some(4),
+            departure: Some(StopTimeEvent {
+                delay: None,
+                time: Some(datetime!(2025-05-08 08:10 +02:00).unix_timestamp()),
+            }),
+            ..Default::default()
+        };
+        let update = TripUpdate {
+            trip: TripDescriptor {
+                trip_id: Some("t1".to_string()),
+                start_date: Some("20250508".to_string()),
+                ..Default::default()
+            },
+            stop_time_update: vec![stop(2, 120), at_time],
+            delay: None,
+        };
+
+        assert_eq!(Some(date!(2025 - 05 - 08)), update.service_day());
+        assert_eq!(
+            vec![None, Some(120), Some(120), Some(60)],
+            update.delays(&stops)
+        );
+    }
+
+    #[test]
+    fn crowding_is_read_per_trip() {
+        let position = |trip_id: &str, occupancy_status| FeedEntity {
+            id: trip_id.to_string(),
+            trip_update: None,
+            vehicle: Some(VehiclePosition {
+                trip: Some(TripDescriptor {
+                    trip_id: Some(trip_id.to_string()),
+                    ..Default::default()
+                }),
+                occupancy_status,
+                ..Default::default()
+            }),
+        };
+        let feed = FeedMessage {
+            header: FeedHeader::default(),
+            entity: vec![
+                position("t1", Some(0)),
+                position("t2", Some(4)),
+                position("t3", Some(7)),
+                position("t4", None),
+            ],
+        };
+        let decoded = FeedMessage::decode(feed.encode_to_vec().as_slice()).unwrap();
+
+        let crowding = crowding_by_trip(&decoded);
+        assert_eq!(Some(&Crowding::ManySeats), crowding.get("t1"));
+        assert_eq!(Some(&Crowding::StandingOnly), crowding.get("t2"));
+        assert_eq!(2, crowding.len());
+    }
+
+    #[test]
+    fn feeds_decode_and_skip_unknown_fields() {
+        let feed = FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".to_string(),
+                timestamp: Some(1_746_690_000),
+            },
+            entity: vec![FeedEntity {
+                id: "1".to_string(),
+                trip_update: None,
+                vehicle: Some(VehiclePosition {
+                    trip: Some(TripDescriptor {
+                        trip_id: Some("t1".to_string()),
+                        ..Default::default()
+                    }),
+                    position: Some(Position {
+                        latitude: 59.32,
+                        longitude: 18.07,
+                        bearing: Some(90.0),
+                    }),
+                    ..Default::default()
+                }),
+            }],
+        };
+        let mut bytes = feed.encode_to_vec();
+        // An alert (field 5) on top, which slq doesn't read
+        bytes.extend_from_slice(&[0x12, 0x07, 0x0a, 0x01, b'2', 0x2a, 0x02, 0x08, 0x01]);
+
+        let decoded = FeedMessage::decode(bytes.as_slice()).unwrap();
+        assert_eq!(2, decoded.entity.len());
+        assert_eq!(feed.entity[0], decoded.entity[0]);
+        assert!(decoded.entity[1].vehicle.is_none());
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, bail};
+
+/// Restrictions for deployments that run slq with configuration files the
+/// person running it doesn't control, such as a shared system wide config.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sandbox {
+    /// Refuse to send data anywhere, e.g. notifications to ntfy, Pushover or
+    /// Gotify. Reading from the SL APIs is unaffected.
+    pub no_net_write: bool,
+    /// Never start another program. The only one slq starts is the pager.
+    pub no_exec: bool,
+}
+
+impl Sandbox {
+    fn is_active(&self) -> bool {
+        self.no_net_write || self.no_exec
+    }
+}
+
+static SANDBOX: OnceLock<Sandbox> = OnceLock::new();
+
+/// Set the restrictions for this process. Only the first call has an effect.
+pub fn init_sandbox(sandbox: Sandbox) {
+    let _ = SANDBOX.set(sandbox);
+}
+
+/// Fail when the sandbox doesn't allow sending `what` over the network.
+pub fn ensure_net_write(what: &str) -> Result<()> {
+    if SANDBOX.get().is_some_and(|sandbox| sandbox.no_net_write) {
+        bail!("refusing to send {what}, network writes are disabled by --no-net-write");
+    }
+    Ok(())
+}
+
+/// Fail when the sandbox is on and the config file at `path` can be changed
+/// by any user on the machine, since its settings can't be trusted then.
+pub fn ensure_trusted_config(path: &Path) -> Result<()> {
+    if !SANDBOX.get().is_some_and(Sandbox::is_active) {
+        return Ok(());
+    }
+    let writable = world_writable(path)
+        .with_context(|| format!("failed to check the permissions of {}", path.display()))?;
+    if writable {
+        bail!(
+            "refusing to read {}, every user can write to it. Run `chmod o-w` on it to use it \
+             with --no-exec or --no-net-write",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn world_writable(path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(path.metadata()?.permissions().mode() & 0o002 != 0)
+}
+
+#[cfg(not(unix))]
+fn world_writable(_path: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,644 @@
+//! `slq serve`: a small JSON API in front of one [`SlClient`], so several
+//! machines share its cache and rate limit instead of each polling SL.
+//!
+//! - `GET /sites/:id/departures?line=&destination=&transport_mode=&limit=`,
+//!   with `format=hass` for a Home Assistant sensor
+//! - `GET /search?q=&limit=`
+//! - `GET /sites/:id/departures/stream`, the same departures as server-sent
+//!   events whenever they change, polling every `interval` seconds
+//! - `GET /board/:id`, an auto-refreshing HTML departure board taking the
+//!   same parameters as the departures, and `refresh` in seconds
+
+use std::fmt::Write as _;
+use std::io::{self, Cursor, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use reqwest::Url;
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::debug;
+
+use crate::client::{ClientError, Departure, DepartureFilter, Freshness, SlClient};
+use crate::clock::{Clock, SystemClock};
+use crate::dates::Timezone;
+use crate::notice;
+use crate::output::{HASS_ROWS, escape_markup, hass_sensor};
+use crate::query::{MIN_POLL_SECONDS, Query};
+use crate::types::{SiteId, TransportMode};
+
+/// Requests handled at the same time. Every request that misses the cache
+/// waits for the client's rate limit anyway, so a few are plenty.
+const WORKERS: usize = 4;
+
+/// Rows on an HTML board unless `limit` says otherwise, about what a
+/// landscape tablet fits at the board's font size
+const BOARD_ROWS: usize = 12;
+
+/// Seconds between reloads of an HTML board unless `refresh` says otherwise
+const BOARD_REFRESH_SECONDS: u64 = 30;
+
+/// Seconds between polls for an event stream unless `interval` says
+/// otherwise. Departure boards are cached for as long, so polling faster
+/// would only send the same board again.
+const STREAM_POLL_SECONDS: u64 = 15;
+
+/// Event streams open at the same time. Each one holds a thread and polls
+/// SL on its own, so this keeps a forgotten browser tab farm in check.
+const MAX_STREAMS: usize = 32;
+/// How long an event stream may be quiet before it gets a heartbeat, unless
+/// `--heartbeat` says otherwise. Writing is the only way to notice a client
+/// has gone away, and free its stream.
+const STREAM_HEARTBEAT: Duration = Duration::from_secs(20);
+
+/// A response before it is turned into HTTP
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    pub status: u16,
+    pub body: Body,
+    /// Extra headers, e.g. to mark a stale departure board
+    pub headers: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Body {
+    Json(Value),
+    Html(String),
+    /// Keep the connection open and push departures as they change
+    Stream(Box<DepartureStream>),
+}
+
+/// What a client subscribed to on `/sites/:id/departures/stream`
+#[derive(Debug, PartialEq)]
+pub struct DepartureStream {
+    pub site_id: SiteId,
+    pub query: Query,
+    pub interval: Duration,
+}
+
+impl Reply {
+    fn ok(body: Value) -> Reply {
+        Reply {
+            status: 200,
+            body: Body::Json(body),
+            headers: Vec::new(),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Reply {
+        Reply {
+            status,
+            body: Body::Json(json!({ "error": message.into() })),
+            headers: Vec::new(),
+        }
+    }
+}
+
+/// Listen on `addr` and answer requests until the process is stopped.
+/// Event streams that have been quiet for `heartbeat`, 20 seconds unless
+/// given, get a comment line, so proxies don't take them for dead
+/// connections and clients that went away are noticed.
+pub fn serve(client: &SlClient, addr: SocketAddr, heartbeat: Option<Duration>) -> Result<()> {
+    let heartbeat = heartbeat.unwrap_or(STREAM_HEARTBEAT);
+    let server = Server::http(addr).map_err(|err| anyhow!("failed to listen on {addr}: {err}"))?;
+    notice!("Listening on http://{addr}");
+
+    let open_streams = AtomicUsize::new(0);
+    let open_streams = &open_streams;
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| {
+                while let Ok(request) = server.recv() {
+                    let Some((request, stream)) = respond(client, request) else {
+                        continue;
+                    };
+                    if open_streams.fetch_add(1, Ordering::Relaxed) >= MAX_STREAMS {
+                        open_streams.fetch_sub(1, Ordering::Relaxed);
+                        send(request, Reply::error(503, "too many open event streams"));
+                        continue;
+                    }
+                    scope.spawn(move || {
+                        if let Err(err) = stream_departures(client, request, &stream, heartbeat) {
+                            debug!("event stream for site {} closed: {err}", stream.site_id);
+                        }
+                        open_streams.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Answer `request`, or hand it back when it asked for an event stream.
+fn respond(client: &SlClient, request: Request) -> Option<(Request, DepartureStream)> {
+    let reply = if *request.method() == Method::Get {
+        handle(client, request.url())
+    } else {
+        Reply::error(405, "only GET is supported")
+    };
+    debug!("{} {} -> {}", request.method(), request.url(), reply.status);
+
+    if let Body::Stream(stream) = reply.body {
+        return Some((request, *stream));
+    }
+    send(request, reply);
+    None
+}
+
+fn send(request: Request, reply: Reply) {
+    let (content_type, body) = match reply.body {
+        Body::Json(value) => ("application/json", value.to_string()),
+        Body::Html(html) => ("text/html; charset=utf-8", html),
+        Body::Stream(_) => unreachable!("streams are answered by stream_departures"),
+    };
+    let mut response = Response::new(
+        reply.status.into(),
+        Vec::new(),
+        Cursor::new(body.into_bytes()),
+        None,
+        None,
+    )
+    .with_header(header("Content-Type", content_type));
+    for (name, value) in &reply.headers {
+        response.add_header(header(name, value));
+    }
+    if let Err(err) = request.respond(response) {
+        debug!("failed to send the response: {err}");
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+/// Poll the board behind `stream` and send it as a `departures` event each
+/// time it changes, until the client goes away. Failed polls are sent as
+/// `error` events and retried on the next poll.
+fn stream_departures(
+    client: &SlClient,
+    request: Request,
+    stream: &DepartureStream,
+    heartbeat: Duration,
+) -> io::Result<()> {
+    let mut events = EventStream::open(request.into_writer())?;
+    let filter = DepartureFilter::default()
+        .line(stream.query.line.clone())
+        .count(stream.query.limit)
+        .mode(stream.query.transport_mode)
+        .destination(stream.query.destination.as_deref());
+    let mut last_sent = None;
+    let mut next_poll = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= next_poll {
+            let mut departures = Vec::new();
+            let event = client
+                .open_departures(stream.site_id)
+                .and_then(|board| board.for_each(&filter, |departure| departures.push(departure)))
+                .map_err(anyhow::Error::from)
+                .and_then(|()| Ok(("departures", serde_json::to_string(&departures)?)))
+                .unwrap_or_else(|err| {
+                    ("error", json!({ "error": format!("{err:#}") }).to_string())
+                });
+            if last_sent.as_ref() != Some(&event) {
+                events.event(event.0, &event.1)?;
+                last_sent = Some(event);
+            }
+            next_poll = now + stream.interval;
+        } else if events.idle() >= heartbeat {
+            events.heartbeat()?;
+        }
+
+        let wake = next_poll.min(events.last_write + heartbeat);
+        thread::sleep(wake.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Server-sent events on a raw connection. Every event is flushed on its
+/// own, so browsers and proxies see it as soon as it is written.
+struct EventStream<W: Write> {
+    writer: W,
+    last_write: Instant,
+}
+
+impl<W: Write> EventStream<W> {
+    /// Write the response head. The body has no length and ends when
+    /// either side closes the connection.
+    fn open(mut writer: W) -> io::Result<Self> {
+        writer.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Connection: close\r\n\r\n",
+        )?;
+        writer.flush()?;
+        Ok(EventStream {
+            writer,
+            last_write: Instant::now(),
+        })
+    }
+
+    /// Send `data`, which must be a single line such as compact JSON.
+    fn event(&mut self, name: &str, data: &str) -> io::Result<()> {
+        write!(self.writer, "event: {name}\ndata: {data}\n\n")?;
+        self.flush()
+    }
+
+    fn heartbeat(&mut self) -> io::Result<()> {
+        self.writer.write_all(b": heartbeat\n\n")?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    fn idle(&self) -> Duration {
+        self.last_write.elapsed()
+    }
+}
+
+/// Answer a GET request for `path`, which includes the query string.
+pub fn handle(client: &SlClient, path: &str) -> Reply {
+    let Ok(url) = Url::parse("http://slq").and_then(|base| base.join(path)) else {
+        return Reply::error(400, "malformed request path");
+    };
+    let params: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    let segments: Vec<&str> = url.path_segments().map_or(Vec::new(), Iterator::collect);
+
+    let result = match segments.as_slice() {
+        ["sites", id, "departures"] => departures(client, id, &param),
+        ["sites", id, "departures", "stream"] => Ok(departure_stream(id, &param)),
+        ["search"] => search(client, &param),
+        ["board", id] => board(client, id, &param),
+        _ => return Reply::error(404, format!("no such endpoint: {}", url.path())),
+    };
+    result.unwrap_or_else(|err| error_reply(&err))
+}
+
+fn departures(
+    client: &SlClient,
+    id: &str,
+    param: &dyn Fn(&str) -> Option<String>,
+) -> Result<Reply> {
+    let site_id = match id.parse::<SiteId>() {
+        Ok(site_id) => site_id,
+        Err(err) => return Ok(Reply::error(400, err)),
+    };
+    let hass = match param("format").as_deref() {
+        None | Some("json") => false,
+        Some("hass") => true,
+        Some(other) => return Ok(Reply::error(400, format!("unknown format '{other}'"))),
+    };
+    let mut query = match query(param) {
+        Ok(query) => query,
+        Err(err) => return Ok(Reply::error(400, err.to_string())),
+    };
+    if hass {
+        query.limit = query.limit.or(Some(HASS_ROWS));
+    }
+
+    let board = client.open_departures(site_id)?;
+    let stale = matches!(board.freshness, Freshness::Stale { .. });
+    let headers = match board.freshness {
+        Freshness::Fresh => Vec::new(),
+        // Same as HTTP caches do when serving a response they couldn't revalidate
+        Freshness::Stale { age } => vec![
+            ("Age", age.as_secs().to_string()),
+            ("Warning", "110 - \"Response is Stale\"".to_string()),
+        ],
+    };
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .count(query.limit)
+        .mode(query.transport_mode)
+        .destination(query.destination.as_deref());
+    let mut departures = Vec::new();
+    board.for_each(&filter, |departure| departures.push(departure))?;
+    let body = if hass {
+        let name = client
+            .get_site(site_id)?
+            .map_or_else(|| id.to_string(), |site| site.name);
+        hass_sensor(&name, &departures, stale, &SystemClock)
+    } else {
+        serde_json::to_value(departures)?
+    };
+    Ok(Reply {
+        status: 200,
+        body: Body::Json(body),
+        headers,
+    })
+}
+
+fn departure_stream(id: &str, param: &dyn Fn(&str) -> Option<String>) -> Reply {
+    let site_id = match id.parse::<SiteId>() {
+        Ok(site_id) => site_id,
+        Err(err) => return Reply::error(400, err),
+    };
+    let interval = match poll_seconds(param, "interval", STREAM_POLL_SECONDS) {
+        Ok(seconds) => seconds,
+        Err(err) => return Reply::error(400, err.to_string()),
+    };
+    let query = match query(param) {
+        Ok(query) => query,
+        Err(err) => return Reply::error(400, err.to_string()),
+    };
+    Reply {
+        status: 200,
+        body: Body::Stream(Box::new(DepartureStream {
+            site_id,
+            query,
+            interval: Duration::from_secs(interval),
+        })),
+        headers: Vec::new(),
+    }
+}
+
+fn board(client: &SlClient, id: &str, param: &dyn Fn(&str) -> Option<String>) -> Result<Reply> {
+    let site_id = match id.parse::<SiteId>() {
+        Ok(site_id) => site_id,
+        Err(err) => return Ok(Reply::error(400, err)),
+    };
+    let refresh = match poll_seconds(param, "refresh", BOARD_REFRESH_SECONDS) {
+        Ok(seconds) => seconds,
+        Err(err) => return Ok(Reply::error(400, err.to_string())),
+    };
+    let mut query = match query(param) {
+        Ok(query) => query,
+        Err(err) => return Ok(Reply::error(400, err.to_string())),
+    };
+    query.limit = query.limit.or(Some(BOARD_ROWS));
+
+    let Some(site) = client.get_site(site_id)? else {
+        return Ok(Reply::error(404, format!("site {site_id} was not found")));
+    };
+    let board = client.open_departures(site_id)?;
+    let stale = match board.freshness {
+        Freshness::Fresh => None,
+        Freshness::Stale { age } => Some(age),
+    };
+    let filter = DepartureFilter::default()
+        .line(query.line.clone())
+        .count(query.limit)
+        .mode(query.transport_mode)
+        .destination(query.destination.as_deref());
+    let mut departures = Vec::new();
+    board.for_each(&filter, |departure| departures.push(departure))?;
+
+    let html = render_board(&site.name, &departures, refresh, stale, &SystemClock);
+    Ok(Reply {
+        status: 200,
+        body: Body::Html(html),
+        headers: Vec::new(),
+    })
+}
+
+/// A full screen departure board that reloads itself every `refresh`
+/// seconds, for browsers on wall-mounted tablets and TVs.
+pub fn render_board(
+    name: &str,
+    departures: &[Departure],
+    refresh: u64,
+    stale: Option<Duration>,
+    clock: &dyn Clock,
+) -> String {
+    let now = clock.now().to_offset(clock.local_offset());
+    let name = escape_markup(name);
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{refresh}">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{name}</title>
+<style>
+body {{ background: #000; color: #f5c400; font: 5vmin/1.3 system-ui, sans-serif; margin: 2vmin 4vmin; }}
+h1 {{ font-size: 7vmin; margin: 0 0 2vmin; color: #fff; }}
+table {{ width: 100%; border-collapse: collapse; }}
+td {{ padding: 0.4vmin 1vmin; white-space: nowrap; }}
+td.destination {{ width: 100%; overflow: hidden; text-overflow: ellipsis; max-width: 0; }}
+td.wait {{ text-align: right; }}
+p {{ color: #888; font-size: 3vmin; }}
+</style>
+</head>
+<body>
+<h1>{name}</h1>
+"#
+    );
+    if let Some(age) = stale {
+        let _ = writeln!(
+            html,
+            "<p>SL could not be reached, departures as of {} minutes ago</p>",
+            age.as_secs() / 60
+        );
+    }
+    if departures.is_empty() {
+        html.push_str(
+            "<p>No departures right now</p>
+",
+        );
+    } else {
+        html.push_str(
+            "<table>
+",
+        );
+        for departure in departures {
+            let minutes = (departure.expected - now).whole_minutes();
+            let wait = if minutes < 1 {
+                "now".to_string()
+            } else {
+                format!("{minutes} min")
+            };
+            let expected = Timezone::sl().local(departure.expected);
+            let time = format!("{:02}:{:02}", expected.hour(), expected.minute());
+            let _ = writeln!(
+                html,
+                r#"<tr><td class="line">{}</td><td class="destination">{}</td><td class="time">{time}</td><td class="wait">{wait}</td></tr>"#,
+                escape_markup(&departure.line.designation),
+                escape_markup(&departure.destination),
+            );
+        }
+        html.push_str(
+            "</table>
+",
+        );
+    }
+    let _ = writeln!(
+        html,
+        "<p>Updated {:02}:{:02}</p>
+</body>
+</html>",
+        now.hour(),
+        now.minute()
+    );
+    html
+}
+
+fn search(client: &SlClient, param: &dyn Fn(&str) -> Option<String>) -> Result<Reply> {
+    let Some(q) = param("q").filter(|q| !q.trim().is_empty()) else {
+        return Ok(Reply::error(400, "missing search query, use ?q=<name>"));
+    };
+    let query = match query(param) {
+        Ok(query) => query,
+        Err(err) => return Ok(Reply::error(400, err.to_string())),
+    };
+    let mut sites = client.search_for_sites(&q)?;
+    sites.truncate(query.limit.unwrap_or(usize::MAX));
+    Ok(Reply::ok(serde_json::to_value(sites)?))
+}
+
+/// Read a number of seconds between polls from the `name` parameter.
+fn poll_seconds(param: &dyn Fn(&str) -> Option<String>, name: &str, default: u64) -> Result<u64> {
+    match param(name).map(|seconds| seconds.parse::<u64>()) {
+        None => Ok(default),
+        Some(Ok(seconds)) if seconds >= MIN_POLL_SECONDS => Ok(seconds),
+        Some(_) => Err(anyhow!(
+            "{name} must be at least {MIN_POLL_SECONDS} seconds"
+        )),
+    }
+}
+
+/// Lower the query string into a [`Query`], like the command line does.
+fn query(param: &dyn Fn(&str) -> Option<String>) -> Result<Query> {
+    let limit = param("limit")
+        .map(|limit| {
+            limit
+                .parse()
+                .map_err(|_| anyhow!("limit must be a number, got '{limit}'"))
+        })
+        .transpose()?;
+    let transport_mode = param("transport_mode")
+        .map(|mode| {
+            TransportMode::from_str(&mode, true)
+                .map_err(|_| anyhow!("unknown transport_mode '{mode}'"))
+        })
+        .transpose()?;
+    let query = Query {
+        limit,
+        line: param("line"),
+        destination: param("destination"),
+        transport_mode,
+        ..Default::default()
+    };
+    query.validate()?;
+    Ok(query)
+}
+
+fn error_reply(err: &anyhow::Error) -> Reply {
+    let status = match err.downcast_ref::<ClientError>() {
+        Some(ClientError::NotFound(_)) => 404,
+        Some(ClientError::Unavailable { .. } | ClientError::RateLimited) => 503,
+        Some(ClientError::Network(_)) => 504,
+        _ => 502,
+    };
+    Reply::error(status, format!("{err:#}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientSettings, Line};
+    use crate::clock::FixedClock;
+    use time::macros::datetime;
+
+    fn client() -> SlClient {
+        SlClient::new(ClientSettings::default()).expect("default settings should be usable")
+    }
+
+    #[test]
+    fn search_uses_the_site_directory() {
+        let reply = handle(&client(), "/search?q=t-centralen&limit=1");
+        assert_eq!(200, reply.status);
+        let Body::Json(body) = reply.body else {
+            panic!("search should answer with JSON");
+        };
+        let sites = body.as_array().unwrap();
+        assert_eq!(1, sites.len());
+        assert_eq!("T-Centralen", sites[0]["name"]);
+    }
+
+    #[test]
+    fn bad_requests_are_rejected_before_calling_sl() {
+        let client = client();
+        assert_eq!(404, handle(&client, "/nope").status);
+        assert_eq!(400, handle(&client, "/search").status);
+        assert_eq!(400, handle(&client, "/search?q=slussen&limit=0").status);
+        assert_eq!(400, handle(&client, "/sites/abc/departures").status);
+        assert_eq!(
+            400,
+            handle(&client, "/sites/9192/departures?transport_mode=rocket").status
+        );
+        assert_eq!(400, handle(&client, "/board/9192?refresh=1").status);
+        assert_eq!(
+            400,
+            handle(&client, "/sites/9192/departures/stream?interval=1").status
+        );
+    }
+
+    #[test]
+    fn streams_are_handed_back_with_their_filters() {
+        let reply = handle(&client(), "/sites/9192/departures/stream?line=43");
+        let Body::Stream(stream) = reply.body else {
+            panic!("expected an event stream, got {reply:?}");
+        };
+        assert_eq!(SiteId::from(9192), stream.site_id);
+        assert_eq!(Some("43".to_string()), stream.query.line);
+        assert_eq!(Duration::from_secs(STREAM_POLL_SECONDS), stream.interval);
+    }
+
+    #[test]
+    fn events_are_framed_for_server_sent_events() {
+        let mut events = EventStream::open(Vec::new()).unwrap();
+        events.event("departures", "[]").unwrap();
+        events.heartbeat().unwrap();
+        let written = String::from_utf8(events.writer).unwrap();
+        let (head, body) = written.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Content-Type: text/event-stream"));
+        assert_eq!("event: departures\ndata: []\n\n: heartbeat\n\n", body);
+    }
+
+    #[test]
+    fn boards_escape_names_and_show_wait_times() {
+        let departures = vec![Departure {
+            destination: "Hammarby <sjöstad>".to_string(),
+            expected: datetime!(2025-05-08 10:05:00 +2),
+            line: Line {
+                designation: "43".to_string(),
+                transport_mode: TransportMode::Bus,
+                id: None,
+                group_of_lines: None,
+                transport_authority_id: None,
+            },
+            stop_point: None,
+            journey: None,
+            scheduled: None,
+            state: None,
+            display: None,
+            direction: None,
+            direction_code: None,
+            stop_area: None,
+        }];
+        let clock = FixedClock(datetime!(2025-05-08 10:01:30 +2));
+        let html = render_board("Slussen & co", &departures, 30, None, &clock);
+
+        assert!(html.contains(r#"<meta http-equiv="refresh" content="30">"#));
+        assert!(html.contains("<h1>Slussen &amp; co</h1>"));
+        assert!(html.contains("Hammarby &lt;sjöstad&gt;"));
+        assert!(html.contains(r#"<td class="time">10:05</td><td class="wait">3 min</td>"#));
+        assert!(html.contains("Updated 10:01"));
+    }
+}
@@ -0,0 +1,151 @@
+//! Network wide summary of how services are running, built from the
+//! deviations in effect right now.
+
+use crate::client::Deviation;
+use crate::types::TransportMode;
+
+/// Modes listed by `slq status`, in the order the SL app shows them
+pub const MODES: [TransportMode; 6] = [
+    TransportMode::Metro,
+    TransportMode::Train,
+    TransportMode::Tram,
+    TransportMode::Bus,
+    TransportMode::Ship,
+    TransportMode::Ferry,
+];
+
+/// SL ranks deviations from 1 to 9. Anything from here up is more than a
+/// minor inconvenience.
+const MAJOR_IMPORTANCE: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ServiceLevel {
+    Good,
+    Minor,
+    Major,
+}
+
+impl ServiceLevel {
+    pub fn label(self) -> &'static str {
+        match self {
+            ServiceLevel::Good => "good service",
+            ServiceLevel::Minor => "minor disruptions",
+            ServiceLevel::Major => "major disruptions",
+        }
+    }
+}
+
+/// How one transport mode is running
+pub struct ModeStatus<'a> {
+    pub mode: TransportMode,
+    pub level: ServiceLevel,
+    /// Affected line groups, like "Tunnelbanans gröna linje", or line
+    /// numbers for modes without groups
+    pub affected: Vec<String>,
+    /// Deviations affecting the mode, most important first
+    pub deviations: Vec<&'a Deviation>,
+}
+
+/// Sort `deviations` by the transport modes of the lines they affect. A
+/// deviation affecting several modes is listed under each of them, and
+/// deviations without lines aren't listed at all.
+pub fn summarize(deviations: &[Deviation]) -> Vec<ModeStatus<'_>> {
+    MODES
+        .iter()
+        .map(|&mode| {
+            let mut affected: Vec<String> = Vec::new();
+            let mut matching: Vec<&Deviation> = Vec::new();
+            for deviation in deviations {
+                let lines: Vec<_> = deviation
+                    .scope
+                    .lines
+                    .iter()
+                    .filter(|line| line.transport_mode == Some(mode))
+                    .collect();
+                if lines.is_empty() {
+                    continue;
+                }
+                for line in lines {
+                    let name = line
+                        .group_of_lines
+                        .clone()
+                        .filter(|_| mode != TransportMode::Bus)
+                        .unwrap_or_else(|| line.designation.clone());
+                    if !affected.contains(&name) {
+                        affected.push(name);
+                    }
+                }
+                matching.push(deviation);
+            }
+            matching.sort_by_key(|deviation| std::cmp::Reverse(deviation.importance()));
+
+            let level = match matching.first().map(|deviation| deviation.importance()) {
+                None => ServiceLevel::Good,
+                Some(importance) if importance >= MAJOR_IMPORTANCE => ServiceLevel::Major,
+                Some(_) => ServiceLevel::Minor,
+            };
+            ModeStatus {
+                mode,
+                level,
+                affected,
+                deviations: matching,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deviation(importance: u8, lines: &str) -> Deviation {
+        serde_json::from_str(&format!(
+            r#"{{
+                "publish": {{"from": "2025-05-16T06:00:00.000+02:00"}},
+                "message_variants": [{{"header": "Delays", "language": "en"}}],
+                "priority": {{"importance_level": {importance}, "influence_level": 3}},
+                "scope": {{"lines": {lines}}}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn modes_are_rated_by_their_worst_deviation() {
+        let deviations = vec![
+            deviation(
+                3,
+                r#"[{"designation": "17", "transport_mode": "METRO", "group_of_lines": "Tunnelbanans gröna linje"},
+                    {"designation": "18", "transport_mode": "METRO", "group_of_lines": "Tunnelbanans gröna linje"}]"#,
+            ),
+            deviation(
+                8,
+                r#"[{"designation": "43", "transport_mode": "BUS", "group_of_lines": "Innerstadsbussar"},
+                    {"designation": "13", "transport_mode": "METRO", "group_of_lines": "Tunnelbanans röda linje"}]"#,
+            ),
+            deviation(2, r#"[{"designation": "4", "transport_mode": "BUS"}]"#),
+            deviation(9, "[]"),
+        ];
+        let statuses = summarize(&deviations);
+
+        let metro = &statuses[0];
+        assert_eq!(TransportMode::Metro, metro.mode);
+        assert_eq!(ServiceLevel::Major, metro.level);
+        assert_eq!(
+            vec!["Tunnelbanans gröna linje", "Tunnelbanans röda linje"],
+            metro.affected
+        );
+        assert_eq!(2, metro.deviations.len());
+
+        let bus = statuses
+            .iter()
+            .find(|status| status.mode == TransportMode::Bus)
+            .unwrap();
+        assert_eq!(vec!["43", "4"], bus.affected);
+        assert_eq!(8, bus.deviations[0].importance());
+
+        let train = &statuses[1];
+        assert_eq!(ServiceLevel::Good, train.level);
+        assert!(train.deviations.is_empty());
+    }
+}
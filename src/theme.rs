@@ -0,0 +1,244 @@
+//! Colors of departure boards in text output, set up under `[theme]` in the
+//! config file. Without one, waits go from green to red as they get close
+//! and lines are drawn in SL's colors.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::client::Line;
+use crate::types::TransportMode;
+
+/// A color for text in the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Color {
+    /// Whatever the terminal draws text in, `none` in the config file
+    Plain,
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    /// One of the terminal's 256 colors
+    Fixed(u8),
+    /// `#rrggbb`, for terminals with true color
+    Rgb(u8, u8, u8),
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let color = match value.to_ascii_lowercase().as_str() {
+            "none" => Color::Plain,
+            "black" => Color::Black,
+            "red" => Color::Red,
+            "green" => Color::Green,
+            "yellow" => Color::Yellow,
+            "blue" => Color::Blue,
+            "magenta" | "pink" => Color::Magenta,
+            "cyan" => Color::Cyan,
+            "white" => Color::White,
+            hex if hex.len() == 7 && hex.starts_with('#') => {
+                let channel = |at: usize| u8::from_str_radix(&hex[at..at + 2], 16).ok();
+                match (channel(1), channel(3), channel(5)) {
+                    (Some(r), Some(g), Some(b)) => Color::Rgb(r, g, b),
+                    _ => return Err(not_a_color(value)),
+                }
+            }
+            number => Color::Fixed(number.parse().map_err(|_| not_a_color(value))?),
+        };
+        Ok(color)
+    }
+}
+
+fn not_a_color(value: &str) -> String {
+    format!("'{value}' is not a color, use a name like `red`, a number up to 255 or `#rrggbb`")
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Wrap text in the escape codes for a color
+pub fn paint(text: &str, color: Color) -> String {
+    let code = match color {
+        Color::Plain => return text.to_string(),
+        Color::Black => "30".to_string(),
+        Color::Red => "31".to_string(),
+        Color::Green => "32".to_string(),
+        Color::Yellow => "33".to_string(),
+        Color::Blue => "34".to_string(),
+        Color::Magenta => "35".to_string(),
+        Color::Cyan => "36".to_string(),
+        Color::White => "37".to_string(),
+        Color::Fixed(n) => format!("38;5;{n}"),
+        Color::Rgb(r, g, b) => format!("38;2;{r};{g};{b}"),
+    };
+    format!("\x1b[{code}m{text}\x1b[0m")
+}
+
+/// Colors of a departure board
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Theme {
+    /// The wait is `soon` with fewer minutes than this to spare after
+    /// walking to the stop
+    pub soon_minutes: i64,
+    /// The wait is `near` with fewer minutes than this to spare, and
+    /// `later` otherwise
+    pub near_minutes: i64,
+    pub soon: Color,
+    pub near: Color,
+    pub later: Color,
+    pub time: Color,
+    pub mode: Color,
+    pub destination: Color,
+    /// Line colors by transport mode, instead of SL's
+    pub modes: BTreeMap<TransportMode, Color>,
+    /// Line colors by designation, e.g. `"43" = "#e06c75"`, ahead of `modes`
+    pub lines: BTreeMap<String, Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            soon_minutes: 2,
+            near_minutes: 5,
+            soon: Color::Red,
+            near: Color::Yellow,
+            later: Color::Green,
+            time: Color::Plain,
+            mode: Color::Plain,
+            destination: Color::Plain,
+            modes: BTreeMap::new(),
+            lines: BTreeMap::new(),
+        }
+    }
+}
+
+impl Theme {
+    /// The color of a wait with `spare_minutes` left after walking to the stop
+    pub fn wait(&self, spare_minutes: i64) -> Color {
+        if spare_minutes < self.soon_minutes {
+            self.soon
+        } else if spare_minutes < self.near_minutes {
+            self.near
+        } else {
+            self.later
+        }
+    }
+
+    /// The color of a line: its own from `lines`, its transport mode's from
+    /// `modes`, or the one SL paints it in
+    pub fn line(&self, line: &Line) -> Color {
+        self.lines
+            .get(&line.designation)
+            .or_else(|| self.modes.get(&line.transport_mode))
+            .copied()
+            .unwrap_or_else(|| sl_line_color(line))
+    }
+}
+
+/// The color SL paints a line in: metro lines in the color of their line,
+/// commuter trains pink, trams yellow, buses red and boats cyan
+fn sl_line_color(line: &Line) -> Color {
+    match line.transport_mode {
+        TransportMode::Metro => {
+            let group = line.group_of_lines.as_deref().unwrap_or_default();
+            let number = line
+                .designation
+                .trim_end_matches(|c: char| !c.is_ascii_digit());
+            if group.contains("gröna") || matches!(number, "17" | "18" | "19") {
+                Color::Green
+            } else if group.contains("röda") || matches!(number, "13" | "14") {
+                Color::Red
+            } else if group.contains("blå") || matches!(number, "10" | "11") {
+                Color::Blue
+            } else {
+                Color::Plain
+            }
+        }
+        TransportMode::Train => Color::Magenta,
+        TransportMode::Tram => Color::Yellow,
+        TransportMode::Bus => Color::Red,
+        TransportMode::Ferry | TransportMode::Ship => Color::Cyan,
+        TransportMode::Taxi | TransportMode::Other => Color::Plain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(designation: &str, transport_mode: TransportMode, group: Option<&str>) -> Line {
+        Line {
+            designation: designation.to_string(),
+            transport_mode,
+            id: None,
+            group_of_lines: group.map(str::to_string),
+            transport_authority_id: None,
+        }
+    }
+
+    #[test]
+    fn metro_lines_get_the_color_of_their_line() {
+        let theme = Theme::default();
+        let metro = TransportMode::Metro;
+        assert_eq!(Color::Green, theme.line(&line("17", metro, None)));
+        assert_eq!(Color::Red, theme.line(&line("14", metro, None)));
+        assert_eq!(
+            Color::Blue,
+            theme.line(&line("12", metro, Some("Tunnelbanans blå linje")))
+        );
+        assert_eq!(Color::Plain, theme.line(&line("20", metro, None)));
+        assert_eq!(
+            Color::Magenta,
+            theme.line(&line("43", TransportMode::Train, None))
+        );
+        assert_eq!("\x1b[33m3 min\x1b[0m", paint("3 min", theme.wait(3)));
+        assert_eq!(Color::Red, theme.wait(-1));
+        assert_eq!(Color::Green, theme.wait(5));
+    }
+
+    #[test]
+    fn themes_are_read_from_the_config_file() {
+        let theme: Theme = toml::from_str(
+            r##"
+            soon_minutes = 3
+            later = "none"
+            destination = "245"
+            modes = { bus = "#0089ca" }
+            lines = { "43" = "white" }
+            "##,
+        )
+        .unwrap();
+        assert_eq!(Color::Red, theme.wait(2));
+        assert_eq!(Color::Plain, theme.wait(10));
+        assert_eq!(Color::Fixed(245), theme.destination);
+        assert_eq!(
+            Color::Rgb(0, 0x89, 0xca),
+            theme.line(&line("4", TransportMode::Bus, None))
+        );
+        assert_eq!(
+            Color::White,
+            theme.line(&line("43", TransportMode::Bus, None))
+        );
+        assert_eq!(
+            "\x1b[38;2;0;137;202m4\x1b[0m",
+            paint("4", theme.line(&line("4", TransportMode::Bus, None)))
+        );
+
+        let error = toml::from_str::<Theme>(r##"soon = "#12""##).unwrap_err();
+        assert!(error.to_string().contains("'#12' is not a color"));
+    }
+}
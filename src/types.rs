@@ -1,14 +1,198 @@
+use std::fmt;
+use std::str::FromStr;
+
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum TransportMode {
+    #[serde(alias = "bus")]
     Bus,
+    #[serde(alias = "tram")]
     Tram,
+    #[serde(alias = "metro")]
     Metro,
+    #[serde(alias = "train")]
     Train,
+    #[serde(alias = "ferry")]
     Ferry,
+    #[serde(alias = "ship")]
     Ship,
+    #[serde(alias = "taxi")]
     Taxi,
+    /// A mode SL has started using that slq doesn't know about yet, so one
+    /// new kind of vehicle doesn't break the whole board
+    #[serde(other)]
+    #[value(skip)]
+    Other,
+}
+
+/// The id of an SL site, e.g. `9192` for Slussen. Departure boards and
+/// deviations are fetched per site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SiteId(u32);
+
+impl SiteId {
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// Ids that come from SL itself, like those in the site list, are trusted as
+/// they are. Ids typed by a user go through [`FromStr`] instead.
+impl From<u32> for SiteId {
+    fn from(id: u32) -> SiteId {
+        SiteId(id)
+    }
+}
+
+impl FromStr for SiteId {
+    type Err = String;
+
+    /// A positive whole number, with nothing around it. `+9192`, `09192.0`
+    /// or `0` are names as far as slq is concerned, not site ids.
+    fn from_str(value: &str) -> Result<SiteId, String> {
+        let invalid = || format!("'{value}' is not a site id, those look like 9192");
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        match value.parse::<u32>() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(id) => Ok(SiteId(id)),
+        }
+    }
+}
+
+impl fmt::Display for SiteId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Output format for commands that can emit machine readable data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Csv,
+    Geojson,
+    /// A Home Assistant sensor, see [`crate::output::hass_sensor`]
+    Hass,
+    /// A custom module for Waybar, with the next departure as text and the
+    /// ones after it in the tooltip
+    Waybar,
+    /// A single line for polybar, i3blocks and similar, e.g. `43→Ruddammen 4m`
+    Statusbar,
+    /// A `<table>` to embed in a web page
+    Html,
+    /// A table for wikis and chat messages
+    Markdown,
+    /// One JSON object per line for every refresh of the board, to stream
+    /// with `--watch`
+    Ndjson,
+}
+
+/// File format for `slq history export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+    /// Needs slq built with the `parquet` feature
+    Parquet,
+}
+
+/// A column of a departure board in text output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Column {
+    /// Time left until it leaves, e.g. `3m`
+    Wait,
+    /// Expected time of day
+    Time,
+    Line,
+    /// Transport mode, e.g. `Metro`
+    Mode,
+    Destination,
+    /// Journey id, for `slq track`
+    Journey,
+}
+
+impl Column {
+    /// The columns of a board unless `--columns` says otherwise
+    pub const DEFAULT: [Column; 5] = [
+        Column::Wait,
+        Column::Time,
+        Column::Line,
+        Column::Mode,
+        Column::Destination,
+    ];
+}
+
+/// When to color text output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// When writing to a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Order of `slq search` results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SearchSort {
+    /// Exact matches first, then names starting with the query, then names
+    /// with a word starting with it, then the rest
+    #[default]
+    Relevance,
+    /// Alphabetically
+    Name,
+    /// Closest to `--near` first
+    Distance,
+}
+
+/// Language used for dates and service messages in human readable output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Language {
+    #[default]
+    #[value(name = "en", alias = "english")]
+    English,
+    #[value(name = "sv", alias = "swedish")]
+    Swedish,
+}
+
+impl Language {
+    /// ISO 639-1 code, as used by the SL APIs
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Swedish => "sv",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn site_ids_are_positive_whole_numbers() {
+        assert_eq!(Ok(SiteId(9192)), "9192".parse());
+        assert_eq!(Ok(SiteId(9192)), "09192".parse());
+        for invalid in [
+            "",
+            "0",
+            "-1",
+            "+9192",
+            "91.92",
+            " 9192",
+            "Slussen",
+            "99999999999",
+        ] {
+            assert!(invalid.parse::<SiteId>().is_err(), "{invalid}");
+        }
+    }
 }
@@ -0,0 +1,232 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use reqwest::blocking::Client;
+use reqwest::header::USER_AGENT;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+pub const LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/divanvisagie/slq/releases/latest";
+
+/// Version of the running binary
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A published GitHub release
+#[derive(Deserialize, Debug)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// The version the release was tagged with, without the leading `v`
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    /// Whether the release is newer than the running binary
+    pub fn is_newer(&self) -> bool {
+        is_newer(self.version(), CURRENT_VERSION)
+    }
+
+    /// The binary built for this platform, see [`asset_name`]
+    pub fn asset(&self) -> Option<&Asset> {
+        let name = asset_name();
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+
+    /// The SHA-256 published next to `asset`, named like it with `.sha256`
+    /// added, in the format `sha256sum` writes
+    pub fn checksum(&self, asset: &Asset) -> Option<&Asset> {
+        let name = format!("{}.sha256", asset.name);
+        self.assets.iter().find(|checksum| checksum.name == name)
+    }
+}
+
+/// Name of the release asset for the platform slq runs on, e.g.
+/// `slq-x86_64-linux` or `slq-aarch64-macos`. `make publish` uploads the
+/// binaries under these names.
+pub fn asset_name() -> String {
+    format!(
+        "slq-{}-{}{}",
+        env::consts::ARCH,
+        env::consts::OS,
+        env::consts::EXE_SUFFIX
+    )
+}
+
+/// Look up the latest release on GitHub.
+pub fn latest_release(http: &Client) -> Result<Release> {
+    http.get(LATEST_RELEASE_URL)
+        // GitHub rejects API requests without a user agent
+        .header(USER_AGENT, format!("slq/{CURRENT_VERSION}"))
+        .send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|res| res.json())
+        .context("failed to look up the latest slq release on GitHub")
+}
+
+fn download(http: &Client, asset: &Asset) -> Result<reqwest::blocking::Response> {
+    http.get(&asset.browser_download_url)
+        .header(USER_AGENT, format!("slq/{CURRENT_VERSION}"))
+        .send()
+        .and_then(|res| res.error_for_status())
+        .with_context(|| format!("failed to download {}", asset.name))
+}
+
+/// Download `asset` and put it in place of the binary at `exe`, once it
+/// matches the SHA-256 in `checksum`. The new binary is written next to the
+/// old one and renamed over it, so an interrupted or tampered download never
+/// leaves a broken `slq` behind.
+pub fn install(http: &Client, asset: &Asset, checksum: &Asset, exe: &Path) -> Result<()> {
+    let expected = download(http, checksum)?
+        .text()
+        .with_context(|| format!("failed to download {}", checksum.name))?;
+    let expected = parse_checksum(&expected)
+        .with_context(|| format!("{} is not a SHA-256 checksum", checksum.name))?;
+
+    let mut res = download(http, asset)?;
+    let download = exe.with_extension("download");
+    let written = fs::File::create(&download)
+        .and_then(|mut file| io::copy(&mut res, &mut file))
+        .with_context(|| format!("failed to write {}", download.display()))
+        .and_then(|_| verify(&download, &expected, &asset.name));
+    if let Err(err) = written.and_then(|_| make_executable(&download)) {
+        let _ = fs::remove_file(&download);
+        return Err(err);
+    }
+
+    // Windows won't replace a running executable, but it will rename one
+    if cfg!(windows) {
+        let old = exe.with_extension("old");
+        let _ = fs::remove_file(&old);
+        fs::rename(exe, &old).with_context(|| format!("failed to move {}", exe.display()))?;
+    }
+    fs::rename(&download, exe).with_context(|| format!("failed to replace {}", exe.display()))
+}
+
+/// The hex digest at the start of a `sha256sum` line, lowercased
+fn parse_checksum(text: &str) -> Result<String> {
+    let digest = text.split_whitespace().next().unwrap_or_default();
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("expected 64 hex digits");
+    }
+    Ok(digest.to_ascii_lowercase())
+}
+
+/// Refuse a downloaded file whose SHA-256 isn't `expected`
+fn verify(path: &Path, expected: &str, name: &str) -> Result<()> {
+    let mut hasher = Sha256::new();
+    fs::File::open(path)
+        .and_then(|mut file| io::copy(&mut file, &mut hasher))
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let actual = hex(&hasher.finalize());
+    if actual != expected {
+        bail!("{name} has SHA-256 {actual} but the release says {expected}, not installing it");
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("failed to make {} executable", path.display()))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Compare two `major.minor.patch` versions. Anything that doesn't parse
+/// is never considered newer.
+fn is_newer(candidate: &str, current: &str) -> bool {
+    match (parse_version(candidate), parse_version(current)) {
+        (Ok(candidate), Ok(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+fn parse_version(version: &str) -> Result<(u64, u64, u64)> {
+    let parts = version
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()
+        .with_context(|| format!("'{version}' is not a version number"))?;
+    match parts[..] {
+        [major, minor, patch] => Ok((major, minor, patch)),
+        _ => bail!("'{version}' is not a major.minor.patch version"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert!(is_newer("0.10.0", "0.9.3"));
+        assert!(is_newer("1.0.0", "0.5.0"));
+        assert!(!is_newer("0.5.0", "0.5.0"));
+        assert!(!is_newer("0.4.9", "0.5.0"));
+        assert!(!is_newer("nightly", "0.5.0"));
+    }
+
+    #[test]
+    fn downloads_must_match_their_checksum() {
+        let path = env::temp_dir().join(format!("slq-verify-{}", std::process::id()));
+        fs::write(&path, "hello\n").unwrap();
+        let line =
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  slq-x86_64-linux\n";
+        let expected = parse_checksum(line).unwrap();
+        assert!(verify(&path, &expected, "slq").is_ok());
+        assert!(verify(&path, &"0".repeat(64), "slq").is_err());
+        assert!(parse_checksum("not a checksum").is_err());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn release_asset_is_picked_by_platform() {
+        let release = Release {
+            tag_name: "v9.0.0".to_string(),
+            assets: vec![
+                Asset {
+                    name: "slq-sparc-plan9".to_string(),
+                    browser_download_url: "https://example.com/plan9".to_string(),
+                },
+                Asset {
+                    name: asset_name(),
+                    browser_download_url: "https://example.com/here".to_string(),
+                },
+                Asset {
+                    name: format!("{}.sha256", asset_name()),
+                    browser_download_url: "https://example.com/here.sha256".to_string(),
+                },
+            ],
+        };
+        assert_eq!("9.0.0", release.version());
+        assert!(release.is_newer());
+        let asset = release.asset().unwrap();
+        assert_eq!("https://example.com/here", asset.browser_download_url);
+        assert_eq!(
+            "https://example.com/here.sha256",
+            release.checksum(asset).unwrap().browser_download_url
+        );
+    }
+}
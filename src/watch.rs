@@ -0,0 +1,173 @@
+//! Deviations already reported by `slq deviations --watch`, kept in a state
+//! file so only new disruptions, or ones that have become more serious, are
+//! reported again, even across restarts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::client::Deviation;
+use crate::config::data_dir;
+
+/// What changed about a deviation since it was last seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    New,
+    /// SL raised its importance level from `from`
+    Escalated {
+        from: u8,
+    },
+}
+
+/// Importance levels of the deviations seen so far, keyed by deviation
+pub struct SeenDeviations {
+    path: Option<PathBuf>,
+    seen: BTreeMap<String, u8>,
+}
+
+impl SeenDeviations {
+    /// `$SLQ_DATA_DIR/deviations.json`, or `$XDG_DATA_HOME/slq/deviations.json`
+    /// (falling back to `~/.local/share/slq/deviations.json`).
+    pub fn path() -> Option<PathBuf> {
+        Some(data_dir()?.join("deviations.json"))
+    }
+
+    /// The deviations seen by earlier runs. A missing state file means
+    /// nothing has been seen yet, and a damaged one is started over.
+    pub fn load() -> SeenDeviations {
+        let path = SeenDeviations::path();
+        let seen = path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|contents| match serde_json::from_slice(&contents) {
+                Ok(seen) => Some(seen),
+                Err(err) => {
+                    eprintln!("Warning: ignoring the damaged deviation state file: {err}");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        SeenDeviations { path, seen }
+    }
+
+    /// State that is never written to disk
+    pub fn in_memory() -> SeenDeviations {
+        SeenDeviations {
+            path: None,
+            seen: BTreeMap::new(),
+        }
+    }
+
+    /// Record `deviations` as the ones in effect now and return those that
+    /// are new or more important than when they were last seen. Deviations
+    /// that have ended are forgotten.
+    pub fn update<'a>(&mut self, deviations: &'a [Deviation]) -> Vec<(Change, &'a Deviation)> {
+        let mut changes = Vec::new();
+        let mut current = BTreeMap::new();
+        for deviation in deviations {
+            let key = key(deviation);
+            let importance = deviation.importance();
+            match self.seen.get(&key) {
+                None => changes.push((Change::New, deviation)),
+                Some(&from) if importance > from => {
+                    changes.push((Change::Escalated { from }, deviation));
+                }
+                Some(_) => {}
+            }
+            // A deviation that calms down and flares up again is reported
+            // again, so remember the level it is at now
+            current.insert(key, importance);
+        }
+        self.seen = current;
+        changes
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        fs::write(path, serde_json::to_vec(&self.seen)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+/// SL's case id, which survives updates to the text, or the text itself for
+/// deviations without one
+fn key(deviation: &Deviation) -> String {
+    match deviation.deviation_case_id {
+        Some(id) => id.to_string(),
+        None => deviation
+            .message_variants
+            .first()
+            .map(|message| message.header.clone())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deviation(id: u64, importance: u8) -> Deviation {
+        serde_json::from_str(&format!(
+            r#"{{
+                "deviation_case_id": {id},
+                "publish": {{"from": "2025-05-16T06:00:00.000+02:00"}},
+                "message_variants": [{{"header": "Delays", "language": "en"}}],
+                "priority": {{"importance_level": {importance}, "influence_level": 3}},
+                "scope": {{"lines": [{{"designation": "43"}}]}}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    fn changes(seen: &mut SeenDeviations, deviations: &[Deviation]) -> Vec<(u64, Change)> {
+        seen.update(deviations)
+            .into_iter()
+            .map(|(change, deviation)| (deviation.deviation_case_id.unwrap(), change))
+            .collect()
+    }
+
+    #[test]
+    fn only_new_and_escalated_deviations_are_reported() {
+        let mut seen = SeenDeviations::in_memory();
+        assert_eq!(
+            vec![(1, Change::New), (2, Change::New)],
+            changes(&mut seen, &[deviation(1, 3), deviation(2, 5)])
+        );
+        assert!(changes(&mut seen, &[deviation(1, 3), deviation(2, 5)]).is_empty());
+
+        // 2 got worse, 1 calmed down and 3 is new
+        assert_eq!(
+            vec![(2, Change::Escalated { from: 5 }), (3, Change::New)],
+            changes(
+                &mut seen,
+                &[deviation(1, 2), deviation(2, 7), deviation(3, 4)]
+            )
+        );
+        assert_eq!(
+            vec![(1, Change::Escalated { from: 2 })],
+            changes(
+                &mut seen,
+                &[deviation(1, 3), deviation(2, 7), deviation(3, 4)]
+            )
+        );
+    }
+
+    #[test]
+    fn ended_deviations_are_forgotten() {
+        let mut seen = SeenDeviations::in_memory();
+        changes(&mut seen, &[deviation(1, 3)]);
+        changes(&mut seen, &[]);
+        assert_eq!(
+            vec![(1, Change::New)],
+            changes(&mut seen, &[deviation(1, 3)])
+        );
+    }
+}
@@ -0,0 +1,511 @@
+//! End to end tests of the `slq` binary against a local mock of the SL
+//! Transport API, so parsing, filtering and error handling are covered
+//! without the network.
+
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
+use predicates::prelude::*;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const DEPARTURES_9600: &str = include_str!("fixtures/departures-9600.json");
+
+/// A mock API answering `GET /sites/{site}/departures` with `response`
+async fn departures_api(site: u32, response: ResponseTemplate) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/sites/{site}/departures")))
+        .respond_with(response)
+        .mount(&server)
+        .await;
+    server
+}
+
+fn json(body: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(body, "application/json")
+}
+
+/// `slq` talking to `server`, with its own empty cache and no config file,
+/// on a machine in UTC
+fn slq(server: &MockServer) -> Command {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let run = RUNS.fetch_add(1, Ordering::Relaxed);
+    let cache = env::temp_dir().join(format!("slq-test-{}-{run}", process::id()));
+    fs::remove_dir_all(&cache).ok();
+    let mut cmd = cargo_bin_cmd!("slq");
+    cmd.env("SLQ_API_BASE", server.uri())
+        .env("SLQ_CACHE_DIR", cache)
+        .env("SLQ_CONFIG", "/nonexistent/slq/config.toml")
+        .env("TZ", "UTC")
+        .args(["--retries", "0", "--lang", "en"]);
+    cmd
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn departures_are_listed_as_csv() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args(["departures", "9600", "-f", "csv", "--show-departed"])
+        .assert()
+        .success()
+        .stdout(
+            "expected,line,transport_mode,destination,journey_id,scheduled
+2025-05-16T08:03:12+02:00,27,train,Kårsta,20250516027001,2025-05-16T08:02:00+02:00
+2025-05-16T08:05:00+02:00,28,train,Österskär,20250516028001,2025-05-16T08:05:00+02:00
+2025-05-16T08:09:00+02:00,29,train,Näsbypark,20250516029001,2025-05-16T08:09:00+02:00
+2025-05-16T08:12:00+02:00,28S,train,Vallentuna,20250516028002,2025-05-16T08:12:00+02:00
+2025-05-16T08:17:00+02:00,27,train,Lindholmen,20250516027002,2025-05-16T08:17:00+02:00
+2025-05-16T08:20:00+02:00,28,train,Österskär,20250516028003,2025-05-16T08:20:00+02:00
+",
+        );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn station_names_are_resolved_and_filters_applied() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args(["departures", "Stockholms östra", "-l", "28", "-f", "csv"])
+        .arg("--show-departed")
+        .assert()
+        .success()
+        .stdout(
+            "expected,line,transport_mode,destination,journey_id,scheduled
+2025-05-16T08:05:00+02:00,28,train,Österskär,20250516028001,2025-05-16T08:05:00+02:00
+2025-05-16T08:12:00+02:00,28S,train,Vallentuna,20250516028002,2025-05-16T08:12:00+02:00
+2025-05-16T08:20:00+02:00,28,train,Österskär,20250516028003,2025-05-16T08:20:00+02:00
+",
+        );
+
+    slq(&server)
+        .args([
+            "departures",
+            "9600",
+            "-l",
+            "28S",
+            "-f",
+            "csv",
+            "--show-departed",
+        ])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("Vallentuna").and(predicate::str::contains("Österskär").not()),
+        );
+
+    slq(&server)
+        .args([
+            "departures",
+            "9600",
+            "-d",
+            "kårsta",
+            "-t",
+            "train",
+            "-n",
+            "1",
+        ])
+        .args(["-f", "json", "--show-departed"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(r#""destination": "Kårsta""#))
+        .stdout(predicate::str::contains("Lindholmen").not());
+
+    slq(&server)
+        .args(["departures", "9600", "--operator", "14", "-f", "csv"])
+        .arg("--show-departed")
+        .assert()
+        .success()
+        .stdout("expected,line,transport_mode,destination,journey_id,scheduled\n");
+    slq(&server)
+        .args(["departures", "9600", "--operator", "UL"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "use SL or the transport authority id",
+        ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn departed_departures_are_hidden_unless_asked_for() {
+    // Every departure in the fixture left long ago
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args(["departures", "9600", "-f", "csv"])
+        .assert()
+        .success()
+        .stdout("expected,line,transport_mode,destination,journey_id,scheduled\n");
+    slq(&server)
+        .args(["departures", "9600", "-n", "1", "--show-departed"])
+        .assert()
+        .success()
+        .stdout("Departures from 9600:\ndeparted\t08:03\t27\tTrain\tKårsta\n");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn headers_can_be_left_out_for_scripts() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args([
+            "departures",
+            "9600",
+            "-n",
+            "1",
+            "--show-departed",
+            "--no-header",
+        ])
+        .assert()
+        .success()
+        .stdout("departed\t08:03\t27\tTrain\tKårsta\n");
+    slq(&server)
+        .args(["departures", "9600", "-f", "csv", "--no-header"])
+        .assert()
+        .success()
+        .stdout("");
+    slq(&server)
+        .args(["-q", "departures", "9600", "-n", "1", "--show-departed"])
+        .assert()
+        .success()
+        .stdout("departed\t08:03\t27\tTrain\tKårsta\n")
+        .stderr("");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn queries_pick_values_out_of_json() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args([
+            "departures",
+            "9600",
+            "-n",
+            "2",
+            "--show-departed",
+            "-f",
+            "json",
+        ])
+        .args(["--query", ".[].destination"])
+        .assert()
+        .success()
+        .stdout("Kårsta\nÖsterskär\n");
+    slq(&server)
+        .args(["departures", "9600", "--query", ".[0]"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("use it with --format json"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn destinations_are_cut_short_to_the_width() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args([
+            "departures",
+            "9600",
+            "-n",
+            "1",
+            "--show-departed",
+            "--width",
+            "44",
+        ])
+        .assert()
+        .success()
+        .stdout("Departures from 9600:\ndeparted\t08:03\t27\tTrain\tKår…\n");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn times_are_shown_in_the_chosen_timezone() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args(["departures", "9600", "-n", "1", "--show-departed"])
+        .args(["--timezone", "America/New_York", "--time-format", "12h"])
+        .assert()
+        .success()
+        .stdout("Departures from 9600:\ndeparted\t2:03 AM\t27\tTrain\tKårsta\n");
+    slq(&server)
+        .args(["departures", "9600", "--timezone", "Mars/Olympus_Mons"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("not in the tz database"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn raw_output_is_the_response_untouched() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args(["departures", "9600", "--raw"])
+        .assert()
+        .success()
+        .stdout(DEPARTURES_9600);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn empty_boards_only_fail_when_asked_to() {
+    let server = departures_api(9600, json(r#"{"departures": [], "stop_deviations": []}"#)).await;
+    slq(&server)
+        .args(["departures", "9600", "-f", "csv"])
+        .assert()
+        .success()
+        .stdout("expected,line,transport_mode,destination,journey_id,scheduled\n");
+    slq(&server)
+        .args(["departures", "9600", "--fail-if-empty"])
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains(
+            "No departures matched the filters",
+        ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_sites_are_not_found() {
+    let body = r#"{"message": "Site id is not valid", "status": 400}"#;
+    let server = departures_api(1, ResponseTemplate::new(400).set_body_string(body)).await;
+    slq(&server)
+        .args(["departures", "1"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("site 1 was not found"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn api_errors_are_explained() {
+    let body = r#"{"message": "Something broke", "status": 500}"#;
+    let server = departures_api(9600, ResponseTemplate::new(500).set_body_string(body)).await;
+    slq(&server)
+        .args(["departures", "9600"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "the SL API responded with HTTP 500: Something broke",
+        ));
+
+    let server = departures_api(9600, ResponseTemplate::new(503)).await;
+    slq(&server)
+        .args(["departures", "9600"])
+        .assert()
+        .code(4)
+        .stderr(predicate::str::contains(
+            "temporarily unavailable (HTTP 503)",
+        ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_transport_modes_are_shown_as_other() {
+    let body = r#"{"departures": [{
+        "destination": "Djurgården",
+        "expected": "2025-05-16T08:00:00",
+        "line": {"designation": "80", "transport_mode": "HOVERCRAFT"}
+    }]}"#;
+    let server = departures_api(9600, json(body)).await;
+    slq(&server)
+        .args(["departures", "9600", "--show-departed"])
+        .assert()
+        .success()
+        .stdout("Departures from 9600:\ndeparted\t08:00\t80\tOther\tDjurgården\n");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn malformed_responses_are_reported() {
+    let server = departures_api(9600, json(r#"{"departures": [{"destination": "#)).await;
+    slq(&server)
+        .args(["departures", "9600"])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains(
+            "could not understand the SL API response",
+        ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn output_goes_to_a_file() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    let dir = env::temp_dir().join(format!("slq-output-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("board.csv");
+    let departures = [
+        "departures",
+        "9600",
+        "-n",
+        "1",
+        "--show-departed",
+        "-f",
+        "csv",
+        "--no-header",
+    ];
+    let row =
+        "2025-05-16T08:03:12+02:00,27,train,Kårsta,20250516027001,2025-05-16T08:02:00+02:00\n";
+    slq(&server)
+        .args(departures)
+        .arg("-o")
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(row, fs::read_to_string(&file).unwrap());
+    slq(&server)
+        .args(departures)
+        .args(["--append", "-o"])
+        .arg(&file)
+        .assert()
+        .success();
+    assert_eq!(row.repeat(2), fs::read_to_string(&file).unwrap());
+    // A failed run leaves the last good output alone
+    slq(&server)
+        .args(["departures", "9601", "-o"])
+        .arg(&file)
+        .assert()
+        .failure();
+    assert_eq!(row.repeat(2), fs::read_to_string(&file).unwrap());
+    assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+    // Commands that don't talk to SL write there too
+    let completions = dir.join("slq.bash");
+    slq(&server)
+        .args(["completions", "bash", "-o"])
+        .arg(&completions)
+        .assert()
+        .success()
+        .stdout("");
+    assert!(
+        fs::read_to_string(&completions)
+            .unwrap()
+            .contains("complete -F _slq_with_stations")
+    );
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn watching_streams_one_record_per_line() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    let output = slq(&server)
+        .args(["departures", "9600", "-n", "1", "--show-departed"])
+        .args(["--watch", "-f", "ndjson", "--heartbeat", "1s"])
+        .timeout(std::time::Duration::from_millis(1500))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let records: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(2, records.len(), "{stdout}");
+    assert_eq!("departures", records[0]["type"]);
+    assert_eq!("Kårsta", records[0]["departures"][0]["destination"]);
+    assert_eq!("heartbeat", records[1]["type"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stations_can_be_read_from_stdin() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args(["departures", "--stdin", "-n", "1", "--show-departed"])
+        .args(["-f", "json", "--query", ".[].destination"])
+        .write_stdin("9600\n# the same again\n\n9600\n")
+        .assert()
+        .success()
+        .stdout("Kårsta\nKårsta\n");
+    let output = slq(&server)
+        .args([
+            "departures",
+            "--stdin",
+            "-n",
+            "1",
+            "--show-departed",
+            "-f",
+            "json",
+        ])
+        .write_stdin("9600\n9600\n")
+        .output()
+        .unwrap();
+    let merged: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(2, merged.as_array().map_or(0, Vec::len));
+    slq(&server)
+        .args(["departures", "-", "-n", "1", "--show-departed"])
+        .write_stdin("9600\n")
+        .assert()
+        .success()
+        .stdout("Departures from 9600:\ndeparted\t08:03\t27\tTrain\tKårsta\n");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn questions_in_plain_words_become_a_departures_query() {
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    slq(&server)
+        .args(["q", "next 2 trains to Kårsta from 9600"])
+        .assert()
+        .success()
+        .stderr("= slq departures 9600 --transport-mode train --destination Kårsta --limit 2\n");
+    slq(&server)
+        .args(["q", "next", "2", "to", "Kårsta"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("say which station"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn addresses_are_geocoded_to_the_closest_stops() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/search"))
+        .respond_with(json(
+            r#"[{ "lat": "59.3454", "lon": "18.0546", "display_name": "100, Sveavägen, Stockholm" }]"#,
+        ))
+        .mount(&server)
+        .await;
+    let config = env::temp_dir().join(format!("slq-geocoder-{}.toml", process::id()));
+    fs::write(
+        &config,
+        format!("[geocoder]\nurl = \"{}/search\"\n", server.uri()),
+    )
+    .unwrap();
+    slq(&server)
+        .env("SLQ_CONFIG", &config)
+        .args([
+            "nearby",
+            "--address",
+            "Sveavägen 100",
+            "-n",
+            "1",
+            "-f",
+            "csv",
+        ])
+        .assert()
+        .success()
+        .stderr("Closest to 100, Sveavägen, Stockholm\n")
+        .stdout(predicate::str::contains("\n1071,Frejgatan (på Sveavägen),"));
+    fs::remove_file(&config).ok();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sorting_by_distance_needs_a_position() {
+    let server = MockServer::start().await;
+    slq(&server)
+        .args(["search", "Slussen", "--sort", "distance"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--near <LAT> <LON>"));
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn sandboxed_runs_refuse_world_writable_configs() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = departures_api(9600, json(DEPARTURES_9600)).await;
+    let config = env::temp_dir().join(format!("slq-shared-{}.toml", process::id()));
+    fs::write(&config, "[aliases]\nhome = 9600\n").unwrap();
+    fs::set_permissions(&config, fs::Permissions::from_mode(0o666)).unwrap();
+    slq(&server)
+        .env("SLQ_CONFIG", &config)
+        .args(["departures", "home", "-n", "1", "--show-departed"])
+        .assert()
+        .success();
+    slq(&server)
+        .env("SLQ_CONFIG", &config)
+        .args(["--no-exec", "departures", "home"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("every user can write to it"));
+    fs::remove_file(&config).ok();
+}